@@ -0,0 +1,103 @@
+//! Compares the two ways a cache HIT can be sent to a client: as a single body (the fast path for
+//! small images) or streamed in chunks (how every HIT used to be sent). Both go through the actual
+//! HIT path of the client, loading the image from a cache in RAM, only configured differently.
+//!
+//! Alongside the timings, the number of allocations each way takes is printed before the
+//! benchmarks run.
+
+use actix_web::body::MessageBody;
+use actix_web::test::TestRequest;
+use actix_web::HttpRequest;
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use scalpel::config::AppConfig;
+use scalpel::HitBench;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Allocator that counts the allocations made through it
+struct Counting;
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Configuration of a client that sends HITs up to `small_hit_kibibytes` as a single body
+fn config(small_hit_kibibytes: u64) -> AppConfig {
+    serde_yaml::from_str(&format!(
+        "
+client_secret: secret
+max_grace_period: -1
+cache_size_mebibytes: 1024
+cache_engine: none
+port: 443
+bind_address: 127.0.0.1
+keep_alive: 30
+enforce_secure_tls: false
+disable_ssl: true
+token_policy: disabled
+worker_threads: 1
+small_hit_kibibytes: {}
+",
+        small_hit_kibibytes
+    ))
+    .expect("bench config")
+}
+
+/// Responds to a HIT and reads its entire body chunk by chunk without copying it, like the server
+/// does when writing it to a connection. Returns the length of the body.
+fn send(rt: &tokio::runtime::Runtime, hits: &HitBench, req: &HttpRequest) -> usize {
+    rt.block_on(async {
+        let mut body = hits.hit(req).await.into_body();
+        let mut len = 0;
+        while let Some(chunk) =
+            futures::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await
+        {
+            len += chunk.expect("body is readable").len();
+        }
+        len
+    })
+}
+
+fn small_hit(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("runtime");
+    let req = TestRequest::default().to_http_request();
+    // streaming every HIT, or sending all of the benchmarked sizes as a single body
+    let ways = [("streamed", 0), ("single", 128)];
+
+    let mut group = c.benchmark_group("small_hit");
+    for &size in &[4 * 1024, 32 * 1024, 128 * 1024] {
+        let image = Bytes::from(vec![0u8; size]);
+        for &(name, small_hit_kibibytes) in ways.iter() {
+            let hits = rt.block_on(HitBench::new(config(small_hit_kibibytes), image.clone()));
+
+            // the first response initializes some statics, which shouldn't be counted
+            send(&rt, &hits, &req);
+            let before = ALLOCATIONS.load(Ordering::Relaxed);
+            send(&rt, &hits, &req);
+            let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+            println!("{}/{}: {} allocations", name, size, allocations);
+
+            group.bench_with_input(BenchmarkId::new(name, size), &hits, |b, hits| {
+                b.iter(|| send(&rt, hits, &req))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, small_hit);
+criterion_main!(benches);
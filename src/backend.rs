@@ -2,6 +2,7 @@ use crate::config::AppConfig;
 use crate::utils::constants as c;
 use crate::utils::Secret;
 use arc_swap::ArcSwap;
+use async_trait::async_trait;
 use lazy_static::lazy_static;
 use std::sync::Arc;
 use std::time::Duration;
@@ -42,6 +43,18 @@ pub struct TlsPayload {
     pub(crate) certificate: String,
 }
 
+impl TlsPayload {
+    /// Creates a payload from a PEM encoded private key and certificate (chain), for backends that
+    /// don't receive the certificate from the MD@Home API
+    pub fn new(created_at: String, private_key: String, certificate: String) -> Self {
+        Self {
+            created_at,
+            private_key,
+            certificate,
+        }
+    }
+}
+
 // custom fmt::Debug implementation so that when debug printing PingResponse it won't print data
 // that wouldn't make any sense
 impl std::fmt::Debug for TlsPayload {
@@ -81,6 +94,7 @@ impl std::fmt::Display for BackendError {
 }
 impl std::error::Error for BackendError {}
 
+/// Information from the last successful ping to the backend
 #[derive(Debug)]
 pub struct PingStore {
     tls: TlsPayload,
@@ -89,18 +103,75 @@ pub struct PingStore {
     pub upstream_url: url::Url,
     pub client_url: url::Url,
 }
-pub struct Backend {
+impl PingStore {
+    pub fn new(
+        tls: TlsPayload,
+        token_key: String,
+        upstream_url: url::Url,
+        client_url: url::Url,
+    ) -> Self {
+        Self {
+            tls,
+            token_key,
+            upstream_url,
+            client_url,
+        }
+    }
+
+    /// The TLS certificate the client should serve
+    pub fn certificate(&self) -> &TlsPayload {
+        &self.tls
+    }
+}
+#[cfg(test)]
+impl PingStore {
+    /// Creates ping info that points at the upstream URL provided, for use in tests
+    pub(crate) fn for_tests(upstream_url: url::Url) -> Self {
+        Self {
+            tls: TlsPayload {
+                created_at: String::new(),
+                private_key: String::new(),
+                certificate: String::new(),
+            },
+            token_key: String::new(),
+            client_url: upstream_url.clone(),
+            upstream_url,
+        }
+    }
+}
+
+/// Interactions with the MD@Home control plane (the "backend").
+///
+/// Each version of the backend API gets its own implementation, so that the rest of the client
+/// doesn't need to change when the API does.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Pings the backend, which also registers the client on the first ping. Returns the TLS
+    /// certificate and the key that tokens are verified with, each only if it's new.
+    async fn ping(
+        &self,
+    ) -> Result<(Option<TlsPayload>, Option<String>), Box<dyn std::error::Error>>;
+
+    /// Tells the backend that the client is stopping, deregistering it
+    async fn stop(&self) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Information from the last successful ping, or `None` if there hasn't been one yet
+    fn ping_info(&self) -> Arc<Option<PingStore>>;
+}
+
+/// [`Backend`] for the current version of the MD@Home API (see [`c::SPEC`])
+pub struct ApiBackend {
     config: Arc<AppConfig>,
     client: reqwest::Client,
 
-    pub ping_info: ArcSwap<Option<PingStore>>,
+    ping_info: ArcSwap<Option<PingStore>>,
 }
 
 lazy_static! {
     static ref BASE_URL: reqwest::Url = url::Url::parse("https://api.mangadex.network").unwrap();
 }
-impl Backend {
-    /// Creates a skeleton [`Backend`] that is ready to start pinging. If no ping has been
+impl ApiBackend {
+    /// Creates a skeleton [`ApiBackend`] that is ready to start pinging. If no ping has been
     /// completed yet, then all getters will return `None`.
     pub fn new(config: Arc<AppConfig>) -> Self {
         Self {
@@ -116,43 +187,84 @@ impl Backend {
         }
     }
 
+    /// Structures the JSON request of a ping using the configuration
+    fn ping_request(&self) -> PingRequest {
+        // find the tls_created_at field from the last ping info
+        let last_ping = self.ping_info.load();
+        let tls_created_at = last_ping
+            .as_ref()
+            .as_ref()
+            .map(|x| x.tls.created_at.clone());
+
+        PingRequest {
+            secret: Secret::clone(&self.config.client_secret),
+            disk_space: self.config.cache_size_mebibytes as u64 * 1024 * 1024,
+            port: self.config.external_port.unwrap_or(self.config.port),
+            network_speed: self
+                .config
+                .external_max_speed
+                .map(|x| x as u64 * 1000 / 8)
+                .unwrap_or(0),
+            build_version: c::SPEC,
+            ip_address: self.config.external_ip.clone(),
+            tls_created_at,
+        }
+    }
+
+    /// Updates the internal structures based on the OK response in [`ping`](Backend::ping)
+    ///
+    /// Returns Some(token_key) if there is a new token key, otherwise None
+    fn update_from_response(&self, res: &PingResponse) -> Option<String> {
+        let last_info = self.ping_info.load();
+        let last_info = Option::as_ref(&last_info);
+
+        // find whether or not we have a new token key
+        let new_token_key = match last_info {
+            Some(x) => x.token_key != res.token_key,
+            // if this is the initial ping, then we do have a new token key
+            _ => true,
+        };
+
+        let info = PingStore {
+            // either use the new TlsPayload or the one from the previous ping
+            //
+            // this should only panic if there is no TlsPayload on the previous ping *and* this
+            // ping, which means there is a critical problem.
+            tls: res
+                .tls
+                .as_ref()
+                .or_else(|| last_info.map(PingStore::certificate))
+                .map(TlsPayload::clone)
+                .unwrap(),
+            token_key: res.token_key.clone(),
+
+            // NOTE: if the below fails, there's something seriously wrong
+            upstream_url: url::Url::parse(&res.image_server)
+                .expect("url parse: malformed image_server"),
+            client_url: url::Url::parse(&res.url).expect("url parse: malformed url"),
+        };
+        self.ping_info.store(Arc::new(Some(info)));
+
+        if new_token_key {
+            Some(res.token_key.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for ApiBackend {
     /// Pings the backend with a created payload and returns a TLS payload (if it's new) and a
     /// token key (if it's new)
     ///
     /// This function will handle all mutability by using interior mutability that is thread safe
-    /// (using [`RwLock`]s). This is the only function in the entire implementation that will lock
-    /// the `RwLock`s.
-    ///
-    /// # Panic
-    ///
-    /// This function may panic if any of the `RwLock`s are considered poisoned, as this function
-    /// will unwrap all `RwLock`s that it is writing to or reading from
-    pub async fn ping(
+    /// (using [`ArcSwap`]). This is the only function in the entire implementation that will swap
+    /// the ping info. Swapping is lock-free, so there are no locks that could be poisoned.
+    async fn ping(
         &self,
     ) -> Result<(Option<TlsPayload>, Option<String>), Box<dyn std::error::Error>> {
-        // structure JSON request using configuration
-        let payload = {
-            // find the tls_created_at field from the last ping info
-            let last_ping = self.ping_info.load();
-            let tls_created_at = last_ping
-                .as_ref()
-                .as_ref()
-                .map(|x| x.tls.created_at.clone());
-
-            PingRequest {
-                secret: Secret::clone(&self.config.client_secret),
-                disk_space: self.config.cache_size_mebibytes as u64 * 1024 * 1024,
-                port: self.config.external_port.unwrap_or(self.config.port),
-                network_speed: self
-                    .config
-                    .external_max_speed
-                    .map(|x| x as u64 * 1000 / 8)
-                    .unwrap_or(0),
-                build_version: c::SPEC,
-                ip_address: self.config.external_ip.clone(),
-                tls_created_at,
-            }
-        };
+        let payload = self.ping_request();
         log::debug!("sending ping payload to server: {:?}", &payload);
 
         // format URL and make the request to the server (handling any errors that happen in the
@@ -199,52 +311,11 @@ impl Backend {
         Ok((client_setup.tls, new_token_key))
     }
 
-    /// Updates the internal structures based on the OK response in [`ping`](Self::ping)
-    ///
-    /// Returns Some(token_key) if there is a new token key, otherwise None
-    fn update_from_response(&self, res: &PingResponse) -> Option<String> {
-        let last_info = self.ping_info.load();
-        let last_info = Option::as_ref(&last_info);
-
-        // find whether or not we have a new token key
-        let new_token_key = match last_info {
-            Some(x) => x.token_key != res.token_key,
-            // if this is the initial ping, then we do have a new token key
-            _ => true,
-        };
-
-        let info = PingStore {
-            // either use the new TlsPayload or the one from the previous ping
-            //
-            // this should only panic if there is no TlsPayload on the previous ping *and* this
-            // ping, which means there is a critical problem.
-            tls: res
-                .tls
-                .as_ref()
-                .or_else(|| last_info.map(|x| &x.tls))
-                .map(TlsPayload::clone)
-                .unwrap(),
-            token_key: res.token_key.clone(),
-
-            // NOTE: if the below fails, there's something seriously wrong
-            upstream_url: url::Url::parse(&res.image_server)
-                .expect("url parse: malformed image_server"),
-            client_url: url::Url::parse(&res.url).expect("url parse: malformed url"),
-        };
-        self.ping_info.store(Arc::new(Some(info)));
-
-        if new_token_key {
-            Some(res.token_key.clone())
-        } else {
-            None
-        }
-    }
-
     /// Pings the backend API alerting to the stop of the client
     ///
     /// This function does not modify the internal state, therefore doesn't lock any of the
     /// internal structures at all.
-    pub async fn stop(&self) -> Result<(), Box<dyn std::error::Error>> {
+    async fn stop(&self) -> Result<(), Box<dyn std::error::Error>> {
         // create payload for JSON request
         let payload = StopRequest {
             secret: Secret(&self.config.client_secret),
@@ -273,4 +344,164 @@ impl Backend {
             ))),
         }
     }
+
+    fn ping_info(&self) -> Arc<Option<PingStore>> {
+        self.ping_info.load_full()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cache::tests::TestCache;
+    use crate::config::tests::config_with;
+    use crate::GlobalState;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Backend that never leaves the process, counting the calls made to it
+    #[derive(Default)]
+    pub(crate) struct MockBackend {
+        info: ArcSwap<Option<PingStore>>,
+        /// the token key that pings respond with
+        token_key: String,
+        pings: AtomicUsize,
+        stops: AtomicUsize,
+        /// how many of the first stops fail, like when the backend is briefly unreachable
+        failed_stops: usize,
+    }
+
+    impl MockBackend {
+        /// Creates a backend that was already pinged, pointing the client at `upstream_url`
+        pub(crate) fn with_upstream(upstream_url: url::Url) -> Self {
+            let backend = Self::default();
+            let info = PingStore::for_tests(upstream_url);
+            backend.info.store(Arc::new(Some(info)));
+            backend
+        }
+    }
+
+    #[async_trait]
+    impl Backend for MockBackend {
+        async fn ping(
+            &self,
+        ) -> Result<(Option<TlsPayload>, Option<String>), Box<dyn std::error::Error>> {
+            self.pings.fetch_add(1, Ordering::SeqCst);
+            // only the first ping hands out the certificate and token key, like the real backend
+            if self.ping_info().is_some() {
+                return Ok((None, None));
+            }
+            let upstream = url::Url::parse("https://upstream.invalid").unwrap();
+            let info = PingStore {
+                token_key: self.token_key.clone(),
+                ..PingStore::for_tests(upstream)
+            };
+            let tls = info.certificate().clone();
+            self.info.store(Arc::new(Some(info)));
+            Ok((Some(tls), Some(self.token_key.clone())))
+        }
+
+        async fn stop(&self) -> Result<(), Box<dyn std::error::Error>> {
+            if self.stops.fetch_add(1, Ordering::SeqCst) < self.failed_stops {
+                return Err("backend unreachable".into());
+            }
+            Ok(())
+        }
+
+        fn ping_info(&self) -> Arc<Option<PingStore>> {
+            self.info.load_full()
+        }
+    }
+
+    /// Points the client at `upstream_url` for fetching images. Must be called before the global
+    /// state is shared.
+    pub(crate) fn use_upstream(gs: &mut Arc<GlobalState>, upstream_url: url::Url) {
+        let backend = MockBackend::with_upstream(upstream_url);
+        Arc::get_mut(gs).expect("global state is shared").backend = Arc::new(backend);
+    }
+
+    #[test]
+    fn network_speed_is_sent_when_configured() {
+        let payload = |config: AppConfig| {
+            config.validate().unwrap();
+            let backend = ApiBackend::new(Arc::new(config));
+            serde_json::to_value(backend.ping_request()).unwrap()
+        };
+
+        let json = payload(config_with(""));
+        assert_eq!(json["network_speed"], 0);
+        let json = payload(config_with("external_max_speed: 8000\n"));
+        assert_eq!(json["network_speed"], 1_000_000);
+
+        // 0 turns the limiter off, like leaving it unset
+        let json = payload(config_with("external_max_speed: 0\n"));
+        assert_eq!(json["network_speed"], 0);
+    }
+
+    #[tokio::test]
+    async fn lifecycle_drives_backend() {
+        let (token_key, token) = crate::tokens::tests::key_and_token("chapter");
+        let backend = Arc::new(MockBackend {
+            token_key,
+            ..Default::default()
+        });
+        let mut config = config_with("");
+        config.max_grace_period = -1;
+        let mut gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+        Arc::get_mut(&mut gs).unwrap().backend = Arc::clone(&backend) as _;
+        let app = crate::Node {
+            gs: Arc::clone(&gs),
+            compaction: std::sync::Mutex::new(None),
+            janitor: Arc::new(crate::cache::Janitor::new(
+                1024 * 1024,
+                Default::default(),
+                Default::default(),
+            )),
+            stop: Default::default(),
+            bytes_at_ping: Default::default(),
+            forced: tokio::sync::watch::channel(false).0,
+        };
+
+        // the first ping registers the client, handing out the certificate and token key
+        assert!(app.ping_for_cert().await.is_ok());
+        assert!(gs
+            .verifier
+            .load()
+            .verify_url_token(&token, "chapter")
+            .is_ok());
+        assert!(gs.backend.ping_info().is_some());
+
+        // later pings don't have anything new
+        assert!(app.ping_backend().await.unwrap().is_none());
+        assert_eq!(backend.pings.load(Ordering::SeqCst), 2);
+
+        app.graceful_shutdown(None).await;
+        assert_eq!(backend.stops.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_stops_are_retried() {
+        let backend = Arc::new(MockBackend {
+            failed_stops: 2,
+            ..Default::default()
+        });
+        let mut config = config_with("");
+        config.max_grace_period = -1;
+        let mut gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+        Arc::get_mut(&mut gs).unwrap().backend = Arc::clone(&backend) as _;
+        let app = crate::Node {
+            gs,
+            compaction: std::sync::Mutex::new(None),
+            janitor: Arc::new(crate::cache::Janitor::new(
+                1024 * 1024,
+                Default::default(),
+                Default::default(),
+            )),
+            stop: Default::default(),
+            bytes_at_ping: Default::default(),
+            forced: tokio::sync::watch::channel(false).0,
+        };
+
+        app.graceful_shutdown(None).await;
+        assert_eq!(backend.stops.load(Ordering::SeqCst), 3);
+    }
 }
@@ -1,9 +1,10 @@
-use super::{ImageCache, ImageEntry, ImageKey};
+use super::{CacheInfo, ImageCache, ImageEntry, ImageKey, ShrinkResult};
 use crate::config::FsConfig;
-use crate::utils::now_as_millis;
+use crate::utils::{Clock, SystemClock};
 use bytes::Bytes;
 use std::convert::TryInto;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum CacheError {
@@ -18,6 +19,10 @@ pub struct FileSystemCache {
     last_fetch: AtomicU64,
     /// total db bytes counter
     total: AtomicU64,
+    /// source of the save times of entries
+    clock: Arc<dyn Clock>,
+
+    info: CacheInfo,
 }
 
 impl FileSystemCache {
@@ -31,22 +36,42 @@ impl FileSystemCache {
 
         let s = Self {
             cache,
-            last_fetch: AtomicU64::new(now_as_millis()),
+            last_fetch: AtomicU64::new(SystemClock.now_as_millis()),
             total: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
+            info: CacheInfo::new("fs")
+                .with_path(config.path.clone())
+                .with_setting("lru_size_mebibytes", config.lru_size_mebibytes)
+                .with_setting("rw_buffer_kibibytes", config.rw_buffer_size),
         };
         s.update_real_size();
         Ok(s)
     }
 
+    /// Sets the clock that the save times of entries are taken from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_fetch = AtomicU64::new(clock.now_as_millis());
+        self.clock = clock;
+        self
+    }
+
     /// Updates the internally kept database total bytes counter to the actual database value. This
     /// function is costly as it does an entire iteration over the database metadata.
     fn update_real_size(&self) -> u64 {
-        let sz = self.cache.metadata_iter().fold(0u64, |acc, x| match x {
-            Ok((_, meta)) => acc + meta.get_size(),
-            _ => acc,
-        });
+        self.update_real_size_and_count().0
+    }
+
+    /// Same as [`Self::update_real_size`], but also returns the number of entries in the database
+    fn update_real_size_and_count(&self) -> (u64, u64) {
+        let (sz, count) = self
+            .cache
+            .metadata_iter()
+            .fold((0u64, 0u64), |(sz, count), x| match x {
+                Ok((_, meta)) => (sz + meta.get_size(), count + 1),
+                _ => (sz, count),
+            });
         self.total.store(sz, Ordering::SeqCst);
-        sz
+        (sz, count)
     }
 
     /// Finds an estimated total size of the database. If a certain amount of time has passed, then
@@ -55,7 +80,7 @@ impl FileSystemCache {
         // 1 hr in milliseconds
         const MIN_TIME: u64 = 1000 * 60 * 60;
         let last_fetch = self.last_fetch.load(Ordering::Relaxed);
-        let now = now_as_millis();
+        let now = self.clock.now_as_millis();
 
         // if it's been over an hour since the last fetch, then fetch again and update `last_fetch`
         // to the correct value
@@ -85,7 +110,7 @@ impl FileSystemCache {
         mime_type: String,
         data: Bytes,
     ) -> Result<(), CacheError> {
-        let entry = ImageEntry::new_assume(data, mime_type);
+        let entry = ImageEntry::new(data, mime_type, self.clock.now());
         let ser_bytes: Bytes = entry.try_into().map_err(CacheError::Bincode)?;
         self.cache
             .write(key.as_bkey(), &ser_bytes)
@@ -111,6 +136,11 @@ impl ImageCache for FileSystemCache {
         res.ok()
     }
     async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        // an empty body is never a valid image (i.e. a truncated upstream response)
+        if data.is_empty() {
+            log::warn!("refusing to save empty image {} to db", key);
+            return false;
+        }
         if let Err(e) = self.save_to_db(key, mime_type, data).await {
             log::error!("error writing data to db: {}", e);
             false
@@ -123,14 +153,30 @@ impl ImageCache for FileSystemCache {
         self.find_size()
     }
 
-    async fn shrink(&self, min: u64) -> Result<u64, ()> {
+    fn info(&self) -> CacheInfo {
+        self.info.clone()
+    }
+
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
         use forceps::evictors::FifoEvictor;
 
+        // forceps doesn't report what it evicted, so compare the database before and after
+        let (start_sz, start_count) = self.update_real_size_and_count();
         if let Err(e) = self.cache.evict_with(FifoEvictor::new(min)).await {
             log::error!("error shrinking db occured: {}", CacheError::Forceps(e));
             return Err(());
         }
-        Ok(self.update_real_size())
+        let (sz, count) = self.update_real_size_and_count();
+        Ok(ShrinkResult {
+            size: sz,
+            bytes_evicted: start_sz.saturating_sub(sz),
+            entries_evicted: start_count.saturating_sub(count),
+        })
+    }
+
+    /// forceps can't clear the whole database at once, so this evicts every entry instead
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        self.shrink(0).await
     }
 }
 
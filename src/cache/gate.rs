@@ -0,0 +1,142 @@
+use super::{
+    CacheInfo, CacheStats, ImageCache, ImageEntry, ImageKey, ShrinkResult, SizeReconciliation,
+};
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+/// A cache that stands in for the real cache while it's being initialized in the background.
+///
+/// Until the gate is opened with the real cache, it reports that it isn't ready, loads find
+/// nothing, saves fail and maintenance does nothing. Afterwards, everything is forwarded to the
+/// real cache. Clones of the gate share the same cache, so one clone can be handed to the task
+/// initializing the cache.
+#[derive(Clone, Default)]
+pub struct CacheGate {
+    inner: Arc<OnceCell<Box<dyn ImageCache>>>,
+}
+
+impl CacheGate {
+    /// Creates a closed gate
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the gate with the initialized cache. The cache can only be set once.
+    pub fn open(&self, cache: Box<dyn ImageCache>) {
+        if self.inner.set(cache).is_err() {
+            log::error!("cache gate was opened more than once, ignoring");
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageCache for CacheGate {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        self.inner.get()?.load(key).await
+    }
+    async fn load_many(&self, keys: &[ImageKey]) -> Vec<Option<ImageEntry>> {
+        match self.inner.get() {
+            Some(cache) => cache.load_many(keys).await,
+            None => vec![None; keys.len()],
+        }
+    }
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        match self.inner.get() {
+            Some(cache) => cache.save(key, mime_type, data).await,
+            None => false,
+        }
+    }
+    fn is_ready(&self) -> bool {
+        self.inner.get().is_some_and(|x| x.is_ready())
+    }
+    fn report(&self) -> u64 {
+        self.inner.get().map_or(0, |x| x.report())
+    }
+    fn info(&self) -> CacheInfo {
+        match self.inner.get() {
+            Some(cache) => cache.info(),
+            None => CacheInfo::new("initializing"),
+        }
+    }
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        self.inner.get().ok_or(())?.shrink(min).await
+    }
+    fn report_archive(&self, data_saver: bool) -> Option<u64> {
+        self.inner.get()?.report_archive(data_saver)
+    }
+    fn report_pinned(&self) -> Option<u64> {
+        self.inner.get()?.report_pinned()
+    }
+    fn report_entries(&self) -> Option<u64> {
+        self.inner.get()?.report_entries()
+    }
+    fn stats(&self) -> CacheStats {
+        self.inner.get().map(|x| x.stats()).unwrap_or_default()
+    }
+    async fn shrink_archive(&self, data_saver: bool, min: u64) -> Result<ShrinkResult, ()> {
+        self.inner
+            .get()
+            .ok_or(())?
+            .shrink_archive(data_saver, min)
+            .await
+    }
+    async fn finish_shrink(&self, res: &ShrinkResult) {
+        if let Some(cache) = self.inner.get() {
+            cache.finish_shrink(res).await
+        }
+    }
+    async fn sweep_idle(&self, max_idle: std::time::Duration) -> Result<ShrinkResult, ()> {
+        self.inner.get().ok_or(())?.sweep_idle(max_idle).await
+    }
+    async fn compact(&self) {
+        if let Some(cache) = self.inner.get() {
+            cache.compact().await
+        }
+    }
+    async fn flush(&self) {
+        if let Some(cache) = self.inner.get() {
+            cache.flush().await
+        }
+    }
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        self.inner.get().ok_or(())?.clear().await
+    }
+    async fn rotate(&self, path: &str) -> Result<Box<dyn ImageCache>, ()> {
+        self.inner.get().ok_or(())?.rotate(path).await
+    }
+    async fn reconcile_size(
+        &self,
+        sample: Option<usize>,
+        max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        let cache = self.inner.get().ok_or(())?;
+        cache.reconcile_size(sample, max_drift).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tests::TestCache;
+
+    #[tokio::test]
+    async fn closed_until_opened() {
+        let gate = CacheGate::new();
+        let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+        let image = Bytes::from_static(b"image");
+
+        assert!(!gate.is_ready());
+        assert!(
+            !gate
+                .save(&key, "image/png".to_string(), image.clone())
+                .await
+        );
+        assert_eq!(gate.info().backend, "initializing");
+
+        gate.clone().open(Box::new(TestCache::default()));
+        assert!(gate.is_ready());
+        assert!(gate.save(&key, "image/png".to_string(), image).await);
+        assert!(gate.load(&key).await.is_some());
+    }
+}
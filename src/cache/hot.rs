@@ -0,0 +1,359 @@
+use super::{
+    CacheInfo, CacheStats, ImageCache, ImageEntry, ImageKey, MemoryCache, ShrinkResult,
+    SizeReconciliation,
+};
+use bytes::Bytes;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of independently locked parts the images in RAM are split into, so concurrent loads of
+/// different images rarely wait on each other
+const SHARDS: usize = 16;
+
+/// Number of generation counters that keys are spread across. Keys that share a counter only cost
+/// each other a keep in RAM now and then, so this just has to be large enough to make that rare.
+const GENERATIONS: usize = 1024;
+
+/// A cache that keeps the most recently served images of another cache in RAM.
+///
+/// Loads are answered from RAM when possible, and fall through to the inner cache on a miss, after
+/// which the image is kept in RAM for the next request. Saves go straight to the inner cache, only
+/// dropping the copy in RAM so it's never stale. The images in RAM are limited to a fixed size of
+/// their own, so all size reporting and shrinking only applies to the inner cache.
+///
+/// The images in RAM are spread across [`SHARDS`] shards by key, each with an equal part of the
+/// maximum size, and images are only evicted by the other images of their shard.
+///
+/// Every save bumps the generation of its key once written, and a load only keeps the image it got
+/// from the inner cache if the generation didn't change in the meantime. Otherwise a load that raced a save
+/// could keep the old image in RAM after the save dropped it.
+pub struct HotCache<C> {
+    hot: Vec<MemoryCache>,
+    generations: Vec<AtomicU64>,
+    inner: C,
+    max_size: u64,
+
+    /// total number of loads that were answered from RAM or had to go to the inner cache
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<C: ImageCache> HotCache<C> {
+    /// Puts a hot cache of at most `max_size` bytes in front of `inner`
+    pub fn new(inner: C, max_size: u64) -> Self {
+        let shard_size = max_size / SHARDS as u64;
+        Self {
+            hot: (0..SHARDS)
+                .map(|_| MemoryCache::with_max_size(shard_size))
+                .collect(),
+            generations: (0..GENERATIONS).map(|_| AtomicU64::new(0)).collect(),
+            inner,
+            max_size,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Hash of `key` that picks its shard and generation
+    fn hash(key: &ImageKey) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.to_string().hash(&mut hasher);
+        hasher.finish() as usize
+    }
+
+    /// The shard that the image of `key` is kept in
+    fn shard(&self, key: &ImageKey) -> &MemoryCache {
+        &self.hot[Self::hash(key) % self.hot.len()]
+    }
+
+    /// The generation counter of `key`, bumped on every save of it
+    fn generation(&self, key: &ImageKey) -> &AtomicU64 {
+        &self.generations[Self::hash(key) % self.generations.len()]
+    }
+
+    /// Keeps an entry loaded from the inner cache in RAM, unless the key was saved since
+    /// `generation` was read before loading it
+    fn keep(&self, key: &ImageKey, entry: &ImageEntry, generation: u64) {
+        let current = self.generation(key);
+        if current.load(Ordering::SeqCst) != generation {
+            return;
+        }
+
+        // entries only share their bytes, so cloning them is cheap
+        let shard = self.shard(key);
+        shard.insert(key.to_string(), entry.clone());
+
+        // a save that bumped the generation after the check above may already have dropped the
+        // image from RAM, in which case the stale copy has to go again
+        if current.load(Ordering::SeqCst) != generation {
+            shard.remove(key);
+        }
+    }
+
+    /// Total size of the images kept in RAM
+    fn hot_size(&self) -> u64 {
+        self.hot.iter().map(|x| x.report()).sum()
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: ImageCache + 'static> ImageCache for HotCache<C> {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        if let Some(entry) = self.shard(key).load(key).await {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(entry);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let generation = self.generation(key).load(Ordering::SeqCst);
+        let entry = self.inner.load(key).await?;
+        self.keep(key, &entry, generation);
+        Some(entry)
+    }
+
+    async fn load_many(&self, keys: &[ImageKey]) -> Vec<Option<ImageEntry>> {
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            entries.push(self.shard(key).load(key).await);
+        }
+
+        // only look up the images missing from RAM in the inner cache
+        let missing: Vec<usize> = (0..keys.len()).filter(|&i| entries[i].is_none()).collect();
+        self.hits
+            .fetch_add((keys.len() - missing.len()) as u64, Ordering::Relaxed);
+        if !missing.is_empty() {
+            self.misses
+                .fetch_add(missing.len() as u64, Ordering::Relaxed);
+            let missing_keys: Vec<ImageKey> = missing.iter().map(|&i| keys[i].clone()).collect();
+            let generations: Vec<u64> = missing_keys
+                .iter()
+                .map(|key| self.generation(key).load(Ordering::SeqCst))
+                .collect();
+            let found = self.inner.load_many(&missing_keys).await;
+            for ((i, entry), generation) in missing.into_iter().zip(found).zip(generations) {
+                if let Some(entry) = &entry {
+                    self.keep(&keys[i], entry, generation);
+                }
+                entries[i] = entry;
+            }
+        }
+        entries
+    }
+
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        // the image is kept again once it's served, with the save time of the inner cache
+        self.shard(key).remove(key);
+        let saved = self.inner.save(key, mime_type, data).await;
+
+        // loads that raced the save may have gotten the old image, so they must not keep it. one
+        // that already kept it did so before the bump, and is dropped again here
+        self.generation(key).fetch_add(1, Ordering::SeqCst);
+        self.shard(key).remove(key);
+        saved
+    }
+
+    fn is_ready(&self) -> bool {
+        self.inner.is_ready()
+    }
+    fn report(&self) -> u64 {
+        self.inner.report()
+    }
+    fn info(&self) -> CacheInfo {
+        let inner = self.inner.info();
+        CacheInfo::new("hot")
+            .with_setting("max_size_bytes", self.max_size)
+            .with_features(&inner.features)
+            .with_inner(inner)
+    }
+
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        self.inner.shrink(min).await
+    }
+    fn report_archive(&self, data_saver: bool) -> Option<u64> {
+        self.inner.report_archive(data_saver)
+    }
+    fn report_pinned(&self) -> Option<u64> {
+        self.inner.report_pinned()
+    }
+    fn report_entries(&self) -> Option<u64> {
+        self.inner.report_entries()
+    }
+    fn stats(&self) -> CacheStats {
+        self.inner
+            .stats()
+            .with_stat("hot_bytes", self.hot_size())
+            .with_stat("hot_hits", self.hits.load(Ordering::Relaxed))
+            .with_stat("hot_misses", self.misses.load(Ordering::Relaxed))
+    }
+    async fn shrink_archive(&self, data_saver: bool, min: u64) -> Result<ShrinkResult, ()> {
+        self.inner.shrink_archive(data_saver, min).await
+    }
+    async fn finish_shrink(&self, res: &ShrinkResult) {
+        self.inner.finish_shrink(res).await
+    }
+
+    async fn sweep_idle(&self, max_idle: std::time::Duration) -> Result<ShrinkResult, ()> {
+        self.inner.sweep_idle(max_idle).await
+    }
+    async fn compact(&self) {
+        self.inner.compact().await
+    }
+    async fn flush(&self) {
+        self.inner.flush().await
+    }
+
+    /// Clears RAM as well, since the images kept there would still be served
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        for shard in &self.hot {
+            shard.clear().await?;
+        }
+        self.inner.clear().await
+    }
+
+    async fn rotate(&self, path: &str) -> Result<Box<dyn ImageCache>, ()> {
+        let inner = self.inner.rotate(path).await?;
+        Ok(Box::new(HotCache::new(inner, self.max_size)))
+    }
+
+    async fn reconcile_size(
+        &self,
+        sample: Option<usize>,
+        max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        self.inner.reconcile_size(sample, max_drift).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::TestCache;
+    use super::*;
+    use std::sync::Arc;
+
+    fn key(image: &str) -> ImageKey {
+        ImageKey::new("chapter".to_string(), image.to_string(), false)
+    }
+
+    #[tokio::test]
+    async fn served_images_are_kept_in_ram() {
+        let inner = TestCache::default();
+        let loads = Arc::clone(&inner.loads);
+        let cache = HotCache::new(inner, 1024 * 1024);
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), data)
+                .await
+        );
+
+        assert!(cache.load(&key("1.png")).await.is_some());
+        assert!(cache.load(&key("1.png")).await.is_some());
+        let keys = vec![key("1.png"), key("2.png")];
+        let entries = cache.load_many(&keys).await;
+        assert!(entries[0].is_some() && entries[1].is_none());
+
+        // only the first load and the missing image went to the inner cache
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        let stats = cache.stats();
+        assert_eq!(stats.backend["hot_hits"], 2);
+        assert_eq!(stats.backend["hot_misses"], 2);
+    }
+
+    #[tokio::test]
+    async fn saves_and_clears_drop_images_from_ram() {
+        let cache = HotCache::new(TestCache::default(), 1024 * 1024);
+        let old = Bytes::from_static(b"old image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), old)
+                .await
+        );
+        assert!(cache.load(&key("1.png")).await.is_some());
+
+        let new = Bytes::from_static(b"new image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), new.clone())
+                .await
+        );
+        assert_eq!(cache.load(&key("1.png")).await.unwrap().get_bytes(), new);
+
+        cache.clear().await.unwrap();
+        assert_eq!(cache.hot_size(), 0);
+        assert!(cache.load(&key("1.png")).await.is_none());
+    }
+
+    /// Cache whose loads wait to return the image they read until they're released
+    #[derive(Default)]
+    struct PausedLoads {
+        inner: TestCache,
+        release: tokio::sync::Notify,
+    }
+
+    #[async_trait::async_trait]
+    impl ImageCache for PausedLoads {
+        async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+            let entry = self.inner.load(key).await;
+            self.release.notified().await;
+            entry
+        }
+        async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+            self.inner.save(key, mime_type, data).await
+        }
+        fn report(&self) -> u64 {
+            self.inner.report()
+        }
+        fn info(&self) -> CacheInfo {
+            self.inner.info()
+        }
+        async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+            self.inner.shrink(min).await
+        }
+    }
+
+    #[tokio::test]
+    async fn loads_racing_saves_keep_nothing() {
+        let cache = HotCache::new(PausedLoads::default(), 1024 * 1024);
+        let key = key("1.png");
+        let old = Bytes::from_static(b"old image");
+        assert!(
+            cache
+                .inner
+                .save(&key, "image/png".to_string(), old.clone())
+                .await
+        );
+
+        // the load reads the old image, then the save finishes before the load does
+        let new = Bytes::from_static(b"new image");
+        let (loaded, saved) = tokio::join!(cache.load(&key), async {
+            let saved = cache.save(&key, "image/png".to_string(), new).await;
+            cache.inner.release.notify_one();
+            saved
+        });
+        assert_eq!(loaded.unwrap().get_bytes(), old);
+        assert!(saved);
+        assert!(cache.shard(&key).load(&key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn images_are_spread_across_shards() {
+        let inner = TestCache::default();
+        let loads = Arc::clone(&inner.loads);
+        let cache = HotCache::new(inner, 1024 * 1024);
+        let keys: Vec<ImageKey> = (0..64).map(|i| key(&format!("{}.png", i))).collect();
+        for key in &keys {
+            let data = Bytes::from_static(b"image");
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+            assert!(cache.load(key).await.is_some());
+        }
+
+        let used = cache.hot.iter().filter(|x| x.report() > 0).count();
+        assert!(used > 1, "all images were kept in {} shard", used);
+        // every image is still served from RAM
+        for key in &keys {
+            assert!(cache.load(key).await.is_some());
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), keys.len());
+    }
+}
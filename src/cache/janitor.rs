@@ -0,0 +1,127 @@
+use super::{ImageCache, MaintenancePacer, ShrinkResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shrinks caches in steps of a fixed size instead of all at once.
+///
+/// A single large shrink keeps the cache busy evicting for as long as it takes, which shows up as
+/// latency spikes for the requests that are served in the meantime. The janitor shrinks the cache
+/// a step at a time instead, pausing in between steps (see [`MaintenancePacer`]), and stops early
+/// once the client is shutting down. Once all of the steps are done, the cache gets to clean up
+/// after them at once (see [`ImageCache::finish_shrink`]).
+pub struct Janitor {
+    /// the most bytes evicted in a single step
+    step: u64,
+    pacer: MaintenancePacer,
+    stop: Arc<AtomicBool>,
+}
+
+impl Janitor {
+    pub fn new(step: u64, pacer: MaintenancePacer, stop: Arc<AtomicBool>) -> Self {
+        Self { step, pacer, stop }
+    }
+
+    /// Shrinks the whole cache down to `min` bytes (see [`ImageCache::shrink`]), returning what
+    /// all of the steps evicted together
+    pub async fn shrink(&self, cache: &dyn ImageCache, min: u64) -> Result<ShrinkResult, ()> {
+        self.shrink_in_steps(cache, None, min).await
+    }
+
+    /// Shrinks one archive type down to `min` bytes (see [`ImageCache::shrink_archive`]),
+    /// returning what all of the steps evicted together
+    pub async fn shrink_archive(
+        &self,
+        cache: &dyn ImageCache,
+        data_saver: bool,
+        min: u64,
+    ) -> Result<ShrinkResult, ()> {
+        self.shrink_in_steps(cache, Some(data_saver), min).await
+    }
+
+    async fn shrink_in_steps(
+        &self,
+        cache: &dyn ImageCache,
+        archive: Option<bool>,
+        min: u64,
+    ) -> Result<ShrinkResult, ()> {
+        let size = match archive {
+            Some(data_saver) => cache.report_archive(data_saver).ok_or(())?,
+            None => cache.report(),
+        };
+        let mut total = ShrinkResult {
+            size,
+            ..Default::default()
+        };
+
+        let mut failed = false;
+        while total.size > min && !self.stop.load(Ordering::SeqCst) {
+            let target = total.size.saturating_sub(self.step).max(min);
+            let res = match archive {
+                Some(data_saver) => cache.shrink_archive(data_saver, target).await,
+                None => cache.shrink(target).await,
+            };
+            let res = match res {
+                Ok(res) => res,
+                Err(()) => {
+                    failed = true;
+                    break;
+                }
+            };
+            total.bytes_evicted += res.bytes_evicted;
+            total.entries_evicted += res.entries_evicted;
+
+            // a cache that can't get any smaller (i.e. only pinned entries are left) is left as is
+            let shrunk = res.size < total.size;
+            total.size = res.size;
+            if !shrunk {
+                break;
+            }
+            self.pacer.pause().await;
+        }
+
+        // whatever the steps before a failed one evicted is still cleaned up
+        cache.finish_shrink(&total).await;
+        if failed {
+            Err(())
+        } else {
+            Ok(total)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ImageKey, MemoryCache};
+    use super::*;
+    use bytes::Bytes;
+
+    async fn filled_cache() -> MemoryCache {
+        let cache = MemoryCache::with_max_size(1024 * 1024);
+        for i in 0..100 {
+            let key = ImageKey::new("chapter".to_string(), format!("{}.png", i), false);
+            let data = Bytes::from(vec![0u8; 1000]);
+            assert!(cache.save(&key, "image/png".to_string(), data).await);
+        }
+        cache
+    }
+
+    #[tokio::test]
+    async fn shrinks_add_up_over_the_steps() {
+        let cache = filled_cache().await;
+        let start = cache.report();
+        let stop = Arc::new(AtomicBool::new(false));
+        let janitor = Janitor::new(5000, MaintenancePacer::default(), Arc::clone(&stop));
+
+        let res = janitor.shrink(&cache, start / 2).await.unwrap();
+        assert_eq!(res.size, cache.report());
+        assert!(res.size <= start / 2);
+        assert_eq!(res.bytes_evicted, start - res.size);
+        assert_eq!(res.entries_evicted, 100 - cache.report_entries().unwrap());
+
+        // nothing more is evicted once the client is shutting down
+        stop.store(true, Ordering::SeqCst);
+        let res = janitor.shrink(&cache, 0).await.unwrap();
+        assert_eq!(res.entries_evicted, 0);
+        assert_eq!(res.size, cache.report());
+    }
+}
@@ -0,0 +1,230 @@
+use super::{CacheInfo, ImageCache, ImageEntry, ImageKey, ShrinkResult};
+use crate::config::MemoryConfig;
+use crate::utils::{Clock, SystemClock};
+use bytes::Bytes;
+use lru::LruCache;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+/// Bytes that each entry takes up besides its key, mime type and image: the entry itself, the key
+/// and the bookkeeping of the LRU (a node with two links, and a slot in its hash table)
+const ENTRY_OVERHEAD: u64 = (size_of::<ImageEntry>() + size_of::<String>() * 2 + 64) as u64;
+
+/// The memory an entry takes up in the cache
+fn footprint(key: &str, entry: &ImageEntry) -> u64 {
+    (key.len() + entry.mime_type.len()) as u64 + entry.get_bytes_len() + ENTRY_OVERHEAD
+}
+
+/// A cache engine that keeps every image in RAM, evicting the least recently used images to stay
+/// under a fixed size. The cache is lost when the client stops.
+///
+/// The size includes the keys and bookkeeping of each entry, not just the images, and images are
+/// evicted before an image that doesn't fit is inserted, so the cache never takes up more than
+/// its maximum size.
+pub struct MemoryCache {
+    state: Mutex<MemoryState>,
+    max_size: u64,
+    /// source of the save times of entries
+    clock: Arc<dyn Clock>,
+    info: CacheInfo,
+}
+
+struct MemoryState {
+    entries: LruCache<String, ImageEntry>,
+    /// total footprint of the entries
+    size: u64,
+}
+
+impl MemoryState {
+    /// Removes the least recently used entry, returning its footprint
+    fn pop_lru(&mut self) -> Option<u64> {
+        let (key, entry) = self.entries.pop_lru()?;
+        let len = footprint(&key, &entry);
+        self.size -= len;
+        Some(len)
+    }
+}
+
+impl MemoryCache {
+    pub fn new(conf: &MemoryConfig) -> Self {
+        Self::with_max_size(conf.max_size_mebibytes * 1024 * 1024)
+    }
+
+    /// Creates a cache that takes up at most `max_size` bytes
+    pub fn with_max_size(max_size: u64) -> Self {
+        Self {
+            state: Mutex::new(MemoryState {
+                entries: LruCache::unbounded(),
+                size: 0,
+            }),
+            max_size,
+            clock: Arc::new(SystemClock),
+            info: CacheInfo::new("memory").with_setting("max_size_bytes", max_size),
+        }
+    }
+
+    /// Sets the clock that the save times of entries are taken from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Inserts an entry as it is (keeping its save time and checksum), evicting the least recently
+    /// used entries to make room. Returns whether the entry fit into the cache at all.
+    pub(super) fn insert(&self, key: String, entry: ImageEntry) -> bool {
+        let len = footprint(&key, &entry);
+        if len > self.max_size {
+            return false;
+        }
+
+        let mut state = self.lock();
+        if let Some(old) = state.entries.pop(&key) {
+            state.size -= footprint(&key, &old);
+        }
+        // make room before inserting, so the cache is never over its maximum size
+        while state.size + len > self.max_size {
+            if state.pop_lru().is_none() {
+                break;
+            }
+        }
+        state.entries.put(key, entry);
+        state.size += len;
+        true
+    }
+
+    /// Removes the entry of `key`, if there is one
+    pub(super) fn remove(&self, key: &ImageKey) {
+        let key = key.to_string();
+        let mut state = self.lock();
+        if let Some(old) = state.entries.pop(&key) {
+            state.size -= footprint(&key, &old);
+        }
+    }
+
+    /// Evicts the least recently used entries until the cache takes up at most `min` bytes
+    fn evict_until(&self, min: u64) -> ShrinkResult {
+        let mut state = self.lock();
+        let mut res = ShrinkResult::default();
+        while state.size > min {
+            match state.pop_lru() {
+                Some(len) => {
+                    res.bytes_evicted += len;
+                    res.entries_evicted += 1;
+                }
+                None => break,
+            }
+        }
+        res.size = state.size;
+        res
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageCache for MemoryCache {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        // entries only share their bytes, so cloning them is cheap
+        self.lock().entries.get(&key.to_string()).cloned()
+    }
+
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        // an empty body is never a valid image (i.e. a truncated upstream response)
+        if data.is_empty() {
+            log::warn!("refusing to save empty image {} to memory", key);
+            return false;
+        }
+        let entry = ImageEntry::new(data, mime_type, self.clock.now());
+        if !self.insert(key.to_string(), entry) {
+            log::warn!("image {} is larger than the memory cache, not saving", key);
+            return false;
+        }
+        true
+    }
+
+    fn report(&self) -> u64 {
+        self.lock().size
+    }
+
+    fn info(&self) -> CacheInfo {
+        self.info.clone()
+    }
+
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        Ok(self.evict_until(min))
+    }
+
+    fn report_entries(&self) -> Option<u64> {
+        Some(self.lock().entries.len() as u64)
+    }
+
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        Ok(self.evict_until(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> ImageKey {
+        ImageKey::new("chapter".to_string(), format!("{}.png", i), false)
+    }
+
+    /// The footprint of an image of `len` bytes saved under [`key`]
+    fn entry_size(i: usize, len: usize) -> u64 {
+        let entry = ImageEntry::new_assume(Bytes::from(vec![0u8; len]), "image/png".to_string());
+        footprint(&key(i).to_string(), &entry)
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_are_evicted() {
+        let cache = MemoryCache::with_max_size(entry_size(0, 100) * 3);
+        for i in 0..3 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+        }
+        // the oldest entry is used again, so the second one is evicted instead
+        assert!(cache.load(&key(0)).await.is_some());
+        let data = Bytes::from(vec![0u8; 100]);
+        assert!(cache.save(&key(3), "image/png".to_string(), data).await);
+
+        assert!(cache.load(&key(1)).await.is_none());
+        for &i in &[0, 2, 3] {
+            assert!(cache.load(&key(i)).await.is_some());
+        }
+        assert_eq!(cache.report_entries(), Some(3));
+
+        let res = cache.shrink(entry_size(0, 100)).await.unwrap();
+        assert_eq!(res.entries_evicted, 2);
+        assert_eq!(res.size, cache.report());
+        assert!(cache.load(&key(3)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn size_never_exceeds_maximum() {
+        let max_size = 10_000;
+        let cache = MemoryCache::with_max_size(max_size);
+        for i in 0..100 {
+            let data = Bytes::from(vec![0u8; 100 + (i * 37) % 2000]);
+            assert!(
+                cache
+                    .save(&key(i % 30), "image/png".to_string(), data)
+                    .await
+            );
+            let size = cache.report();
+            assert!(size <= max_size, "{} bytes after saving {}", size, i);
+            // the reported size is the sum of the entries that are actually in the cache
+            let state = cache.lock();
+            let actual: u64 = state.entries.iter().map(|(k, e)| footprint(k, e)).sum();
+            assert_eq!(actual, size);
+        }
+
+        // images that could never fit aren't saved at all
+        let data = Bytes::from(vec![0u8; max_size as usize]);
+        assert!(!cache.save(&key(0), "image/png".to_string(), data).await);
+        assert!(cache.report() <= max_size);
+    }
+}
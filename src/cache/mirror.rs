@@ -0,0 +1,357 @@
+use super::{
+    CacheInfo, CacheStats, ImageCache, ImageEntry, ImageKey, ShrinkResult, SizeReconciliation,
+};
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// A message for the task that mirrors writes to the secondary cache
+enum MirrorWrite {
+    /// a write waiting to be mirrored
+    Save(ImageKey, String, Bytes),
+    /// a request to be told once every write queued before it was mirrored
+    Flush(oneshot::Sender<()>),
+}
+
+/// A cache that mirrors all writes from a primary cache to a secondary cache in the background.
+///
+/// Saves are written to the primary cache immediately, then queued to be written to the secondary
+/// cache by a background task. If the queue is full (the secondary cache can't keep up), then the
+/// write is dropped from the secondary cache instead of stalling the request.
+///
+/// Loads will check the primary cache first and then fall back to the secondary cache. All size
+/// reporting and shrinking only applies to the primary cache, as the secondary cache is expected to
+/// manage its own size.
+pub struct MirroredCache<P, S> {
+    primary: P,
+    secondary: Arc<S>,
+    queue: mpsc::Sender<MirrorWrite>,
+    queue_size: usize,
+
+    /// total number of writes that were dropped because the queue was full, shared with the caches
+    /// this one is rotated to
+    dropped: Arc<AtomicU64>,
+}
+
+impl<P: ImageCache, S: ImageCache + 'static> MirroredCache<P, S> {
+    /// Creates the mirrored cache, spawning the background task that writes to the secondary
+    /// cache. `queue_size` is the maximum number of writes that can be waiting for the secondary.
+    ///
+    /// Must be called from inside of the tokio runtime.
+    pub fn new(primary: P, secondary: S, queue_size: usize) -> Self {
+        let dropped = Arc::new(AtomicU64::new(0));
+        Self::with_secondary(primary, Arc::new(secondary), queue_size, dropped)
+    }
+
+    /// Creates the mirrored cache around a secondary cache that may be shared with another
+    /// mirrored cache, spawning its own background writer
+    fn with_secondary(
+        primary: P,
+        secondary: Arc<S>,
+        queue_size: usize,
+        dropped: Arc<AtomicU64>,
+    ) -> Self {
+        let (queue, mut rx) = mpsc::channel::<MirrorWrite>(queue_size);
+
+        // task will run until the mirrored cache (and therefore the sender) is dropped
+        let writer = Arc::clone(&secondary);
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    MirrorWrite::Save(key, mime_type, data) => {
+                        if !writer.save(&key, mime_type, data).await {
+                            log::warn!("failed to mirror {} to the secondary cache", key);
+                        }
+                    }
+                    // the receiver might have given up waiting, which is fine
+                    MirrorWrite::Flush(done) => drop(done.send(())),
+                }
+            }
+        });
+
+        Self {
+            primary,
+            secondary,
+            queue,
+            queue_size,
+            dropped,
+        }
+    }
+
+    /// Waits until every write queued so far was mirrored to the secondary cache
+    async fn flush_queue(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.queue.send(MirrorWrite::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: ImageCache, S: ImageCache + 'static> ImageCache for MirroredCache<P, S> {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        match self.primary.load(key).await {
+            Some(entry) => Some(entry),
+            None => self.secondary.load(key).await,
+        }
+    }
+
+    async fn load_many(&self, keys: &[ImageKey]) -> Vec<Option<ImageEntry>> {
+        let mut entries = self.primary.load_many(keys).await;
+
+        // only look up the images missing from the primary in the secondary
+        let missing: Vec<usize> = (0..keys.len()).filter(|&i| entries[i].is_none()).collect();
+        if !missing.is_empty() {
+            let missing_keys: Vec<ImageKey> = missing.iter().map(|&i| keys[i].clone()).collect();
+            let found = self.secondary.load_many(&missing_keys).await;
+            for (i, entry) in missing.into_iter().zip(found) {
+                entries[i] = entry;
+            }
+        }
+        entries
+    }
+
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        let saved = self
+            .primary
+            .save(key, mime_type.clone(), data.clone())
+            .await;
+
+        // queue the write to the secondary, dropping it if the secondary can't keep up
+        let write = MirrorWrite::Save(key.clone(), mime_type, data);
+        if self.queue.try_send(write).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            log::warn!(
+                "mirror queue is full, dropped write of {} (total dropped: {})",
+                key,
+                dropped
+            );
+        }
+        saved
+    }
+
+    /// Only the primary has to be ready, since the secondary is only a fallback
+    fn is_ready(&self) -> bool {
+        self.primary.is_ready()
+    }
+    fn report(&self) -> u64 {
+        self.primary.report()
+    }
+    fn info(&self) -> CacheInfo {
+        // all of the maintenance is forwarded to the primary, so it decides what is supported
+        let primary = self.primary.info();
+        CacheInfo::new("mirror")
+            .with_setting("queue_size", self.queue_size)
+            .with_features(&primary.features)
+            .with_inner(primary)
+            .with_inner(self.secondary.info())
+    }
+
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        self.primary.shrink(min).await
+    }
+    fn report_archive(&self, data_saver: bool) -> Option<u64> {
+        self.primary.report_archive(data_saver)
+    }
+    fn report_pinned(&self) -> Option<u64> {
+        self.primary.report_pinned()
+    }
+    fn report_entries(&self) -> Option<u64> {
+        self.primary.report_entries()
+    }
+    fn stats(&self) -> CacheStats {
+        self.primary.stats().with_stat(
+            "mirror_dropped_writes",
+            self.dropped.load(Ordering::Relaxed),
+        )
+    }
+    async fn shrink_archive(&self, data_saver: bool, min: u64) -> Result<ShrinkResult, ()> {
+        self.primary.shrink_archive(data_saver, min).await
+    }
+    async fn finish_shrink(&self, res: &ShrinkResult) {
+        self.primary.finish_shrink(res).await
+    }
+
+    async fn sweep_idle(&self, max_idle: std::time::Duration) -> Result<ShrinkResult, ()> {
+        self.primary.sweep_idle(max_idle).await
+    }
+    async fn compact(&self) {
+        self.primary.compact().await
+    }
+    async fn flush(&self) {
+        self.primary.flush().await;
+        self.secondary.flush().await
+    }
+
+    /// Clears both caches, since images left in the secondary would still be served. The queued
+    /// writes are mirrored first, so that they don't end up in the secondary after it was cleared.
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        self.flush_queue().await;
+        let res = self.primary.clear().await?;
+        self.secondary.clear().await?;
+        Ok(res)
+    }
+
+    /// Rotates the primary, and keeps mirroring the writes to it to the same secondary
+    async fn rotate(&self, path: &str) -> Result<Box<dyn ImageCache>, ()> {
+        let primary = self.primary.rotate(path).await?;
+        Ok(Box::new(MirroredCache::with_secondary(
+            primary,
+            Arc::clone(&self.secondary),
+            self.queue_size,
+            Arc::clone(&self.dropped),
+        )))
+    }
+
+    /// Only reconciles the primary, since the secondary's size isn't used for anything
+    async fn reconcile_size(
+        &self,
+        sample: Option<usize>,
+        max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        self.primary.reconcile_size(sample, max_drift).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::TestCache;
+    use super::*;
+    use std::time::Duration;
+
+    fn key(image: &str) -> ImageKey {
+        ImageKey::new("chapter".to_string(), image.to_string(), false)
+    }
+
+    #[tokio::test]
+    async fn writes_are_mirrored() {
+        let cache = MirroredCache::new(TestCache::default(), TestCache::default(), 16);
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), data)
+                .await
+        );
+
+        // primary is written immediately
+        assert!(cache.primary.load(&key("1.png")).await.is_some());
+
+        // secondary is written eventually
+        for _ in 0..100 {
+            if cache.secondary.load(&key("1.png")).await.is_some() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("write was never mirrored to the secondary");
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_writes() {
+        // the writer task can't run until we yield, so the second save overflows the queue
+        let cache = MirroredCache::new(TestCache::default(), TestCache::default(), 1);
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), data.clone())
+                .await
+        );
+        assert!(
+            cache
+                .save(&key("2.png"), "image/png".to_string(), data)
+                .await
+        );
+        assert_eq!(cache.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.stats().backend["mirror_dropped_writes"], 1);
+
+        // both are still in the primary, and the dropped one is still loadable through it
+        assert!(cache.load(&key("2.png")).await.is_some());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cache.secondary.load(&key("1.png")).await.is_some());
+        assert!(cache.secondary.load(&key("2.png")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clear_drains_queued_writes() {
+        // the writer task can't run until we yield, so the write is still queued when clearing
+        let cache = MirroredCache::new(TestCache::default(), TestCache::default(), 16);
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), data)
+                .await
+        );
+        assert!(cache.clear().await.is_ok());
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cache.load(&key("1.png")).await.is_none());
+        assert!(cache.secondary.load(&key("1.png")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rotated_cache_keeps_mirroring() {
+        let cache = MirroredCache::new(TestCache::default(), TestCache::default(), 16);
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), data.clone())
+                .await
+        );
+
+        let rotated = cache.rotate("rotated").await.unwrap();
+        assert!(rotated.is_ready());
+        assert_eq!(rotated.info().inner[0].path.as_deref(), Some("rotated"));
+        assert!(rotated.load(&key("1.png")).await.is_some());
+
+        // writes to the rotated cache still reach the original secondary
+        assert!(
+            rotated
+                .save(&key("2.png"), "image/png".to_string(), data)
+                .await
+        );
+        for _ in 0..100 {
+            if cache.secondary.load(&key("2.png")).await.is_some() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("write to the rotated cache was never mirrored to the secondary");
+    }
+
+    #[tokio::test]
+    async fn load_many_matches_load() {
+        let cache = MirroredCache::new(TestCache::default(), TestCache::default(), 16);
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .primary
+                .save(&key("1.png"), "image/png".to_string(), data.clone())
+                .await
+        );
+        assert!(
+            cache
+                .secondary
+                .save(&key("3.png"), "image/png".to_string(), data)
+                .await
+        );
+
+        let keys = vec![key("1.png"), key("2.png"), key("3.png"), key("4.png")];
+        let batched = cache.load_many(&keys).await;
+        let mut single = Vec::new();
+        for key in &keys {
+            single.push(cache.load(key).await);
+        }
+
+        let checksums = |x: &[Option<ImageEntry>]| {
+            x.iter()
+                .map(|x| x.as_ref().map(ImageEntry::get_checksum_hex))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(checksums(&batched), checksums(&single));
+        assert_eq!(
+            batched.iter().map(Option::is_some).collect::<Vec<_>>(),
+            vec![true, false, true, false]
+        );
+    }
+}
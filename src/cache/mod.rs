@@ -1,12 +1,41 @@
+use crate::config::CompactionWindow;
 use async_trait::async_trait;
 use bytes::Bytes;
+use chrono::{NaiveDate, NaiveDateTime, Timelike};
+use serde::Serialize;
 use sha2::Digest;
+use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time;
 
 // re-export different caches
+mod mirror;
+pub use mirror::MirroredCache;
+
+mod gate;
+pub use gate::CacheGate;
+
+mod swap;
+pub use swap::SwappableCache;
+
+mod warm;
+pub use warm::{read_snapshot, warm_cache, HotKeys};
+
+mod mem;
+pub use mem::MemoryCache;
+
+mod hot;
+pub use hot::HotCache;
+
+mod tier;
+pub use tier::TieredCache;
+
+mod janitor;
+pub use janitor::Janitor;
+
 #[cfg(feature = "ce-filesystem")]
 mod fs;
 #[cfg(feature = "ce-filesystem")]
@@ -17,11 +46,27 @@ mod rocks;
 #[cfg(feature = "ce-rocksdb")]
 pub use rocks::RocksCache;
 
+#[cfg(feature = "ce-sled")]
+mod sled;
+#[cfg(feature = "ce-sled")]
+pub use self::sled::SledCache;
+
+#[cfg(feature = "ce-redis")]
+mod redis;
+#[cfg(feature = "ce-redis")]
+pub use self::redis::RedisCache;
+
+#[cfg(feature = "ce-s3")]
+mod s3;
+#[cfg(feature = "ce-s3")]
+pub use s3::S3Cache;
+
 #[derive(Debug)]
 struct ImageKeyInner {
     chapter: String,
     image: String,
     data_saver: bool,
+    variant: Option<ImageVariant>,
 }
 
 /// A data structure that represents the three components of an image path:
@@ -29,6 +74,9 @@ struct ImageKeyInner {
 /// - The Image Name
 /// - Whether it's `data` or `data-saver`
 ///
+/// Transcoded or resized copies of an image are told apart from the original by an optional
+/// [`ImageVariant`].
+///
 /// This data structure allows for shallow cloning that won't copy any actual memory
 #[derive(Debug, Clone)]
 pub struct ImageKey {
@@ -43,6 +91,20 @@ impl ImageKey {
                 chapter,
                 image,
                 data_saver,
+                variant: None,
+            }),
+        }
+    }
+
+    /// Creates a key for a `variant` of the same image, which is cached separately from the
+    /// original
+    pub fn with_variant(&self, variant: ImageVariant) -> Self {
+        Self {
+            inner: Arc::new(ImageKeyInner {
+                chapter: self.chapter().to_string(),
+                image: self.image().to_string(),
+                data_saver: self.data_saver(),
+                variant: Some(variant),
             }),
         }
     }
@@ -62,6 +124,11 @@ impl ImageKey {
     pub fn data_saver(&self) -> bool {
         self.inner.data_saver
     }
+    /// Retrieves the variant of the image, or `None` for the original
+    #[inline]
+    pub fn variant(&self) -> Option<&ImageVariant> {
+        self.inner.variant.as_ref()
+    }
 
     /// Returns a string representation of `data_saver`
     #[inline]
@@ -75,13 +142,18 @@ impl ImageKey {
 
     /// Calculates a predicatable unqiue key for the chap_hash, image, saver combo
     ///
-    /// Essentially calculates the md5 hash of the chapter hash and image name together, taking
-    /// into account if the image is data-saver
+    /// Essentially calculates the SHA-256 hash of the chapter hash and image name together, taking
+    /// into account if the image is data-saver. The variant (if any) is appended after a NUL byte,
+    /// which can't be part of an image name, so keys of originals are unchanged.
     pub fn as_bkey(&self) -> [u8; 32] {
         let mut ctx = sha2::Sha256::new();
         ctx.update([self.data_saver() as u8]);
         ctx.update(self.chapter());
         ctx.update(self.image());
+        if let Some(variant) = self.variant() {
+            ctx.update([0]);
+            ctx.update(variant.to_string());
+        }
         ctx.finalize().into()
     }
 }
@@ -94,7 +166,97 @@ impl std::fmt::Display for ImageKey {
             self.archive_name(),
             self.chapter(),
             self.image()
-        )
+        )?;
+        match self.variant() {
+            Some(variant) => write!(fmt, "#{}", variant),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for ImageKey {
+    type Err = String;
+
+    /// Parses a key from the same `/{archive}/{chapter}/{image}[#{variant}]` format that it's
+    /// displayed as
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.strip_prefix('/').unwrap_or(s).splitn(3, '/');
+        let (archive, chapter, image) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(archive), Some(chapter), Some(image))
+                if !chapter.is_empty() && !image.is_empty() =>
+            {
+                (archive, chapter, image)
+            }
+            _ => return Err(format!("invalid image path \"{}\"", s)),
+        };
+        let data_saver = match archive {
+            "data" => false,
+            "data-saver" => true,
+            _ => return Err(format!("invalid archive type \"{}\"", archive)),
+        };
+        let (image, variant) = match image.rsplit_once('#') {
+            Some((image, variant)) => (image, Some(variant.parse::<ImageVariant>()?)),
+            None => (image, None),
+        };
+        let key = Self::new(chapter.to_string(), image.to_string(), data_saver);
+        Ok(match variant {
+            Some(variant) => key.with_variant(variant),
+            None => key,
+        })
+    }
+}
+
+/// A transcoded or resized copy of an image, described by the format it was encoded to and
+/// optionally its quality and dimensions.
+///
+/// Displayed as `{format}[-q{quality}][-{width}x{height}]`, e.g. `webp-q80-800x1200`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageVariant {
+    pub format: String,
+    pub quality: Option<u8>,
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl std::fmt::Display for ImageVariant {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.format)?;
+        if let Some(quality) = self.quality {
+            write!(fmt, "-q{}", quality)?;
+        }
+        if let Some((width, height)) = self.dimensions {
+            write!(fmt, "-{}x{}", width, height)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ImageVariant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid image variant \"{}\"", s);
+        let mut parts = s.split('-');
+        let format = match parts.next() {
+            Some(x) if !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric()) => x,
+            _ => return Err(invalid()),
+        };
+        let mut variant = Self {
+            format: format.to_string(),
+            quality: None,
+            dimensions: None,
+        };
+        for part in parts {
+            if let Some(quality) = part.strip_prefix('q') {
+                variant.quality = Some(quality.parse().map_err(|_| invalid())?);
+            } else if let Some((width, height)) = part.split_once('x') {
+                let width = width.parse().map_err(|_| invalid())?;
+                let height = height.parse().map_err(|_| invalid())?;
+                variant.dimensions = Some((width, height));
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(variant)
     }
 }
 
@@ -103,10 +265,11 @@ impl std::fmt::Display for ImageKey {
 /// This structure contains the data that makes up an image, with additional information included
 /// for HTTP responses. It includes:
 /// - Last Modified timestamp
-/// - A checksum
+/// - A SHA-256 checksum of the bytes (which is strong enough to detect tampering as well as
+///   corruption, so it's also used for the `Digest` header)
 /// - The mime type of the image
 /// - The bytes of the image itself
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct ImageEntry {
     // milliseconds since epoch
     save_time: u128,
@@ -117,7 +280,41 @@ pub struct ImageEntry {
     bytes: Bytes,
 }
 
+/// Magic bytes at the start of an image, and the type of image they belong to
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+];
+
+/// Detects the type of an image from the magic bytes at the start of it (PNG, JPEG, GIF or WebP)
+pub fn sniff_mime(bytes: &[u8]) -> Option<mime::Mime> {
+    // webp images are a RIFF container, which has the size of the file in between the magic bytes
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return "image/webp".parse().ok();
+    }
+    SIGNATURES
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+        .and_then(|(_, mime)| mime.parse().ok())
+}
+
+/// The mime type to store for an image: `mime_type` unless it's missing or generic (which happens
+/// when upstream headers are unreliable), in which case the type is sniffed from the `bytes`
+fn mime_or_sniffed(mime_type: String, bytes: &[u8]) -> String {
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+    let generic = essence.is_empty()
+        || essence.eq_ignore_ascii_case(mime::APPLICATION_OCTET_STREAM.essence_str());
+    match sniff_mime(bytes) {
+        Some(sniffed) if generic => sniffed.to_string(),
+        _ => mime_type,
+    }
+}
+
 impl ImageEntry {
+    /// Creates an entry for an image that's being saved. A missing or generic `mime_type` is
+    /// replaced with the type sniffed from the image (see [`sniff_mime`]).
     pub fn new(bytes: Bytes, mime_type: String, save_time: time::SystemTime) -> Self {
         let mut ctx = sha2::Sha256::new();
         ctx.update(&bytes);
@@ -128,7 +325,7 @@ impl ImageEntry {
                 .map(|x| x.as_millis())
                 .unwrap_or_default(),
             checksum: ctx.finalize().into(),
-            mime_type,
+            mime_type: mime_or_sniffed(mime_type, &bytes),
             bytes_len: bytes.len() as u64,
             bytes,
         }
@@ -144,6 +341,11 @@ impl ImageEntry {
         Self::new(bytes, mime_type, time::SystemTime::now())
     }
 
+    /// When the entry was saved to the cache
+    #[inline]
+    pub fn get_save_time(&self) -> time::SystemTime {
+        time::UNIX_EPOCH + time::Duration::from_millis(self.save_time as u64)
+    }
     /// Reference to the internal [`Bytes`] store
     #[inline]
     pub fn get_bytes(&self) -> Bytes {
@@ -154,17 +356,23 @@ impl ImageEntry {
     pub fn get_bytes_len(&self) -> u64 {
         self.bytes_len
     }
-    /// Hexadecimal representation of the image checksum
+    /// Hexadecimal representation of the image checksum (SHA-256)
     #[inline]
     pub fn get_checksum_hex(&self) -> String {
         hex::encode(&self.checksum)
     }
+    /// Base64 representation of the image checksum
+    #[inline]
+    pub fn get_checksum_base64(&self) -> String {
+        use sodiumoxide::base64;
+        base64::encode(self.checksum, base64::Variant::Original)
+    }
 
-    /// The stored [`Mime`](mime::Mime) type of the image. Defaults to `image/png` if somehow
+    /// The stored [`Mime`](mime::Mime) type of the image. Defaults to `fallback` if somehow
     /// corrupted or otherwise invalid.
     #[inline]
-    pub fn get_mime(&self) -> mime::Mime {
-        mime::Mime::from_str(&self.mime_type).unwrap_or(mime::IMAGE_PNG)
+    pub fn get_mime(&self, fallback: mime::Mime) -> mime::Mime {
+        mime::Mime::from_str(&self.mime_type).unwrap_or(fallback)
     }
 }
 
@@ -182,8 +390,156 @@ impl TryFrom<Bytes> for ImageEntry {
     type Error = bincode::Error;
 
     /// Deserializes the datastructure from an array of bytes
+    ///
+    /// The image isn't copied out of `bytes`, the entry shares it instead, so loading an entry
+    /// doesn't allocate a second buffer the size of the image.
     fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
-        bincode::deserialize(&bytes)
+        let entry: BorrowedEntry = bincode::deserialize(&bytes)?;
+        Ok(Self {
+            save_time: entry.save_time,
+            checksum: entry.checksum,
+            mime_type: entry.mime_type,
+            bytes_len: entry.bytes_len,
+            bytes: bytes.slice_ref(entry.bytes),
+        })
+    }
+}
+
+/// An [`ImageEntry`] whose image borrows from the buffer it's deserialized from (deserializing
+/// [`Bytes`] always copies). The fields must stay in the same order as those of [`ImageEntry`].
+#[derive(serde::Deserialize)]
+struct BorrowedEntry<'a> {
+    save_time: u128,
+    checksum: [u8; 32],
+    mime_type: String,
+
+    bytes_len: u64,
+    #[serde(borrow)]
+    bytes: &'a [u8],
+}
+
+/// Why an entry was removed from a cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// To bring the cache (or one of its archive types) under its maximum size
+    Shrink,
+    /// The entry wasn't accessed for longer than the idle TTL
+    Idle,
+    /// The entry was older than the maximum entry age when it was loaded
+    Expired,
+    /// The whole cache was cleared
+    Cleared,
+}
+
+/// A callback invoked whenever a cache implementation removes an entry.
+///
+/// It's called with the key of the image, the number of image bytes that were freed and why the
+/// entry was removed. Hooks are called synchronously after the removal, so they should be cheap
+/// and must not call back into the cache.
+pub type EvictionHook = Box<dyn Fn(&ImageKey, u64, EvictionReason) + Send + Sync>;
+
+/// The outcome of a successful [`ImageCache::shrink`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShrinkResult {
+    /// The new total size of the cache in bytes
+    pub size: u64,
+    /// The number of bytes freed by evicting entries
+    pub bytes_evicted: u64,
+    /// The number of entries that were evicted
+    pub entries_evicted: u64,
+}
+
+/// The outcome of a successful [`ImageCache::reconcile_size`]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SizeReconciliation {
+    /// The number of entries that were checked
+    pub entries_checked: u64,
+    /// The size tracked for the checked entries in bytes
+    pub tracked: u64,
+    /// The size of the data actually stored for the checked entries in bytes
+    pub actual: u64,
+    /// Whether the size was recounted because it drifted too far
+    pub corrected: bool,
+}
+
+impl SizeReconciliation {
+    /// The difference between the tracked and actual size, as a fraction of the actual size
+    pub fn drift(&self) -> f64 {
+        self.tracked.abs_diff(self.actual) as f64 / self.actual.max(1) as f64
+    }
+}
+
+/// A description of a cache engine and how it was configured, for diagnostics
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CacheInfo {
+    /// Identifier of the engine (i.e. `rocksdb`)
+    pub backend: &'static str,
+    /// Where the engine stores its data, if anywhere
+    pub path: Option<String>,
+    /// The configured values of the engine, such as sizes and limits
+    pub settings: BTreeMap<&'static str, serde_json::Value>,
+    /// Optional capabilities of the [`ImageCache`] trait that the engine supports
+    pub features: Vec<&'static str>,
+    /// The caches this cache is composed of, if any
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inner: Vec<CacheInfo>,
+}
+
+impl CacheInfo {
+    pub fn new(backend: &'static str) -> Self {
+        Self {
+            backend,
+            path: None,
+            settings: BTreeMap::new(),
+            features: Vec::new(),
+            inner: Vec::new(),
+        }
+    }
+
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn with_setting(mut self, name: &'static str, value: impl Into<serde_json::Value>) -> Self {
+        self.settings.insert(name, value.into());
+        self
+    }
+
+    pub fn with_features(mut self, features: &[&'static str]) -> Self {
+        self.features.extend_from_slice(features);
+        self
+    }
+
+    pub fn with_inner(mut self, inner: CacheInfo) -> Self {
+        self.inner.push(inner);
+        self
+    }
+}
+
+/// A snapshot of the statistics of a cache, see [`ImageCache::stats`]
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    /// The total size of the images in bytes, the same as [`ImageCache::report`]
+    pub size: u64,
+    /// The number of images in the cache, which is allowed to be an estimate
+    pub entries: Option<u64>,
+    /// Statistics that only apply to the engine, such as the size of its files on disk
+    pub backend: BTreeMap<&'static str, serde_json::Value>,
+}
+
+impl CacheStats {
+    pub fn new(size: u64, entries: Option<u64>) -> Self {
+        Self {
+            size,
+            entries,
+            backend: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_stat(mut self, name: &'static str, value: impl Into<serde_json::Value>) -> Self {
+        self.backend.insert(name, value.into());
+        self
     }
 }
 
@@ -213,6 +569,19 @@ pub trait ImageCache: Send + Sync {
     /// wherever possible, as this will be called frequently
     async fn load(&self, key: &ImageKey) -> Option<ImageEntry>;
 
+    /// Load many cached images at once, returning an entry (or `None`) for each key in the same
+    /// order as `keys`.
+    ///
+    /// The default implementation loads the images one at a time. Implementations that are able to
+    /// batch lookups should override this to avoid the overhead of each individual load.
+    async fn load_many(&self, keys: &[ImageKey]) -> Vec<Option<ImageEntry>> {
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            entries.push(self.load(key).await);
+        }
+        entries
+    }
+
     /// Save an image to the cache, returning whether it was successful.
     ///
     /// Implementation should return `true` if it was successfully saved, otherwise `false`. It is
@@ -226,6 +595,15 @@ pub trait ImageCache: Send + Sync {
     /// wherever possible, as this can be called frequently
     async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool;
 
+    /// Whether the cache is initialized and able to serve images. Images aren't served until it
+    /// is, and the client reports itself as not ready.
+    ///
+    /// Caches are ready as soon as they're created by default, this is only for caches that are
+    /// initialized in the background (like [`CacheGate`]).
+    fn is_ready(&self) -> bool {
+        true
+    }
+
     /// Reports the total size of the cache database in bytes.
     ///
     /// Function is not implemented in async because it is discouraged to constantly use
@@ -233,13 +611,516 @@ pub trait ImageCache: Send + Sync {
     /// stores the cache size internally and automatically updates on save or shrink.
     fn report(&self) -> u64;
 
+    /// Describes the cache engine and its configuration
+    fn info(&self) -> CacheInfo;
+
     /// Shrink the cache database to a minimum size.
     ///
     /// `min` is the minimum size the cache should shrink to in bytes.
     ///
-    /// Implementation should return `Ok` with a new total cache size if successful. If there was
-    /// an error, should return `Err(())`
+    /// Implementation should return `Ok` with the new total cache size and what was evicted if
+    /// successful. If there was an error, should return `Err(())`
+    ///
+    /// This is called infrequently, so it doesn't need to be efficient
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()>;
+
+    /// Reports the size in bytes of the images from a single archive type (`data` or
+    /// `data-saver`) in the cache.
+    ///
+    /// Implementations that don't keep track of archive types should return `None` (the default),
+    /// which disables per-archive budgets
+    fn report_archive(&self, _data_saver: bool) -> Option<u64> {
+        None
+    }
+
+    /// Reports the size in bytes of the pinned images in the cache, which are never evicted.
+    ///
+    /// Implementations that don't support pinning should return `None` (the default)
+    fn report_pinned(&self) -> Option<u64> {
+        None
+    }
+
+    /// Reports the number of images in the cache, which is allowed to be an estimate.
+    ///
+    /// Implementations that can't count their images cheaply should return `None` (the default)
+    fn report_entries(&self) -> Option<u64> {
+        None
+    }
+
+    /// Takes a snapshot of the statistics of the cache.
+    ///
+    /// The default implementation only reports the size and the number of images. Implementations
+    /// that keep statistics of their own should override this to add them to
+    /// [`CacheStats::backend`]. This may be called often, so it should be cheap
+    fn stats(&self) -> CacheStats {
+        CacheStats::new(self.report(), self.report_entries())
+    }
+
+    /// Shrink the images of a single archive type to a minimum size, without evicting images from
+    /// the other archive type. Otherwise the same as [`Self::shrink`], except that the reported
+    /// size is the new size of the archive type.
+    ///
+    /// Only called if [`Self::report_archive`] returns `Some`
+    async fn shrink_archive(&self, _data_saver: bool, _min: u64) -> Result<ShrinkResult, ()> {
+        Err(())
+    }
+
+    /// Called once after a whole run of shrinks (see [`Janitor`]) with what all of them evicted
+    /// together, even if one of them failed. Implementations that clean up after evicting entries
+    /// (like compacting) should do so here, instead of after every single shrink.
+    ///
+    /// Implementations without anything to clean up can leave this as a no-op (the default)
+    async fn finish_shrink(&self, _res: &ShrinkResult) {}
+
+    /// Evicts every entry that hasn't been accessed within `max_idle`, regardless of the total
+    /// size of the cache.
+    ///
+    /// Implementations that don't keep track of accesses can leave this as a no-op (the default).
+    /// This is called infrequently, so it doesn't need to be efficient
+    async fn sweep_idle(&self, _max_idle: time::Duration) -> Result<ShrinkResult, ()> {
+        Ok(ShrinkResult {
+            size: self.report(),
+            ..Default::default()
+        })
+    }
+
+    /// Performs a full compaction of the cache database, reclaiming the space left behind by
+    /// evicted entries.
+    ///
+    /// Implementations without any notion of compaction can leave this as a no-op. This is called
+    /// infrequently, so it doesn't need to be efficient
+    async fn compact(&self) {}
+
+    /// Writes everything that's only buffered in memory to disk, so no saved image is lost when
+    /// the client stops. Called once on shutdown, after the last request was served.
+    ///
+    /// Implementations that write everything right away can leave this as a no-op (the default)
+    async fn flush(&self) {}
+
+    /// Removes every entry from the cache (including pinned ones), leaving it empty with a size
+    /// of 0. Returns what was removed, which is allowed to be an estimate.
+    ///
+    /// Implementations that can't remove all of their entries should return `Err(())` (the
+    /// default)
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        Err(())
+    }
+
+    /// Opens a new cache at `path` with the same configuration, migrating every entry into it,
+    /// and returns it. The cache keeps serving requests in the meantime, and the entries saved or
+    /// removed while the migration runs must be carried over as well.
     ///
+    /// Implementations that can't be moved to a new path should return `Err(())` (the default).
     /// This is called infrequently, so it doesn't need to be efficient
-    async fn shrink(&self, min: u64) -> Result<u64, ()>;
+    async fn rotate(&self, _path: &str) -> Result<Box<dyn ImageCache>, ()> {
+        Err(())
+    }
+
+    /// Compares the size tracked for the entries of the cache with the size of the data actually
+    /// stored for them, checking at most `sample` entries (or every entry if `None`). If they
+    /// drifted apart by more than `max_drift` (a fraction of the actual size), the tracked sizes
+    /// of every entry are corrected from the stored data.
+    ///
+    /// Implementations that don't track sizes separately from their data should return `Err(())`
+    /// (the default). This is only called on startup, so it doesn't need to be efficient
+    async fn reconcile_size(
+        &self,
+        _sample: Option<usize>,
+        _max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        Err(())
+    }
+}
+
+// allows dynamically created caches to be composed with other caches (like `MirroredCache`)
+#[async_trait]
+impl ImageCache for Box<dyn ImageCache> {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        (**self).load(key).await
+    }
+    async fn load_many(&self, keys: &[ImageKey]) -> Vec<Option<ImageEntry>> {
+        (**self).load_many(keys).await
+    }
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        (**self).save(key, mime_type, data).await
+    }
+    fn is_ready(&self) -> bool {
+        (**self).is_ready()
+    }
+    fn report(&self) -> u64 {
+        (**self).report()
+    }
+    fn info(&self) -> CacheInfo {
+        (**self).info()
+    }
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        (**self).shrink(min).await
+    }
+    fn report_archive(&self, data_saver: bool) -> Option<u64> {
+        (**self).report_archive(data_saver)
+    }
+    fn report_pinned(&self) -> Option<u64> {
+        (**self).report_pinned()
+    }
+    fn report_entries(&self) -> Option<u64> {
+        (**self).report_entries()
+    }
+    fn stats(&self) -> CacheStats {
+        (**self).stats()
+    }
+    async fn shrink_archive(&self, data_saver: bool, min: u64) -> Result<ShrinkResult, ()> {
+        (**self).shrink_archive(data_saver, min).await
+    }
+    async fn finish_shrink(&self, res: &ShrinkResult) {
+        (**self).finish_shrink(res).await
+    }
+    async fn sweep_idle(&self, max_idle: time::Duration) -> Result<ShrinkResult, ()> {
+        (**self).sweep_idle(max_idle).await
+    }
+    async fn compact(&self) {
+        (**self).compact().await
+    }
+    async fn flush(&self) {
+        (**self).flush().await
+    }
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        (**self).clear().await
+    }
+    async fn rotate(&self, path: &str) -> Result<Box<dyn ImageCache>, ()> {
+        (**self).rotate(path).await
+    }
+    async fn reconcile_size(
+        &self,
+        sample: Option<usize>,
+        max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        (**self).reconcile_size(sample, max_drift).await
+    }
+}
+
+/// Decides when the scheduled full compaction of the cache should run.
+///
+/// Compaction will run at most once per [`CompactionWindow`], and only if the request rate is below
+/// the configured maximum when the window is checked.
+pub struct CompactionScheduler {
+    window: CompactionWindow,
+    last_run: Option<NaiveDate>,
+}
+
+impl CompactionScheduler {
+    pub fn new(window: CompactionWindow) -> Self {
+        Self {
+            window,
+            last_run: None,
+        }
+    }
+
+    /// Returns whether a compaction should be started at `now`, marking the current window as
+    /// completed if it should.
+    pub fn should_run(&mut self, now: NaiveDateTime, requests_per_sec: f64) -> bool {
+        if !self.window.contains(now.hour()) {
+            return false;
+        }
+
+        // find the date that the current window started on. windows that wrap around midnight
+        // started on the day before if we're past midnight
+        let window_date = if self.window.start_hour > now.hour() {
+            now.date().pred()
+        } else {
+            now.date()
+        };
+        if self.last_run == Some(window_date) {
+            return false;
+        }
+
+        // wait until traffic dies down if there are too many requests
+        if let Some(max) = self.window.max_requests_per_second {
+            if requests_per_sec > max {
+                log::debug!(
+                    "delaying compaction, traffic too high ({:.2} req/s)",
+                    requests_per_sec
+                );
+                return false;
+            }
+        }
+
+        self.last_run = Some(window_date);
+        true
+    }
+}
+
+/// Paces heavy maintenance operations (like shrinking) based on the number of requests currently
+/// being served, so they don't hurt response times during peak traffic.
+///
+/// Maintenance runs at full speed unless the number of in-flight requests reaches the configured
+/// threshold, in which case it's done in smaller batches with longer pauses in between.
+#[derive(Clone, Default)]
+pub struct MaintenancePacer {
+    in_flight: Arc<AtomicUsize>,
+    busy_threshold: Option<usize>,
+}
+
+impl MaintenancePacer {
+    /// Pause in between batches while the client is busy
+    const BUSY_PAUSE: time::Duration = time::Duration::from_millis(100);
+    /// Fraction of a full batch that is processed while the client is busy
+    const BUSY_BATCH_DIVISOR: usize = 8;
+
+    pub fn new(in_flight: Arc<AtomicUsize>, busy_threshold: Option<usize>) -> Self {
+        Self {
+            in_flight,
+            busy_threshold,
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        match self.busy_threshold {
+            Some(threshold) => self.in_flight.load(Ordering::Relaxed) >= threshold,
+            None => false,
+        }
+    }
+
+    /// The number of items that should be processed in the next batch, where `full` is the batch
+    /// size when the client is idle
+    pub fn batch_size(&self, full: usize) -> usize {
+        if self.is_busy() {
+            (full / Self::BUSY_BATCH_DIVISOR).max(1)
+        } else {
+            full
+        }
+    }
+
+    /// Waits in between two batches, only yielding to the runtime if the client isn't busy
+    pub async fn pause(&self) {
+        if self.is_busy() {
+            tokio::time::sleep(Self::BUSY_PAUSE).await
+        } else {
+            tokio::task::yield_now().await
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Bare-bones in-memory cache for testing the components that depend on an [`ImageCache`]
+    #[derive(Default)]
+    pub(crate) struct TestCache {
+        entries: Mutex<HashMap<String, ImageEntry>>,
+        /// path the cache was rotated to, if it was created by a rotation
+        path: Option<String>,
+        /// number of calls to `load` and `save`, shared so they can be read after boxing the cache
+        pub(crate) loads: Arc<AtomicUsize>,
+        pub(crate) saves: Arc<AtomicUsize>,
+        pub(crate) flushes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ImageCache for TestCache {
+        async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+            self.loads.fetch_add(1, Ordering::SeqCst);
+            self.entries.lock().unwrap().get(&key.to_string()).cloned()
+        }
+        async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+            self.saves.fetch_add(1, Ordering::SeqCst);
+            let entry = ImageEntry::new_assume(data, mime_type);
+            self.entries.lock().unwrap().insert(key.to_string(), entry);
+            true
+        }
+        fn report(&self) -> u64 {
+            let entries = self.entries.lock().unwrap();
+            entries.values().map(ImageEntry::get_bytes_len).sum()
+        }
+        fn report_entries(&self) -> Option<u64> {
+            Some(self.entries.lock().unwrap().len() as u64)
+        }
+        fn info(&self) -> CacheInfo {
+            match &self.path {
+                Some(path) => CacheInfo::new("test").with_path(path.as_str()),
+                None => CacheInfo::new("test"),
+            }
+        }
+        async fn shrink(&self, _: u64) -> Result<ShrinkResult, ()> {
+            Ok(ShrinkResult {
+                size: self.report(),
+                ..Default::default()
+            })
+        }
+        async fn flush(&self) {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+        }
+        async fn clear(&self) -> Result<ShrinkResult, ()> {
+            let res = ShrinkResult {
+                size: 0,
+                bytes_evicted: self.report(),
+                entries_evicted: self.report_entries().unwrap_or_default(),
+            };
+            self.entries.lock().unwrap().clear();
+            Ok(res)
+        }
+        async fn rotate(&self, path: &str) -> Result<Box<dyn ImageCache>, ()> {
+            let entries = self.entries.lock().unwrap().clone();
+            Ok(Box::new(TestCache {
+                entries: Mutex::new(entries),
+                path: Some(path.to_string()),
+                ..Default::default()
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn variants_are_cached_separately() {
+        let original = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+        let variant = original.with_variant("webp-q80-800x1200".parse().unwrap());
+        assert_eq!(variant.to_string(), "/data/chapter/1.png#webp-q80-800x1200");
+        assert_ne!(original.as_bkey(), variant.as_bkey());
+
+        // originals keep the key they had before variants existed
+        let mut ctx = sha2::Sha256::new();
+        ctx.update([0]);
+        ctx.update("chapter");
+        ctx.update("1.png");
+        assert_eq!(original.as_bkey(), <[u8; 32]>::from(ctx.finalize()));
+
+        let parsed: ImageKey = variant.to_string().parse().unwrap();
+        assert_eq!(parsed.as_bkey(), variant.as_bkey());
+        assert_eq!(parsed.variant().unwrap().dimensions, Some((800, 1200)));
+        assert!("/data/chapter/1.png#webp-big".parse::<ImageKey>().is_err());
+
+        let cache = TestCache::default();
+        let (png, webp) = (Bytes::from_static(b"png"), Bytes::from_static(b"webp"));
+        assert!(cache.save(&original, "image/png".to_string(), png).await);
+        assert!(cache.save(&variant, "image/webp".to_string(), webp).await);
+        assert_eq!(
+            cache.load(&original).await.unwrap().get_bytes(),
+            &b"png"[..]
+        );
+        assert_eq!(
+            cache.load(&variant).await.unwrap().get_bytes(),
+            &b"webp"[..]
+        );
+    }
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2021, 11, day).and_hms(hour, 30, 0)
+    }
+
+    #[test]
+    fn entries_share_the_buffer_they_are_loaded_from() {
+        let entry = ImageEntry::new_assume(Bytes::from_static(b"image"), "image/png".to_string());
+        let buf: Bytes = entry.clone().try_into().unwrap();
+        let loaded = ImageEntry::try_from(buf.clone()).unwrap();
+
+        assert_eq!(loaded.get_bytes(), entry.get_bytes());
+        assert_eq!(loaded.get_bytes_len(), 5);
+        assert_eq!(loaded.get_checksum_hex(), entry.get_checksum_hex());
+        assert_eq!(loaded.get_mime(mime::IMAGE_JPEG), mime::IMAGE_PNG);
+        assert_eq!(loaded.get_save_time(), entry.get_save_time());
+        // the image points into the serialized buffer instead of a copy of it
+        let range = buf.as_ptr_range();
+        assert!(range.contains(&loaded.get_bytes().as_ptr()));
+    }
+
+    #[tokio::test]
+    async fn stats_default_to_the_reported_size() {
+        let cache: Box<dyn ImageCache> = Box::new(TestCache::default());
+        let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+        assert!(
+            cache
+                .save(&key, "image/png".to_string(), Bytes::from("image"))
+                .await
+        );
+
+        let stats = cache.stats();
+        assert_eq!(stats, CacheStats::new(5, Some(1)));
+        assert_eq!(
+            serde_json::to_value(&stats).unwrap(),
+            serde_json::json!({"size": 5, "entries": 1, "backend": {}})
+        );
+    }
+
+    #[test]
+    fn generic_mime_types_are_sniffed() {
+        let jpeg = Bytes::from_static(b"\xff\xd8\xff\xe0\0\x10JFIF");
+        for generic in &[
+            "",
+            "application/octet-stream",
+            "Application/Octet-Stream; x=y",
+        ] {
+            let entry = ImageEntry::new_assume(jpeg.clone(), generic.to_string());
+            assert_eq!(entry.get_mime(mime::IMAGE_PNG), mime::IMAGE_JPEG);
+        }
+
+        // explicit types are never second-guessed, and unknown bytes keep the generic type
+        let entry = ImageEntry::new_assume(jpeg, "image/webp".to_string());
+        assert_eq!(entry.get_mime(mime::IMAGE_PNG).essence_str(), "image/webp");
+        let entry = ImageEntry::new_assume(Bytes::from_static(b"??"), String::new());
+        assert_eq!(entry.get_mime(mime::IMAGE_PNG), mime::IMAGE_PNG);
+    }
+
+    #[test]
+    fn compaction_runs_once_per_window() {
+        let mut scheduler = CompactionScheduler::new(CompactionWindow {
+            start_hour: 3,
+            end_hour: 5,
+            max_requests_per_second: None,
+        });
+
+        assert!(!scheduler.should_run(at(1, 2), 0.0));
+        assert!(scheduler.should_run(at(1, 3), 0.0));
+        assert!(!scheduler.should_run(at(1, 4), 0.0));
+        assert!(!scheduler.should_run(at(1, 5), 0.0));
+        assert!(scheduler.should_run(at(2, 4), 0.0));
+    }
+
+    #[test]
+    fn compaction_window_wraps_midnight() {
+        let mut scheduler = CompactionScheduler::new(CompactionWindow {
+            start_hour: 23,
+            end_hour: 2,
+            max_requests_per_second: None,
+        });
+
+        assert!(!scheduler.should_run(at(1, 22), 0.0));
+        assert!(scheduler.should_run(at(1, 23), 0.0));
+        // still the same window after midnight
+        assert!(!scheduler.should_run(at(2, 1), 0.0));
+        assert!(scheduler.should_run(at(3, 0), 0.0));
+    }
+
+    #[test]
+    fn compaction_waits_for_low_traffic() {
+        let mut scheduler = CompactionScheduler::new(CompactionWindow {
+            start_hour: 3,
+            end_hour: 5,
+            max_requests_per_second: Some(10.0),
+        });
+
+        assert!(!scheduler.should_run(at(1, 3), 50.0));
+        assert!(scheduler.should_run(at(1, 4), 5.0));
+    }
+
+    #[tokio::test]
+    async fn pacer_slows_down_when_busy() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let pacer = MaintenancePacer::new(Arc::clone(&in_flight), Some(10));
+        assert_eq!(pacer.batch_size(256), 256);
+        let timer = time::Instant::now();
+        pacer.pause().await;
+        assert!(timer.elapsed() < MaintenancePacer::BUSY_PAUSE);
+
+        in_flight.store(10, Ordering::Relaxed);
+        assert_eq!(pacer.batch_size(256), 32);
+        assert_eq!(pacer.batch_size(4), 1);
+        let timer = time::Instant::now();
+        pacer.pause().await;
+        assert!(timer.elapsed() >= MaintenancePacer::BUSY_PAUSE);
+
+        // pacing is off without a threshold
+        let pacer = MaintenancePacer::new(in_flight, None);
+        assert_eq!(pacer.batch_size(256), 256);
+    }
 }
@@ -0,0 +1,494 @@
+use super::{CacheInfo, ImageCache, ImageEntry, ImageKey, ShrinkResult};
+use crate::config::RedisConfig;
+use crate::utils::{Clock, SystemClock};
+use bytes::Bytes;
+use redis::aio::ConnectionManager;
+use std::convert::{TryFrom, TryInto};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum CacheError {
+    Redis(redis::RedisError),
+    Bincode(bincode::Error),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Redis(e) => write!(fmt, "ce-redis/redis - \"{}\"", e),
+            Self::Bincode(e) => write!(fmt, "ce-redis/bincode - \"{}\"", e),
+        }
+    }
+}
+impl std::error::Error for CacheError {}
+
+/// A cache engine that stores images in Redis, so that many clients can share the same cache.
+///
+/// Redis evicts entries on its own once it reaches its `maxmemory` (according to its
+/// `maxmemory-policy`), so shrinking only refreshes the estimated size of the cache. Every key of
+/// the cache starts with the configured `key_prefix`, so the database can be shared with other
+/// data. The size is estimated from the keys found by scanning for the prefix (`SCAN`) and the
+/// memory usage of a sample of them (`MEMORY USAGE`).
+pub struct RedisCache {
+    /// connections that requests are spread across, each of which reconnects on its own after
+    /// the connection is dropped
+    pool: Vec<ConnectionManager>,
+    next_conn: AtomicUsize,
+
+    key_prefix: String,
+    /// seconds until entries expire (0 is never)
+    ttl_secs: u64,
+
+    /// estimated size of the cache, updated on saves and refreshed from Redis on shrinks
+    size: AtomicU64,
+    entries: AtomicU64,
+    /// source of the save times of entries
+    clock: Arc<dyn Clock>,
+    info: CacheInfo,
+}
+
+impl RedisCache {
+    /// The number of keys sampled to estimate the average size of an entry
+    const SIZE_SAMPLE: usize = 16;
+
+    pub async fn new(conf: &RedisConfig) -> Result<Self, CacheError> {
+        let client = redis::Client::open(conf.url.as_str()).map_err(CacheError::Redis)?;
+        let mut pool = Vec::with_capacity(conf.pool_size);
+        for _ in 0..conf.pool_size {
+            let conn = ConnectionManager::new(client.clone())
+                .await
+                .map_err(CacheError::Redis)?;
+            pool.push(conn);
+        }
+
+        let this = Self {
+            pool,
+            next_conn: AtomicUsize::new(0),
+            key_prefix: conf.key_prefix.clone(),
+            ttl_secs: conf.ttl_hours * 60 * 60,
+            size: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
+            info: CacheInfo::new("redis")
+                .with_setting("pool_size", conf.pool_size)
+                .with_setting("ttl_hours", conf.ttl_hours)
+                .with_setting("key_prefix", conf.key_prefix.clone()),
+        };
+        this.refresh_size().await?;
+        Ok(this)
+    }
+
+    /// Sets the clock that the save times of entries are taken from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Picks the next connection from the pool
+    fn conn(&self) -> ConnectionManager {
+        let i = self.next_conn.fetch_add(1, Ordering::Relaxed);
+        self.pool[i % self.pool.len()].clone()
+    }
+
+    /// The Redis key that an image is stored under
+    fn redis_key(&self, key: &ImageKey) -> String {
+        format!("{}{}", self.key_prefix, hex::encode(key.as_bkey()))
+    }
+
+    /// Re-estimates the size of the cache from the number of keys under the prefix and the average
+    /// memory usage of a sample of the entries.
+    ///
+    /// The keys are counted by scanning for the prefix, since the database might be shared with
+    /// other applications (making DBSIZE count their keys as well).
+    async fn refresh_size(&self) -> Result<u64, CacheError> {
+        let mut conn = self.conn();
+        let (mut cursor, mut entries) = (0u64, 0u64);
+        let mut sample = Vec::with_capacity(Self::SIZE_SAMPLE);
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", self.key_prefix))
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(&mut conn)
+                .await
+                .map_err(CacheError::Redis)?;
+            entries += keys.len() as u64;
+            let missing = Self::SIZE_SAMPLE - sample.len();
+            sample.extend(keys.into_iter().take(missing));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        let (mut sampled, mut sampled_size) = (0, 0);
+        for key in &sample {
+            let usage: Option<u64> = redis::cmd("MEMORY")
+                .arg("USAGE")
+                .arg(key)
+                .query_async(&mut conn)
+                .await
+                .map_err(CacheError::Redis)?;
+            // the key might have expired since it was scanned
+            if let Some(usage) = usage {
+                sampled += 1;
+                sampled_size += usage;
+            }
+        }
+
+        let size = sampled_size
+            .checked_div(sampled)
+            .map_or(0, |average| average * entries);
+        self.size.store(size, Ordering::SeqCst);
+        self.entries.store(entries, Ordering::SeqCst);
+        Ok(size)
+    }
+
+    /// Loads an entry from Redis
+    async fn load_entry(&self, key: &ImageKey) -> Result<Option<ImageEntry>, CacheError> {
+        let val: Option<Vec<u8>> = redis::cmd("GET")
+            .arg(self.redis_key(key))
+            .query_async(&mut self.conn())
+            .await
+            .map_err(CacheError::Redis)?;
+        val.map(|x| ImageEntry::try_from(Bytes::from(x)))
+            .transpose()
+            .map_err(CacheError::Bincode)
+    }
+
+    /// Saves an entry to Redis, expiring it after the configured TTL
+    async fn save_entry(&self, key: &ImageKey, entry: ImageEntry) -> Result<(), CacheError> {
+        let ser: Bytes = entry.try_into().map_err(CacheError::Bincode)?;
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(self.redis_key(key)).arg(ser.as_ref());
+        if self.ttl_secs > 0 {
+            cmd.arg("EX").arg(self.ttl_secs);
+        }
+        cmd.query_async::<_, ()>(&mut self.conn())
+            .await
+            .map_err(CacheError::Redis)?;
+
+        // replaced entries are counted twice until the size is refreshed
+        self.size.fetch_add(ser.len() as u64, Ordering::SeqCst);
+        self.entries.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Deletes every key with the cache's prefix, leaving the rest of the database alone
+    async fn clear_entries(&self) -> Result<ShrinkResult, CacheError> {
+        let mut conn = self.conn();
+        let bytes_evicted = self.size.load(Ordering::SeqCst);
+        let (mut cursor, mut entries_evicted) = (0u64, 0);
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", self.key_prefix))
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(&mut conn)
+                .await
+                .map_err(CacheError::Redis)?;
+            if !keys.is_empty() {
+                let deleted: u64 = redis::cmd("DEL")
+                    .arg(keys)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(CacheError::Redis)?;
+                entries_evicted += deleted;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        self.size.store(0, Ordering::SeqCst);
+        self.entries.store(0, Ordering::SeqCst);
+        Ok(ShrinkResult {
+            size: 0,
+            bytes_evicted,
+            entries_evicted,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageCache for RedisCache {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        match self.load_entry(key).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::error!("error loading entry from redis: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        // an empty body is never a valid image (i.e. a truncated upstream response)
+        if data.is_empty() {
+            log::warn!("refusing to save empty image {} to redis", key);
+            return false;
+        }
+        let entry = ImageEntry::new(data, mime_type, self.clock.now());
+        if let Err(e) = self.save_entry(key, entry).await {
+            log::error!("error saving entry to redis: {}", e);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn report(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    fn report_entries(&self) -> Option<u64> {
+        Some(self.entries.load(Ordering::SeqCst))
+    }
+
+    fn info(&self) -> CacheInfo {
+        self.info.clone()
+    }
+
+    /// Redis evicts entries itself (see `maxmemory`), so this only refreshes the estimated size
+    async fn shrink(&self, _min: u64) -> Result<ShrinkResult, ()> {
+        match self.refresh_size().await {
+            Ok(size) => Ok(ShrinkResult {
+                size,
+                ..Default::default()
+            }),
+            Err(e) => {
+                log::error!("error estimating the size of redis: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        self.clear_entries().await.map_err(|e| {
+            log::error!("error clearing redis: {}", e);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+
+    /// A tiny stand-in for Redis that speaks just enough of the protocol for the cache
+    #[derive(Default)]
+    struct MockRedis {
+        values: Mutex<HashMap<String, Vec<u8>>>,
+        /// the EX argument of the last SET of each key
+        ttls: Mutex<HashMap<String, u64>>,
+        /// closes the connection instead of responding to the next command
+        drop_next: AtomicBool,
+    }
+
+    impl MockRedis {
+        /// Starts the server, returning its URL
+        fn start(self: &Arc<Self>) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("redis://{}", listener.local_addr().unwrap());
+            let this = Arc::clone(self);
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let this = Arc::clone(&this);
+                    std::thread::spawn(move || this.serve(stream));
+                }
+            });
+            url
+        }
+
+        fn serve(&self, stream: TcpStream) {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            while let Some(args) = read_command(&mut reader) {
+                if self.drop_next.swap(false, Ordering::SeqCst) {
+                    return;
+                }
+                let reply = self.respond(&args);
+                if writer.write_all(&reply).is_err() {
+                    return;
+                }
+            }
+        }
+
+        fn respond(&self, args: &[Vec<u8>]) -> Vec<u8> {
+            let arg = |i: usize| String::from_utf8_lossy(&args[i]).into_owned();
+            let mut values = self.values.lock().unwrap();
+            match arg(0).to_uppercase().as_str() {
+                "GET" => bulk(values.get(&arg(1)).map(|x| x.as_slice())),
+                "SET" => {
+                    if args.len() == 5 && arg(3).eq_ignore_ascii_case("EX") {
+                        self.ttls
+                            .lock()
+                            .unwrap()
+                            .insert(arg(1), arg(4).parse().unwrap());
+                    }
+                    values.insert(arg(1), args[2].clone());
+                    b"+OK\r\n".to_vec()
+                }
+                "SCAN" => {
+                    let prefix = arg(3).trim_end_matches('*').to_string();
+                    let keys: Vec<_> = values.keys().filter(|x| x.starts_with(&prefix)).collect();
+                    let mut reply = format!("*2\r\n$1\r\n0\r\n*{}\r\n", keys.len()).into_bytes();
+                    for key in keys {
+                        reply.extend(bulk(Some(key.as_bytes())));
+                    }
+                    reply
+                }
+                "DEL" => {
+                    let deleted = args[1..]
+                        .iter()
+                        .filter(|x| values.remove(&*String::from_utf8_lossy(x)).is_some())
+                        .count();
+                    format!(":{}\r\n", deleted).into_bytes()
+                }
+                "MEMORY" => match values.get(&arg(2)) {
+                    Some(x) => format!(":{}\r\n", x.len()).into_bytes(),
+                    None => bulk(None),
+                },
+                _ => b"-ERR unknown command\r\n".to_vec(),
+            }
+        }
+    }
+
+    /// Reads a command (an array of bulk strings), or `None` once the connection is closed
+    fn read_command(reader: &mut impl BufRead) -> Option<Vec<Vec<u8>>> {
+        let n = read_len(reader, '*')?;
+        let mut args = Vec::with_capacity(n);
+        for _ in 0..n {
+            let len = read_len(reader, '$')?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).ok()?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        Some(args)
+    }
+
+    /// Reads a length line (like `*3` or `$5`) that starts with `prefix`
+    fn read_len(reader: &mut impl BufRead, prefix: char) -> Option<usize> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok().filter(|&n| n > 0)?;
+        line.trim_end().strip_prefix(prefix)?.parse().ok()
+    }
+
+    /// Encodes a bulk string reply (or the nil reply for `None`)
+    fn bulk(val: Option<&[u8]>) -> Vec<u8> {
+        match val {
+            Some(x) => {
+                let mut reply = format!("${}\r\n", x.len()).into_bytes();
+                reply.extend_from_slice(x);
+                reply.extend_from_slice(b"\r\n");
+                reply
+            }
+            None => b"$-1\r\n".to_vec(),
+        }
+    }
+
+    async fn open(url: String, extra: &str) -> RedisCache {
+        let conf: RedisConfig =
+            serde_yaml::from_str(&format!("url: {}\n{}", url, extra)).expect("redis test config");
+        RedisCache::new(&conf).await.expect("open redis cache")
+    }
+
+    fn key(i: usize) -> ImageKey {
+        ImageKey::new("chapter".to_string(), format!("{}.png", i), false)
+    }
+
+    #[tokio::test]
+    async fn entries_round_trip_with_ttl() {
+        let server = Arc::new(MockRedis::default());
+        let cache = open(server.start(), "ttl_hours: 2\npool_size: 2").await;
+        assert_eq!(cache.report(), 0);
+
+        let data = Bytes::from(vec![1u8; 1024]);
+        assert!(
+            cache
+                .save(&key(0), "image/png".to_string(), data.clone())
+                .await
+        );
+        let entry = cache.load(&key(0)).await.unwrap();
+        assert_eq!(entry.get_bytes(), data);
+        assert_eq!(entry.get_mime(mime::IMAGE_JPEG), mime::IMAGE_PNG);
+        assert!(cache.load(&key(1)).await.is_none());
+
+        let redis_key = format!("scalpel:{}", hex::encode(key(0).as_bkey()));
+        assert_eq!(server.ttls.lock().unwrap()[&redis_key], 2 * 60 * 60);
+        assert_eq!(cache.report_entries(), Some(1));
+
+        // the estimate is refreshed from redis when shrinking
+        let stored = server.values.lock().unwrap()[&redis_key].len() as u64;
+        server.values.lock().unwrap().clear();
+        assert_eq!(cache.report(), stored);
+        let res = cache.shrink(0).await.unwrap();
+        assert_eq!(res.size, 0);
+        assert_eq!(cache.report_entries(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn dropped_connections_are_reestablished() {
+        let server = Arc::new(MockRedis::default());
+        let cache = open(server.start(), "pool_size: 1").await;
+        let data = Bytes::from(vec![1u8; 1024]);
+        assert!(cache.save(&key(0), "image/png".to_string(), data).await);
+
+        server.drop_next.store(true, Ordering::SeqCst);
+        assert!(cache.load(&key(0)).await.is_none());
+        assert!(cache.load(&key(0)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn clear_only_deletes_prefixed_keys() {
+        let server = Arc::new(MockRedis::default());
+        let cache = open(server.start(), "").await;
+        for i in 0..3 {
+            let data = Bytes::from(vec![1u8; 1024]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+        }
+        server
+            .values
+            .lock()
+            .unwrap()
+            .insert("unrelated".to_string(), b"value".to_vec());
+
+        let res = cache.clear().await.unwrap();
+        assert_eq!(res.entries_evicted, 3);
+        assert_eq!(cache.report(), 0);
+        for i in 0..3 {
+            assert!(cache.load(&key(i)).await.is_none());
+        }
+        assert!(server.values.lock().unwrap().contains_key("unrelated"));
+    }
+
+    #[tokio::test]
+    async fn size_only_counts_prefixed_keys() {
+        let server = Arc::new(MockRedis::default());
+        let cache = open(server.start(), "").await;
+        let data = Bytes::from(vec![1u8; 1024]);
+        assert!(cache.save(&key(0), "image/png".to_string(), data).await);
+        let stored = cache.report();
+        {
+            // another application sharing the database
+            let mut values = server.values.lock().unwrap();
+            for i in 0..10 {
+                values.insert(format!("other:{}", i), vec![0u8; 4096]);
+            }
+        }
+
+        assert_eq!(cache.shrink(0).await.unwrap().size, stored);
+        assert_eq!(cache.report_entries(), Some(1));
+    }
+}
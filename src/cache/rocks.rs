@@ -1,22 +1,34 @@
-use super::{ImageCache, ImageEntry, ImageKey};
+use super::{
+    CacheInfo, CacheStats, EvictionHook, EvictionReason, ImageCache, ImageEntry, ImageKey,
+    MaintenancePacer, ShrinkResult, SizeReconciliation,
+};
 use crate::config::RocksConfig;
-use crate::utils::now_as_millis;
+use crate::utils::{Clock, SystemClock};
 use bytes::Bytes;
 use rocksdb::{
-    BoundColumnFamily, ColumnFamilyDescriptor, DBWithThreadMode, Error as DBError, IteratorMode,
+    BoundColumnFamily, ColumnFamilyDescriptor, DBWithThreadMode, Direction, Error as DBError,
+    IteratorMode,
 };
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, RwLock,
 };
 
 type MultiDB = DBWithThreadMode<rocksdb::MultiThreaded>;
+/// The database that entries are being migrated to, if any (see [`RocksCache::migrate_entries`])
+type MigrationTarget = Arc<RwLock<Option<Arc<MultiDB>>>>;
 
 #[derive(Debug)]
 pub enum CacheError {
     Rocks(DBError),
     Bincode(bincode::Error),
     TokioJoin(tokio::task::JoinError),
+    /// The layout version of the database is unknown or malformed (`None`)
+    UnsupportedLayout(Option<u32>),
+    /// The images of the database are spread across a different number of shards than configured
+    ShardMismatch(u64),
 }
 
 impl std::fmt::Display for CacheError {
@@ -56,6 +68,32 @@ fn cf_opts(conf: &RocksConfig) -> rocksdb::Options {
     cf_opts.set_level_compaction_dynamic_level_bytes(true);
     cf_opts.set_block_based_table_factory(&block_cf_opts(conf));
 
+    // compress with zstd if enabled in config, passing the window as the window bits so matches
+    // are searched for further back (a negative window lets zstd pick it based on the level). The
+    // bundled RocksDB only hands the level to zstd so far, but the window bits are stored in the
+    // OPTIONS file and applied once it does
+    match &conf.zstd {
+        Some(zstd) => {
+            // a dictionary trained from samples of each file helps with many small, similar
+            // images (like data-saver images), which don't have much to match against on their own
+            let dict_bytes = zstd.dict_kibibytes.unwrap_or(0) as i32 * 1024;
+            cf_opts.set_compression_type(rocksdb::DBCompressionType::Zstd);
+            cf_opts.set_compression_options(
+                zstd.window_log.unwrap_or(-14),
+                zstd.level,
+                0,
+                dict_bytes,
+            );
+            if dict_bytes > 0 {
+                let train_bytes = zstd
+                    .dict_train_kibibytes
+                    .map_or(dict_bytes * 100, |x| x as i32 * 1024);
+                cf_opts.set_zstd_max_train_bytes(train_bytes);
+            }
+        }
+        None => cf_opts.set_compression_type(rocksdb::DBCompressionType::None),
+    }
+
     cf_opts
 }
 fn db_opts(conf: &RocksConfig) -> rocksdb::Options {
@@ -69,8 +107,9 @@ fn db_opts(conf: &RocksConfig) -> rocksdb::Options {
     opts.increase_parallelism(conf.parallelism.unwrap_or(2));
 
     // optimize compactions
-    opts.set_max_background_jobs(6);
-    opts.set_compaction_readahead_size(8 * MEBIBYTE);
+    opts.set_max_background_jobs(conf.max_background_jobs.unwrap_or(6));
+    opts.set_max_subcompactions(conf.max_subcompactions.unwrap_or(1));
+    opts.set_compaction_readahead_size(conf.compaction_readahead_size.unwrap_or(8) * MEBIBYTE);
     opts.optimize_level_style_compaction(512 * MEBIBYTE);
 
     // optimize writes
@@ -85,47 +124,367 @@ fn db_opts(conf: &RocksConfig) -> rocksdb::Options {
     opts
 }
 
-#[derive(Debug)]
+/// Describes the database options that are created from the configuration above
+fn describe(conf: &RocksConfig) -> CacheInfo {
+    CacheInfo::new("rocksdb")
+        .with_path(conf.path.clone())
+        .with_setting("in_memory", conf.in_memory)
+        .with_setting("shards", conf.shards)
+        .with_setting(
+            "compression",
+            if conf.zstd.is_some() { "zstd" } else { "none" },
+        )
+        .with_setting("zstd_level", conf.zstd.as_ref().map(|x| x.level))
+        .with_setting(
+            "zstd_window_log",
+            conf.zstd.as_ref().and_then(|x| x.window_log),
+        )
+        .with_setting(
+            "zstd_dict_kibibytes",
+            conf.zstd.as_ref().and_then(|x| x.dict_kibibytes),
+        )
+        .with_setting("bloom_filter", !conf.disable_bloom_filter)
+        .with_setting("block_cache_mebibytes", conf.lru_size.unwrap_or(64))
+        .with_setting("parallelism", conf.parallelism.unwrap_or(2))
+        .with_setting(
+            "write_buffer_mebibytes",
+            conf.write_buffer_size.unwrap_or(64),
+        )
+        .with_setting("write_rate_limit_mebibytes", conf.write_rate_limit)
+        .with_setting("max_background_jobs", conf.max_background_jobs.unwrap_or(6))
+        .with_setting("max_subcompactions", conf.max_subcompactions.unwrap_or(1))
+        .with_setting(
+            "compaction_readahead_mebibytes",
+            conf.compaction_readahead_size.unwrap_or(8),
+        )
+        .with_setting(
+            "compact_after_shrink_deletes",
+            conf.compact_after_shrink_deletes,
+        )
+        .with_features(&["archive_budgets", "idle_sweep", "compaction"])
+}
+
+/// Migrations that bring the on-disk layout up to date, where index `n` migrates the database from
+/// layout version `n` to `n + 1`
+const MIGRATIONS: &[fn(&MultiDB) -> Result<(), CacheError>] = &[
+    migrate_unversioned,
+    migrate_saver_cf,
+    migrate_access_cf,
+    migrate_pinned_cf,
+    migrate_keys_cf,
+];
+
+/// Databases created before the layout version was stored already use the version 1 layout, so
+/// there is nothing to do besides stamping the version.
+fn migrate_unversioned(_: &MultiDB) -> Result<(), CacheError> {
+    Ok(())
+}
+
+/// Version 2 added the column family that tracks data-saver entries, which is created when opening
+/// the database. The archive type of existing entries can't be recovered from their hashed keys, so
+/// they are all counted as `data` until they're evicted.
+fn migrate_saver_cf(_: &MultiDB) -> Result<(), CacheError> {
+    Ok(())
+}
+
+/// Parses the values stored in the data-saver and access column families
+fn parse_le_u64(val: &[u8]) -> Option<u64> {
+    use std::convert::TryInto;
+    val.try_into().ok().map(u64::from_le_bytes)
+}
+
+/// Version 3 added the column family that tracks when entries were last accessed, which is created
+/// when opening the database. Entries without an access time fall back to their save time.
+fn migrate_access_cf(_: &MultiDB) -> Result<(), CacheError> {
+    Ok(())
+}
+
+/// Version 4 added the column family that tracks pinned entries, which is created when opening the
+/// database. Existing entries of pinned chapters are pinned the next time they're loaded.
+fn migrate_pinned_cf(_: &MultiDB) -> Result<(), CacheError> {
+    Ok(())
+}
+
+/// Version 5 added the column family that maps the hashed keys back to the image they were saved
+/// for, which is created when opening the database. The image of existing entries can't be
+/// recovered, so they aren't reported to the eviction hook when they're removed.
+fn migrate_keys_cf(_: &MultiDB) -> Result<(), CacheError> {
+    Ok(())
+}
+
 pub struct RocksCache {
     db: Arc<MultiDB>,
 
     db_size: AtomicU64,
+    /// bytes of the db_size that belong to data-saver images
+    saver_size: AtomicU64,
+    /// bytes of the db_size that belong to pinned entries
+    pinned_size: AtomicU64,
     last_fetch: AtomicU64,
+
+    /// chapter hashes whose images are never evicted
+    pins: HashSet<String>,
+    eviction_hook: Option<EvictionHook>,
+    pacer: MaintenancePacer,
+    /// shrinks that evict more entries than this are followed by a compaction (0 is never)
+    compact_after_shrink_deletes: u64,
+    /// source of the save and access times of entries
+    clock: Arc<dyn Clock>,
+    /// configuration the database was opened with, reused when it's rotated to a new path
+    conf: RocksConfig,
+    info: CacheInfo,
+    /// every write is applied to this database as well, once the database is being rotated to it
+    migration: MigrationTarget,
+}
+
+impl std::fmt::Debug for RocksCache {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("RocksCache")
+            .field("db", &self.db)
+            .field("db_size", &self.db_size)
+            .field("saver_size", &self.saver_size)
+            .field("pinned_size", &self.pinned_size)
+            .field("last_fetch", &self.last_fetch)
+            .field("pins", &self.pins)
+            .field("eviction_hook", &self.eviction_hook.is_some())
+            .field("pacer", &"MaintenancePacer")
+            .field(
+                "compact_after_shrink_deletes",
+                &self.compact_after_shrink_deletes,
+            )
+            .field("clock", &"Clock")
+            .field("conf", &self.conf)
+            .field("info", &self.info)
+            .field("migrating", &self.is_migrating())
+            .finish()
+    }
 }
 
 impl RocksCache {
     const IMAGES_CF: &'static str = "data";
     const META_CF: &'static str = "meta";
+    /// Keys of the data-saver entries, mapped to the size of the image (u64, little endian)
+    const SAVER_CF: &'static str = "saver";
+    /// Keys of entries, mapped to the last time they were loaded (millis since epoch, u64 little
+    /// endian)
+    const ACCESS_CF: &'static str = "access";
+    /// Keys of pinned entries, mapped to the size of the image (u64, little endian) followed by the
+    /// chapter hash the entry was pinned for
+    const PINNED_CF: &'static str = "pinned";
+    /// Keys of entries, mapped to the path of the image they were saved for (see [`ImageKey`]'s
+    /// `Display`), so the eviction hook can be told which image was removed
+    const KEYS_CF: &'static str = "keys";
+
+    /// How outdated the stored access time of an entry can be before a load updates it (1 hr in
+    /// milliseconds). This keeps hot entries from causing a write on every load.
+    const ACCESS_GRANULARITY: u64 = 1000 * 60 * 60;
+
+    /// Version of the on-disk layout of the database. This must be bumped (and a migration added to
+    /// [`MIGRATIONS`]) whenever the way entries are stored changes.
+    const LAYOUT_VERSION: u32 = 5;
+    /// Key in the default column family that stores the layout version
+    const LAYOUT_KEY: &'static [u8] = b"scalpel_layout_version";
 
     pub fn new(conf: &RocksConfig) -> Result<Self, CacheError> {
-        let image_cf = ColumnFamilyDescriptor::new(Self::IMAGES_CF, cf_opts(conf));
-        let meta_cf = ColumnFamilyDescriptor::new(Self::META_CF, cf_opts(conf));
+        let cfs: Vec<_> = Self::cf_names(conf.shards)
+            .into_iter()
+            .map(|name| ColumnFamilyDescriptor::new(name, cf_opts(conf)))
+            .collect();
 
-        let db = MultiDB::open_cf_descriptors(&db_opts(conf), &conf.path, vec![image_cf, meta_cf])
-            .map_err(CacheError::Rocks)?;
+        // in-memory databases still use the path, but it never touches the disk
+        let mut opts = db_opts(conf);
+        if conf.in_memory {
+            opts.set_env(&rocksdb::Env::mem_env().map_err(CacheError::Rocks)?);
+        }
+
+        Self::check_shards(&opts, &conf.path, conf.shards)?;
+        let db = MultiDB::open_cf_descriptors(&opts, &conf.path, cfs).map_err(CacheError::Rocks)?;
+        Self::check_layout(&db)?;
 
         let this = Self {
             db: Arc::new(db),
 
             db_size: AtomicU64::new(0),
+            saver_size: AtomicU64::new(0),
+            pinned_size: AtomicU64::new(0),
             last_fetch: AtomicU64::new(0),
+
+            pins: HashSet::new(),
+            eviction_hook: None,
+            pacer: MaintenancePacer::default(),
+            compact_after_shrink_deletes: conf.compact_after_shrink_deletes,
+            clock: Arc::new(SystemClock),
+            conf: conf.clone(),
+            info: describe(conf),
+            migration: Default::default(),
         };
         this.fetch_real_size()?;
         Ok(this)
     }
 
+    /// Registers a hook that is called for every entry that's removed from the database (see
+    /// [`EvictionReason`])
+    pub fn with_eviction_hook(mut self, hook: EvictionHook) -> Self {
+        self.eviction_hook = Some(hook);
+        self
+    }
+
+    /// Pins the images of the chapter hashes provided, so they're never evicted. Entries pinned for
+    /// chapters that aren't in `pins` anymore are unpinned.
+    pub fn with_pins(mut self, pins: HashSet<String>) -> Self {
+        self.pins = pins;
+        if let Err(e) = self.reconcile_pins() {
+            log::error!("error updating pinned RocksDb entries: {}", e);
+        }
+        self
+    }
+
+    /// Sets the pacer used to slow down shrinking and sweeping while the client is busy
+    pub fn with_pacer(mut self, pacer: MaintenancePacer) -> Self {
+        self.pacer = pacer;
+        self
+    }
+
+    /// Sets the clock that the save and access times of entries are taken from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_fetch
+            .store(clock.now_as_millis(), Ordering::SeqCst);
+        self.clock = clock;
+        self
+    }
+
+    /// Checks the layout version of the database, running any migrations needed to bring it up to
+    /// date. Databases written with a newer (or unknown) layout are refused.
+    fn check_layout(db: &MultiDB) -> Result<(), CacheError> {
+        use std::convert::TryInto;
+
+        let version = match db.get(Self::LAYOUT_KEY).map_err(CacheError::Rocks)? {
+            Some(bytes) => bytes
+                .as_slice()
+                .try_into()
+                .map(u32::from_le_bytes)
+                .map_err(|_| CacheError::UnsupportedLayout(None))?,
+            // without a stored version, the database is either brand new or it's from before the
+            // layout was versioned
+            None => {
+                let meta = db.cf_handle(Self::META_CF).expect("cf_handle non-existant");
+                if db.iterator_cf(&meta, IteratorMode::Start).next().is_none() {
+                    Self::LAYOUT_VERSION
+                } else {
+                    0
+                }
+            }
+        };
+
+        if version > Self::LAYOUT_VERSION {
+            log::error!(
+                "RocksDb cache was created by a newer version of the client (layout v{}, \
+                supported up to v{}). please upgrade the client or use a different path",
+                version,
+                Self::LAYOUT_VERSION
+            );
+            return Err(CacheError::UnsupportedLayout(Some(version)));
+        }
+
+        // run all migrations from the stored version up to the current version
+        for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            log::info!(
+                "migrating RocksDb cache layout from v{} to v{}",
+                from,
+                from + 1
+            );
+            migrate(db)?;
+        }
+
+        db.put(Self::LAYOUT_KEY, Self::LAYOUT_VERSION.to_le_bytes())
+            .map_err(CacheError::Rocks)
+    }
+
+    /// Checks that an existing database spreads its images across `shards` column families, since
+    /// images would be looked up in the wrong shard otherwise. This has to happen before opening
+    /// the database, which creates any missing shards.
+    fn check_shards(opts: &rocksdb::Options, path: &str, shards: usize) -> Result<(), CacheError> {
+        // the column families can't be listed if the database doesn't exist yet
+        let names = match MultiDB::list_cf(opts, path) {
+            Ok(names) => names,
+            Err(_) => return Ok(()),
+        };
+        let stored = Self::image_cf_names(256)
+            .filter(|x| names.iter().any(|name| name == x))
+            .count();
+
+        if stored != 0 && stored != shards {
+            log::error!(
+                "RocksDb cache spreads its images across {} shards, but {} are configured. \
+                please change the shards back or use a different path",
+                stored,
+                shards
+            );
+            return Err(CacheError::ShardMismatch(stored as u64));
+        }
+        Ok(())
+    }
+
+    /// Name of the column family (out of `shards`) that stores the image of `key`. The first shard
+    /// is the original images column family, so a single shard keeps the unsharded layout.
+    fn image_cf_name(shards: usize, key: &[u8]) -> Cow<'static, str> {
+        match key.first().map_or(0, |&x| x as usize % shards) {
+            0 => Cow::Borrowed(Self::IMAGES_CF),
+            n => Cow::Owned(format!("{}_{}", Self::IMAGES_CF, n)),
+        }
+    }
+
+    /// Names of the `shards` column families that store images
+    fn image_cf_names(shards: usize) -> impl Iterator<Item = Cow<'static, str>> {
+        (0..shards).map(move |n| Self::image_cf_name(shards, &[n as u8]))
+    }
+
+    /// Names of every column family, starting with the `shards` image column families
+    fn cf_names(shards: usize) -> Vec<Cow<'static, str>> {
+        let others = [
+            Self::META_CF,
+            Self::SAVER_CF,
+            Self::ACCESS_CF,
+            Self::PINNED_CF,
+            Self::KEYS_CF,
+        ];
+        Self::image_cf_names(shards)
+            .chain(others.iter().map(|&x| Cow::Borrowed(x)))
+            .collect()
+    }
+
     /// Obtains a ColumnFamily by name. Panics if the name provided does not exist.
-    fn cf_by_name(&self, name: &'static str) -> Arc<BoundColumnFamily> {
-        self.db.cf_handle(name).expect("cf handle name invalid")
+    fn cf_by_name(&self, name: &str) -> Arc<BoundColumnFamily> {
+        Self::cf_of(&self.db, name)
+    }
+
+    /// Obtains a ColumnFamily of `db` by name, for operations that run on a blocking thread (see
+    /// [`db_op_async`](Self::db_op_async)). Panics if the name provided does not exist.
+    fn cf_of<'a>(db: &'a MultiDB, name: &str) -> Arc<BoundColumnFamily<'a>> {
+        db.cf_handle(name).expect("cf handle name invalid")
     }
 
     /// Fetches the actual size of the database content by iterating through metadata.
     fn fetch_real_size(&self) -> Result<(), CacheError> {
+        let sizes = Self::count_sizes(&self.db, self.conf.shards)?;
+        self.store_real_size(sizes)
+    }
+
+    /// Same as [`fetch_real_size`](Self::fetch_real_size), but iterates on a blocking thread
+    async fn fetch_real_size_async(&self) -> Result<(), CacheError> {
+        let shards = self.conf.shards;
+        let sizes = self
+            .db_op_async(move |db| Self::count_sizes(db, shards))
+            .await?;
+        self.store_real_size(sizes)
+    }
+
+    /// Counts the size of all of the images and of the data-saver images, dropping the entries
+    /// whose metadata is malformed
+    fn count_sizes(db: &MultiDB, shards: usize) -> Result<(u64, u64), CacheError> {
         let mut sz = 0u64;
 
-        let iter = self
-            .db
-            .iterator_cf(&self.cf_by_name(Self::META_CF), IteratorMode::Start);
+        let iter = db.iterator_cf(&Self::cf_of(db, Self::META_CF), IteratorMode::Start);
         for (key, val) in iter {
             // attempt to deserialize the data and add the size to the `sz` iterator
             if let Ok(entry) = bincode::deserialize::<ImageEntry>(&val).map_err(CacheError::Bincode)
@@ -134,12 +493,72 @@ impl RocksCache {
                 continue;
             }
             // drop all entries that could not be successfully deserialized
-            self.drop_entry(&key)?;
+            Self::drop_entry(db, shards, &key)?;
         }
 
+        // the data-saver entries store their size directly
+        let saver_sz: u64 = db
+            .iterator_cf(&Self::cf_of(db, Self::SAVER_CF), IteratorMode::Start)
+            .filter_map(|(_, val)| parse_le_u64(&val))
+            .sum();
+        Ok((sz, saver_sz))
+    }
+
+    /// Stores the sizes counted by [`count_sizes`](Self::count_sizes), and recounts the pinned
+    /// entries
+    fn store_real_size(&self, (sz, saver_sz): (u64, u64)) -> Result<(), CacheError> {
         // store the new size and the last fetch
         self.db_size.store(sz, Ordering::SeqCst);
-        self.last_fetch.store(now_as_millis(), Ordering::SeqCst);
+        self.saver_size.store(saver_sz, Ordering::SeqCst);
+        self.last_fetch
+            .store(self.clock.now_as_millis(), Ordering::SeqCst);
+        self.reconcile_pins()
+    }
+
+    /// Unpins the entries of chapters that aren't pinned anymore, and recounts the size of the
+    /// pinned entries
+    fn reconcile_pins(&self) -> Result<(), CacheError> {
+        let cf = self.cf_by_name(Self::PINNED_CF);
+        let mut sz = 0u64;
+        for (key, val) in self.db.iterator_cf(&cf, IteratorMode::Start) {
+            // malformed values are unpinned as well
+            let (len, chapter) = val.split_at(8.min(val.len()));
+            match (parse_le_u64(len), std::str::from_utf8(chapter)) {
+                (Some(len), Ok(chapter)) if self.pins.contains(chapter) => sz += len,
+                _ => self.db.delete_cf(&cf, &key).map_err(CacheError::Rocks)?,
+            }
+        }
+        self.pinned_size.store(sz, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Whether an entry is pinned, and therefore can't be evicted
+    fn is_pinned(db: &MultiDB, key: &[u8]) -> Result<bool, CacheError> {
+        let val = db
+            .get_cf(&Self::cf_of(db, Self::PINNED_CF), key)
+            .map_err(CacheError::Rocks)?;
+        Ok(val.is_some())
+    }
+
+    /// Pins an entry of a pinned chapter with `len` image bytes, if it isn't already pinned
+    async fn pin_entry(&self, key: &ImageKey, bkey: Bytes, len: u64) -> Result<(), CacheError> {
+        let mut val = len.to_le_bytes().to_vec();
+        val.extend_from_slice(key.chapter().as_bytes());
+        let pinned = self
+            .write_op_async(move |db| {
+                let cf = db
+                    .cf_handle(Self::PINNED_CF)
+                    .expect("cf_handle non-existant");
+                if db.get_cf(&cf, &bkey).map_err(CacheError::Rocks)?.is_some() {
+                    return Ok(false);
+                }
+                db.put_cf(&cf, &bkey, &val).map_err(CacheError::Rocks)?;
+                Ok(true)
+            })
+            .await?;
+        if pinned {
+            self.pinned_size.fetch_add(len, Ordering::Relaxed);
+        }
         Ok(())
     }
 
@@ -149,7 +568,7 @@ impl RocksCache {
         const MIN_TIME: u64 = 1000 * 60 * 60;
 
         // if it's been over an hour since the last fetch, then re-fetch db size
-        let now = now_as_millis();
+        let now = self.clock.now_as_millis();
         if now - self.last_fetch.load(Ordering::Relaxed) > MIN_TIME {
             self.fetch_real_size()?;
         }
@@ -157,17 +576,116 @@ impl RocksCache {
         Ok(self.db_size.load(Ordering::SeqCst))
     }
 
-    // Drops an entry from the data and metadata column families.
-    fn drop_entry(&self, key: &[u8]) -> Result<(), CacheError> {
-        self.db
-            .delete_cf(&self.cf_by_name(Self::IMAGES_CF), key)
+    /// Finds the image size of a data-saver entry. Returns `None` if the entry isn't data-saver.
+    fn saver_len(db: &MultiDB, key: &[u8]) -> Result<Option<u64>, CacheError> {
+        let val = db
+            .get_cf(&Self::cf_of(db, Self::SAVER_CF), key)
             .map_err(CacheError::Rocks)?;
-        self.db
-            .delete_cf(&self.cf_by_name(Self::META_CF), key)
+        Ok(val.and_then(|x| parse_le_u64(&x)))
+    }
+
+    /// Finds the last time an entry was loaded (millis since epoch), if it has been loaded before
+    fn access_time(db: &MultiDB, key: &[u8]) -> Result<Option<u64>, CacheError> {
+        let val = db
+            .get_cf(&Self::cf_of(db, Self::ACCESS_CF), key)
+            .map_err(CacheError::Rocks)?;
+        Ok(val.and_then(|x| parse_le_u64(&x)))
+    }
+
+    /// Records that an entry was just loaded, without waiting for the write to finish
+    fn touch(&self, key: Bytes) {
+        let db = Arc::clone(&self.db);
+        let migration = Arc::clone(&self.migration);
+        let now = self.clock.now_as_millis();
+        tokio::task::spawn_blocking(move || {
+            let res = Self::write_with_migration(&db, &migration, |db| {
+                let cf = db
+                    .cf_handle(Self::ACCESS_CF)
+                    .expect("cf_handle non-existant");
+                db.put_cf(&cf, &key, now.to_le_bytes())
+                    .map_err(CacheError::Rocks)
+            });
+            if let Err(e) = res {
+                log::warn!("error recording RocksDb entry access: {}", e);
+            }
+        });
+    }
+
+    // Drops an entry from all of the column families.
+    fn drop_entry(db: &MultiDB, shards: usize, key: &[u8]) -> Result<(), CacheError> {
+        db.delete_cf(&Self::cf_of(db, &Self::image_cf_name(shards, key)), key)
+            .map_err(CacheError::Rocks)?;
+        db.delete_cf(&Self::cf_of(db, Self::META_CF), key)
+            .map_err(CacheError::Rocks)?;
+        db.delete_cf(&Self::cf_of(db, Self::SAVER_CF), key)
+            .map_err(CacheError::Rocks)?;
+        db.delete_cf(&Self::cf_of(db, Self::ACCESS_CF), key)
+            .map_err(CacheError::Rocks)?;
+        db.delete_cf(&Self::cf_of(db, Self::PINNED_CF), key)
+            .map_err(CacheError::Rocks)?;
+        db.delete_cf(&Self::cf_of(db, Self::KEYS_CF), key)
             .map_err(CacheError::Rocks)?;
         Ok(())
     }
 
+    /// Finds the image that an entry was saved for, if it was saved with layout v5 or later
+    fn image_key(db: &MultiDB, key: &[u8]) -> Result<Option<ImageKey>, CacheError> {
+        let val = db
+            .get_cf(&Self::cf_of(db, Self::KEYS_CF), key)
+            .map_err(CacheError::Rocks)?;
+        Ok(val.and_then(|x| std::str::from_utf8(&x).ok()?.parse().ok()))
+    }
+
+    /// Calls the eviction hook (if any) for each of the removed entries whose image is known
+    fn report_evicted<'a, I>(&self, evicted: I, reason: EvictionReason)
+    where
+        I: IntoIterator<Item = (Option<&'a ImageKey>, u64)>,
+    {
+        if let Some(hook) = &self.eviction_hook {
+            for (key, len) in evicted {
+                if let Some(key) = key {
+                    hook(key, len, reason);
+                }
+            }
+        }
+    }
+
+    /// Evicts a batch of entries (their keys and image sizes) on a blocking thread, then calls the
+    /// eviction hook for each of them with the `reason`. Returns the image that each evicted entry
+    /// was saved for (if known) and its size, along with the size of the image of those that were
+    /// data-saver entries. Entries that were already deleted (i.e. expired by a load) are skipped.
+    ///
+    /// Doesn't update the size counters, which is left up to the caller.
+    #[allow(clippy::type_complexity)]
+    async fn evict_entries(
+        &self,
+        entries: Vec<(Box<[u8]>, u64)>,
+        reason: EvictionReason,
+    ) -> Result<Vec<(Option<ImageKey>, u64, Option<u64>)>, CacheError> {
+        let shards = self.conf.shards;
+        let evicted = self
+            .write_op_async(move |db| {
+                let meta = Self::cf_of(db, Self::META_CF);
+                let mut evicted = Vec::with_capacity(entries.len());
+                for (key, len) in &entries {
+                    if db.get_cf(&meta, key).map_err(CacheError::Rocks)?.is_none() {
+                        continue;
+                    }
+                    let saver_len = Self::saver_len(db, key)?;
+                    let image_key = Self::image_key(db, key)?;
+                    Self::drop_entry(db, shards, key)?;
+                    evicted.push((image_key, *len, saver_len));
+                }
+                Ok(evicted)
+            })
+            .await?;
+        self.report_evicted(
+            evicted.iter().map(|(key, len, _)| (key.as_ref(), *len)),
+            reason,
+        );
+        Ok(evicted)
+    }
+
     /// Function that will spawn a blocking threat to perform an async db operation.
     async fn db_op_async<R, F>(&self, f: F) -> Result<R, CacheError>
     where
@@ -182,39 +700,63 @@ impl RocksCache {
             .map_err(CacheError::TokioJoin)
             .and_then(|x| x)
     }
+
+    /// Same as [`db_op_async`](Self::db_op_async), but for operations that write to the database,
+    /// which are applied to the database that entries are being migrated to as well (if any).
+    async fn write_op_async<R, F>(&self, f: F) -> Result<R, CacheError>
+    where
+        R: Send + 'static,
+        F: Fn(&MultiDB) -> Result<R, CacheError> + Send + 'static,
+    {
+        let migration = Arc::clone(&self.migration);
+        self.db_op_async(move |db| Self::write_with_migration(db, &migration, f))
+            .await
+    }
+
+    /// Applies a write to `db`, and to the database that entries are being migrated to (if any).
+    /// Returns what the write to `db` returned.
+    ///
+    /// The migration is held up until the write is done, so a batch that's being copied never
+    /// overwrites the write with an outdated value, or brings back an entry that was deleted.
+    fn write_with_migration<R, F>(
+        db: &MultiDB,
+        migration: &MigrationTarget,
+        f: F,
+    ) -> Result<R, CacheError>
+    where
+        F: Fn(&MultiDB) -> Result<R, CacheError>,
+    {
+        let dest = migration.read().unwrap_or_else(|e| e.into_inner());
+        let res = f(db)?;
+        if let Some(dest) = dest.as_ref() {
+            f(dest)?;
+        }
+        Ok(res)
+    }
+
+    /// Whether every write is applied to another database as well (see
+    /// [`migrate_entries`](Self::migrate_entries))
+    fn is_migrating(&self) -> bool {
+        let dest = self.migration.read().unwrap_or_else(|e| e.into_inner());
+        dest.is_some()
+    }
+
     /// Utilizes `db_op_async` place an item in a Column Family with async
-    async fn put_cf_async(
-        &self,
-        cf_name: &'static str,
-        key: Bytes,
-        val: Bytes,
-    ) -> Result<(), CacheError> {
-        self.db_op_async(move |db| {
+    async fn put_cf_async<N>(&self, cf_name: N, key: Bytes, val: Bytes) -> Result<(), CacheError>
+    where
+        N: AsRef<str> + Send + 'static,
+    {
+        self.write_op_async(move |db| {
             // find the ColumnFamily by name
-            let cf = db.cf_handle(cf_name).expect("cf_handle non-existant");
+            let cf = db
+                .cf_handle(cf_name.as_ref())
+                .expect("cf_handle non-existant");
 
             // place the entry into the database
             db.put_cf(&cf, &key, &val).map_err(CacheError::Rocks)
         })
         .await
     }
-    /// Utilizes `db_op_async` to obtain the bytes of an entry in a Column Family with async
-    async fn get_cf_async(
-        &self,
-        cf_name: &'static str,
-        key: Bytes,
-    ) -> Result<Option<Bytes>, CacheError> {
-        self.db_op_async(move |db| {
-            // find the ColumnFamily by name
-            let cf = db.cf_handle(cf_name).expect("cf_handle non-existant");
-
-            // fetch from the db and convert from Vec<u8> to Bytes
-            db.get_cf(&cf, &key)
-                .map(|x| x.map(Bytes::from))
-                .map_err(CacheError::Rocks)
-        })
-        .await
-    }
 
     /// Saves an ImageEntry to the database at the specified key
     ///
@@ -225,162 +767,1705 @@ impl RocksCache {
 
         // create the future that will save the image data
         let bytes = std::mem::replace(&mut entry.bytes, Bytes::new());
-        let images_fut = self.put_cf_async(Self::IMAGES_CF, bkey.clone(), bytes);
+        let image_cf = Self::image_cf_name(self.conf.shards, &bkey);
+        let images_fut = self.put_cf_async(image_cf, bkey.clone(), bytes);
 
-        // create the future that will save the metadata (first omitting the bytes)
+        // create the future that will tag data-saver entries with their size
         let len = entry.get_bytes_len();
+        let saver_fut = async {
+            if !key.data_saver() {
+                return Ok(());
+            }
+            let len_bytes = Bytes::copy_from_slice(&len.to_le_bytes());
+            self.put_cf_async(Self::SAVER_CF, bkey.clone(), len_bytes)
+                .await
+        };
+
+        // create the future that will map the hashed key back to the image
+        let image_key = Bytes::from(key.to_string());
+        let keys_fut = self.put_cf_async(Self::KEYS_CF, bkey.clone(), image_key);
+
+        // create the future that will save the metadata (first omitting the bytes)
         let meta_fut = self.put_cf_async(
             Self::META_CF,
-            bkey,
+            bkey.clone(),
             entry.try_into().map_err(CacheError::Bincode)?,
         );
 
-        // update the db size counter
+        // update the db size counters
         self.db_size.fetch_add(len, Ordering::Relaxed);
+        if key.data_saver() {
+            self.saver_size.fetch_add(len, Ordering::Relaxed);
+        }
 
-        tokio::try_join!(images_fut, meta_fut)?;
+        tokio::try_join!(images_fut, meta_fut, saver_fut, keys_fut)?;
+        if self.pins.contains(key.chapter()) {
+            self.pin_entry(key, bkey, len).await?;
+        }
         Ok(())
     }
     /// Loads an ImageEntry from the database at the specified key
     ///
     /// Returns early if an error occurred on any DB operation
     async fn load_entry(&self, key: &ImageKey) -> Result<Option<ImageEntry>, CacheError> {
-        use std::convert::TryFrom;
-        let bkey = Bytes::copy_from_slice(&key.as_bkey());
+        // a single batched lookup only hops to a blocking thread once, instead of once for each
+        // column family
+        Ok(self
+            .load_many_entries(std::slice::from_ref(key))
+            .await?
+            .pop()
+            .flatten())
+    }
+
+    /// Loads many entries from the database in a single batched lookup, in the same order as
+    /// `keys`. Entries of pinned chapters that were cached before their chapter was pinned are
+    /// pinned once they're loaded.
+    async fn load_many_entries(
+        &self,
+        keys: &[ImageKey],
+    ) -> Result<Vec<Option<ImageEntry>>, CacheError> {
+        let bkeys: Vec<Bytes> = keys
+            .iter()
+            .map(|x| Bytes::copy_from_slice(&x.as_bkey()))
+            .collect();
+
+        // look up every key in each of the column families at once, grouped by column family
+        let lookup = bkeys.clone();
+        let shards = self.conf.shards;
+        // whether entries are already pinned only matters if there are pinned chapters
+        let check_pins = !self.pins.is_empty();
+        let mut values = self
+            .db_op_async(move |db| {
+                let cf = |name: &str| db.cf_handle(name).expect("cf_handle non-existant");
+                let (meta, access) = (cf(Self::META_CF), cf(Self::ACCESS_CF));
+                let pinned = cf(Self::PINNED_CF);
+                let pinned_lookup: &[Bytes] = if check_pins { &lookup } else { &[] };
+                // the image of each key may be in a different shard
+                let images: Vec<_> = lookup
+                    .iter()
+                    .map(|k| cf(&Self::image_cf_name(shards, k)))
+                    .collect();
+                let pairs = images
+                    .iter()
+                    .zip(lookup.iter())
+                    .chain(lookup.iter().map(|k| (&meta, k)))
+                    .chain(lookup.iter().map(|k| (&access, k)))
+                    .chain(pinned_lookup.iter().map(|k| (&pinned, k)));
+                db.multi_get_cf(pairs)
+                    .into_iter()
+                    .map(|x| x.map(|x| x.map(Bytes::from)).map_err(CacheError::Rocks))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await?
+            .into_iter();
+
+        let n = bkeys.len();
+        let data: Vec<_> = values.by_ref().take(n).collect();
+        let meta: Vec<_> = values.by_ref().take(n).collect();
+        let access: Vec<_> = values.by_ref().take(n).collect();
+        let pinned: Vec<bool> = if check_pins {
+            values.map(|x| x.is_some()).collect()
+        } else {
+            vec![false; n]
+        };
+        let mut expired = Vec::new();
+        let mut unpinned = Vec::new();
+        let entries = keys
+            .iter()
+            .zip(bkeys)
+            .zip(data)
+            .zip(meta)
+            .zip(access)
+            .zip(pinned)
+            .map(|(((((key, bkey), data), meta), access), pinned)| {
+                let entry = self.assemble_entry(bkey.clone(), data, meta, access)?;
+                match entry {
+                    Some(entry) if self.is_expired(key, &entry) => {
+                        log::debug!("RocksDb entry {} expired, deleting it", key);
+                        expired.push((key, bkey, entry.get_bytes_len()));
+                        Ok(None)
+                    }
+                    Some(entry) if !pinned && self.pins.contains(key.chapter()) => {
+                        unpinned.push((key, bkey, entry.get_bytes_len()));
+                        Ok(Some(entry))
+                    }
+                    entry => Ok(entry),
+                }
+            })
+            .collect::<Result<Vec<_>, CacheError>>()?;
+
+        for (key, bkey, len) in expired {
+            if let Err(e) = self.expire_entry(key, bkey, len).await {
+                log::warn!("error deleting expired RocksDb entry: {}", e);
+            }
+        }
+        for (key, bkey, len) in unpinned {
+            self.pin_entry(key, bkey, len).await?;
+        }
+        Ok(entries)
+    }
+
+    /// Whether an entry was saved longer than `max_entry_age_hours` ago. Entries of pinned
+    /// chapters never expire.
+    fn is_expired(&self, key: &ImageKey, entry: &ImageEntry) -> bool {
+        let max_age = self.conf.max_entry_age_hours * 60 * 60 * 1000;
+        max_age != 0
+            && !self.pins.contains(key.chapter())
+            && self
+                .clock
+                .now_as_millis()
+                .saturating_sub(entry.save_time as u64)
+                > max_age
+    }
+
+    /// Deletes an expired entry with `len` image bytes from all of the column families, and
+    /// reports it to the eviction hook. The deletes run on a blocking thread, so other loads aren't
+    /// held up by them.
+    async fn expire_entry(&self, key: &ImageKey, bkey: Bytes, len: u64) -> Result<(), CacheError> {
+        let image_cf = Self::image_cf_name(self.conf.shards, &bkey);
+        let deleted = self
+            .write_op_async(move |db| {
+                let cf = |name: &str| db.cf_handle(name).expect("cf_handle non-existant");
+                // a concurrent load of the same entry may have deleted it already
+                if db
+                    .get_cf(&cf(Self::META_CF), &bkey)
+                    .map_err(CacheError::Rocks)?
+                    .is_none()
+                {
+                    return Ok(None);
+                }
+                let saver_len = db
+                    .get_cf(&cf(Self::SAVER_CF), &bkey)
+                    .map_err(CacheError::Rocks)?
+                    .and_then(|x| parse_le_u64(&x));
+                let cfs = [
+                    &*image_cf,
+                    Self::META_CF,
+                    Self::SAVER_CF,
+                    Self::ACCESS_CF,
+                    Self::PINNED_CF,
+                    Self::KEYS_CF,
+                ];
+                for &name in cfs.iter() {
+                    db.delete_cf(&cf(name), &bkey).map_err(CacheError::Rocks)?;
+                }
+                Ok(Some(saver_len))
+            })
+            .await?;
 
-        // load the entire image entry from the database
-        let images_fut = self.get_cf_async(Self::IMAGES_CF, bkey.clone());
-        let meta_fut = self.get_cf_async(Self::META_CF, bkey);
+        if let Some(saver_len) = deleted {
+            self.db_size.fetch_sub(len, Ordering::SeqCst);
+            if let Some(saver_len) = saver_len {
+                self.saver_size.fetch_sub(saver_len, Ordering::SeqCst);
+            }
+            self.report_evicted(Some((Some(key), len)), EvictionReason::Expired);
+        }
+        Ok(())
+    }
 
-        // wait for both futures and deserialize
-        match tokio::try_join!(images_fut, meta_fut)? {
+    /// Assembles an entry from the values stored for it in each column family, returning `None` if
+    /// the image or its metadata is missing
+    fn assemble_entry(
+        &self,
+        bkey: Bytes,
+        data: Option<Bytes>,
+        meta: Option<Bytes>,
+        access: Option<Bytes>,
+    ) -> Result<Option<ImageEntry>, CacheError> {
+        use std::convert::TryFrom;
+
+        match (data, meta) {
             // if there is data for both cfs, then integrate data and return
             (Some(data), Some(meta)) => {
                 let mut entry = ImageEntry::try_from(meta).map_err(CacheError::Bincode)?;
                 entry.bytes = data;
+
+                // keep the access time up to date for eviction and the idle sweep
+                let last_access = access
+                    .and_then(|x| parse_le_u64(&x))
+                    .unwrap_or(entry.save_time as u64);
+                if self.clock.now_as_millis().saturating_sub(last_access)
+                    >= Self::ACCESS_GRANULARITY
+                {
+                    self.touch(bkey);
+                }
                 Ok(Some(entry))
             }
             _ => Ok(None),
         }
     }
 
-    /// Eviction algorithm to evict the oldest entries in the database
-    fn evict_entries_fifo(&self, until_size: u64) -> Result<u64, CacheError> {
+    /// Eviction algorithm to evict the least recently used entries in the database, going by the
+    /// last time they were loaded (or saved, if they were never loaded)
+    ///
+    /// If `archive` is provided, then only entries of that archive type (`true` being data-saver)
+    /// are evicted, and `until_size` applies to the size of that archive type.
+    ///
+    /// The entries are ranked in a single scan on a blocking thread, then evicted from the least
+    /// recently used up in batches that are paced (see [`MaintenancePacer`]). The size counters
+    /// are reduced as each batch is evicted, so saves made in the meantime are still counted.
+    async fn evict_entries_lru(
+        &self,
+        until_size: u64,
+        archive: Option<bool>,
+    ) -> Result<ShrinkResult, CacheError> {
         // make sure we're working with the actual db size
-        self.fetch_real_size()?;
-        let mut sz = self.get_db_size()?;
+        self.fetch_real_size_async().await?;
 
-        'evictor: loop {
-            // create a queue of entries to evict based on the save time of the entry
-            // this queue is automatically sorted based on the find_top_entries fn
-            let queue = self.find_top_entries(256, |x, y| y.save_time.cmp(&x.save_time))?;
+        // the size that's being shrunk, which is either an archive type or the whole db
+        let total = self.db_size.load(Ordering::SeqCst);
+        let saver_total = self.saver_size.load(Ordering::SeqCst);
+        let start_sz = match archive {
+            Some(true) => saver_total,
+            Some(false) => total.saturating_sub(saver_total),
+            None => total,
+        };
+        let mut sz = start_sz;
+        let mut evicted = 0u64;
+        if sz <= until_size {
+            return Ok(ShrinkResult {
+                size: sz,
+                ..Default::default()
+            });
+        }
 
-            // how did we get here? we'll break anyways but how
-            if queue.is_empty() {
-                log::debug!("how did we get here?");
-                break;
+        // the bytes that evicting an entry takes off the size that's being shrunk
+        let freed = |len: u64, saver_len: Option<u64>| match archive {
+            Some(true) => saver_len.unwrap_or_default(),
+            _ => len,
+        };
+
+        let shards = self.conf.shards;
+        let mut queue = self
+            .db_op_async(move |db| Self::find_lru_entries(db, shards, archive))
+            .await?
+            .into_iter();
+        while sz > until_size {
+            // take entries off the queue until the minimum size would be met
+            let n = self.pacer.batch_size(256);
+            let mut batch = Vec::with_capacity(n);
+            let mut planned = sz;
+            while planned > until_size && batch.len() < n {
+                match queue.next() {
+                    Some((key, len, saver_len)) => {
+                        planned = planned.saturating_sub(freed(len, saver_len));
+                        batch.push((key, len));
+                    }
+                    None => break,
+                }
             }
 
-            // drop entries in the queue until we meet the minimum size
-            // drops are considered fatal, so it'll be pushed up the stack if failed
-            // if minimum size isn't met, then 'evictor loop will continue around, building a new queue
-            for (key, entry) in queue {
-                self.drop_entry(&key)?;
-                sz -= entry.get_bytes_len();
+            // everything that's left is pinned, so there's nothing more that can be evicted
+            if batch.is_empty() {
+                log::warn!("only pinned entries are left, unable to shrink any further");
+                break;
+            }
 
-                if sz <= until_size {
-                    log::debug!("{} <= {}", sz, until_size);
-                    break 'evictor;
+            // drops are considered fatal, so it'll be pushed up the stack if failed. entries that
+            // were deleted since the scan aren't counted
+            for (_, len, saver_len) in self.evict_entries(batch, EvictionReason::Shrink).await? {
+                self.db_size.fetch_sub(len, Ordering::SeqCst);
+                if let Some(saver_len) = saver_len {
+                    self.saver_size.fetch_sub(saver_len, Ordering::SeqCst);
                 }
+                sz = sz.saturating_sub(freed(len, saver_len));
+                evicted += 1;
             }
+
+            if sz <= until_size {
+                log::debug!("{} <= {}", sz, until_size);
+                break;
+            }
+            self.pacer.pause().await;
         }
 
-        self.db_size.store(sz, Ordering::SeqCst);
-        Ok(sz)
+        Ok(ShrinkResult {
+            size: sz,
+            bytes_evicted: start_sz - sz,
+            entries_evicted: evicted,
+        })
     }
 
-    /// Returns a vector of `n` number of  ImageKey and ImageEntry pairs that best fit the
-    /// comparator provided.
-    ///
-    /// WARNING: This function is not fast and it's not intended to be fast. Use with care.
-    #[allow(clippy::type_complexity)]
-    fn find_top_entries<C>(
-        &self,
-        n: usize,
-        comparator: C,
-    ) -> Result<Vec<(Box<[u8]>, ImageEntry)>, CacheError>
-    where
-        C: Fn(&ImageEntry, &ImageEntry) -> std::cmp::Ordering,
-    {
-        let mut acc = Vec::with_capacity(n);
-
-        let iter = self
-            .db
-            .iterator_cf(&self.cf_by_name(Self::META_CF), IteratorMode::Start);
-        for (key, val) in iter {
-            // deserialize the metadata entry, if it fails then drop it from db
-            let entry = match bincode::deserialize::<ImageEntry>(&val) {
-                Ok(e) => e,
-                Err(_) => {
-                    self.drop_entry(&key)?;
-                    continue;
-                }
-            };
+    /// Compacts the images column families, dropping the tombstones that evictions left behind
+    async fn compact_images(&self) -> Result<(), CacheError> {
+        let shards = self.conf.shards;
+        self.db_op_async(move |db| {
+            for name in Self::image_cf_names(shards) {
+                let cf = db.cf_handle(&name).expect("cf_handle non-existant");
+                db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+            }
+            Ok(())
+        })
+        .await
+    }
 
-            // if accumulator isn't filled yet, then just add the entry
-            if acc.len() < n {
-                acc.push((key, entry));
-                continue;
+    /// Flushes the memtables of all of the column families to disk
+    async fn flush_memtables(&self) -> Result<(), CacheError> {
+        let shards = self.conf.shards;
+        self.db_op_async(move |db| {
+            for name in Self::cf_names(shards).iter() {
+                let cf = db.cf_handle(name).expect("cf_handle non-existant");
+                db.flush_cf(&cf).map_err(CacheError::Rocks)?;
             }
+            Ok(())
+        })
+        .await
+    }
 
-            // the accumulator is completely filled, so replace based on comparator
-            //
-            // in this case, we use the `find` operation to find an entry that should be evicted
-            // with less priority than the `entry` variable. if found, then replace entry in accumulator
-            if let Some((i, _)) = acc
-                .iter()
-                .enumerate()
-                .find(|(_, (_, other))| comparator(&entry, other).is_gt())
-            {
-                acc[i] = (key, entry);
+    /// Deletes every entry from all of the column families, resetting the size counters. Every
+    /// entry is reported to the eviction hook, if there is one.
+    async fn clear_entries(&self) -> Result<ShrinkResult, CacheError> {
+        let res = ShrinkResult {
+            size: 0,
+            bytes_evicted: self.get_db_size()?,
+            entries_evicted: self.report_entries().unwrap_or_default(),
+        };
+        let shards = self.conf.shards;
+        let cleared = match self.eviction_hook {
+            Some(_) => self.db_op_async(Self::list_entries).await?,
+            None => Vec::new(),
+        };
+        self.write_op_async(move |db| {
+            // keys are 32 byte hashes, so this range covers all of them
+            let (from, to): (&[u8], &[u8]) = (&[], &[0xff; 33]);
+            for name in Self::cf_names(shards).iter() {
+                let cf = db.cf_handle(name).expect("cf_handle non-existant");
+                db.delete_range_cf(&cf, from, to)
+                    .map_err(CacheError::Rocks)?;
             }
-        }
+            Ok(())
+        })
+        .await?;
+
+        self.db_size.store(0, Ordering::SeqCst);
+        self.saver_size.store(0, Ordering::SeqCst);
+        self.pinned_size.store(0, Ordering::SeqCst);
+
+        self.report_evicted(
+            cleared.iter().map(|(key, len)| (Some(key), *len)),
+            EvictionReason::Cleared,
+        );
 
-        // as a finishing touch, completely sort the accumulator
-        acc.sort_unstable_by(|(_, first), (_, second)| comparator(first, second).reverse());
-        Ok(acc)
+        // the deleted range only leaves a tombstone behind until the images are compacted
+        self.compact_images().await?;
+        Ok(res)
     }
-}
 
-#[async_trait::async_trait]
-impl ImageCache for RocksCache {
-    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
-        match self.load_entry(key).await {
-            Ok(entry) => entry,
-            Err(e) => {
-                log::error!("fatal error occurred loading entry from RocksDb: {}", e);
-                None
+    /// Lists the image and image size of every entry whose image is known
+    fn list_entries(db: &MultiDB) -> Result<Vec<(ImageKey, u64)>, CacheError> {
+        let meta = Self::cf_of(db, Self::META_CF);
+        let mut entries = Vec::new();
+        for (key, val) in db.iterator_cf(&Self::cf_of(db, Self::KEYS_CF), IteratorMode::Start) {
+            let image_key: Option<ImageKey> =
+                std::str::from_utf8(&val).ok().and_then(|x| x.parse().ok());
+            let len = db
+                .get_cf(&meta, &key)
+                .map_err(CacheError::Rocks)?
+                .and_then(|x| bincode::deserialize::<ImageEntry>(&x).ok())
+                .map(|x| x.get_bytes_len());
+            if let (Some(image_key), Some(len)) = (image_key, len) {
+                entries.push((image_key, len));
             }
         }
+        Ok(entries)
     }
 
-    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
-        let entry = ImageEntry::new_assume(data, mime_type);
-        if let Err(e) = self.save_entry(key, entry).await {
-            log::error!("fatal error occurred saving entry to RocksDb: {}", e);
-            false
-        } else {
-            true
+    /// Compares the image sizes recorded in the metadata with the images actually stored, for at
+    /// most `sample` entries. Meant to run on a blocking thread (see
+    /// [`db_op_async`](Self::db_op_async)).
+    fn check_sizes(
+        db: &MultiDB,
+        shards: usize,
+        sample: Option<usize>,
+    ) -> Result<SizeReconciliation, CacheError> {
+        let mut res = SizeReconciliation::default();
+        let iter = db.iterator_cf(&Self::cf_of(db, Self::META_CF), IteratorMode::Start);
+        for (key, val) in iter.take(sample.unwrap_or(usize::MAX)) {
+            res.entries_checked += 1;
+            // malformed metadata is dropped whenever the size is fetched, so it isn't tracked
+            if let Ok(entry) = bincode::deserialize::<ImageEntry>(&val) {
+                res.tracked += entry.get_bytes_len();
+            }
+            res.actual += Self::stored_len(db, shards, &key)?.unwrap_or(0);
         }
+        Ok(res)
     }
 
-    fn report(&self) -> u64 {
-        self.get_db_size().unwrap_or_default()
+    /// The size of the image actually stored under the key, if there is one
+    fn stored_len(db: &MultiDB, shards: usize, key: &[u8]) -> Result<Option<u64>, CacheError> {
+        let val = db
+            .get_cf(&Self::cf_of(db, &Self::image_cf_name(shards, key)), key)
+            .map_err(CacheError::Rocks)?;
+        Ok(val.map(|x| x.len() as u64))
     }
 
-    async fn shrink(&self, min: u64) -> Result<u64, ()> {
-        self.evict_entries_fifo(min).map_err(|e| {
-            log::error!("fatal error occurred while shrinking RocksDb: {}", e);
+    /// Drops every entry whose stored image doesn't match the size recorded in its metadata (its
+    /// checksum wouldn't match either). Meant to run on a blocking thread, after which the size of
+    /// the database has to be fetched again.
+    fn correct_sizes(db: &MultiDB, shards: usize) -> Result<(), CacheError> {
+        let iter = db.iterator_cf(&Self::cf_of(db, Self::META_CF), IteratorMode::Start);
+        for (key, val) in iter {
+            let recorded = bincode::deserialize::<ImageEntry>(&val)
+                .ok()
+                .map(|x| x.get_bytes_len());
+            if recorded.is_none() || recorded != Self::stored_len(db, shards, &key)? {
+                Self::drop_entry(db, shards, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens a database with the same configuration at `path`, and migrates every entry into it.
+    ///
+    /// Once the migration started, every write is applied to the new database as well, for as long
+    /// as this database is still used (i.e. by requests that started before it was replaced). The
+    /// eviction hook isn't carried over to the new database.
+    async fn open_rotated(&self, path: &str) -> Result<RocksCache, CacheError> {
+        let mut conf = self.conf.clone();
+        conf.path = path.to_string();
+
+        // opening the database can take a while, and is done while requests are being served
+        let dest = tokio::task::spawn_blocking(move || RocksCache::new(&conf))
+            .await
+            .map_err(CacheError::TokioJoin)??
+            .with_pacer(self.pacer.clone())
+            .with_clock(Arc::clone(&self.clock));
+
+        let migrated = match self.migrate_entries(&dest).await {
+            Ok(migrated) => migrated,
+            Err(e) => {
+                // the new database is abandoned, so there's no point in writing to it anymore
+                *self.migration.write().unwrap_or_else(|e| e.into_inner()) = None;
+                return Err(e);
+            }
+        };
+        log::info!("migrated {} RocksDb entries to {}", migrated, path);
+
+        let dest = dest.with_pins(self.pins.clone());
+        dest.fetch_real_size()?;
+        Ok(dest)
+    }
+
+    /// Copies every entry into `dest`, returning the number of entries copied.
+    ///
+    /// The column families are copied in batches, pausing in between them so the migration
+    /// doesn't hold up the runtime for too long (see [`MaintenancePacer`]). Every write made from
+    /// the start of the migration on is applied to `dest` as well (see
+    /// [`write_with_migration`](Self::write_with_migration)), so saves and deletes made while the
+    /// entries are copied aren't lost. The sizes of `dest` aren't updated by those writes, so they
+    /// should be fetched again afterwards.
+    async fn migrate_entries(&self, dest: &RocksCache) -> Result<u64, CacheError> {
+        const BATCH_SIZE: usize = 256;
+        // both databases have the same configuration, so their images are sharded the same way
+        let cfs = Self::cf_names(self.conf.shards);
+        *self.migration.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::clone(&dest.db));
+
+        let mut migrated = 0;
+        for name in cfs {
+            let mut resume_key: Option<Box<[u8]>> = None;
+            loop {
+                let (src, dst) = (Arc::clone(&self.db), Arc::clone(&dest.db));
+                let migration = Arc::clone(&self.migration);
+                let batch_size = self.pacer.batch_size(BATCH_SIZE);
+                let cf_name = name.clone();
+                let (copied, next) = tokio::task::spawn_blocking(move || {
+                    // no write can be made in between reading a batch and copying it
+                    let _copying = migration.write().unwrap_or_else(|e| e.into_inner());
+                    Self::copy_batch(&src, &dst, &cf_name, resume_key.as_deref(), batch_size)
+                })
+                .await
+                .map_err(CacheError::TokioJoin)??;
+                if name == Self::META_CF {
+                    migrated += copied;
+                }
+
+                match next {
+                    Some(key) => resume_key = Some(key),
+                    None => break,
+                }
+                self.pacer.pause().await;
+            }
+        }
+        Ok(migrated)
+    }
+
+    /// Copies up to `n` values of a column family (starting at the key `from`) from `src` to
+    /// `dest`, returning the number of values copied along with the key to resume copying from (if
+    /// the copy isn't finished).
+    fn copy_batch(
+        src: &MultiDB,
+        dest: &MultiDB,
+        cf_name: &str,
+        from: Option<&[u8]>,
+        n: usize,
+    ) -> Result<(u64, Option<Box<[u8]>>), CacheError> {
+        let mode = match from {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        let src_cf = src.cf_handle(cf_name).expect("cf_handle non-existant");
+        let dest_cf = dest.cf_handle(cf_name).expect("cf_handle non-existant");
+        let mut iter = src.iterator_cf(&src_cf, mode);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut copied = 0;
+        for (key, val) in iter.by_ref().take(n) {
+            batch.put_cf(&dest_cf, key, val);
+            copied += 1;
+        }
+        dest.write(batch).map_err(CacheError::Rocks)?;
+
+        let next = iter.next().map(|(key, _)| key);
+        Ok((copied, next))
+    }
+
+    /// Compacts the images if the shrinks that produced `res` evicted more entries than the
+    /// configured `compact_after_shrink_deletes`
+    async fn compact_after_shrink(&self, res: &ShrinkResult) {
+        let threshold = self.compact_after_shrink_deletes;
+        if threshold == 0 || res.entries_evicted <= threshold {
+            return;
+        }
+
+        log::info!(
+            "shrink evicted {} entries, compacting RocksDb...",
+            res.entries_evicted
+        );
+        let timer = crate::utils::Timer::start();
+        match self.compact_images().await {
+            Ok(()) => log::info!("compacted RocksDb in {:#}", timer),
+            Err(e) => log::error!("error compacting RocksDb after shrink: {}", e),
+        }
+    }
+
+    /// Evicts all of the entries that were last accessed (or saved, if never accessed) before
+    /// `cutoff` in millis since epoch.
+    ///
+    /// The database is scanned in batches on a blocking thread, pausing in between them so the
+    /// sweep doesn't get in the way of requests for too long (see [`MaintenancePacer`]).
+    async fn evict_idle_entries(&self, cutoff: u64) -> Result<ShrinkResult, CacheError> {
+        const BATCH_SIZE: usize = 256;
+
+        let mut res = ShrinkResult::default();
+        let mut resume_key: Option<Box<[u8]>> = None;
+        loop {
+            let batch_size = self.pacer.batch_size(BATCH_SIZE);
+            let (idle, next) = self
+                .db_op_async(move |db| {
+                    Self::find_idle_batch(db, cutoff, resume_key.as_deref(), batch_size)
+                })
+                .await?;
+            for (_, len, saver_len) in self.evict_entries(idle, EvictionReason::Idle).await? {
+                self.db_size.fetch_sub(len, Ordering::SeqCst);
+                if let Some(saver_len) = saver_len {
+                    self.saver_size.fetch_sub(saver_len, Ordering::SeqCst);
+                }
+                res.bytes_evicted += len;
+                res.entries_evicted += 1;
+            }
+
+            match next {
+                Some(key) => resume_key = Some(key),
+                None => break,
+            }
+            self.pacer.pause().await;
+        }
+
+        res.size = self.db_size.load(Ordering::SeqCst);
+        Ok(res)
+    }
+
+    /// Scans up to `n` entries (starting at the key `from`) for entries that were last accessed
+    /// before `cutoff`, returning their keys and sizes along with the key to resume scanning from
+    /// (if the scan isn't finished).
+    #[allow(clippy::type_complexity)]
+    fn find_idle_batch(
+        db: &MultiDB,
+        cutoff: u64,
+        from: Option<&[u8]>,
+        n: usize,
+    ) -> Result<(Vec<(Box<[u8]>, u64)>, Option<Box<[u8]>>), CacheError> {
+        let mode = match from {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        let mut iter = db.iterator_cf(&Self::cf_of(db, Self::META_CF), mode);
+
+        let mut idle = Vec::new();
+        for (key, val) in iter.by_ref().take(n) {
+            // malformed entries are cleaned up by the size fetch, so they can be skipped here
+            let entry = match bincode::deserialize::<ImageEntry>(&val) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            let last_access = Self::access_time(db, &key)?.unwrap_or(entry.save_time as u64);
+            if last_access < cutoff && !Self::is_pinned(db, &key)? {
+                idle.push((key, entry.get_bytes_len()));
+            }
+        }
+
+        let next = iter.next().map(|(key, _)| key);
+        Ok((idle, next))
+    }
+
+    /// Finds every entry that can be evicted, sorted from the least recently used up, going by
+    /// the last time they were loaded (or saved, if they were never loaded). Returns their keys
+    /// and image sizes, along with the size of the image of those that are data-saver entries.
+    /// Entries whose metadata is malformed are dropped.
+    ///
+    /// If `archive` is provided, then only entries of that archive type are considered.
+    #[allow(clippy::type_complexity)]
+    fn find_lru_entries(
+        db: &MultiDB,
+        shards: usize,
+        archive: Option<bool>,
+    ) -> Result<Vec<(Box<[u8]>, u64, Option<u64>)>, CacheError> {
+        let mut acc = Vec::new();
+
+        let iter = db.iterator_cf(&Self::cf_of(db, Self::META_CF), IteratorMode::Start);
+        for (key, val) in iter {
+            // deserialize the metadata entry, if it fails then drop it from db
+            let entry = match bincode::deserialize::<ImageEntry>(&val) {
+                Ok(e) => e,
+                Err(_) => {
+                    Self::drop_entry(db, shards, &key)?;
+                    continue;
+                }
+            };
+            if Self::is_pinned(db, &key)? {
+                continue;
+            }
+            let saver_len = Self::saver_len(db, &key)?;
+            if matches!(archive, Some(saver) if saver_len.is_some() != saver) {
+                continue;
+            }
+            let last_access = Self::access_time(db, &key)?.unwrap_or(entry.save_time as u64);
+            acc.push((last_access, key, entry.get_bytes_len(), saver_len));
+        }
+
+        acc.sort_unstable_by_key(|(last_access, ..)| *last_access);
+        Ok(acc
+            .into_iter()
+            .map(|(_, key, len, saver_len)| (key, len, saver_len))
+            .collect())
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageCache for RocksCache {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        match self.load_entry(key).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::error!("fatal error occurred loading entry from RocksDb: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn load_many(&self, keys: &[ImageKey]) -> Vec<Option<ImageEntry>> {
+        match self.load_many_entries(keys).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("fatal error occurred loading entries from RocksDb: {}", e);
+                vec![None; keys.len()]
+            }
+        }
+    }
+
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        // an empty body is never a valid image (i.e. a truncated upstream response)
+        if data.is_empty() {
+            log::warn!("refusing to save empty image {} to RocksDb", key);
+            return false;
+        }
+        let entry = ImageEntry::new(data, mime_type, self.clock.now());
+        if let Err(e) = self.save_entry(key, entry).await {
+            log::error!("fatal error occurred saving entry to RocksDb: {}", e);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn report(&self) -> u64 {
+        self.get_db_size().unwrap_or_default()
+    }
+
+    fn info(&self) -> CacheInfo {
+        self.info.clone()
+    }
+
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        self.evict_entries_lru(min, None).await.map_err(|e| {
+            log::error!("fatal error occurred while shrinking RocksDb: {}", e);
+        })
+    }
+
+    fn report_archive(&self, data_saver: bool) -> Option<u64> {
+        let total = self.report();
+        let saver = self.saver_size.load(Ordering::SeqCst);
+        Some(if data_saver {
+            saver
+        } else {
+            total.saturating_sub(saver)
+        })
+    }
+
+    fn report_pinned(&self) -> Option<u64> {
+        Some(self.pinned_size.load(Ordering::SeqCst))
+    }
+
+    fn report_entries(&self) -> Option<u64> {
+        // every entry has metadata, and RocksDB keeps a (cheap) estimate of the keys in each cf
+        self.db
+            .property_int_value_cf(&self.cf_by_name(Self::META_CF), "rocksdb.estimate-num-keys")
+            .ok()
+            .flatten()
+    }
+
+    fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::new(self.report(), self.report_entries())
+            .with_stat("data_saver_bytes", self.saver_size.load(Ordering::SeqCst))
+            .with_stat("pinned_bytes", self.pinned_size.load(Ordering::SeqCst));
+        match self.db.live_files() {
+            Ok(files) => {
+                let sst_bytes: u64 = files.iter().map(|x| x.size as u64).sum();
+                stats = stats
+                    .with_stat("sst_files", files.len())
+                    .with_stat("sst_bytes", sst_bytes);
+            }
+            Err(e) => log::warn!("error listing RocksDb files: {}", e),
+        }
+
+        // the memtables and compactions are tracked for each column family
+        let sum_property = |property: &str| -> u64 {
+            Self::cf_names(self.conf.shards)
+                .iter()
+                .filter_map(|name| {
+                    self.db
+                        .property_int_value_cf(&self.cf_by_name(name), property)
+                        .ok()
+                        .flatten()
+                })
+                .sum()
+        };
+        stats
+            .with_stat(
+                "memtable_bytes",
+                sum_property("rocksdb.cur-size-all-mem-tables"),
+            )
+            .with_stat(
+                "pending_compaction_bytes",
+                sum_property("rocksdb.estimate-pending-compaction-bytes"),
+            )
+    }
+
+    async fn shrink_archive(&self, data_saver: bool, min: u64) -> Result<ShrinkResult, ()> {
+        self.evict_entries_lru(min, Some(data_saver))
+            .await
+            .map_err(|e| {
+                log::error!(
+                    "fatal error occurred while shrinking RocksDb archive: {}",
+                    e
+                );
+            })
+    }
+
+    /// Compacts once after all of the shrinks, since a run of small shrinks leaves behind as many
+    /// tombstones as a single large one
+    async fn finish_shrink(&self, res: &ShrinkResult) {
+        self.compact_after_shrink(res).await
+    }
+
+    async fn sweep_idle(&self, max_idle: std::time::Duration) -> Result<ShrinkResult, ()> {
+        let cutoff = self
+            .clock
+            .now_as_millis()
+            .saturating_sub(max_idle.as_millis() as u64);
+        self.evict_idle_entries(cutoff).await.map_err(|e| {
+            log::error!(
+                "fatal error occurred while sweeping idle RocksDb entries: {}",
+                e
+            );
+        })
+    }
+
+    async fn compact(&self) {
+        if let Err(e) = self.compact_images().await {
+            log::error!("fatal error occurred while compacting RocksDb: {}", e);
+        }
+    }
+
+    async fn flush(&self) {
+        if let Err(e) = self.flush_memtables().await {
+            log::error!("fatal error occurred while flushing RocksDb: {}", e);
+        }
+    }
+
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        self.clear_entries().await.map_err(|e| {
+            log::error!("fatal error occurred while clearing RocksDb: {}", e);
         })
     }
+
+    async fn rotate(&self, path: &str) -> Result<Box<dyn ImageCache>, ()> {
+        match self.open_rotated(path).await {
+            Ok(cache) => Ok(Box::new(cache)),
+            Err(e) => {
+                log::error!("fatal error occurred rotating RocksDb to {}: {}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    async fn reconcile_size(
+        &self,
+        sample: Option<usize>,
+        max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        // both scan the whole database, so they're kept off the runtime
+        let shards = self.conf.shards;
+        let reconcile = async {
+            let mut res = self
+                .db_op_async(move |db| Self::check_sizes(db, shards, sample))
+                .await?;
+            if res.drift() > max_drift {
+                self.db_op_async(move |db| Self::correct_sizes(db, shards))
+                    .await?;
+                self.fetch_real_size_async().await?;
+                res.corrected = true;
+            }
+            Ok::<_, CacheError>(res)
+        };
+        reconcile.await.map_err(|e| {
+            log::error!(
+                "fatal error occurred reconciling the size of RocksDb: {}",
+                e
+            );
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    /// Creates a fresh temporary directory path for a [`RocksCache`]
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("scalpel-rocks-{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    /// Opens a [`RocksCache`] at the path provided
+    fn open(path: &Path) -> Result<RocksCache, CacheError> {
+        open_with(path, "")
+    }
+
+    /// Opens a [`RocksCache`] at the path provided, with the extra yaml options appended to the
+    /// configuration
+    fn open_with(path: &Path, extra: &str) -> Result<RocksCache, CacheError> {
+        let conf: RocksConfig =
+            serde_yaml::from_str(&format!("path: {}\n{}", path.display(), extra))
+                .expect("rocks test config");
+        RocksCache::new(&conf)
+    }
+
+    /// Opens a [`RocksCache`] in a fresh temporary directory, returning the cache and its path
+    fn open_temp(name: &str) -> (RocksCache, PathBuf) {
+        let path = temp_path(name);
+        (open(&path).expect("open rocks cache"), path)
+    }
+
+    fn test_key() -> ImageKey {
+        ImageKey::new("chapter".to_string(), "1.png".to_string(), false)
+    }
+
+    #[tokio::test]
+    async fn eviction_hook_fires_on_shrink() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let (cache, path) = open_temp("eviction-hook");
+        let hook_evicted = Arc::clone(&evicted);
+        let cache = cache.with_eviction_hook(Box::new(move |key, len, reason| {
+            hook_evicted
+                .lock()
+                .unwrap()
+                .push((key.to_string(), len, reason));
+        }));
+
+        let key = test_key();
+        assert!(
+            cache
+                .save(&key, "image/png".to_string(), Bytes::from(vec![0u8; 100]))
+                .await
+        );
+        assert_eq!(cache.shrink(0).await.map(|x| x.size), Ok(0));
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            vec![(key.to_string(), 100u64, EvictionReason::Shrink)]
+        );
+
+        // clearing reports every entry that was left as well
+        let other = ImageKey::new("chapter".to_string(), "2.png".to_string(), true);
+        assert!(
+            cache
+                .save(&other, "image/png".to_string(), Bytes::from(vec![0u8; 50]))
+                .await
+        );
+        cache.clear().await.unwrap();
+        assert_eq!(
+            evicted.lock().unwrap()[1],
+            (other.to_string(), 50u64, EvictionReason::Cleared)
+        );
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn clear_removes_every_entry() {
+        let (cache, path) = open_temp("clear");
+        let keys: Vec<ImageKey> = (0..4)
+            .map(|i| ImageKey::new("chapter".to_string(), format!("{}.png", i), i % 2 == 0))
+            .collect();
+        for key in &keys {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+        }
+
+        let res = cache.clear().await.unwrap();
+        assert_eq!(res.bytes_evicted, 400);
+        assert_eq!(cache.report(), 0);
+        assert_eq!(cache.report_archive(true), Some(0));
+        for key in &keys {
+            assert!(cache.load(key).await.is_none());
+        }
+
+        // the cleared entries stay gone after reopening
+        drop(cache);
+        let cache = open(&path).unwrap();
+        assert_eq!(cache.report(), 0);
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn rotation_serves_every_entry_from_new_path() {
+        let (cache, old_path) = open_temp("rotate-from");
+        let new_path = temp_path("rotate-to");
+        let keys: Vec<ImageKey> = (0..600)
+            .map(|i| ImageKey::new("chapter".to_string(), format!("{}.png", i), i % 2 == 0))
+            .collect();
+        for key in &keys {
+            let data = Bytes::from(key.to_string());
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+        }
+
+        let swap = crate::cache::SwappableCache::new(Box::new(cache));
+        swap.rotate_to(new_path.to_str().unwrap()).await.unwrap();
+        assert_eq!(swap.info().path, Some(new_path.display().to_string()));
+        for key in &keys {
+            let entry = swap.load(key).await.expect("entry migrated");
+            assert_eq!(entry.bytes, Bytes::from(key.to_string()));
+        }
+        // the sizes are counted again at the new path
+        assert_eq!(
+            swap.report(),
+            keys.iter().map(|x| x.to_string().len() as u64).sum()
+        );
+        assert!(swap.report_archive(true) > Some(0));
+
+        // the entries are stored at the new path, which can be opened on its own
+        drop(swap);
+        let cache = open(&new_path).unwrap();
+        assert!(cache.load(&keys[0]).await.is_some());
+        drop(cache);
+        let _ = std::fs::remove_dir_all(old_path);
+        let _ = std::fs::remove_dir_all(new_path);
+    }
+
+    #[tokio::test]
+    async fn writes_during_rotation_are_migrated() {
+        let (cache, old_path) = open_temp("rotate-writes-from");
+        let new_path = temp_path("rotate-writes-to");
+        let key = |i: usize| ImageKey::new("chapter".to_string(), format!("{}.png", i), false);
+        for i in 0..600 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+        }
+
+        // keep on saving new entries and expiring old ones for as long as the rotation runs
+        let swap = crate::cache::SwappableCache::new(Box::new(cache));
+        let rotation = swap.rotate_to(new_path.to_str().unwrap());
+        let writes = async {
+            let mut written = 0;
+            while written == 0 || swap.is_rotating() {
+                let data = Bytes::from(key(1000 + written).to_string());
+                assert!(
+                    swap.save(&key(1000 + written), "image/png".to_string(), data)
+                        .await
+                );
+                swap.shrink(swap.report() - 100).await.unwrap();
+                written += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            written
+        };
+        let (rotated, written) = tokio::join!(rotation, writes);
+        rotated.unwrap();
+        assert_eq!(swap.info().path, Some(new_path.display().to_string()));
+
+        // an old entry was evicted for every write, and none of them came back with the migration
+        let mut evicted = 0;
+        for i in 0..600 {
+            if swap.load(&key(i)).await.is_none() {
+                evicted += 1;
+            }
+        }
+        assert_eq!(evicted, written);
+        for i in 1000..1000 + written {
+            let entry = swap
+                .load(&key(i))
+                .await
+                .expect("entry saved during rotation");
+            assert_eq!(entry.bytes, Bytes::from(key(i).to_string()));
+        }
+
+        drop(swap);
+        let _ = std::fs::remove_dir_all(old_path);
+        let _ = std::fs::remove_dir_all(new_path);
+    }
+
+    #[tokio::test]
+    async fn shrink_reports_evicted_entries() {
+        let (cache, path) = open_temp("shrink-count");
+        let keys: Vec<ImageKey> = (0..4)
+            .map(|i| ImageKey::new("chapter".to_string(), format!("{}.png", i), false))
+            .collect();
+        for key in &keys {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+        }
+
+        let res = cache.shrink(250).await.unwrap();
+        let mut missing = 0;
+        for key in &keys {
+            if cache.load(key).await.is_none() {
+                missing += 1;
+            }
+        }
+        assert_eq!(res.entries_evicted, missing);
+        assert_eq!(res.bytes_evicted, missing * 100);
+        assert!(res.size <= 250);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn shrink_evicts_oldest_entries_first() {
+        use crate::utils::TestClock;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let path = temp_path("shrink-oldest");
+        let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+        let cache = open(&path)
+            .expect("open rocks cache")
+            .with_clock(Arc::clone(&clock) as _);
+        // keys are stored by their hash, so the save order has nothing to do with the key order
+        let keys: Vec<ImageKey> = (0..4)
+            .map(|i| ImageKey::new("chapter".to_string(), format!("{}.png", i), false))
+            .collect();
+        for key in &keys {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+            clock.advance(Duration::from_secs(60));
+        }
+
+        let res = cache.shrink(200).await.unwrap();
+        assert_eq!(res.entries_evicted, 2);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(cache.load(key).await.is_some(), i >= 2, "entry {}", i);
+        }
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn shrink_evicts_least_recently_used_first() {
+        use crate::utils::TestClock;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let path = temp_path("shrink-lru");
+        let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+        let cache = open(&path)
+            .expect("open rocks cache")
+            .with_clock(Arc::clone(&clock) as _);
+        let keys: Vec<ImageKey> = (0..4)
+            .map(|i| ImageKey::new("chapter".to_string(), format!("{}.png", i), false))
+            .collect();
+        for key in &keys {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+            clock.advance(Duration::from_secs(60));
+        }
+
+        // the oldest entry is loaded long enough after it was saved for the access to be recorded
+        clock.advance(Duration::from_secs(2 * 60 * 60));
+        assert!(cache.load(&keys[0]).await.is_some());
+        for _ in 0..100 {
+            if RocksCache::access_time(&cache.db, &keys[0].as_bkey())
+                .unwrap()
+                .is_some()
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let res = cache.shrink(200).await.unwrap();
+        assert_eq!(res.entries_evicted, 2);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(
+                cache.load(key).await.is_some(),
+                i != 1 && i != 2,
+                "entry {}",
+                i
+            );
+        }
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn large_shrinks_are_compacted() {
+        // a compaction flushes the memtable, which otherwise still holds the puts and deletes
+        let memtable_entries = |cache: &RocksCache| {
+            let cf = cache.cf_by_name(RocksCache::IMAGES_CF);
+            cache
+                .db
+                .property_int_value_cf(&cf, "rocksdb.num-entries-active-mem-table")
+                .unwrap()
+                .unwrap()
+        };
+
+        for &(threshold, compacted) in &[(3, true), (4, false), (0, false)] {
+            let path = temp_path(&format!("shrink-compaction-{}", threshold));
+            let extra = format!("compact_after_shrink_deletes: {}\n", threshold);
+            let cache = open_with(&path, &extra).unwrap();
+            for i in 0..4 {
+                let key = ImageKey::new("chapter".to_string(), format!("{}.png", i), false);
+                let data = Bytes::from(vec![0u8; 100]);
+                assert!(cache.save(&key, "image/png".to_string(), data).await);
+            }
+
+            // the steps of a janitor run add up, so 4 steps of 1 entry are compacted like 1 of 4
+            let janitor = crate::cache::Janitor::new(100, Default::default(), Default::default());
+            assert_eq!(janitor.shrink(&cache, 0).await.unwrap().entries_evicted, 4);
+            assert_eq!(memtable_entries(&cache) == 0, compacted);
+
+            drop(cache);
+            let _ = std::fs::remove_dir_all(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_shrink_only_evicts_archive() {
+        let (cache, path) = open_temp("archive-shrink");
+        let key = |i: usize, saver: bool| {
+            ImageKey::new("chapter".to_string(), format!("{}.png", i), saver)
+        };
+        for i in 0..2 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(
+                cache
+                    .save(&key(i, false), "image/png".to_string(), data)
+                    .await
+            );
+        }
+        // flood the cache with data-saver images
+        for i in 0..6 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(
+                cache
+                    .save(&key(i, true), "image/png".to_string(), data)
+                    .await
+            );
+        }
+        assert_eq!(cache.report_archive(true), Some(600));
+        assert_eq!(cache.report_archive(false), Some(200));
+
+        let res = cache.shrink_archive(true, 300).await.unwrap();
+        assert_eq!(res.entries_evicted, 3);
+        assert_eq!(cache.report_archive(true), Some(300));
+        assert_eq!(cache.report_archive(false), Some(200));
+        for i in 0..2 {
+            assert!(cache.load(&key(i, false)).await.is_some());
+        }
+
+        // the counters survive a full size fetch
+        cache.fetch_real_size().unwrap();
+        assert_eq!(cache.report_archive(true), Some(300));
+        assert_eq!(cache.report(), 500);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn compaction_limits_are_applied() {
+        let path = temp_path("compaction-limits");
+        let cache = open_with(
+            &path,
+            "write_rate_limit: 1\nmax_background_jobs: 2\nmax_subcompactions: 2\n\
+            compaction_readahead_size: 2",
+        )
+        .expect("open rocks cache with compaction limits");
+
+        // writes and reads still work under a tight rate limit
+        let key = test_key();
+        let data = Bytes::from(vec![1u8; 64 * 1024]);
+        assert!(
+            cache
+                .save(&key, "image/png".to_string(), data.clone())
+                .await
+        );
+        cache.compact().await;
+        assert_eq!(cache.load(&key).await.map(|x| x.get_bytes()), Some(data));
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn idle_entries_are_swept() {
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+        let (cache, path) = open_temp("idle-sweep");
+        let hook_reasons = Arc::clone(&reasons);
+        let cache = cache.with_eviction_hook(Box::new(move |_, _, reason| {
+            hook_reasons.lock().unwrap().push(reason);
+        }));
+        let idle = ImageKey::new("chapter".to_string(), "idle.png".to_string(), false);
+        let recent = ImageKey::new("chapter".to_string(), "recent.png".to_string(), false);
+        for key in &[&idle, &recent] {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+        }
+
+        // backdate both entries, then access one of them recently
+        let day_ago = cache.clock.now_as_millis() - 1000 * 60 * 60 * 24;
+        let access_cf = cache.cf_by_name(RocksCache::ACCESS_CF);
+        for key in &[&idle, &recent] {
+            cache
+                .db
+                .put_cf(&access_cf, key.as_bkey(), day_ago.to_le_bytes())
+                .unwrap();
+        }
+        cache
+            .db
+            .put_cf(
+                &access_cf,
+                recent.as_bkey(),
+                cache.clock.now_as_millis().to_le_bytes(),
+            )
+            .unwrap();
+
+        let res = cache
+            .sweep_idle(std::time::Duration::from_secs(60 * 60))
+            .await
+            .unwrap();
+        assert_eq!(res.entries_evicted, 1);
+        assert_eq!(res.size, 100);
+        assert!(cache.load(&idle).await.is_none());
+        assert!(cache.load(&recent).await.is_some());
+        assert_eq!(*reasons.lock().unwrap(), vec![EvictionReason::Idle]);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn idle_ttl_follows_the_clock() {
+        use crate::utils::TestClock;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let path = temp_path("idle-clock");
+        let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+        let cache = open(&path)
+            .expect("open rocks cache")
+            .with_clock(Arc::clone(&clock) as _);
+        let idle = ImageKey::new("chapter".to_string(), "idle.png".to_string(), false);
+        let recent = ImageKey::new("chapter".to_string(), "recent.png".to_string(), false);
+        for key in &[&idle, &recent] {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+        }
+
+        // nothing has been idle for long enough yet
+        let ttl = Duration::from_secs(60 * 60);
+        assert_eq!(cache.sweep_idle(ttl).await.unwrap().entries_evicted, 0);
+
+        // two hours later, only the entry that was just loaded survives
+        clock.advance(ttl * 2);
+        let entry = cache.load(&recent).await.unwrap();
+        assert_eq!(
+            entry.get_save_time(),
+            UNIX_EPOCH + Duration::from_secs(1_000_000)
+        );
+        // the access time is recorded in the background
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let res = cache.sweep_idle(ttl).await.unwrap();
+        assert_eq!(res.entries_evicted, 1);
+        assert!(cache.load(&idle).await.is_none());
+        assert!(cache.load(&recent).await.is_some());
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn old_entries_expire_on_load() {
+        use crate::utils::TestClock;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let path = temp_path("expiry");
+        let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let hook_expired = Arc::clone(&expired);
+        let cache = open_with(&path, "max_entry_age_hours: 24")
+            .expect("open rocks cache")
+            .with_clock(Arc::clone(&clock) as _)
+            .with_eviction_hook(Box::new(move |key, len, reason| {
+                hook_expired
+                    .lock()
+                    .unwrap()
+                    .push((key.to_string(), len, reason));
+            }));
+        let key = test_key();
+        let data = Bytes::from(vec![0u8; 100]);
+        assert!(cache.save(&key, "image/png".to_string(), data).await);
+
+        clock.advance(Duration::from_secs(23 * 60 * 60));
+        assert!(cache.load(&key).await.is_some());
+        assert!(expired.lock().unwrap().is_empty());
+
+        // a day after it was saved, the entry is a MISS and is gone from the database
+        clock.advance(Duration::from_secs(2 * 60 * 60));
+        assert!(cache.load(&key).await.is_none());
+        assert_eq!(cache.report(), 0);
+        cache.fetch_real_size().unwrap();
+        assert_eq!(cache.report(), 0);
+        assert_eq!(
+            *expired.lock().unwrap(),
+            vec![(key.to_string(), 100u64, EvictionReason::Expired)]
+        );
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn in_memory_db_stays_off_disk() {
+        let path = temp_path("in-memory");
+        let cache = open_with(&path, "in_memory: true").expect("open in-memory rocks cache");
+
+        let key = test_key();
+        let data = Bytes::from(vec![1u8; 100]);
+        assert!(
+            cache
+                .save(&key, "image/png".to_string(), data.clone())
+                .await
+        );
+        assert_eq!(cache.load(&key).await.map(|x| x.get_bytes()), Some(data));
+        let res = cache.shrink(0).await.unwrap();
+        assert_eq!(res.entries_evicted, 1);
+        assert!(cache.load(&key).await.is_none());
+
+        drop(cache);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn layout_version_is_checked() {
+        // matching layout versions open fine
+        let (cache, path) = open_temp("layout-version");
+        let key = test_key();
+        assert!(
+            cache
+                .save(&key, "image/png".to_string(), Bytes::from(vec![0u8; 100]))
+                .await
+        );
+        drop(cache);
+        let cache = open(&path).unwrap();
+
+        // databases from before the layout version existed get migrated
+        cache.db.delete(RocksCache::LAYOUT_KEY).unwrap();
+        drop(cache);
+        let cache = open(&path).unwrap();
+        assert_eq!(
+            cache.db.get(RocksCache::LAYOUT_KEY).unwrap(),
+            Some(RocksCache::LAYOUT_VERSION.to_le_bytes().to_vec())
+        );
+        assert!(cache.load(&key).await.is_some());
+
+        // unknown future layouts are refused
+        cache
+            .db
+            .put(RocksCache::LAYOUT_KEY, 99u32.to_le_bytes())
+            .unwrap();
+        drop(cache);
+        assert!(matches!(
+            open(&path),
+            Err(CacheError::UnsupportedLayout(Some(99)))
+        ));
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn images_are_spread_across_shards() {
+        let path = temp_path("shards");
+        let cache = open_with(&path, "shards: 4\n").expect("open rocks cache");
+        let keys: Vec<_> = (0..32)
+            .map(|i| ImageKey::new("chapter".to_string(), format!("{}.png", i), false))
+            .collect();
+        for key in &keys {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+        }
+        for key in &keys {
+            assert!(cache.load(key).await.is_some(), "{}", key);
+        }
+
+        // every shard holds some of the images, and only the images of its keys
+        for shard in 0..4u8 {
+            let name = RocksCache::image_cf_name(4, &[shard]);
+            let cf = cache.cf_by_name(&name);
+            let stored: Vec<_> = cache.db.iterator_cf(&cf, IteratorMode::Start).collect();
+            assert!(!stored.is_empty(), "shard {}", shard);
+            assert!(stored.iter().all(|(key, _)| key[0] % 4 == shard));
+        }
+
+        let res = cache.shrink(0).await.unwrap();
+        assert_eq!(res.entries_evicted, 32);
+        assert!(cache.load(&keys[0]).await.is_none());
+
+        // the shard count can't change once the images are spread out
+        let data = Bytes::from(vec![0u8; 100]);
+        assert!(cache.save(&keys[0], "image/png".to_string(), data).await);
+        drop(cache);
+        for &shards in &[1, 8] {
+            assert!(matches!(
+                open_with(&path, &format!("shards: {}\n", shards)),
+                Err(CacheError::ShardMismatch(4))
+            ));
+        }
+        // refusing to open doesn't leave any new shards behind
+        let cache = open_with(&path, "shards: 4\n").expect("reopen rocks cache");
+        assert!(cache.load(&keys[0]).await.is_some());
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn stats_include_files_on_disk() {
+        let (cache, path) = open_temp("stats");
+        for i in 0..4 {
+            let key = ImageKey::new("chapter".to_string(), format!("{}.png", i), i % 2 == 0);
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key, "image/png".to_string(), data).await);
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.size, 400);
+        assert_eq!(stats.backend["data_saver_bytes"], 200);
+        assert!(stats.backend["memtable_bytes"].as_u64().unwrap() > 0);
+
+        // the images only end up in files once they're flushed
+        cache.flush().await;
+        let stats = cache.stats();
+        assert_eq!(stats.entries, Some(4));
+        assert!(stats.backend["sst_files"].as_u64().unwrap() > 0);
+        assert!(stats.backend["sst_bytes"].as_u64().unwrap() > 0);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn info_reports_configuration() {
+        let path = temp_path("info");
+        let cache = open_with(
+            &path,
+            "lru_size: 32\nmax_subcompactions: 4\nwrite_rate_limit: 24",
+        )
+        .expect("open rocks cache");
+
+        let info = cache.info();
+        assert_eq!(info.backend, "rocksdb");
+        assert_eq!(info.path, Some(path.display().to_string()));
+        assert_eq!(info.settings["block_cache_mebibytes"], 32);
+        assert_eq!(info.settings["max_subcompactions"], 4);
+        assert_eq!(info.settings["write_rate_limit_mebibytes"], 24);
+        assert_eq!(info.settings["write_buffer_mebibytes"], 64);
+        assert_eq!(info.settings["compression"], "none");
+        assert!(info.features.contains(&"archive_budgets"));
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn load_many_matches_load() {
+        let (cache, path) = open_temp("load-many");
+        let key = |image: &str| ImageKey::new("chapter".to_string(), image.to_string(), false);
+        for (image, len) in &[("1.png", 10), ("3.png", 30)] {
+            let data = Bytes::from(vec![1u8; *len]);
+            assert!(cache.save(&key(image), "image/png".to_string(), data).await);
+        }
+
+        let keys = vec![key("3.png"), key("2.png"), key("1.png"), key("4.png")];
+        let batched = cache.load_many(&keys).await;
+        let mut single = Vec::new();
+        for key in &keys {
+            single.push(cache.load(key).await);
+        }
+
+        let lens = |x: &[Option<ImageEntry>]| {
+            x.iter()
+                .map(|x| x.as_ref().map(ImageEntry::get_bytes_len))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lens(&batched), vec![Some(30), None, Some(10), None]);
+        assert_eq!(lens(&batched), lens(&single));
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn pinned_entries_survive_shrink() {
+        let path = temp_path("pinned");
+        let pins: HashSet<String> = vec!["hot".to_string()].into_iter().collect();
+        let cache = open(&path).expect("open rocks cache").with_pins(pins);
+
+        let pinned = ImageKey::new("hot".to_string(), "1.png".to_string(), false);
+        let unpinned = ImageKey::new("cold".to_string(), "1.png".to_string(), false);
+        for key in &[&pinned, &unpinned] {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(key, "image/png".to_string(), data).await);
+        }
+        assert_eq!(cache.report_pinned(), Some(100));
+
+        // shrinking as far as possible only evicts the unpinned entry
+        let res = cache.shrink(0).await.unwrap();
+        assert_eq!(res.size, 100);
+        assert_eq!(res.entries_evicted, 1);
+        assert!(cache.load(&pinned).await.is_some());
+        assert!(cache.load(&unpinned).await.is_none());
+
+        // removing the chapter from the pins allows it to be evicted again
+        let cache = cache.with_pins(HashSet::new());
+        assert_eq!(cache.report_pinned(), Some(0));
+        assert_eq!(cache.shrink(0).await.map(|x| x.size), Ok(0));
+        assert!(cache.load(&pinned).await.is_none());
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn existing_entries_are_pinned_once_loaded() {
+        let (cache, path) = open_temp("pinned-on-load");
+        let key = ImageKey::new("hot".to_string(), "1.png".to_string(), false);
+        let data = Bytes::from(vec![0u8; 100]);
+        assert!(cache.save(&key, "image/png".to_string(), data).await);
+
+        let pins: HashSet<String> = vec!["hot".to_string()].into_iter().collect();
+        let cache = cache.with_pins(pins);
+        assert_eq!(cache.report_pinned(), Some(0));
+        for _ in 0..3 {
+            assert!(cache.load(&key).await.is_some());
+        }
+        assert_eq!(cache.report_pinned(), Some(100));
+        assert!(RocksCache::is_pinned(&cache.db, &key.as_bkey()).unwrap());
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn zstd_long_window_round_trips() {
+        let path = temp_path("zstd");
+        let cache = open_with(&path, "zstd:\n    level: 19\n    window_log: 27")
+            .expect("open rocks cache with zstd");
+
+        // repetitive data, larger than a single block
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let key = test_key();
+        assert!(
+            cache
+                .save(&key, "image/png".to_string(), Bytes::from(data.clone()))
+                .await
+        );
+        assert_eq!(cache.load(&key).await.unwrap().get_bytes(), &data[..]);
+        assert_eq!(cache.info().settings["compression"], "zstd");
+
+        // the window is passed on as the window bits of every column family (besides the unused
+        // default one)
+        let options = std::fs::read_dir(&path)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .filter(|x| {
+                x.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with("OPTIONS-")
+            })
+            .max()
+            .expect("OPTIONS file");
+        let options = std::fs::read_to_string(options).unwrap();
+        let cfs = RocksCache::cf_names(cache.conf.shards).len();
+        assert_eq!(options.matches("window_bits=27;").count(), cfs);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn zstd_dictionary_round_trips() {
+        let path = temp_path("zstd-dict");
+        let cache = open_with(&path, "zstd:\n    dict_kibibytes: 16\n")
+            .expect("open rocks cache with a zstd dictionary");
+        assert_eq!(cache.info().settings["zstd_dict_kibibytes"], 16);
+
+        // many small images that share most of their bytes
+        let image = |i: usize| {
+            let mut data = b"\xff\xd8\xff\xe0\0\x10JFIF".repeat(64);
+            data.extend_from_slice(&i.to_le_bytes());
+            data
+        };
+        let keys: Vec<_> = (0..256)
+            .map(|i| ImageKey::new("chapter".to_string(), format!("{}.jpg", i), true))
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            let data = Bytes::from(image(i));
+            assert!(cache.save(key, "image/jpeg".to_string(), data).await);
+        }
+
+        // the dictionary is trained when the images are written to a file
+        cache.flush().await;
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(cache.load(key).await.unwrap().get_bytes(), &image(i)[..]);
+        }
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
 }
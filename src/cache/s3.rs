@@ -0,0 +1,687 @@
+use super::{CacheInfo, ImageCache, ImageEntry, ImageKey, ShrinkResult};
+use crate::config::S3Config;
+use crate::utils::{Backoff, Clock, Secret, SystemClock};
+use bytes::Bytes;
+use chrono::{DateTime, FixedOffset, Utc};
+use openssl::{error::ErrorStack, hash::MessageDigest, pkey::PKey, sign::Signer};
+use reqwest::{header, Method, StatusCode};
+use sha2::Digest;
+use std::convert::{TryFrom, TryInto};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+#[derive(Debug)]
+pub enum CacheError {
+    Http(reqwest::Error),
+    /// The object store responded with an unexpected status
+    Status(StatusCode),
+    Bincode(bincode::Error),
+    Signing(ErrorStack),
+    /// A listing of the bucket couldn't be parsed
+    Listing(String),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(e) => write!(fmt, "ce-s3/http - \"{}\"", e),
+            Self::Status(status) => write!(fmt, "ce-s3/http - unexpected status {}", status),
+            Self::Bincode(e) => write!(fmt, "ce-s3/bincode - \"{}\"", e),
+            Self::Signing(e) => write!(fmt, "ce-s3/signing - \"{}\"", e),
+            Self::Listing(e) => write!(fmt, "ce-s3/listing - \"{}\"", e),
+        }
+    }
+}
+impl std::error::Error for CacheError {}
+
+/// The headers included in the signature of every request
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Computes the HMAC-SHA256 of `data`
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+/// Derives the key that AWS Signature Version 4 requests are signed with
+fn signing_key(
+    secret: &str,
+    date: &str,
+    region: &str,
+    service: &str,
+) -> Result<Vec<u8>, ErrorStack> {
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes())?;
+    let key = hmac(&key, region.as_bytes())?;
+    let key = hmac(&key, service.as_bytes())?;
+    hmac(&key, b"aws4_request")
+}
+
+/// Percent-encodes everything but the unreserved characters, the way AWS expects in signatures
+fn aws_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The query string of a URL in the canonical form of AWS signatures (sorted and encoded)
+fn canonical_query(url: &Url) -> String {
+    let mut pairs: Vec<_> = url
+        .query_pairs()
+        .map(|(k, v)| (aws_encode(&k), aws_encode(&v)))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Iterates over the contents of every `<tag>` element in `xml`. Only meant for the simple
+/// listings of S3, which don't nest elements of the same name.
+fn xml_elements<'a>(xml: &'a str, tag: &str) -> impl Iterator<Item = &'a str> + 'a {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let len = rest[start..].find(&close)?;
+        let element = &rest[start..start + len];
+        rest = &rest[start + len + close.len()..];
+        Some(element)
+    })
+}
+
+/// Reverses the escaping of text in XML
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// An object in a listing of the bucket
+struct ObjectInfo {
+    key: String,
+    last_modified: DateTime<FixedOffset>,
+    size: u64,
+}
+
+/// A cold tier that stores images in an S3-compatible object store, meant to sit behind a faster
+/// local cache.
+///
+/// The size of the cache is tracked locally, and reconciled with a listing of the bucket whenever
+/// the cache is shrunk (which evicts the objects that were modified the longest ago). Failed
+/// requests are retried, and images missing from the object store (i.e. ones that were just saved
+/// to a store that's only eventually consistent) are treated as a MISS.
+pub struct S3Cache {
+    client: reqwest::Client,
+    /// the path-style URL of the bucket
+    bucket_url: Url,
+    /// the Host header of requests, which is part of their signature
+    host: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: Secret<String>,
+    key_prefix: String,
+    max_retries: u32,
+
+    /// total size of the objects in the bucket
+    size: AtomicU64,
+    entries: AtomicU64,
+    /// source of the save times of entries and the time requests are signed at
+    clock: Arc<dyn Clock>,
+    info: CacheInfo,
+}
+
+impl S3Cache {
+    pub async fn new(conf: &S3Config) -> Result<Self, CacheError> {
+        let bucket_url = Url::parse(&format!(
+            "{}/{}",
+            conf.endpoint.trim_end_matches('/'),
+            conf.bucket
+        ))
+        .map_err(|e| CacheError::Listing(format!("invalid endpoint: {}", e)))?;
+        let host = match bucket_url.port() {
+            Some(port) => format!("{}:{}", bucket_url.host_str().unwrap_or_default(), port),
+            None => bucket_url.host_str().unwrap_or_default().to_string(),
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(CacheError::Http)?;
+
+        let this = Self {
+            client,
+            bucket_url,
+            host,
+            region: conf.region.clone(),
+            access_key_id: conf.access_key_id.clone(),
+            secret_access_key: conf.secret_access_key.clone(),
+            key_prefix: conf.key_prefix.clone(),
+            max_retries: conf.max_retries,
+            size: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
+            info: CacheInfo::new("s3")
+                .with_path(format!("{}/{}", conf.endpoint, conf.bucket))
+                .with_setting("region", conf.region.clone())
+                .with_setting("key_prefix", conf.key_prefix.clone())
+                .with_setting("max_retries", conf.max_retries),
+        };
+        let objects = this.list_objects().await?;
+        this.size
+            .store(objects.iter().map(|x| x.size).sum(), Ordering::SeqCst);
+        this.entries.store(objects.len() as u64, Ordering::SeqCst);
+        Ok(this)
+    }
+
+    /// Sets the clock that the save times of entries are taken from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The URL of the object with the key provided
+    fn object_url(&self, object_key: &str) -> Url {
+        let mut url = self.bucket_url.clone();
+        url.set_path(&format!("{}/{}", self.bucket_url.path(), object_key));
+        url
+    }
+
+    /// The object key that an image is stored under
+    fn object_key(&self, key: &ImageKey) -> String {
+        format!("{}{}", self.key_prefix, hex::encode(key.as_bkey()))
+    }
+
+    /// Creates a request that's signed with AWS Signature Version 4
+    fn signed(
+        &self,
+        method: Method,
+        url: &Url,
+        body: Bytes,
+    ) -> Result<reqwest::RequestBuilder, CacheError> {
+        let now: DateTime<Utc> = self.clock.now().into();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+        let payload_hash = hex::encode(sha2::Sha256::digest(&body));
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            url.path(),
+            canonical_query(url),
+            self.host,
+            payload_hash,
+            amz_date,
+            SIGNED_HEADERS,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(sha2::Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.secret_access_key, date, &self.region, "s3")
+            .map_err(CacheError::Signing)?;
+        let signature = hmac(&key, string_to_sign.as_bytes()).map_err(CacheError::Signing)?;
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id,
+            scope,
+            SIGNED_HEADERS,
+            hex::encode(signature)
+        );
+
+        Ok(self
+            .client
+            .request(method, url.clone())
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(header::AUTHORIZATION, authorization)
+            .body(body))
+    }
+
+    /// Sends a signed request, retrying it if it fails in a way that might be temporary (i.e.
+    /// connection errors or the object store being overloaded)
+    async fn send(
+        &self,
+        method: Method,
+        url: &Url,
+        body: Bytes,
+    ) -> Result<reqwest::Response, CacheError> {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(2));
+        let mut attempt = 0;
+        loop {
+            let res = self.signed(method.clone(), url, body.clone())?.send().await;
+            let retry = match &res {
+                Ok(res) => {
+                    res.status().is_server_error() || res.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => e.is_timeout() || e.is_connect(),
+            };
+            if !retry || attempt >= self.max_retries {
+                return res.map_err(CacheError::Http);
+            }
+
+            attempt += 1;
+            let delay = backoff.next_delay();
+            match &res {
+                Ok(res) => log::warn!("{} {} failed with {}, retrying", method, url, res.status()),
+                Err(e) => log::warn!("{} {} failed ({}), retrying", method, url, e),
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Lists every object of the cache in the bucket
+    async fn list_objects(&self) -> Result<Vec<ObjectInfo>, CacheError> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut url = self.bucket_url.clone();
+            {
+                let mut query = url.query_pairs_mut();
+                query
+                    .append_pair("list-type", "2")
+                    .append_pair("prefix", &self.key_prefix);
+                if let Some(token) = &token {
+                    query.append_pair("continuation-token", token);
+                }
+            }
+
+            let res = self.send(Method::GET, &url, Bytes::new()).await?;
+            if !res.status().is_success() {
+                return Err(CacheError::Status(res.status()));
+            }
+            let body = res.text().await.map_err(CacheError::Http)?;
+            for contents in xml_elements(&body, "Contents") {
+                let field = |name| {
+                    xml_elements(contents, name)
+                        .next()
+                        .ok_or_else(|| CacheError::Listing(format!("object without {}", name)))
+                };
+                let last_modified = DateTime::parse_from_rfc3339(field("LastModified")?)
+                    .map_err(|e| CacheError::Listing(e.to_string()))?;
+                let size = field("Size")?
+                    .parse()
+                    .map_err(|_| CacheError::Listing("invalid object size".to_string()))?;
+                objects.push(ObjectInfo {
+                    key: xml_unescape(field("Key")?),
+                    last_modified,
+                    size,
+                });
+            }
+
+            let truncated = xml_elements(&body, "IsTruncated").next() == Some("true");
+            token = xml_elements(&body, "NextContinuationToken")
+                .next()
+                .filter(|_| truncated)
+                .map(xml_unescape);
+            if token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+
+    /// Loads an entry from the object store
+    async fn load_entry(&self, key: &ImageKey) -> Result<Option<ImageEntry>, CacheError> {
+        let url = self.object_url(&self.object_key(key));
+        let res = self.send(Method::GET, &url, Bytes::new()).await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let bytes = res.bytes().await.map_err(CacheError::Http)?;
+                ImageEntry::try_from(bytes)
+                    .map(Some)
+                    .map_err(CacheError::Bincode)
+            }
+            status => Err(CacheError::Status(status)),
+        }
+    }
+
+    /// Saves an entry to the object store
+    async fn save_entry(&self, key: &ImageKey, entry: ImageEntry) -> Result<(), CacheError> {
+        let ser: Bytes = entry.try_into().map_err(CacheError::Bincode)?;
+        let len = ser.len() as u64;
+        let url = self.object_url(&self.object_key(key));
+        let res = self.send(Method::PUT, &url, ser).await?;
+        if !res.status().is_success() {
+            return Err(CacheError::Status(res.status()));
+        }
+
+        // replaced objects are counted twice until the next shrink
+        self.size.fetch_add(len, Ordering::SeqCst);
+        self.entries.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Deletes an object, which is fine if it's already gone
+    async fn delete_object(&self, object_key: &str) -> Result<(), CacheError> {
+        let url = self.object_url(object_key);
+        let res = self.send(Method::DELETE, &url, Bytes::new()).await?;
+        match res.status() {
+            status if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+            status => Err(CacheError::Status(status)),
+        }
+    }
+
+    /// Deletes the objects that were modified the longest ago until the bucket is at most `min`
+    /// bytes, reconciling the tracked size with the listing of the bucket
+    async fn evict_oldest(&self, min: u64) -> Result<ShrinkResult, CacheError> {
+        let mut objects = self.list_objects().await?;
+        objects.sort_by(|a, b| (a.last_modified, &a.key).cmp(&(b.last_modified, &b.key)));
+
+        // the listing is the most accurate size there is, and every delete is counted as soon as
+        // it's done so that the counters stay right if a later one fails
+        let mut size: u64 = objects.iter().map(|x| x.size).sum();
+        self.size.store(size, Ordering::SeqCst);
+        self.entries.store(objects.len() as u64, Ordering::SeqCst);
+
+        let mut res = ShrinkResult::default();
+        for object in &objects {
+            if size <= min {
+                break;
+            }
+            self.delete_object(&object.key).await?;
+            size -= object.size;
+            sub_saturating(&self.size, object.size);
+            sub_saturating(&self.entries, 1);
+            res.bytes_evicted += object.size;
+            res.entries_evicted += 1;
+        }
+
+        res.size = size;
+        Ok(res)
+    }
+}
+
+/// Subtracts `n` from a counter without wrapping around, in case another eviction raced this one
+fn sub_saturating(counter: &AtomicU64, n: u64) {
+    let _ = counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+        Some(x.saturating_sub(n))
+    });
+}
+
+#[async_trait::async_trait]
+impl ImageCache for S3Cache {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        match self.load_entry(key).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::error!("error loading entry from s3: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        // an empty body is never a valid image (i.e. a truncated upstream response)
+        if data.is_empty() {
+            log::warn!("refusing to save empty image {} to s3", key);
+            return false;
+        }
+        let entry = ImageEntry::new(data, mime_type, self.clock.now());
+        if let Err(e) = self.save_entry(key, entry).await {
+            log::error!("error saving entry to s3: {}", e);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn report(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    fn report_entries(&self) -> Option<u64> {
+        Some(self.entries.load(Ordering::SeqCst))
+    }
+
+    fn info(&self) -> CacheInfo {
+        self.info.clone()
+    }
+
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        self.evict_oldest(min).await.map_err(|e| {
+            log::error!("error shrinking s3: {}", e);
+        })
+    }
+
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        self.evict_oldest(0).await.map_err(|e| {
+            log::error!("error clearing s3: {}", e);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    /// A tiny stand-in for an S3-compatible object store, serving a single bucket
+    #[derive(Default)]
+    struct MockStore {
+        /// objects by key, along with the order they were written in (their "modified" second)
+        objects: Mutex<BTreeMap<String, (Vec<u8>, u32)>>,
+        writes: AtomicUsize,
+        /// responds with 503 to this many of the next requests
+        fail_next: AtomicUsize,
+        /// responds with 503 to every delete once this many more were done, if set
+        deletes_left: Mutex<Option<usize>>,
+    }
+
+    impl MockStore {
+        /// Starts the server, returning its endpoint
+        fn start(self: &Arc<Self>) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let this = Arc::clone(self);
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let this = Arc::clone(&this);
+                    std::thread::spawn(move || this.serve(stream));
+                }
+            });
+            endpoint
+        }
+
+        fn serve(&self, stream: TcpStream) {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split_whitespace();
+            let (method, target) = (parts.next().unwrap(), parts.next().unwrap());
+
+            let (mut content_length, mut signed) = (0, false);
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim_end().to_lowercase();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(len) = header.strip_prefix("content-length: ") {
+                    content_length = len.parse().unwrap();
+                }
+                signed |= header.starts_with("authorization: aws4-hmac-sha256 credential=");
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let (status, reply) = if !signed {
+                ("403 Forbidden", Vec::new())
+            } else if self.fail_next.load(Ordering::SeqCst) > 0 {
+                self.fail_next.fetch_sub(1, Ordering::SeqCst);
+                ("503 Service Unavailable", Vec::new())
+            } else {
+                self.respond(method, target, body)
+            };
+            let mut stream = stream;
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                reply.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(&reply).unwrap();
+        }
+
+        fn respond(&self, method: &str, target: &str, body: Vec<u8>) -> (&'static str, Vec<u8>) {
+            let mut objects = self.objects.lock().unwrap();
+            let key = target.strip_prefix("/bucket/").map(|x| x.to_string());
+            match (method, key) {
+                ("GET", Some(key)) => match objects.get(&key) {
+                    Some((data, _)) => ("200 OK", data.clone()),
+                    None => ("404 Not Found", Vec::new()),
+                },
+                ("PUT", Some(key)) => {
+                    let order = self.writes.fetch_add(1, Ordering::SeqCst) as u32;
+                    objects.insert(key, (body, order));
+                    ("200 OK", Vec::new())
+                }
+                ("DELETE", Some(key)) => {
+                    let mut deletes_left = self.deletes_left.lock().unwrap();
+                    match deletes_left.as_mut() {
+                        Some(0) => return ("503 Service Unavailable", Vec::new()),
+                        Some(left) => *left -= 1,
+                        None => {}
+                    }
+                    objects.remove(&key);
+                    ("204 No Content", Vec::new())
+                }
+                ("GET", None) => {
+                    let mut xml = "<ListBucketResult><IsTruncated>false</IsTruncated>".to_string();
+                    for (key, (data, order)) in objects.iter() {
+                        xml += &format!(
+                            "<Contents><Key>{}</Key><LastModified>2021-01-01T00:00:{:02}.000Z\
+                            </LastModified><Size>{}</Size></Contents>",
+                            key,
+                            order,
+                            data.len()
+                        );
+                    }
+                    xml += "</ListBucketResult>";
+                    ("200 OK", xml.into_bytes())
+                }
+                _ => ("400 Bad Request", Vec::new()),
+            }
+        }
+    }
+
+    async fn open(endpoint: String) -> S3Cache {
+        let conf: S3Config = serde_yaml::from_str(&format!(
+            "endpoint: {}\nbucket: bucket\naccess_key_id: AKID\nsecret_access_key: secret\n",
+            endpoint
+        ))
+        .expect("s3 test config");
+        S3Cache::new(&conf).await.expect("open s3 cache")
+    }
+
+    fn key(i: usize) -> ImageKey {
+        ImageKey::new("chapter".to_string(), format!("{}.png", i), false)
+    }
+
+    #[test]
+    fn signing_key_matches_aws_example() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[tokio::test]
+    async fn entries_round_trip() {
+        let store = Arc::new(MockStore::default());
+        let cache = open(store.start()).await;
+
+        let data = Bytes::from(vec![1u8; 1024]);
+        assert!(
+            cache
+                .save(&key(0), "image/png".to_string(), data.clone())
+                .await
+        );
+        let entry = cache.load(&key(0)).await.unwrap();
+        assert_eq!(entry.get_bytes(), data);
+        assert_eq!(entry.get_mime(mime::IMAGE_JPEG), mime::IMAGE_PNG);
+        assert!(cache.load(&key(1)).await.is_none());
+        assert!(store
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .all(|x| x.starts_with("images/")));
+
+        // failures that might be temporary are retried
+        store.fail_next.store(2, Ordering::SeqCst);
+        assert!(cache.load(&key(0)).await.is_some());
+        store.fail_next.store(4, Ordering::SeqCst);
+        assert!(cache.load(&key(0)).await.is_none());
+
+        // the size is picked up from the bucket when opened again
+        let size = cache.report();
+        let cache = open(cache.bucket_url.origin().ascii_serialization()).await;
+        assert_eq!(cache.report(), size);
+        assert_eq!(cache.report_entries(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn shrink_deletes_oldest_objects() {
+        let store = Arc::new(MockStore::default());
+        let cache = open(store.start()).await;
+        for i in 0..4 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+        }
+
+        let entry_size = cache.report() / 4;
+        let res = cache.shrink(entry_size * 2).await.unwrap();
+        assert_eq!(res.entries_evicted, 2);
+        assert_eq!(res.bytes_evicted, entry_size * 2);
+        assert_eq!(cache.report(), entry_size * 2);
+        assert!(cache.load(&key(0)).await.is_none());
+        assert!(cache.load(&key(1)).await.is_none());
+        assert!(cache.load(&key(3)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn failed_shrink_counts_finished_deletes() {
+        let store = Arc::new(MockStore::default());
+        let cache = open(store.start()).await;
+        for i in 0..4 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+        }
+
+        // only the first delete goes through
+        let entry_size = cache.report() / 4;
+        *store.deletes_left.lock().unwrap() = Some(1);
+        assert!(cache.shrink(0).await.is_err());
+        assert_eq!(cache.report(), entry_size * 3);
+        assert_eq!(cache.report_entries(), Some(3));
+        assert_eq!(store.objects.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,616 @@
+use super::{CacheInfo, ImageCache, ImageEntry, ImageKey, ShrinkResult, SizeReconciliation};
+use crate::config::SledConfig;
+use crate::utils::{Clock, SystemClock};
+use bytes::Bytes;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Transactional, Tree};
+use std::convert::{TryFrom, TryInto};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum CacheError {
+    Sled(sled::Error),
+    Bincode(bincode::Error),
+    TokioJoin(tokio::task::JoinError),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sled(e) => write!(fmt, "ce-sled/sled - \"{}\"", e),
+            Self::Bincode(e) => write!(fmt, "ce-sled/bincode - \"{}\"", e),
+            Self::TokioJoin(e) => write!(fmt, "ce-sled/tokio - \"{}\"", e),
+        }
+    }
+}
+impl std::error::Error for CacheError {}
+
+/// Converts the error of a transaction, which are never aborted by this cache
+fn tx_error(e: TransactionError<()>) -> CacheError {
+    match e {
+        TransactionError::Storage(e) => CacheError::Sled(e),
+        TransactionError::Abort(()) => unreachable!("sled transactions are never aborted"),
+    }
+}
+
+/// Parses a little endian u64 from a value in the database
+fn parse_le_u64(val: &[u8]) -> Option<u64> {
+    val.try_into().ok().map(u64::from_le_bytes)
+}
+
+/// The key of an entry in the age tree: the time it was saved or last loaded (big endian, so keys
+/// sort from least to most recently used) followed by the key of the entry
+fn age_key(save_time: u64, bkey: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(8 + bkey.len());
+    key.extend_from_slice(&save_time.to_be_bytes());
+    key.extend_from_slice(bkey);
+    key
+}
+
+/// A pure Rust cache engine powered by sled, for platforms where building RocksDB is a pain.
+/// Cloning is cheap and shares the database, which lets blocking work run off the runtime.
+#[derive(Clone)]
+pub struct SledCache {
+    /// serialized entries
+    images: Tree,
+    /// the time each entry was saved or last loaded (millis since epoch, u64 little endian)
+    save_times: Tree,
+    /// the size of each entry (u64 little endian), keyed by [`age_key`] so that iterating goes
+    /// from the least to the most recently used entry
+    by_age: Tree,
+
+    /// total size of the serialized entries
+    size: Arc<AtomicU64>,
+    entries: Arc<AtomicU64>,
+    /// source of the save and access times of entries
+    clock: Arc<dyn Clock>,
+    info: CacheInfo,
+}
+
+impl SledCache {
+    const IMAGES_TREE: &'static str = "images";
+    const SAVE_TIMES_TREE: &'static str = "save_times";
+    const BY_AGE_TREE: &'static str = "by_age";
+
+    /// How outdated the stored time of an entry can be before a load moves it to the back of the
+    /// age tree (1 hr in milliseconds), like with RocksDB. This keeps hot entries from causing a
+    /// write on every load.
+    const ACCESS_GRANULARITY: u64 = 1000 * 60 * 60;
+
+    pub fn new(conf: &SledConfig) -> Result<Self, CacheError> {
+        let db = sled::Config::new()
+            .path(&conf.path)
+            .cache_capacity(conf.cache_capacity_mebibytes * 1024 * 1024)
+            .open()
+            .map_err(CacheError::Sled)?;
+        let open_tree = |name| db.open_tree(name).map_err(CacheError::Sled);
+
+        let this = Self {
+            images: open_tree(Self::IMAGES_TREE)?,
+            save_times: open_tree(Self::SAVE_TIMES_TREE)?,
+            by_age: open_tree(Self::BY_AGE_TREE)?,
+            size: Arc::new(AtomicU64::new(0)),
+            entries: Arc::new(AtomicU64::new(0)),
+            clock: Arc::new(SystemClock),
+            info: CacheInfo::new("sled")
+                .with_path(conf.path.clone())
+                .with_setting("cache_capacity_mebibytes", conf.cache_capacity_mebibytes),
+        };
+        this.fetch_real_size()?;
+        Ok(this)
+    }
+
+    /// Sets the clock that the save and access times of entries are taken from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Runs work that walks or rewrites the database on a blocking thread, since it can take a
+    /// while for big databases
+    async fn blocking<T, F>(&self, op: F) -> Result<T, CacheError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self) -> Result<T, CacheError> + Send + 'static,
+    {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || op(&this))
+            .await
+            .map_err(CacheError::TokioJoin)?
+    }
+
+    /// Sums up the sizes of every entry in the database, updating the internal counters
+    fn fetch_real_size(&self) -> Result<(), CacheError> {
+        let (mut size, mut entries) = (0, 0);
+        for val in self.by_age.iter().values() {
+            size += parse_le_u64(&val.map_err(CacheError::Sled)?).unwrap_or(0);
+            entries += 1;
+        }
+        self.size.store(size, Ordering::SeqCst);
+        self.entries.store(entries, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Compares the sizes recorded in the age tree with the entries actually stored, for at most
+    /// `sample` entries (oldest first)
+    fn check_sizes(&self, sample: Option<usize>) -> Result<SizeReconciliation, CacheError> {
+        let mut res = SizeReconciliation::default();
+        for record in self.by_age.iter().take(sample.unwrap_or(usize::MAX)) {
+            let (age_key, val) = record.map_err(CacheError::Sled)?;
+            res.entries_checked += 1;
+            res.tracked += parse_le_u64(&val).unwrap_or(0);
+            res.actual += self.stored_len(&age_key[8..])?.unwrap_or(0);
+        }
+        Ok(res)
+    }
+
+    /// The size of the serialized entry actually stored under the key, if there is one
+    fn stored_len(&self, bkey: &[u8]) -> Result<Option<u64>, CacheError> {
+        let val = self.images.get(bkey).map_err(CacheError::Sled)?;
+        Ok(val.map(|x| x.len() as u64))
+    }
+
+    /// Rewrites the size recorded for every entry in the age tree with the size actually stored,
+    /// forgetting entries that aren't stored at all, then recounts the size of the database
+    fn correct_sizes(&self) -> Result<(), CacheError> {
+        for record in self.by_age.iter() {
+            let (age_key, val) = record.map_err(CacheError::Sled)?;
+            let bkey = &age_key[8..];
+            match self.stored_len(bkey)? {
+                Some(len) if parse_le_u64(&val) == Some(len) => {}
+                Some(len) => {
+                    let len = &len.to_le_bytes()[..];
+                    self.by_age
+                        .insert(&age_key, len)
+                        .map_err(CacheError::Sled)?;
+                }
+                None => {
+                    self.by_age.remove(&age_key).map_err(CacheError::Sled)?;
+                    self.save_times.remove(bkey).map_err(CacheError::Sled)?;
+                }
+            }
+        }
+        self.fetch_real_size()
+    }
+
+    /// Loads an entry from the database
+    async fn load_entry(&self, key: &ImageKey) -> Result<Option<ImageEntry>, CacheError> {
+        let trees = (self.images.clone(), self.save_times.clone());
+        let bkey = key.as_bkey();
+        let (val, time) = tokio::task::spawn_blocking(move || {
+            let (images, save_times) = &trees;
+            Ok((images.get(bkey)?, save_times.get(bkey)?))
+        })
+        .await
+        .map_err(CacheError::TokioJoin)?
+        .map_err(CacheError::Sled)?;
+
+        // keep the entry from being evicted while it's still being used
+        if let Some(time) = time.and_then(|x| parse_le_u64(&x)) {
+            if self.clock.now_as_millis().saturating_sub(time) >= Self::ACCESS_GRANULARITY {
+                self.touch(bkey);
+            }
+        }
+        val.map(|x| ImageEntry::try_from(Bytes::copy_from_slice(&x)))
+            .transpose()
+            .map_err(CacheError::Bincode)
+    }
+
+    /// Moves an entry that was just loaded to the back of the age tree, without waiting for the
+    /// write to finish
+    fn touch(&self, bkey: [u8; 32]) {
+        let trees = (self.save_times.clone(), self.by_age.clone());
+        let now = self.clock.now_as_millis();
+        tokio::task::spawn_blocking(move || {
+            let (save_times, by_age) = &trees;
+            let res = (save_times, by_age).transaction(|(save_times, by_age)| {
+                // the entry might have been evicted or saved again in the meantime
+                let time = save_times.get(&bkey[..])?.and_then(|x| parse_le_u64(&x));
+                if let Some(time) = time.filter(|&x| x < now) {
+                    if let Some(len) = by_age.remove(age_key(time, &bkey))? {
+                        by_age.insert(age_key(now, &bkey), len)?;
+                        save_times.insert(&bkey[..], &now.to_le_bytes()[..])?;
+                    }
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            });
+            if let Err(e) = res.map_err(tx_error) {
+                log::warn!("error recording sled entry access: {}", e);
+            }
+        });
+    }
+
+    /// Saves an entry to the database, replacing the entry that was saved under the same key
+    async fn save_entry(&self, key: &ImageKey, entry: ImageEntry) -> Result<(), CacheError> {
+        let bkey = key.as_bkey();
+        let save_time = entry.save_time as u64;
+        let ser: Bytes = entry.try_into().map_err(CacheError::Bincode)?;
+        let len = ser.len() as u64;
+
+        let trees = (
+            self.images.clone(),
+            self.save_times.clone(),
+            self.by_age.clone(),
+        );
+        let replaced = tokio::task::spawn_blocking(move || {
+            let (images, save_times, by_age) = &trees;
+            (images, save_times, by_age).transaction(|(images, save_times, by_age)| {
+                let replaced = images.insert(&bkey[..], &ser[..])?;
+                let old_time = save_times.insert(&bkey[..], &save_time.to_le_bytes()[..])?;
+                if let Some(old_time) = old_time.and_then(|x| parse_le_u64(&x)) {
+                    by_age.remove(age_key(old_time, &bkey))?;
+                }
+                by_age.insert(age_key(save_time, &bkey), &len.to_le_bytes()[..])?;
+                Ok::<_, ConflictableTransactionError<()>>(replaced.map(|x| x.len() as u64))
+            })
+        })
+        .await
+        .map_err(CacheError::TokioJoin)?
+        .map_err(tx_error)?;
+
+        self.size.fetch_add(len, Ordering::SeqCst);
+        match replaced {
+            Some(old_len) => self.size.fetch_sub(old_len, Ordering::SeqCst),
+            None => self.entries.fetch_add(1, Ordering::SeqCst),
+        };
+        Ok(())
+    }
+
+    /// Evicts the entry with the key provided from the age tree, returning its size if it wasn't
+    /// already evicted (or replaced)
+    fn evict_entry(&self, age_key: &[u8]) -> Result<Option<u64>, CacheError> {
+        let bkey = &age_key[8..];
+        let evicted = (&self.images, &self.save_times, &self.by_age)
+            .transaction(|(images, save_times, by_age)| {
+                let len = by_age.remove(age_key)?.and_then(|x| parse_le_u64(&x));
+                if len.is_some() {
+                    images.remove(bkey)?;
+                    save_times.remove(bkey)?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(len)
+            })
+            .map_err(tx_error)?;
+
+        if let Some(len) = evicted {
+            self.size.fetch_sub(len, Ordering::SeqCst);
+            self.entries.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(evicted)
+    }
+
+    /// Evicts the least recently used entries until the database is at most `min` bytes, going by
+    /// the time they were last loaded (or saved, if they were never loaded)
+    fn evict_least_recent(&self, min: u64) -> Result<ShrinkResult, CacheError> {
+        let mut res = ShrinkResult::default();
+        while self.size.load(Ordering::SeqCst) > min {
+            let oldest = match self.by_age.first().map_err(CacheError::Sled)? {
+                Some((key, _)) => key,
+                None => break,
+            };
+            if let Some(len) = self.evict_entry(&oldest)? {
+                res.bytes_evicted += len;
+                res.entries_evicted += 1;
+            }
+        }
+        res.size = self.size.load(Ordering::SeqCst);
+        Ok(res)
+    }
+
+    /// Removes every entry from the database
+    fn clear_entries(&self) -> Result<ShrinkResult, CacheError> {
+        let res = ShrinkResult {
+            size: 0,
+            bytes_evicted: self.size.load(Ordering::SeqCst),
+            entries_evicted: self.entries.load(Ordering::SeqCst),
+        };
+        for tree in &[&self.by_age, &self.save_times, &self.images] {
+            tree.clear().map_err(CacheError::Sled)?;
+        }
+        self.size.store(0, Ordering::SeqCst);
+        self.entries.store(0, Ordering::SeqCst);
+        Ok(res)
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageCache for SledCache {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        match self.load_entry(key).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::error!("error loading entry from sled: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        // an empty body is never a valid image (i.e. a truncated upstream response)
+        if data.is_empty() {
+            log::warn!("refusing to save empty image {} to sled", key);
+            return false;
+        }
+        let entry = ImageEntry::new(data, mime_type, self.clock.now());
+        if let Err(e) = self.save_entry(key, entry).await {
+            log::error!("error saving entry to sled: {}", e);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn report(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    fn report_entries(&self) -> Option<u64> {
+        Some(self.entries.load(Ordering::SeqCst))
+    }
+
+    fn info(&self) -> CacheInfo {
+        self.info.clone()
+    }
+
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        let res = self
+            .blocking(move |this: &Self| this.evict_least_recent(min))
+            .await;
+        res.map_err(|e| {
+            log::error!("error shrinking sled: {}", e);
+        })
+    }
+
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        let res = self.blocking(Self::clear_entries).await;
+        res.map_err(|e| {
+            log::error!("error clearing sled: {}", e);
+        })
+    }
+
+    async fn flush(&self) {
+        // every tree is part of the same database, so this flushes all of them
+        if let Err(e) = self.images.flush_async().await {
+            log::error!("error flushing sled: {}", e);
+        }
+    }
+
+    async fn reconcile_size(
+        &self,
+        sample: Option<usize>,
+        max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        let reconcile = move |this: &Self| {
+            let mut res = this.check_sizes(sample)?;
+            if res.drift() > max_drift {
+                this.correct_sizes()?;
+                res.corrected = true;
+            }
+            Ok(res)
+        };
+        let res = self.blocking(reconcile).await;
+        res.map_err(|e| {
+            log::error!("error reconciling the size of sled: {}", e);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestClock;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    /// Creates a fresh temporary directory path for a [`SledCache`], unique to this test process
+    /// so that concurrent test runs don't open (or delete) each other's databases
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = format!("scalpel-sled-{}-{}", std::process::id(), name);
+        let path = std::env::temp_dir().join(dir);
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    /// Opens a [`SledCache`] at the path provided. sled's background threads can hold on to the
+    /// database for a moment after the last handle to it was dropped, so opening is retried.
+    fn open(path: &Path) -> SledCache {
+        let conf: SledConfig =
+            serde_yaml::from_str(&format!("path: {}", path.display())).expect("sled test config");
+        for _ in 0..50 {
+            if let Ok(cache) = SledCache::new(&conf) {
+                return cache;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        SledCache::new(&conf).expect("open sled cache")
+    }
+
+    fn key(i: usize) -> ImageKey {
+        ImageKey::new("chapter".to_string(), format!("{}.png", i), false)
+    }
+
+    #[tokio::test]
+    async fn drifted_size_is_corrected_on_startup() {
+        let path = temp_path("drifted-size");
+        let cache = open(&path);
+        for i in 0..3 {
+            let data = Bytes::from(vec![0u8; 1024]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+        }
+        let size = cache.report();
+
+        // record the wrong size for one of the entries, which is picked up when it's reopened
+        let (age_key, _) = cache.by_age.first().unwrap().unwrap();
+        let wrong = (1u64 << 30).to_le_bytes();
+        cache.by_age.insert(&age_key, &wrong[..]).unwrap();
+        cache.by_age.flush().unwrap();
+        drop(cache);
+
+        let cache = open(&path);
+        let conf: crate::config::SizeReconciliationConfig =
+            serde_yaml::from_str("sample_entries: 3").unwrap();
+        crate::reconcile_cache_size(&cache, &conf).await;
+        assert_eq!(cache.report(), size);
+
+        // the correction was saved, so there's no drift left
+        let res = cache.reconcile_size(None, 0.0).await.unwrap();
+        assert_eq!((res.entries_checked, res.tracked), (3, size));
+        assert!(!res.corrected);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn entries_round_trip() {
+        let path = temp_path("round-trip");
+        let cache = open(&path);
+
+        let data = Bytes::from(vec![1u8; 1024]);
+        assert!(
+            cache
+                .save(&key(0), "image/png".to_string(), data.clone())
+                .await
+        );
+        let entry = cache.load(&key(0)).await.unwrap();
+        assert_eq!(entry.get_bytes(), data);
+        assert_eq!(entry.get_mime(mime::IMAGE_JPEG), mime::IMAGE_PNG);
+        assert!(cache.load(&key(1)).await.is_none());
+        assert!(
+            !cache
+                .save(&key(1), "image/png".to_string(), Bytes::new())
+                .await
+        );
+
+        // replacing an entry doesn't count it twice
+        let size = cache.report();
+        assert!(size > 1024);
+        let data = Bytes::from(vec![2u8; 1024]);
+        assert!(
+            cache
+                .save(&key(0), "image/png".to_string(), data.clone())
+                .await
+        );
+        assert_eq!(cache.report(), size);
+        assert_eq!(cache.report_entries(), Some(1));
+        assert_eq!(cache.load(&key(0)).await.unwrap().get_bytes(), data);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn shrink_evicts_oldest_first() {
+        let path = temp_path("shrink");
+        let clock = Arc::new(TestClock::new(SystemTime::now()));
+        let cache = open(&path).with_clock(Arc::clone(&clock) as _);
+
+        for i in 0..4 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+            clock.advance(Duration::from_secs(1));
+        }
+        // re-saving the oldest entry makes it the newest
+        let data = Bytes::from(vec![0u8; 100]);
+        assert!(cache.save(&key(0), "image/png".to_string(), data).await);
+
+        let entry_size = cache.report() / 4;
+        let res = cache.shrink(entry_size * 2).await.unwrap();
+        assert_eq!(res.entries_evicted, 2);
+        assert_eq!(res.bytes_evicted, entry_size * 2);
+        assert_eq!(res.size, entry_size * 2);
+        assert_eq!(cache.report_entries(), Some(2));
+        for i in &[1, 2] {
+            assert!(cache.load(&key(*i)).await.is_none());
+        }
+        for i in &[0, 3] {
+            assert!(cache.load(&key(*i)).await.is_some());
+        }
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn loaded_entries_are_evicted_last() {
+        let path = temp_path("shrink-lru");
+        let clock = Arc::new(TestClock::new(SystemTime::now()));
+        let cache = open(&path).with_clock(Arc::clone(&clock) as _);
+        for i in 0..4 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+            clock.advance(Duration::from_secs(1));
+        }
+
+        // the oldest entry is loaded long enough after it was saved for the access to be recorded
+        clock.advance(Duration::from_secs(2 * 60 * 60));
+        assert!(cache.load(&key(0)).await.is_some());
+        let now = clock.now_as_millis().to_le_bytes();
+        for _ in 0..100 {
+            if cache.save_times.get(key(0).as_bkey()).unwrap().as_deref() == Some(&now[..]) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let entry_size = cache.report() / 4;
+        let res = cache.shrink(entry_size * 2).await.unwrap();
+        assert_eq!(res.entries_evicted, 2);
+        for i in &[1, 2] {
+            assert!(cache.load(&key(*i)).await.is_none());
+        }
+        for i in &[0, 3] {
+            assert!(cache.load(&key(*i)).await.is_some());
+        }
+        // the sizes still add up after moving entries around
+        let res = cache.reconcile_size(None, 0.0).await.unwrap();
+        assert_eq!((res.entries_checked, res.tracked), (2, res.actual));
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn size_survives_reopen() {
+        let path = temp_path("reopen");
+        let cache = open(&path);
+        for i in 0..3 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+        }
+        let size = cache.report();
+        drop(cache);
+
+        let cache = open(&path);
+        assert_eq!(cache.report(), size);
+        assert_eq!(cache.report_entries(), Some(3));
+        let res = cache.shrink(0).await.unwrap();
+        assert_eq!(res.entries_evicted, 3);
+        assert_eq!(res.size, 0);
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn clear_removes_every_entry() {
+        let path = temp_path("clear");
+        let cache = open(&path);
+        for i in 0..3 {
+            let data = Bytes::from(vec![0u8; 100]);
+            assert!(cache.save(&key(i), "image/png".to_string(), data).await);
+        }
+
+        let res = cache.clear().await.unwrap();
+        assert_eq!(res.entries_evicted, 3);
+        assert_eq!(cache.report(), 0);
+        assert_eq!(cache.report_entries(), Some(0));
+        for i in 0..3 {
+            assert!(cache.load(&key(i)).await.is_none());
+        }
+        assert!(cache.by_age.is_empty() && cache.save_times.is_empty());
+
+        drop(cache);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
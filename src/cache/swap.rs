@@ -0,0 +1,157 @@
+use super::{
+    CacheInfo, CacheStats, ImageCache, ImageEntry, ImageKey, ShrinkResult, SizeReconciliation,
+};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A cache that can be replaced with another one while it's serving requests.
+///
+/// Everything is forwarded to the active cache. Requests that started before a swap finish with
+/// the old cache, which is closed once the last of them is done with it.
+pub struct SwappableCache {
+    active: ArcSwap<Box<dyn ImageCache>>,
+    /// held for the duration of a rotation, so only one can run at a time
+    rotating: Mutex<()>,
+}
+
+impl SwappableCache {
+    pub fn new(cache: Box<dyn ImageCache>) -> Self {
+        Self {
+            active: ArcSwap::from_pointee(cache),
+            rotating: Mutex::new(()),
+        }
+    }
+
+    /// Whether a rotation is currently running
+    pub fn is_rotating(&self) -> bool {
+        self.rotating.try_lock().is_err()
+    }
+
+    /// Moves the active cache to a new path (see [`ImageCache::rotate`]), swapping to the new
+    /// cache once all of the entries have been migrated. Requests keep being served from the old
+    /// cache until then.
+    ///
+    /// Returns `Err(())` if a rotation is already running, or if the active cache can't be rotated.
+    pub async fn rotate_to(&self, path: &str) -> Result<(), ()> {
+        let _rotating = self.rotating.try_lock().map_err(|_| {
+            log::warn!(
+                "cache is already being rotated, ignoring rotation to {}",
+                path
+            );
+        })?;
+
+        log::info!("rotating cache to {}...", path);
+        let timer = crate::utils::Timer::start();
+        let rotated = self.active.load_full().rotate(path).await.map_err(|_| {
+            log::error!("unable to rotate cache to {}", path);
+        })?;
+        self.active.store(Arc::new(rotated));
+        log::info!("rotated cache to {} in {:#}", path, timer);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageCache for SwappableCache {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        self.active.load_full().load(key).await
+    }
+    async fn load_many(&self, keys: &[ImageKey]) -> Vec<Option<ImageEntry>> {
+        self.active.load_full().load_many(keys).await
+    }
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        self.active.load_full().save(key, mime_type, data).await
+    }
+    fn is_ready(&self) -> bool {
+        self.active.load().is_ready()
+    }
+    fn report(&self) -> u64 {
+        self.active.load().report()
+    }
+    fn info(&self) -> CacheInfo {
+        self.active.load().info()
+    }
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        self.active.load_full().shrink(min).await
+    }
+    fn report_archive(&self, data_saver: bool) -> Option<u64> {
+        self.active.load().report_archive(data_saver)
+    }
+    fn report_pinned(&self) -> Option<u64> {
+        self.active.load().report_pinned()
+    }
+    fn report_entries(&self) -> Option<u64> {
+        self.active.load().report_entries()
+    }
+    fn stats(&self) -> CacheStats {
+        self.active.load().stats()
+    }
+    async fn shrink_archive(&self, data_saver: bool, min: u64) -> Result<ShrinkResult, ()> {
+        self.active
+            .load_full()
+            .shrink_archive(data_saver, min)
+            .await
+    }
+    async fn finish_shrink(&self, res: &ShrinkResult) {
+        self.active.load_full().finish_shrink(res).await
+    }
+    async fn sweep_idle(&self, max_idle: std::time::Duration) -> Result<ShrinkResult, ()> {
+        self.active.load_full().sweep_idle(max_idle).await
+    }
+    async fn compact(&self) {
+        self.active.load_full().compact().await
+    }
+    async fn flush(&self) {
+        self.active.load_full().flush().await
+    }
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        self.active.load_full().clear().await
+    }
+    async fn rotate(&self, path: &str) -> Result<Box<dyn ImageCache>, ()> {
+        self.active.load_full().rotate(path).await
+    }
+    async fn reconcile_size(
+        &self,
+        sample: Option<usize>,
+        max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        let cache = self.active.load_full();
+        cache.reconcile_size(sample, max_drift).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tests::TestCache;
+
+    #[tokio::test]
+    async fn entries_are_served_from_the_new_path() {
+        let cache = SwappableCache::new(Box::new(TestCache::default()));
+        let keys: Vec<_> = (0..3)
+            .map(|i| ImageKey::new("chapter".to_string(), format!("{}.png", i), false))
+            .collect();
+        for key in &keys {
+            let image = Bytes::from(key.to_string());
+            assert!(cache.save(key, "image/png".to_string(), image).await);
+        }
+        assert_eq!(cache.info().path, None);
+
+        cache.rotate_to("/mnt/new-disk/cache").await.unwrap();
+        assert_eq!(cache.info().path.as_deref(), Some("/mnt/new-disk/cache"));
+        for key in &keys {
+            let entry = cache.load(key).await.unwrap();
+            assert_eq!(entry.bytes, Bytes::from(key.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn rotations_dont_overlap() {
+        let cache = SwappableCache::new(Box::new(TestCache::default()));
+        let _rotating = cache.rotating.lock().await;
+        assert!(cache.rotate_to("/mnt/new-disk/cache").await.is_err());
+        assert_eq!(cache.info().path, None);
+    }
+}
@@ -0,0 +1,421 @@
+use super::{
+    CacheInfo, CacheStats, ImageCache, ImageEntry, ImageKey, ShrinkResult, SizeReconciliation,
+};
+use crate::config::TierConfig;
+use bytes::Bytes;
+use lru::LruCache;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// A message for the task that writes back to the slow tier
+enum WriteBack {
+    /// an image waiting to be written back
+    Save(ImageKey, String, Bytes),
+    /// a request to be told once every write back queued before it was written
+    Flush(oneshot::Sender<()>),
+}
+
+/// The most images whose loads from the slow tier are counted towards promoting them at once
+const MAX_PROMOTION_CANDIDATES: usize = 64 * 1024;
+
+/// A cache made of a fast tier in front of a slow tier.
+///
+/// Saves are written to the fast tier immediately, then queued to be written back to the slow tier
+/// by a background task, so the slow tier eventually holds every image. If the queue is full, the
+/// image is only saved to the fast tier instead of stalling the request.
+///
+/// Loads check the fast tier first and then fall back to the slow tier. Images that keep being
+/// loaded from the slow tier are promoted, i.e. copied back into the fast tier. Images are demoted
+/// by being evicted from the fast tier, which is either left to manage its own size or shrunk to a
+/// fixed size along with the slow tier. All other size reporting and shrinking only applies to the
+/// slow tier, since it's the one that holds every image.
+pub struct TieredCache<F, S> {
+    /// shared with the caches this one is rotated to, since only the slow tier is rotated
+    fast: Arc<F>,
+    slow: Arc<S>,
+    queue: mpsc::Sender<WriteBack>,
+    queue_size: usize,
+    /// the size the fast tier is shrunk to, if it doesn't manage its own size
+    fast_max_size: Option<u64>,
+
+    promote_after_loads: u32,
+    /// how often images that weren't promoted yet were loaded from the slow tier
+    candidates: Mutex<LruCache<String, u32>>,
+
+    /// total number of writes that were dropped because the queue was full
+    dropped: AtomicU64,
+    /// total number of images copied from the slow into the fast tier
+    promoted: AtomicU64,
+}
+
+impl<F: ImageCache, S: ImageCache + 'static> TieredCache<F, S> {
+    /// Creates the tiered cache, spawning the background task that writes back to the slow tier.
+    ///
+    /// Must be called from inside of the tokio runtime.
+    pub fn new(fast: F, slow: S, conf: &TierConfig) -> Self {
+        Self::with_tiers(
+            Arc::new(fast),
+            Arc::new(slow),
+            conf.queue_size,
+            conf.fast_size_mebibytes.map(|x| x * 1024 * 1024),
+            conf.promote_after_loads,
+        )
+    }
+
+    /// Creates the tiered cache around tiers that may be shared with another tiered cache,
+    /// spawning its own background writer
+    fn with_tiers(
+        fast: Arc<F>,
+        slow: Arc<S>,
+        queue_size: usize,
+        fast_max_size: Option<u64>,
+        promote_after_loads: u32,
+    ) -> Self {
+        let (queue, mut rx) = mpsc::channel::<WriteBack>(queue_size);
+
+        // task will run until the tiered cache (and therefore the sender) is dropped
+        let writer = Arc::clone(&slow);
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    WriteBack::Save(key, mime_type, data) => {
+                        if !writer.save(&key, mime_type, data).await {
+                            log::warn!("failed to write {} back to the slow tier", key);
+                        }
+                    }
+                    // the receiver might have given up waiting, which is fine
+                    WriteBack::Flush(done) => drop(done.send(())),
+                }
+            }
+        });
+
+        Self {
+            fast,
+            slow,
+            queue,
+            queue_size,
+            fast_max_size,
+            promote_after_loads,
+            candidates: Mutex::new(LruCache::new(MAX_PROMOTION_CANDIDATES)),
+            dropped: AtomicU64::new(0),
+            promoted: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits until every write back queued so far was written to the slow tier
+    async fn flush_queue(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.queue.send(WriteBack::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    /// Counts a load of `key` from the slow tier, returning whether the image should be promoted
+    fn count_slow_load(&self, key: &ImageKey) -> bool {
+        match self.promote_after_loads {
+            0 => false,
+            1 => true,
+            after => {
+                let key = key.to_string();
+                let mut candidates = self.candidates.lock().unwrap_or_else(|e| e.into_inner());
+                let loads = candidates.get(&key).copied().unwrap_or(0) + 1;
+                if loads >= after {
+                    candidates.pop(&key);
+                    true
+                } else {
+                    candidates.put(key, loads);
+                    false
+                }
+            }
+        }
+    }
+
+    /// Copies an image loaded from the slow tier into the fast tier, if it was loaded often enough
+    async fn promote(&self, key: &ImageKey, entry: &ImageEntry) {
+        if !self.count_slow_load(key) {
+            return;
+        }
+        let mime_type = entry.mime_type.clone();
+        if self.fast.save(key, mime_type, entry.get_bytes()).await {
+            self.promoted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: ImageCache + 'static, S: ImageCache + 'static> ImageCache for TieredCache<F, S> {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        if let Some(entry) = self.fast.load(key).await {
+            return Some(entry);
+        }
+        let entry = self.slow.load(key).await?;
+        self.promote(key, &entry).await;
+        Some(entry)
+    }
+
+    async fn load_many(&self, keys: &[ImageKey]) -> Vec<Option<ImageEntry>> {
+        let mut entries = self.fast.load_many(keys).await;
+
+        // only look up the images missing from the fast tier in the slow tier
+        let missing: Vec<usize> = (0..keys.len()).filter(|&i| entries[i].is_none()).collect();
+        if !missing.is_empty() {
+            let missing_keys: Vec<ImageKey> = missing.iter().map(|&i| keys[i].clone()).collect();
+            let found = self.slow.load_many(&missing_keys).await;
+            for (i, entry) in missing.into_iter().zip(found) {
+                if let Some(entry) = &entry {
+                    self.promote(&keys[i], entry).await;
+                }
+                entries[i] = entry;
+            }
+        }
+        entries
+    }
+
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        let saved = self.fast.save(key, mime_type.clone(), data.clone()).await;
+
+        // queue the write back, dropping it if the slow tier can't keep up
+        let write_back = WriteBack::Save(key.clone(), mime_type, data);
+        let queued = self.queue.try_send(write_back).is_ok();
+        if !queued {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            log::warn!(
+                "tier queue is full, dropped write back of {} (total dropped: {})",
+                key,
+                dropped
+            );
+        }
+        // an image that doesn't fit into the fast tier still ends up in the slow tier
+        saved || queued
+    }
+
+    fn is_ready(&self) -> bool {
+        self.fast.is_ready() && self.slow.is_ready()
+    }
+    fn report(&self) -> u64 {
+        self.slow.report()
+    }
+    fn info(&self) -> CacheInfo {
+        // all of the maintenance is forwarded to the slow tier, so it decides what is supported
+        let slow = self.slow.info();
+        let mut info = CacheInfo::new("tiered")
+            .with_setting("queue_size", self.queue_size)
+            .with_setting("promote_after_loads", self.promote_after_loads);
+        if let Some(max_size) = self.fast_max_size {
+            info = info.with_setting("fast_max_size_bytes", max_size);
+        }
+        info.with_features(&slow.features)
+            .with_inner(self.fast.info())
+            .with_inner(slow)
+    }
+
+    async fn shrink(&self, min: u64) -> Result<ShrinkResult, ()> {
+        if let Some(max_size) = self.fast_max_size {
+            if self.fast.shrink(max_size).await.is_err() {
+                log::error!("problem shrinking the fast tier");
+            }
+        }
+        self.slow.shrink(min).await
+    }
+    fn report_archive(&self, data_saver: bool) -> Option<u64> {
+        self.slow.report_archive(data_saver)
+    }
+    fn report_pinned(&self) -> Option<u64> {
+        self.slow.report_pinned()
+    }
+    fn report_entries(&self) -> Option<u64> {
+        self.slow.report_entries()
+    }
+    fn stats(&self) -> CacheStats {
+        self.slow
+            .stats()
+            .with_stat("fast_tier_bytes", self.fast.report())
+            .with_stat("tier_dropped_writes", self.dropped.load(Ordering::Relaxed))
+            .with_stat("tier_promotions", self.promoted.load(Ordering::Relaxed))
+    }
+    async fn shrink_archive(&self, data_saver: bool, min: u64) -> Result<ShrinkResult, ()> {
+        self.slow.shrink_archive(data_saver, min).await
+    }
+    async fn finish_shrink(&self, res: &ShrinkResult) {
+        self.slow.finish_shrink(res).await
+    }
+
+    async fn sweep_idle(&self, max_idle: std::time::Duration) -> Result<ShrinkResult, ()> {
+        self.slow.sweep_idle(max_idle).await
+    }
+    async fn compact(&self) {
+        self.fast.compact().await;
+        self.slow.compact().await
+    }
+    async fn flush(&self) {
+        self.fast.flush().await;
+        self.slow.flush().await
+    }
+
+    /// Clears both tiers, since images left in either would still be served. The queued write
+    /// backs are written first, so that they don't end up in the slow tier after it was cleared.
+    async fn clear(&self) -> Result<ShrinkResult, ()> {
+        self.flush_queue().await;
+        self.fast.clear().await?;
+        self.slow.clear().await
+    }
+
+    /// Rotates the slow tier, since it's the one that holds every image, keeping the same fast
+    /// tier in front of it. The queued write backs are written first, so the rotation copies them.
+    async fn rotate(&self, path: &str) -> Result<Box<dyn ImageCache>, ()> {
+        self.flush_queue().await;
+        let slow = self.slow.rotate(path).await?;
+        let rotated = TieredCache::with_tiers(
+            Arc::clone(&self.fast),
+            Arc::new(slow),
+            self.queue_size,
+            self.fast_max_size,
+            self.promote_after_loads,
+        );
+        rotated
+            .dropped
+            .store(self.dropped.load(Ordering::Relaxed), Ordering::Relaxed);
+        rotated
+            .promoted
+            .store(self.promoted.load(Ordering::Relaxed), Ordering::Relaxed);
+        Ok(Box::new(rotated))
+    }
+
+    /// Only reconciles the slow tier, since the fast tier's size is only used for shrinking it
+    async fn reconcile_size(
+        &self,
+        sample: Option<usize>,
+        max_drift: f64,
+    ) -> Result<SizeReconciliation, ()> {
+        self.slow.reconcile_size(sample, max_drift).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::TestCache;
+    use super::*;
+    use std::time::Duration;
+
+    fn key(image: &str) -> ImageKey {
+        ImageKey::new("chapter".to_string(), image.to_string(), false)
+    }
+
+    fn conf(queue_size: usize, promote_after_loads: u32) -> TierConfig {
+        TierConfig {
+            engine: "test".to_string(),
+            fast_size_mebibytes: None,
+            queue_size,
+            promote_after_loads,
+        }
+    }
+
+    #[tokio::test]
+    async fn saves_are_written_back() {
+        let cache = TieredCache::new(TestCache::default(), TestCache::default(), &conf(16, 1));
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), data)
+                .await
+        );
+
+        // fast tier is written immediately
+        assert!(cache.fast.load(&key("1.png")).await.is_some());
+
+        // slow tier is written eventually
+        for _ in 0..100 {
+            if cache.slow.load(&key("1.png")).await.is_some() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("write was never written back to the slow tier");
+    }
+
+    #[tokio::test]
+    async fn images_are_promoted_after_enough_loads() {
+        let cache = TieredCache::new(TestCache::default(), TestCache::default(), &conf(16, 2));
+        let data = Bytes::from_static(b"image");
+        for image in &["1.png", "2.png"] {
+            assert!(
+                cache
+                    .slow
+                    .save(&key(image), "image/png".to_string(), data.clone())
+                    .await
+            );
+        }
+
+        // the first load is served from the slow tier without promoting the image
+        assert!(cache.load(&key("1.png")).await.is_some());
+        assert!(cache.fast.load(&key("1.png")).await.is_none());
+        let keys = vec![key("1.png"), key("2.png"), key("3.png")];
+        let entries = cache.load_many(&keys).await;
+        assert_eq!(
+            entries.iter().map(Option::is_some).collect::<Vec<_>>(),
+            vec![true, true, false]
+        );
+        assert!(cache.fast.load(&key("1.png")).await.is_some());
+        assert!(cache.fast.load(&key("2.png")).await.is_none());
+        assert_eq!(cache.promoted.load(Ordering::Relaxed), 1);
+
+        // promoted images aren't written back again, since the slow tier already has them
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.slow.saves.load(Ordering::SeqCst), 2);
+
+        let never = TieredCache::new(TestCache::default(), TestCache::default(), &conf(16, 0));
+        assert!(
+            never
+                .slow
+                .save(&key("1.png"), "image/png".to_string(), data)
+                .await
+        );
+        for _ in 0..3 {
+            assert!(never.load(&key("1.png")).await.is_some());
+        }
+        assert!(never.fast.load(&key("1.png")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn queued_write_backs_are_not_restored_by_clear() {
+        // the writer task can't run until we yield, so the write back is still queued
+        let cache = TieredCache::new(TestCache::default(), TestCache::default(), &conf(16, 1));
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), data)
+                .await
+        );
+        cache.clear().await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cache.load(&key("1.png")).await.is_none());
+        assert_eq!(cache.slow.report_entries(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn rotation_moves_the_slow_tier() {
+        let cache = TieredCache::new(TestCache::default(), TestCache::default(), &conf(16, 1));
+        let data = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), data.clone())
+                .await
+        );
+
+        // the queued write back is part of the rotated slow tier
+        let rotated = cache.rotate("rotated").await.unwrap();
+        let info = rotated.info();
+        assert_eq!(info.inner[1].path.as_deref(), Some("rotated"));
+        assert_eq!(rotated.report_entries(), Some(1));
+
+        // the fast tier is shared, so what's saved to it is served by both
+        assert!(
+            rotated
+                .save(&key("2.png"), "image/png".to_string(), data)
+                .await
+        );
+        assert!(cache.fast.load(&key("2.png")).await.is_some());
+    }
+}
@@ -0,0 +1,166 @@
+//! Snapshots of the most requested images, used to warm the cache after a restart.
+//!
+//! While enabled, requests for each image are counted in memory by [`HotKeys`]. On shutdown the
+//! most requested images are written to a snapshot file, which is read on startup so that those
+//! images are loaded from the cache (and into its in-memory caches) before anything else.
+
+use super::{ImageCache, ImageKey};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Approximate count of how many times each image has been requested, only keeping the most
+/// requested images to keep memory use bounded
+pub struct HotKeys {
+    counts: Mutex<HashMap<String, u64>>,
+    keep: usize,
+}
+
+impl HotKeys {
+    /// Multiplier to `keep` for how many images are counted before the least requested ones are
+    /// dropped. Gives newly requested images some time to catch up with the established ones.
+    const SLACK: usize = 4;
+    /// Number of images warmed at once on startup
+    const WARM_BATCH: usize = 64;
+
+    /// Creates an empty counter that snapshots the `keep` most requested images
+    pub fn new(keep: usize) -> Self {
+        Self {
+            counts: Mutex::new(HashMap::new()),
+            keep,
+        }
+    }
+
+    /// Counts a request for the image
+    pub fn record(&self, key: &ImageKey) {
+        // the counts are always valid, even if another request panicked while holding the lock
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        *counts.entry(key.to_string()).or_insert(0) += 1;
+
+        if counts.len() > self.keep * Self::SLACK {
+            let keep: HashMap<_, _> = Self::most_requested(&counts, self.keep)
+                .into_iter()
+                .map(|x| {
+                    let count = counts[&x];
+                    (x, count)
+                })
+                .collect();
+            *counts = keep;
+        }
+    }
+
+    /// The `keep` most requested images, most requested first
+    pub fn top(&self) -> Vec<String> {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        Self::most_requested(&counts, self.keep)
+    }
+
+    fn most_requested(counts: &HashMap<String, u64>, n: usize) -> Vec<String> {
+        let mut sorted: Vec<_> = counts.iter().collect();
+        // ties are broken by the path so snapshots are deterministic
+        sorted.sort_unstable_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        sorted.into_iter().take(n).map(|(x, _)| x.clone()).collect()
+    }
+
+    /// Writes the most requested images to the snapshot file at `path`, one per line.
+    ///
+    /// The snapshot is written to a temporary file first and then moved over the old snapshot, so
+    /// a crash while writing never leaves a partial snapshot behind.
+    pub async fn write_snapshot(&self, path: &Path) -> io::Result<usize> {
+        let keys = self.top();
+        let mut contents = keys.join("\n");
+        contents.push('\n');
+
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(keys.len())
+    }
+}
+
+/// Reads the images in the snapshot file at `path`, skipping any lines that aren't valid images
+pub async fn read_snapshot(path: &Path) -> io::Result<Vec<ImageKey>> {
+    let contents = tokio::fs::read_to_string(path).await?;
+    Ok(contents
+        .lines()
+        .filter_map(|x| match x.parse() {
+            Ok(key) => Some(key),
+            Err(e) => {
+                log::warn!("skipping line in warm snapshot: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Loads each of the images from the cache in batches, returning how many of them were found
+pub async fn warm_cache(cache: &dyn ImageCache, keys: &[ImageKey]) -> usize {
+    let mut found = 0;
+    for batch in keys.chunks(HotKeys::WARM_BATCH) {
+        found += cache
+            .load_many(batch)
+            .await
+            .iter()
+            .filter(|x| x.is_some())
+            .count();
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tests::TestCache;
+    use bytes::Bytes;
+
+    fn key(image: &str) -> ImageKey {
+        ImageKey::new("chapter".to_string(), image.to_string(), false)
+    }
+
+    #[test]
+    fn least_requested_are_dropped() {
+        let hot = HotKeys::new(2);
+        for (image, count) in &[("1.png", 5), ("2.png", 1), ("3.png", 3)] {
+            for _ in 0..*count {
+                hot.record(&key(image));
+            }
+        }
+        assert_eq!(hot.top(), ["/data/chapter/1.png", "/data/chapter/3.png"]);
+
+        // going over the slack only keeps the most requested images around
+        for i in 0..HotKeys::SLACK * 2 {
+            hot.record(&key(&format!("new-{}.png", i)));
+        }
+        assert!(hot.counts.lock().unwrap().len() <= 2 * HotKeys::SLACK);
+        assert_eq!(hot.top(), ["/data/chapter/1.png", "/data/chapter/3.png"]);
+    }
+
+    #[tokio::test]
+    async fn snapshot_round_trips() {
+        let path = std::env::temp_dir().join(format!("scalpel-warm-{}", std::process::id()));
+        let hot = HotKeys::new(2);
+        let cache = TestCache::default();
+        for (image, count) in &[("1.png", 3), ("2.png", 1), ("3.png", 2)] {
+            for _ in 0..*count {
+                hot.record(&key(image));
+            }
+        }
+        let saved = Bytes::from_static(b"image");
+        assert!(
+            cache
+                .save(&key("1.png"), "image/png".to_string(), saved)
+                .await
+        );
+
+        assert_eq!(hot.write_snapshot(&path).await.unwrap(), 2);
+        let keys = read_snapshot(&path).await.unwrap();
+        let paths: Vec<_> = keys.iter().map(ImageKey::to_string).collect();
+        assert_eq!(paths, ["/data/chapter/1.png", "/data/chapter/3.png"]);
+        assert!(!path.with_extension("tmp").exists());
+
+        // only the first image is actually in the cache
+        assert_eq!(warm_cache(&cache, &keys).await, 1);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,54 +1,320 @@
 use crate::utils::Secret;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 
 /// Global application configuration
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct AppConfig {
     // basic client configuration
     pub client_secret: Secret<String>,
     pub max_grace_period: i32,
     #[serde(default)]
+    pub lame_duck_seconds: u64,
+    #[serde(default)]
+    pub token_policy: TokenPolicy,
+    /// deprecated, replaced by `token_policy: disabled` (see [`AppConfig::apply_deprecated`])
+    #[serde(default, skip_serializing)]
     pub skip_tokens: bool,
     #[serde(default)]
     pub disable_ssl: bool,
+    pub admin_token: Option<Secret<String>>,
 
     // cache configuration
     pub cache_size_mebibytes: u32,
+    #[serde(default = "opt_shrink_interval_seconds")]
+    pub shrink_interval_seconds: u64,
+    #[serde(default = "opt_shrink_step_mebibytes")]
+    pub shrink_step_mebibytes: u64,
     pub cache_engine: String,
     #[serde(rename = "rocksdb_options")]
     pub rocks_opt: Option<RocksConfig>,
     #[serde(rename = "fs_options")]
     pub fs_opt: Option<FsConfig>,
+    #[serde(rename = "sled_options")]
+    pub sled_opt: Option<SledConfig>,
+    #[serde(rename = "redis_options")]
+    pub redis_opt: Option<RedisConfig>,
+    #[serde(rename = "s3_options")]
+    pub s3_opt: Option<S3Config>,
+    #[serde(rename = "memory_options")]
+    pub mem_opt: Option<MemoryConfig>,
+    pub compaction_window: Option<CompactionWindow>,
+    pub archive_budgets: Option<ArchiveBudgets>,
+    pub idle_ttl_hours: Option<u64>,
+    pub maintenance_busy_requests: Option<usize>,
+    pub size_reconciliation: Option<SizeReconciliationConfig>,
+    #[serde(default)]
+    pub pinned_chapters: HashSet<String>,
+    pub tiers: Option<TierConfig>,
+    pub mirror_engine: Option<String>,
+    #[serde(default = "opt_mirror_queue_size")]
+    pub mirror_queue_size: usize,
+    pub hot_cache_mebibytes: Option<u64>,
+    pub warm_snapshot: Option<WarmSnapshotConfig>,
+    #[serde(default)]
+    pub serve_during_cache_init: bool,
+    #[serde(default)]
+    pub pass_through: bool,
 
     // webserver settings
     pub port: u16,
     pub bind_address: String,
     pub worker_threads: Option<usize>,
+    #[serde(default = "opt_worker_threads_multiplier")]
+    pub worker_threads_multiplier: f64,
+    pub max_worker_threads: Option<usize>,
+    #[serde(alias = "max_connection_rate")]
+    pub max_concurrent_handshakes: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub max_request_size_kibibytes: Option<usize>,
     pub keep_alive: usize,
+    pub max_requests_per_connection: Option<usize>,
+    #[serde(default = "opt_rotation_drain_seconds")]
+    pub rotation_drain_seconds: u64,
     #[serde(default)]
     pub disable_ad_headers: bool,
+    #[serde(default)]
+    pub digest_header: bool,
+    #[serde(default)]
+    pub server_timing_header: bool,
+    #[serde(default)]
+    pub token_expiry_header: bool,
+    #[serde(default, deserialize_with = "lowercase_keys")]
+    pub content_type_overrides: HashMap<String, String>,
+    #[serde(default)]
+    pub fallback_content_types: FallbackContentTypes,
+    #[serde(default = "opt_max_content_type_length")]
+    pub max_content_type_length: usize,
+    #[serde(default = "opt_upstream_retry_after")]
+    pub upstream_retry_after: u32,
+    #[serde(default = "opt_initializing_retry_after")]
+    pub initializing_retry_after: u32,
+    #[serde(default = "opt_shutting_down_retry_after")]
+    pub shutting_down_retry_after: u32,
+    #[serde(default = "opt_not_found_max_age")]
+    pub not_found_max_age: u32,
+    #[serde(default)]
+    pub upstream_redirect_hosts: Vec<String>,
+    #[serde(default = "opt_upstream_max_redirects")]
+    pub upstream_max_redirects: usize,
+    #[serde(default)]
+    pub concurrent_misses: ConcurrentMissPolicy,
+    #[serde(default = "opt_stream_chunk_kibibytes")]
+    pub stream_chunk_kibibytes: usize,
+    #[serde(default = "opt_small_hit_kibibytes")]
+    pub small_hit_kibibytes: u64,
+    #[serde(default = "opt_allowed_extensions")]
+    pub allowed_extensions: Vec<String>,
+    pub max_egress_kibibytes: Option<u64>,
+    pub ip_byte_quota: Option<ByteQuotaConfig>,
+    pub ip_allowlist: Option<Vec<ipnet::IpNet>>,
+    #[serde(default)]
+    pub unknown_peer_policy: UnknownPeerPolicy,
+    #[serde(default = "opt_metrics_enabled")]
+    pub metrics_enabled: bool,
+    #[serde(default = "opt_health_endpoint")]
+    pub health_endpoint: bool,
+    #[serde(default)]
+    pub metrics_sink: MetricsSinkKind,
+    #[serde(default)]
+    pub error_body_format: ErrorBodyFormat,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub allow_origin: AllowOrigin,
+    #[serde(default = "opt_timing_allow_origin")]
+    pub timing_allow_origin: String,
 
     // ssl/tls settings
     #[serde(default = "opt_reject_invalid_sni")]
     pub reject_invalid_sni: bool,
     pub enforce_secure_tls: bool,
+    #[serde(default = "opt_alpn_protocols")]
+    pub alpn_protocols: Vec<String>,
 
     // info sent to external api
     pub external_ip: Option<String>,
     pub external_port: Option<u16>,
     pub external_max_speed: Option<u32>,
 }
+fn opt_shrink_interval_seconds() -> u64 {
+    300
+}
+fn opt_shrink_step_mebibytes() -> u64 {
+    256
+}
+fn opt_mirror_queue_size() -> usize {
+    256
+}
+fn opt_worker_threads_multiplier() -> f64 {
+    1.0
+}
+fn opt_max_content_type_length() -> usize {
+    128
+}
+fn opt_rotation_drain_seconds() -> u64 {
+    3
+}
+fn opt_upstream_retry_after() -> u32 {
+    5
+}
+fn opt_initializing_retry_after() -> u32 {
+    10
+}
+fn opt_shutting_down_retry_after() -> u32 {
+    60
+}
+fn opt_not_found_max_age() -> u32 {
+    300
+}
+fn opt_upstream_max_redirects() -> usize {
+    3
+}
+fn opt_stream_chunk_kibibytes() -> usize {
+    64
+}
+fn opt_small_hit_kibibytes() -> u64 {
+    128
+}
+fn opt_allowed_extensions() -> Vec<String> {
+    ["png", "jpg", "jpeg", "gif", "webp"]
+        .iter()
+        .map(|&x| x.to_string())
+        .collect()
+}
+fn opt_metrics_enabled() -> bool {
+    true
+}
+fn opt_timing_allow_origin() -> String {
+    "*".to_string()
+}
+fn opt_health_endpoint() -> bool {
+    true
+}
 fn opt_reject_invalid_sni() -> bool {
     true
 }
+fn opt_alpn_protocols() -> Vec<String> {
+    SUPPORTED_ALPN.iter().map(|&x| x.to_string()).collect()
+}
+
+/// Content types are case-insensitive, and they're looked up lowercased
+fn lowercase_keys<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let map = HashMap::<String, String>::deserialize(deserializer)?;
+    Ok(map
+        .into_iter()
+        .map(|(k, v)| (k.to_ascii_lowercase(), v))
+        .collect())
+}
+
+/// How the tokens in image urls are verified
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenPolicy {
+    /// Every request must carry a valid token
+    #[default]
+    Required,
+    /// Tokens are verified when present, but requests without one are served too
+    Optional,
+    /// Tokens aren't verified at all
+    Disabled,
+}
+
+/// What a MISS does when the same image is already being fetched from upstream for another request.
+/// Either way, images are only saved to the cache once they've been received completely.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrentMissPolicy {
+    /// Joins the fetch that's in progress, getting the same bytes as the request that started it
+    #[default]
+    Join,
+    /// Makes its own request to upstream
+    Fetch,
+}
+
+/// What to do with requests when the address of the peer can't be determined (i.e. when the
+/// connection isn't over TCP). Only applies while a policy that depends on the address (like
+/// `ip_allowlist`) is active.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownPeerPolicy {
+    /// Serve the request as if it passed the policy
+    Allow,
+    /// Refuse the request
+    #[default]
+    Reject,
+}
+
+/// Where the metrics of image requests (HITs, MISSes, upstream latency and cache size) are sent
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsSinkKind {
+    /// Exposed on the prometheus metrics route, only with the `metrics` feature
+    #[cfg_attr(feature = "metrics", default)]
+    Prometheus,
+    /// Not recorded at all
+    #[cfg_attr(not(feature = "metrics"), default)]
+    None,
+}
+
+/// Format of the body of error responses
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorBodyFormat {
+    /// The error message as plain text
+    #[default]
+    Plain,
+    /// A JSON object with the status, error message and request ID
+    Json,
+}
+
+/// How responses are compressed for clients that send `Accept-Encoding`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Responses are never compressed
+    #[default]
+    None,
+    /// gzip, if the client accepts it
+    Gzip,
+    /// Brotli, if the client accepts it
+    Br,
+    /// Whichever encoding the client prefers
+    Auto,
+}
+
+/// The origins that are allowed to read responses (with the `Access-Control-Allow-Origin` header)
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum AllowOrigin {
+    /// A single origin (or `*` for any origin), which is sent with every response
+    Single(String),
+    /// Several origins, of which the one that made the request (according to its `Origin` header)
+    /// is sent back, since the header only fits a single origin
+    List(Vec<String>),
+}
+
+impl Default for AllowOrigin {
+    fn default() -> Self {
+        Self::Single("*".to_string())
+    }
+}
+
+/// ALPN protocols that the HTTP server is able to speak, in the default order of preference
+pub const SUPPORTED_ALPN: [&str; 2] = ["h2", "http/1.1"];
 
 /// Configuration for RocksDB cache engine
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RocksConfig {
     pub path: String,
+    #[serde(default)]
+    pub in_memory: bool,
 
     // block options
     #[serde(default)]
@@ -59,10 +325,181 @@ pub struct RocksConfig {
     pub parallelism: Option<i32>,
     pub write_buffer_size: Option<usize>,
     pub write_rate_limit: Option<usize>,
+
+    // compaction options
+    pub max_background_jobs: Option<i32>,
+    pub max_subcompactions: Option<u32>,
+    pub compaction_readahead_size: Option<usize>,
+    #[serde(default)]
+    pub compact_after_shrink_deletes: u64,
+    /// entries saved longer ago than this are treated as a MISS and deleted (0 never expires them)
+    #[serde(default)]
+    pub max_entry_age_hours: u64,
+    /// number of column families the images are spread across, by the first byte of their key
+    #[serde(default = "opt_rocks_shards")]
+    pub shards: usize,
+
+    // compression options
+    pub zstd: Option<ZstdConfig>,
+}
+
+/// Configuration for compressing the RocksDB cache with zstd
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ZstdConfig {
+    #[serde(default = "opt_zstd_level")]
+    pub level: i32,
+    /// log2 of the size of the window that matches are searched for in
+    pub window_log: Option<i32>,
+    /// largest dictionary trained for each file, which turns on dictionary compression
+    pub dict_kibibytes: Option<u32>,
+    /// how much of each file is sampled to train its dictionary (100x the dictionary if unset)
+    pub dict_train_kibibytes: Option<u32>,
+}
+fn opt_rocks_shards() -> usize {
+    1
+}
+fn opt_zstd_level() -> i32 {
+    3
+}
+
+impl ZstdConfig {
+    /// The largest window that zstd will decompress without extra decoder options (128MiB)
+    pub const MAX_WINDOW_LOG: i32 = 27;
+    /// The smallest window zstd supports (1KiB)
+    pub const MIN_WINDOW_LOG: i32 = 10;
+    /// The largest dictionary that can be trained (1MiB), which keeps the samples small enough
+    pub const MAX_DICT_KIBIBYTES: u32 = 1024;
+    /// The most data that can be sampled to train a dictionary (1GiB)
+    pub const MAX_DICT_TRAIN_KIBIBYTES: u32 = 1024 * 1024;
+}
+
+/// Daily window (in local hours) during which a full compaction of the cache is run
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CompactionWindow {
+    pub start_hour: u32,
+    pub end_hour: u32,
+    pub max_requests_per_second: Option<f64>,
+}
+
+impl CompactionWindow {
+    /// Whether the hour of the day falls inside of the window. Windows where `end_hour` is before
+    /// `start_hour` wrap around midnight.
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+/// A slower cache engine behind the main one, which every image is written back to
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TierConfig {
+    pub engine: String,
+    /// the fast tier is shrunk to this size, instead of being left to manage its own size
+    pub fast_size_mebibytes: Option<u64>,
+    #[serde(default = "opt_tier_queue_size")]
+    pub queue_size: usize,
+    /// images are copied back into the fast tier once they're loaded this often from the slow
+    /// tier, or never if 0
+    #[serde(default = "opt_tier_promote_after_loads")]
+    pub promote_after_loads: u32,
+}
+fn opt_tier_queue_size() -> usize {
+    256
+}
+fn opt_tier_promote_after_loads() -> u32 {
+    1
+}
+
+/// Where the most requested images are written to on shutdown, to warm the cache with on startup
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WarmSnapshotConfig {
+    pub path: PathBuf,
+    #[serde(default = "opt_warm_snapshot_keys")]
+    pub keys: usize,
+}
+fn opt_warm_snapshot_keys() -> usize {
+    1000
+}
+
+/// How the size tracked by the cache is checked against the data it actually stores on startup
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SizeReconciliationConfig {
+    /// only checks this many entries instead of all of them
+    pub sample_entries: Option<usize>,
+    #[serde(default = "opt_max_drift_percent")]
+    pub max_drift_percent: f64,
+}
+fn opt_max_drift_percent() -> f64 {
+    1.0
+}
+
+/// Separate maximum sizes (in mebibytes) for each archive type stored in the cache
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ArchiveBudgets {
+    pub data: Option<u32>,
+    pub data_saver: Option<u32>,
+}
+
+impl ArchiveBudgets {
+    /// The budget of the `data` or `data-saver` archive in mebibytes, if it has one
+    pub fn for_archive(&self, data_saver: bool) -> Option<u32> {
+        if data_saver {
+            self.data_saver
+        } else {
+            self.data
+        }
+    }
+}
+
+/// Content types that are assumed for each archive type when an image's type is missing or invalid
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FallbackContentTypes {
+    #[serde(default = "opt_fallback_data")]
+    pub data: String,
+    #[serde(default = "opt_fallback_data_saver")]
+    pub data_saver: String,
+}
+fn opt_fallback_data() -> String {
+    mime::IMAGE_PNG.to_string()
+}
+fn opt_fallback_data_saver() -> String {
+    mime::IMAGE_JPEG.to_string()
+}
+
+impl Default for FallbackContentTypes {
+    fn default() -> Self {
+        Self {
+            data: opt_fallback_data(),
+            data_saver: opt_fallback_data_saver(),
+        }
+    }
+}
+
+impl FallbackContentTypes {
+    /// The fallback content type of the `data` or `data-saver` archive
+    pub fn for_archive(&self, data_saver: bool) -> mime::Mime {
+        let mime = if data_saver {
+            &self.data_saver
+        } else {
+            &self.data
+        };
+        // validated when the config is loaded
+        mime.parse().unwrap_or(mime::IMAGE_PNG)
+    }
+}
+
+/// The maximum number of bytes that are served to a single IP address within any window of time
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ByteQuotaConfig {
+    pub kibibytes: u64,
+    pub window_seconds: u64,
 }
 
 /// Configuration for FileSystem cache engine
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct FsConfig {
     pub path: String,
     #[serde(default = "fsce_rw_buf_sz")]
@@ -77,6 +514,68 @@ fn fsce_lru_sz() -> usize {
     128
 }
 
+/// Configuration for the in-memory cache engine
+#[derive(Deserialize, Serialize, Debug)]
+pub struct MemoryConfig {
+    pub max_size_mebibytes: u64,
+}
+
+/// Configuration for the sled cache engine
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SledConfig {
+    pub path: String,
+    #[serde(default = "sledce_cache_capacity")]
+    pub cache_capacity_mebibytes: u64,
+}
+fn sledce_cache_capacity() -> u64 {
+    1024
+}
+
+/// Configuration for the Redis cache engine
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RedisConfig {
+    pub url: Secret<String>,
+    #[serde(default = "redisce_ttl_hours")]
+    pub ttl_hours: u64,
+    #[serde(default = "redisce_pool_size")]
+    pub pool_size: usize,
+    #[serde(default = "redisce_key_prefix")]
+    pub key_prefix: String,
+}
+fn redisce_ttl_hours() -> u64 {
+    720
+}
+fn redisce_pool_size() -> usize {
+    4
+}
+fn redisce_key_prefix() -> String {
+    "scalpel:".to_string()
+}
+
+/// Configuration for the S3 cache engine
+#[derive(Deserialize, Serialize, Debug)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "s3ce_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: Secret<String>,
+    #[serde(default = "s3ce_key_prefix")]
+    pub key_prefix: String,
+    #[serde(default = "s3ce_max_retries")]
+    pub max_retries: u32,
+}
+fn s3ce_region() -> String {
+    "us-east-1".to_string()
+}
+fn s3ce_key_prefix() -> String {
+    "images/".to_string()
+}
+fn s3ce_max_retries() -> u32 {
+    3
+}
+
 /// Various different errors that could happen when opening or parsing a configuration file.
 #[derive(Debug)]
 enum ConfigError {
@@ -101,6 +600,236 @@ impl std::fmt::Display for ConfigError {
 }
 
 impl AppConfig {
+    /// Maps the deprecated options onto the options that replaced them, warning about each one
+    /// that's still used
+    pub fn apply_deprecated(&mut self) {
+        if self.skip_tokens {
+            log::warn!("skip_tokens is deprecated, use \"token_policy: disabled\" instead");
+            self.token_policy = TokenPolicy::Disabled;
+        }
+    }
+
+    /// Validates the values of the configuration that can't be expressed through deserialization
+    /// alone, returning a description of the first problem found.
+    pub fn validate(&self) -> Result<(), String> {
+        // the ALPN list must be non-empty and only contain protocols we can actually serve
+        if self.alpn_protocols.is_empty() {
+            return Err("alpn_protocols must contain at least one protocol".to_string());
+        }
+        for (i, proto) in self.alpn_protocols.iter().enumerate() {
+            if !SUPPORTED_ALPN.contains(&proto.as_str()) {
+                return Err(format!(
+                    "unsupported ALPN protocol \"{}\", must be one of {:?}",
+                    proto, SUPPORTED_ALPN
+                ));
+            }
+            if self.alpn_protocols[..i].contains(proto) {
+                return Err(format!("duplicate ALPN protocol \"{}\"", proto));
+            }
+        }
+
+        if self.shrink_step_mebibytes == 0 {
+            return Err("shrink_step_mebibytes must be greater than 0".to_string());
+        }
+        if self.mirror_engine.as_ref() == Some(&self.cache_engine) {
+            return Err("mirror_engine must be different from cache_engine".to_string());
+        }
+        if let Some(tiers) = &self.tiers {
+            if tiers.engine == self.cache_engine
+                || Some(&tiers.engine) == self.mirror_engine.as_ref()
+            {
+                return Err(
+                    "tiers engine must be different from cache_engine and mirror_engine"
+                        .to_string(),
+                );
+            }
+            if tiers.fast_size_mebibytes == Some(0) {
+                return Err("tiers fast_size_mebibytes must be greater than 0".to_string());
+            }
+            if tiers.queue_size == 0 {
+                return Err("tiers queue_size must be greater than 0".to_string());
+            }
+        }
+        if self.hot_cache_mebibytes == Some(0) {
+            return Err("hot_cache_mebibytes must be greater than 0".to_string());
+        }
+        if self.mirror_queue_size == 0 {
+            return Err("mirror_queue_size must be greater than 0".to_string());
+        }
+        if matches!(&self.warm_snapshot, Some(x) if x.keys == 0) {
+            return Err("warm_snapshot keys must be greater than 0".to_string());
+        }
+
+        if let Some(window) = &self.compaction_window {
+            if window.start_hour > 23 || window.end_hour > 23 {
+                return Err("compaction_window hours must be between 0 and 23".to_string());
+            }
+            if window.start_hour == window.end_hour {
+                return Err("compaction_window must not be empty".to_string());
+            }
+        }
+
+        if self.worker_threads_multiplier <= 0.0 {
+            return Err("worker_threads_multiplier must be greater than 0".to_string());
+        }
+        if self.worker_threads == Some(0) || self.max_worker_threads == Some(0) {
+            return Err("worker thread counts must be greater than 0".to_string());
+        }
+        if self.max_concurrent_handshakes == Some(0) {
+            return Err("max_concurrent_handshakes must be greater than 0".to_string());
+        }
+
+        for (from, to) in &self.content_type_overrides {
+            if from.parse::<mime::Mime>().is_err() || to.parse::<mime::Mime>().is_err() {
+                return Err(format!(
+                    "invalid content type override \"{}\" -> \"{}\"",
+                    from, to
+                ));
+            }
+        }
+
+        let fallbacks = &self.fallback_content_types;
+        for mime in &[&fallbacks.data, &fallbacks.data_saver] {
+            if mime.parse::<mime::Mime>().is_err() {
+                return Err(format!("invalid fallback content type \"{}\"", mime));
+            }
+        }
+
+        if self.stream_chunk_kibibytes == 0 {
+            return Err("stream_chunk_kibibytes must be greater than 0".to_string());
+        }
+        if self.max_connections == Some(0) {
+            return Err("max_connections must be greater than 0".to_string());
+        }
+        if self.max_request_size_kibibytes == Some(0) {
+            return Err("max_request_size_kibibytes must be greater than 0".to_string());
+        }
+        if self.max_requests_per_connection == Some(0) {
+            return Err("max_requests_per_connection must be greater than 0".to_string());
+        }
+        if self.max_egress_kibibytes == Some(0) {
+            return Err("max_egress_kibibytes must be greater than 0".to_string());
+        }
+        if matches!(&self.ip_byte_quota, Some(x) if x.kibibytes == 0 || x.window_seconds == 0) {
+            return Err("ip_byte_quota values must be greater than 0".to_string());
+        }
+
+        // origins are sent as header values, which can only be printable ASCII
+        let origins = match &self.allow_origin {
+            AllowOrigin::Single(x) => std::slice::from_ref(x),
+            AllowOrigin::List(x) => x.as_slice(),
+        };
+        let is_header_safe = |x: &String| !x.is_empty() && x.bytes().all(|b| b.is_ascii_graphic());
+        if !origins.iter().all(is_header_safe) || !is_header_safe(&self.timing_allow_origin) {
+            return Err("allow_origin and timing_allow_origin must be valid origins".to_string());
+        }
+        if matches!(&self.rocks_opt, Some(x) if !(1..=256).contains(&x.shards)) {
+            return Err("rocksdb shards must be between 1 and 256".to_string());
+        }
+        let zstd = self.rocks_opt.as_ref().and_then(|x| x.zstd.as_ref());
+        if let Some(window_log) = zstd.and_then(|x| x.window_log) {
+            if !(ZstdConfig::MIN_WINDOW_LOG..=ZstdConfig::MAX_WINDOW_LOG).contains(&window_log) {
+                return Err(format!(
+                    "zstd window_log must be between {} and {}",
+                    ZstdConfig::MIN_WINDOW_LOG,
+                    ZstdConfig::MAX_WINDOW_LOG
+                ));
+            }
+        }
+        if let Some(dict) = zstd.and_then(|x| x.dict_kibibytes) {
+            if !(1..=ZstdConfig::MAX_DICT_KIBIBYTES).contains(&dict) {
+                return Err(format!(
+                    "zstd dict_kibibytes must be between 1 and {}",
+                    ZstdConfig::MAX_DICT_KIBIBYTES
+                ));
+            }
+        }
+        if let Some(train) = zstd.and_then(|x| x.dict_train_kibibytes) {
+            let dict = zstd.and_then(|x| x.dict_kibibytes).unwrap_or(1);
+            if !(dict..=ZstdConfig::MAX_DICT_TRAIN_KIBIBYTES).contains(&train) {
+                return Err(format!(
+                    "zstd dict_train_kibibytes must be between dict_kibibytes and {}",
+                    ZstdConfig::MAX_DICT_TRAIN_KIBIBYTES
+                ));
+            }
+        }
+
+        if matches!(&self.redis_opt, Some(x) if x.pool_size == 0) {
+            return Err("redis pool_size must be greater than 0".to_string());
+        }
+        if let Some(s3) = &self.s3_opt {
+            if url::Url::parse(&s3.endpoint).is_err() {
+                return Err(format!("invalid s3 endpoint \"{}\"", s3.endpoint));
+            }
+            // the prefix is used in request paths as-is, so it can't contain anything that would
+            // have to be escaped
+            let valid = |c: char| c.is_ascii_alphanumeric() || "-_.~/".contains(c);
+            if !s3.key_prefix.chars().all(valid) {
+                return Err("s3 key_prefix may only contain letters, digits and -_.~/".to_string());
+            }
+        }
+
+        if self.mem_opt.as_ref().map(|x| x.max_size_mebibytes) == Some(0) {
+            return Err("memory_options.max_size_mebibytes must be greater than 0".to_string());
+        }
+        if let Some(x) = &self.size_reconciliation {
+            if x.sample_entries == Some(0) {
+                return Err("size_reconciliation.sample_entries must be greater than 0".to_string());
+            }
+            if x.max_drift_percent.is_nan() || x.max_drift_percent < 0.0 {
+                return Err("size_reconciliation.max_drift_percent must be at least 0".to_string());
+            }
+        }
+        if self.shrink_interval_seconds == 0 {
+            return Err("shrink_interval_seconds must be greater than 0".to_string());
+        }
+        if self.idle_ttl_hours == Some(0) {
+            return Err("idle_ttl_hours must be greater than 0".to_string());
+        }
+
+        if let Some(budgets) = &self.archive_budgets {
+            let too_large = |x| matches!(x, Some(x) if x > self.cache_size_mebibytes);
+            if too_large(budgets.data) || too_large(budgets.data_saver) {
+                return Err("archive_budgets must not exceed cache_size_mebibytes".to_string());
+            }
+        }
+
+        if cfg!(not(feature = "metrics")) && self.metrics_sink == MetricsSinkKind::Prometheus {
+            return Err("metrics_sink: prometheus needs the metrics feature".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// The maximum size in bytes of each chunk that response bodies are streamed in
+    pub fn stream_chunk_size(&self) -> usize {
+        self.stream_chunk_kibibytes * 1024
+    }
+
+    /// The maximum size in bytes of cached images that are sent as a single body instead of being
+    /// streamed in chunks
+    pub fn small_hit_size(&self) -> u64 {
+        self.small_hit_kibibytes * 1024
+    }
+
+    /// Serializes the configuration into JSON, replacing the values of all secrets
+    pub fn to_redacted_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).expect("config serialization");
+        // the redis url may contain a password too
+        const SECRETS: &[&str] = &[
+            "/client_secret",
+            "/admin_token",
+            "/redis_options/url",
+            "/s3_options/secret_access_key",
+        ];
+        for pointer in SECRETS {
+            if let Some(x) = value.pointer_mut(pointer).filter(|x| !x.is_null()) {
+                *x = "<redacted>".into();
+            }
+        }
+        value
+    }
+
     /// Opens a file and parses into a [AppConfig](Self). Returns Some if it is successful and None
     /// if not. No success might be for various reasons (which are stderr logged).
     ///
@@ -173,3 +902,128 @@ pub async fn init() -> Option<AppConfig> {
     }
     conf
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A configuration containing only the required fields
+    const MINIMAL_YAML: &str = "
+client_secret: secret
+max_grace_period: 0
+cache_size_mebibytes: 40960
+cache_engine: none
+port: 443
+bind_address: 127.0.0.1
+keep_alive: 30
+enforce_secure_tls: false
+";
+
+    /// Parses the minimal configuration with the extra yaml fields appended to it
+    pub(crate) fn config_with(extra: &str) -> AppConfig {
+        serde_yaml::from_str(&format!("{}{}", MINIMAL_YAML, extra)).expect("test config")
+    }
+
+    #[test]
+    fn default_alpn_is_valid() {
+        let config = config_with("");
+        assert_eq!(config.alpn_protocols, vec!["h2", "http/1.1"]);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn invalid_alpn() {
+        assert!(config_with("alpn_protocols: []").validate().is_err());
+        assert!(config_with("alpn_protocols: [spdy/3]").validate().is_err());
+        assert!(config_with("alpn_protocols: [h2, h2]").validate().is_err());
+        config_with("alpn_protocols: [http/1.1]")
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn zstd_window_is_bounded() {
+        let zstd = |window_log| {
+            config_with(&format!(
+                "rocksdb_options:\n    path: ./cache\n    zstd:\n        window_log: {}\n",
+                window_log
+            ))
+        };
+        zstd(27).validate().unwrap();
+        assert!(zstd(28).validate().is_err());
+        assert!(zstd(9).validate().is_err());
+    }
+
+    #[test]
+    fn zstd_dictionary_is_bounded() {
+        let zstd = |options: &str| {
+            config_with(&format!(
+                "rocksdb_options:\n    path: ./cache\n    zstd:\n{}",
+                options
+            ))
+        };
+        zstd("        dict_kibibytes: 16\n").validate().unwrap();
+        zstd("        dict_kibibytes: 16\n        dict_train_kibibytes: 1600\n")
+            .validate()
+            .unwrap();
+        assert!(zstd("        dict_kibibytes: 0\n").validate().is_err());
+        assert!(zstd("        dict_kibibytes: 2048\n").validate().is_err());
+        // the samples have to be at least as large as the dictionary
+        assert!(
+            zstd("        dict_kibibytes: 16\n        dict_train_kibibytes: 8\n")
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn content_type_overrides_are_lowercased() {
+        let config = config_with("content_type_overrides:\n    Application/X-JPG: image/jpeg\n");
+        config.validate().unwrap();
+        assert_eq!(
+            config.content_type_overrides.get("application/x-jpg"),
+            Some(&"image/jpeg".to_string())
+        );
+    }
+
+    #[test]
+    fn skip_tokens_disables_tokens() {
+        let mut config = config_with("skip_tokens: true\n");
+        config.apply_deprecated();
+        assert_eq!(config.token_policy, TokenPolicy::Disabled);
+
+        let mut config = config_with("skip_tokens: false\n");
+        config.apply_deprecated();
+        assert_eq!(config.token_policy, TokenPolicy::Required);
+    }
+
+    #[test]
+    fn redis_url_is_redacted() {
+        let config = config_with("redis_options:\n    url: redis://:hunter2@localhost\n");
+        config.validate().unwrap();
+        let json = config.to_redacted_json();
+        assert_eq!(json["redis_options"]["url"], "<redacted>");
+        assert_eq!(json["redis_options"]["key_prefix"], "scalpel:");
+
+        let config = config_with("redis_options:\n    url: redis://localhost\n    pool_size: 0\n");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn s3_options_are_validated() {
+        let s3 = |extra: &str| {
+            config_with(&format!(
+                "s3_options:\n    endpoint: https://s3.example.com\n    bucket: images\n    \
+                access_key_id: AKID\n    secret_access_key: hunter2\n{}",
+                extra
+            ))
+        };
+        let config = s3("");
+        config.validate().unwrap();
+        let json = config.to_redacted_json();
+        assert_eq!(json["s3_options"]["secret_access_key"], "<redacted>");
+        assert_eq!(json["s3_options"]["access_key_id"], "AKID");
+
+        assert!(s3("    key_prefix: \"images?\"\n").validate().is_err());
+    }
+}
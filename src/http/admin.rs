@@ -0,0 +1,379 @@
+//! Administrative routes for operators of the client.
+//!
+//! All routes are scoped under `/admin` and require the configured `admin_token` as a bearer token.
+//! If no token is configured, the routes act as if they don't exist.
+
+use super::{handler, is_well_formed, unsupported_extension};
+use crate::cache::{ImageCache, ImageKey};
+use crate::metrics::EvictionReason;
+use crate::GlobalState;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use std::sync::Arc;
+
+/// Registers all of the admin routes
+pub(super) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .route("/config", web::get().to(config_service))
+            .route("/cache", web::get().to(cache_service))
+            .route("/stats", web::get().to(stats_service))
+            .route("/clear", web::post().to(clear_service))
+            .route("/rotate", web::post().to(rotate_service))
+            .route(
+                "/refetch/{archive_type}/{chap_hash}/{image}",
+                web::post().to(refetch_service),
+            ),
+    );
+}
+
+/// Checks that the request carries the configured admin token, returning the response that should
+/// be sent instead if it doesn't
+fn authorize(req: &HttpRequest, gs: &GlobalState) -> Result<(), HttpResponse> {
+    let token = match &gs.config.admin_token {
+        Some(token) => token,
+        None => return Err(HttpResponse::NotFound().body("no valid route found")),
+    };
+
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    match provided {
+        // compare in constant time so the token can't be guessed through timing
+        Some(x)
+            if x.len() == token.len() && openssl::memcmp::eq(x.as_bytes(), token.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => Err(HttpResponse::Unauthorized().body("invalid admin token")),
+    }
+}
+
+/// Responds with the effective configuration of the client, with all secrets redacted
+async fn config_service(req: HttpRequest, gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &gs) {
+        return res;
+    }
+    HttpResponse::Ok().json(gs.config.to_redacted_json())
+}
+
+/// Responds with a description of the cache engine and how it's configured
+async fn cache_service(req: HttpRequest, gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &gs) {
+        return res;
+    }
+    HttpResponse::Ok().json(gs.cache.info())
+}
+
+/// Responds with how warm the cache is relative to its budget, and the last eviction from it
+async fn stats_service(req: HttpRequest, gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &gs) {
+        return res;
+    }
+    let mut stats = serde_json::json!(gs.warmth());
+    stats["last_eviction"] = serde_json::json!(gs.metrics.last_eviction());
+    HttpResponse::Ok().json(stats)
+}
+
+/// Removes every image from the cache, responding with how much was removed
+async fn clear_service(req: HttpRequest, gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &gs) {
+        return res;
+    }
+
+    match gs.cache.clear().await {
+        Ok(res) => {
+            log::warn!(
+                "cleared the cache through the admin api ({} entries, {}b)",
+                res.entries_evicted,
+                res.bytes_evicted
+            );
+            gs.metrics.record_eviction(EvictionReason::Clear, &res);
+            HttpResponse::Ok().json(serde_json::json!({
+                "entries_evicted": res.entries_evicted,
+                "bytes_evicted": res.bytes_evicted,
+            }))
+        }
+        Err(()) => HttpResponse::InternalServerError().body("unable to clear the cache"),
+    }
+}
+
+/// Body of a request to rotate the cache
+#[derive(serde::Deserialize)]
+struct RotateRequest {
+    /// where the new cache is created
+    path: String,
+}
+
+/// Starts moving the cache to a new path in the background. Images keep being served from the
+/// current cache until the migration is done, then the new one takes over.
+async fn rotate_service(
+    req: HttpRequest,
+    body: web::Bytes,
+    gs: web::Data<Arc<GlobalState>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &gs) {
+        return res;
+    }
+
+    let path = match serde_json::from_slice::<RotateRequest>(&body) {
+        Ok(x) if !x.path.is_empty() => x.path,
+        _ => return HttpResponse::BadRequest().body("expected a json body with the new \"path\""),
+    };
+    if gs.cache.is_rotating() {
+        return HttpResponse::Conflict().body("the cache is already being rotated");
+    }
+
+    log::warn!("rotating the cache to {} through the admin api", path);
+    let gs = Arc::clone(&gs);
+    tokio::spawn(async move {
+        // failures are logged by the rotation itself
+        let _ = gs.cache.rotate_to(&path).await;
+    });
+    HttpResponse::Accepted().finish()
+}
+
+/// Fetches an image from upstream and overwrites the cached copy, responding with the checksum
+/// and size of the new copy
+async fn refetch_service(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    gs: web::Data<Arc<GlobalState>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &gs) {
+        return res;
+    }
+
+    let (archive_type, chap_hash, image) = path.into_inner();
+    let saver = match archive_type.as_str() {
+        "data" => false,
+        "data-saver" => true,
+        _ => return HttpResponse::NotFound().body("invalid archive type"),
+    };
+
+    // refuse anything the image route would refuse, so upstream is never asked for it
+    if !is_well_formed(&chap_hash, &image) {
+        return HttpResponse::BadRequest().body("malformed chapter hash or image name");
+    }
+    if let Some(ext) = unsupported_extension(&gs.config, &image) {
+        return HttpResponse::BadRequest().body(format!("unsupported image type \"{}\"", ext));
+    }
+    let key = ImageKey::new(chap_hash, image, saver);
+
+    match handler::refetch("admin", &gs, &key).await {
+        Ok(entry) => {
+            log::info!("force-refetched {} from upstream", key);
+            HttpResponse::Ok().json(serde_json::json!({
+                "checksum": entry.get_checksum_hex(),
+                "size": entry.get_bytes_len(),
+            }))
+        }
+        Err(e) => {
+            log::error!("error force-refetching {}: {}", key, e);
+            HttpResponse::BadGateway().body(format!("error refetching image: {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::tests::use_upstream;
+    use crate::cache::tests::TestCache;
+    use crate::config::tests::config_with;
+    use crate::http::tests::{image_response, mock_upstream, CHAP_HASH};
+    use actix_web::{http::StatusCode, test, App};
+
+    #[test]
+    fn config_dump_is_redacted() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("admin_token: hunter2\nworker_threads: 7\n");
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let app =
+                test::init_service(App::new().app_data(web::Data::new(gs)).configure(configure))
+                    .await;
+
+            let req = test::TestRequest::get().uri("/admin/config").to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+            let req = test::TestRequest::get()
+                .uri("/admin/config")
+                .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&app, req).await;
+            assert_eq!(body["client_secret"], "<redacted>");
+            assert_eq!(body["admin_token"], "<redacted>");
+            assert_eq!(body["worker_threads"], 7);
+
+            let req = test::TestRequest::get()
+                .uri("/admin/cache")
+                .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&app, req).await;
+            assert_eq!(body["backend"], "test");
+        });
+    }
+
+    #[test]
+    fn stats_report_warmth() {
+        actix_web::rt::System::new().block_on(async {
+            let mut config = config_with("admin_token: hunter2\n");
+            config.cache_size_mebibytes = 1;
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+            let image = bytes::Bytes::from(vec![0u8; 256 * 1024]);
+            assert!(gs.cache.save(&key, "image/png".to_string(), image).await);
+            gs.recent_hits.record(true);
+            gs.recent_hits.record(false);
+
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(Arc::clone(&gs)))
+                    .configure(configure),
+            )
+            .await;
+            let req = test::TestRequest::get()
+                .uri("/admin/stats")
+                .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&app, req).await;
+            assert_eq!(body["size_ratio"], 0.25);
+            assert_eq!(body["entries"], 1);
+            assert_eq!(body["recent_hit_ratio"], 0.5);
+        });
+    }
+
+    #[test]
+    fn clear_requires_auth_and_empties_cache() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("admin_token: hunter2\n");
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+            let image = bytes::Bytes::from_static(b"image");
+            assert!(gs.cache.save(&key, "image/png".to_string(), image).await);
+
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(Arc::clone(&gs)))
+                    .configure(configure),
+            )
+            .await;
+            let req = test::TestRequest::post().uri("/admin/clear").to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert!(gs.cache.load(&key).await.is_some());
+
+            let req = test::TestRequest::post()
+                .uri("/admin/clear")
+                .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&app, req).await;
+            assert_eq!(body["entries_evicted"], 1);
+            assert_eq!(gs.cache.report(), 0);
+            assert!(gs.cache.load(&key).await.is_none());
+
+            let req = test::TestRequest::get()
+                .uri("/admin/stats")
+                .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&app, req).await;
+            assert_eq!(body["last_eviction"]["reason"], "clear");
+            assert_eq!(body["last_eviction"]["entries_evicted"], 1);
+        });
+    }
+
+    #[test]
+    fn rotate_moves_cache_in_background() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("admin_token: hunter2\n");
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+            let image = bytes::Bytes::from_static(b"image");
+            assert!(gs.cache.save(&key, "image/png".to_string(), image).await);
+
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(Arc::clone(&gs)))
+                    .configure(configure),
+            )
+            .await;
+            let rotate = |body: &'static str| {
+                test::TestRequest::post()
+                    .uri("/admin/rotate")
+                    .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+                    .set_payload(body)
+                    .to_request()
+            };
+            let res = test::call_service(&app, rotate("{}")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+            let res = test::call_service(&app, rotate(r#"{"path": "/mnt/new"}"#)).await;
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+            for _ in 0..100 {
+                if !gs.cache.is_rotating() && gs.cache.info().path.is_some() {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            assert_eq!(gs.cache.info().path.as_deref(), Some("/mnt/new"));
+            assert!(gs.cache.load(&key).await.is_some());
+        });
+    }
+
+    #[test]
+    fn refetch_replaces_entry() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("admin_token: hunter2\n");
+            let mut gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            use_upstream(&mut gs, mock_upstream(image_response(b"fresh bytes")));
+
+            let key = ImageKey::new(CHAP_HASH.to_string(), "1.png".to_string(), false);
+            let stale = bytes::Bytes::from_static(b"stale bytes");
+            assert!(gs.cache.save(&key, "image/png".to_string(), stale).await);
+
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(Arc::clone(&gs)))
+                    .configure(configure),
+            )
+            .await;
+            let req = test::TestRequest::post()
+                .uri(&format!("/admin/refetch/data/{}/1.png", CHAP_HASH))
+                .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&app, req).await;
+
+            let entry = gs.cache.load(&key).await.unwrap();
+            assert_eq!(entry.get_bytes(), &b"fresh bytes"[..]);
+            assert_eq!(body["checksum"], entry.get_checksum_hex());
+            assert_eq!(body["size"], 11);
+        });
+    }
+
+    #[test]
+    fn refetch_refuses_invalid_paths() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("admin_token: hunter2\nallowed_extensions: [png]\n");
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let app =
+                test::init_service(App::new().app_data(web::Data::new(gs)).configure(configure))
+                    .await;
+
+            // there is no upstream, so any of these that was fetched would be a 502 instead
+            for uri in &[
+                "/admin/refetch/data/chapter/1.png".to_string(),
+                format!("/admin/refetch/data/{}/..png", CHAP_HASH),
+                format!("/admin/refetch/data/{}/1.tiff", CHAP_HASH),
+            ] {
+                let req = test::TestRequest::post()
+                    .uri(uri)
+                    .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+                    .to_request();
+                let res = test::call_service(&app, req).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+            }
+        });
+    }
+}
@@ -1,6 +1,13 @@
-use crate::{cache::ImageKey, utils::Timer, GlobalState};
+use super::flight::SharedFetch;
+use super::handler::MAX_RESPONSE_BYTES;
+use crate::{
+    cache::{ImageCache, ImageKey},
+    utils::Timer,
+    GlobalState,
+};
 use bytes::{Bytes, BytesMut};
 use futures::stream::Stream;
+use std::convert::Infallible;
 use std::error::Error;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -67,18 +74,42 @@ impl BytesAgg {
     }
 }
 
-pub(super) type UpstreamStream<E> = dyn Stream<Item = Result<Bytes, E>> + Unpin;
+pub(super) type UpstreamStream<E> = dyn Stream<Item = Result<Bytes, E>> + Send + Unpin;
+
+/// Splits the bytes into a stream of chunks that are at most `chunk_size` bytes long, without
+/// copying them
+pub(super) fn chunk_bytes(
+    mut bytes: Bytes,
+    chunk_size: usize,
+) -> impl Stream<Item = Result<Bytes, Infallible>> + Unpin {
+    futures::stream::iter(std::iter::from_fn(move || {
+        if bytes.is_empty() {
+            return None;
+        }
+        let len = bytes.len().min(chunk_size);
+        Some(Ok(bytes.split_to(len)))
+    }))
+}
 
 /// A stream to handle cache MISSes by streaming content to the user and saving it until the stream
 /// it complete, then saving it to the cache database.
 ///
 /// To break it down: This structure converts a `reqwest` [`Stream`] into an `actix_web` stream,
 /// saving all data to an aggregator, then saving the aggregator to cache once the stream is
-/// completely done.
+/// completely done. Streams that end early (because of an error, or because the client went away)
+/// are never saved.
 pub(super) struct ChunkedUpstreamPoll<E: Error> {
     gs: Arc<GlobalState>,
     upstream: Pin<Box<UpstreamStream<E>>>,
     agg: BytesAgg,
+    /// whether upstream sent the whole image
+    finished: bool,
+    /// the fetch that other requests for the image can join, if it was registered
+    shared: Option<Arc<SharedFetch>>,
+    /// Bytes received from upstream that haven't been sent yet, since upstream chunks are split
+    /// up to the configured chunk size
+    pending: Bytes,
+    chunk_size: usize,
     cache_info: Arc<(ImageKey, mime::Mime)>,
     req_start: Timer,
 }
@@ -96,16 +127,42 @@ impl<E: Error> ChunkedUpstreamPoll<E> {
             gs: Arc::clone(gs),
             upstream: Pin::new(stream),
             agg: BytesAgg::new(size_hint),
+            finished: false,
+            shared: None,
+            pending: Bytes::new(),
+            chunk_size: gs.config.stream_chunk_size(),
             cache_info: Arc::new((key, mime_type)),
             req_start,
         }
     }
+
+    /// Shares the chunks received from upstream with the requests that join the fetch
+    pub(super) fn with_followers(mut self, fetch: Option<Arc<SharedFetch>>) -> Self {
+        self.shared = fetch;
+        self
+    }
+
+    /// Takes the next chunk to send out of the pending bytes, sharing it with the requests that
+    /// joined the fetch
+    fn next_chunk(&mut self) -> Bytes {
+        let len = self.pending.len().min(self.chunk_size);
+        let chunk = self.pending.split_to(len);
+        if let Some(fetch) = &self.shared {
+            fetch.push(chunk.clone());
+        }
+        chunk
+    }
 }
 
 impl<E: Error + 'static> Stream for ChunkedUpstreamPoll<E> {
     type Item = Result<Bytes, actix_web::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // finish sending the last upstream chunk before polling for more
+        if !self.pending.is_empty() {
+            return Poll::Ready(Some(Ok(self.next_chunk())));
+        }
+
         // match upstream's stream state and return based on that
         let u = self.upstream.as_mut();
         match u.poll_next(cx) {
@@ -113,7 +170,17 @@ impl<E: Error + 'static> Stream for ChunkedUpstreamPoll<E> {
             Poll::Ready(Some(Ok(bytes))) => {
                 // copy new bytes to aggregator and then return value
                 self.agg.put(&bytes);
-                Poll::Ready(Some(Ok(bytes)))
+
+                // stop assembling the image if upstream sends more than could possibly be valid
+                if self.agg.len() as u64 > MAX_RESPONSE_BYTES {
+                    log::error!("upstream download exceeded the maximum response size");
+                    self.agg.poison();
+                    return Poll::Ready(Some(Err(actix_web::error::ErrorBadGateway(
+                        "upstream image exceeds maximum response size",
+                    ))));
+                }
+                self.pending = bytes;
+                Poll::Ready(Some(Ok(self.next_chunk())))
             }
             // unsuccessful upstream poll
             Poll::Ready(Some(Err(e))) => {
@@ -129,7 +196,23 @@ impl<E: Error + 'static> Stream for ChunkedUpstreamPoll<E> {
                 let len = self.agg.len();
                 log::debug!("stream complete (total = {}b)", len);
 
+                // an empty body is never a valid image, so fail the response instead of
+                // completing it (and caching nothing)
+                if len == 0 && !self.agg.is_poisoned() {
+                    log::error!("upstream sent an empty image");
+                    self.agg.poison();
+                    return Poll::Ready(Some(Err(actix_web::error::ErrorBadGateway(
+                        "upstream image is empty",
+                    ))));
+                }
+
                 // complete saying there is no more data
+                if !self.agg.is_poisoned() {
+                    self.finished = true;
+                    if let Some(fetch) = &self.shared {
+                        fetch.complete();
+                    }
+                }
                 Poll::Ready(None)
             }
 
@@ -142,11 +225,20 @@ impl<E: Error + 'static> Stream for ChunkedUpstreamPoll<E> {
 impl<E: Error> Drop for ChunkedUpstreamPoll<E> {
     /// Schedules a tokio task to save the cache aggregator when this value is dropped
     fn drop(&mut self) {
-        // take the bytes from the aggreator. if the bytes have already been taken
-        // or the bytes have been poisoned (because of an error), this will ret None
+        // take the bytes from the aggreator. if the bytes have already been taken, the bytes have
+        // been poisoned (because of an error) or the stream didn't finish, nothing is saved
         let bytes = match self.agg.take() {
-            Some(b) => b,
-            None => {
+            Some(b) if self.finished => b,
+            partial => {
+                // the requests that joined the fetch can't get the rest of the image either
+                if let Some(fetch) = self.shared.take() {
+                    fetch.fail();
+                    self.gs.fetches.finish(&self.cache_info.0, &fetch);
+                }
+                if partial.is_some() {
+                    log::warn!("stream ended before the image was complete, skipping cache save");
+                    return;
+                }
                 log::warn!("no byte aggregator found, skipping cache save");
                 // if poisoned, then mark as a failed request
                 if self.agg.is_poisoned() {
@@ -156,27 +248,34 @@ impl<E: Error> Drop for ChunkedUpstreamPoll<E> {
             }
         };
 
-        // spawn a cache save task with tokio
+        // spawn a cache save task with tokio (unless the cache is bypassed). the fetch can be
+        // joined until the image is in the cache
         let bytes_len = bytes.len() as u64;
-        let gs = Arc::clone(&self.gs);
-        let cache_info = Arc::clone(&self.cache_info);
-        tokio::spawn(async move {
-            let (key, mime) = cache_info.as_ref();
-
-            let timer = crate::utils::Timer::start();
-            gs.cache.save(key, mime.to_string(), bytes).await;
-            log::debug!("cache save in {}", timer);
-            gs.metrics
-                .cache_save_histo
-                .observe(timer.elapsed_secs() as f64);
-        });
+        let shared = self.shared.take();
+        if !self.gs.config.pass_through {
+            let gs = Arc::clone(&self.gs);
+            let cache_info = Arc::clone(&self.cache_info);
+            tokio::spawn(async move {
+                let (key, mime) = cache_info.as_ref();
+
+                let timer = crate::utils::Timer::start();
+                gs.cache.save(key, mime.to_string(), bytes).await;
+                log::debug!("cache save in {}", timer);
+                gs.metrics
+                    .cache_save_histo
+                    .observe(timer.elapsed_secs() as f64);
+                if let Some(fetch) = shared {
+                    gs.fetches.finish(key, &fetch);
+                }
+            });
+        } else if let Some(fetch) = shared {
+            self.gs.fetches.finish(&self.cache_info.0, &fetch);
+        }
 
         // update all metrics
         self.gs
-            .metrics
-            .miss_request_process_seconds
-            .observe(self.req_start.elapsed_secs() as f64);
-        self.gs.metrics.miss_requests_total.inc();
+            .sink
+            .record_miss(self.req_start.elapsed_secs() as f64);
         self.gs.metrics.bytes_up.inc_by(bytes_len);
         self.gs.metrics.bytes_down.inc_by(bytes_len);
     }
@@ -205,3 +304,76 @@ impl<E: Error> actix_web::ResponseError for UpstreamError<E> {
         actix_web::http::StatusCode::BAD_GATEWAY
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tests::TestCache;
+    use crate::config::tests::config_with;
+    use futures::StreamExt;
+
+    #[test]
+    fn bodies_are_streamed_in_configured_chunks() {
+        actix_web::rt::System::new().block_on(async {
+            let body = Bytes::from(vec![7u8; 2500]);
+            let lens: Vec<usize> = chunk_bytes(body.clone(), 1024)
+                .map(|x| x.unwrap().len())
+                .collect()
+                .await;
+            assert_eq!(lens, vec![1024, 1024, 452]);
+
+            // upstream chunks are split up the same way, no matter how upstream sent them
+            let gs = GlobalState::for_tests(
+                config_with("stream_chunk_kibibytes: 1\n"),
+                Box::new(TestCache::default()),
+            );
+            let upstream = futures::stream::iter(vec![
+                Ok::<_, std::io::Error>(body.slice(..2100)),
+                Ok(body.slice(2100..)),
+            ]);
+            let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+            let poll = ChunkedUpstreamPoll::new(
+                &gs,
+                key,
+                mime::IMAGE_PNG,
+                Box::new(upstream),
+                body.len(),
+                Timer::start(),
+            );
+            let lens: Vec<usize> = poll.map(|x| x.unwrap().len()).collect().await;
+            assert_eq!(lens, vec![1024, 1024, 52, 400]);
+        });
+    }
+
+    #[test]
+    fn empty_upstream_body_is_not_cached() {
+        actix_web::rt::System::new().block_on(async {
+            let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+            let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+            let poll = |body: &'static [u8]| {
+                let upstream =
+                    futures::stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from_static(body))]);
+                ChunkedUpstreamPoll::new(
+                    &gs,
+                    key.clone(),
+                    mime::IMAGE_PNG,
+                    Box::new(upstream),
+                    body.len(),
+                    Timer::start(),
+                )
+            };
+
+            let chunks: Vec<_> = poll(b"").collect().await;
+            assert!(chunks.last().unwrap().is_err());
+            assert_eq!(gs.metrics.failed_requests_total.get(), 1);
+
+            let chunks: Vec<_> = poll(b"image").collect().await;
+            assert!(chunks.iter().all(|x| x.is_ok()));
+
+            // the save happens in the background once the stream is dropped
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let entry = gs.cache.load(&key).await.unwrap();
+            assert_eq!(entry.get_bytes(), &b"image"[..]);
+        });
+    }
+}
@@ -0,0 +1,111 @@
+//! Cap on the number of requests served over a single connection.
+//!
+//! Connections are told apart by the address of the peer, since the port of a TCP connection is
+//! unique among the open connections of a peer. Once a connection made its last allowed request,
+//! the response asks the client to close it and the count starts over. Connections that haven't
+//! made a request within the keep-alive time are closed by the server, so they're forgotten too.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counts the requests made over each connection
+pub struct RequestLimit {
+    max_requests: usize,
+    idle: Duration,
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    connections: HashMap<SocketAddr, Connection>,
+    last_purge: Instant,
+}
+
+struct Connection {
+    requests: usize,
+    last_request: Instant,
+}
+
+impl RequestLimit {
+    /// Creates a limit of `max_requests` for each connection, where connections without a request
+    /// for `idle` are considered closed
+    pub fn new(max_requests: usize, idle: Duration) -> Self {
+        Self {
+            max_requests,
+            idle,
+            state: Mutex::new(LimitState {
+                connections: HashMap::new(),
+                last_purge: Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts a request made over the connection from `peer`, returning whether it's the last
+    /// request the connection is allowed to make
+    pub fn record(&self, peer: SocketAddr) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.purge(&mut state, now);
+
+        let idle = self.idle;
+        let conn = state.connections.entry(peer).or_insert(Connection {
+            requests: 0,
+            last_request: now,
+        });
+        // an idle connection was closed by the server, so this is a new one from the same port
+        if now.duration_since(conn.last_request) >= idle {
+            conn.requests = 0;
+        }
+        conn.requests += 1;
+        conn.last_request = now;
+
+        let last = conn.requests >= self.max_requests;
+        if last {
+            state.connections.remove(&peer);
+        }
+        last
+    }
+
+    /// Forgets the connections that have been idle for too long, at most once per idle period
+    fn purge(&self, state: &mut LimitState, now: Instant) {
+        if now.duration_since(state.last_purge) < self.idle {
+            return;
+        }
+        let idle = self.idle;
+        state
+            .connections
+            .retain(|_, conn| now.duration_since(conn.last_request) < idle);
+        state.last_purge = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_connection() {
+        let limit = RequestLimit::new(2, Duration::from_secs(30));
+        let conn: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.1:5001".parse().unwrap();
+
+        assert!(!limit.record(conn));
+        assert!(!limit.record(other));
+        assert!(limit.record(conn));
+        // the closed connection's port can be reused by a new connection
+        assert!(!limit.record(conn));
+        assert!(limit.record(other));
+    }
+
+    #[test]
+    fn idle_connections_are_forgotten() {
+        let limit = RequestLimit::new(2, Duration::from_millis(50));
+        let conn: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+
+        assert!(!limit.record(conn));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!limit.record(conn));
+        assert!(limit.record(conn));
+    }
+}
@@ -0,0 +1,119 @@
+//! Correction of image content types that upstream mislabeled.
+//!
+//! Types are first rewritten using the configured override table. If the type still isn't an image
+//! type and the bytes of the image are available, the type is detected from the magic bytes instead.
+
+use crate::cache::sniff_mime as sniff;
+use std::collections::HashMap;
+
+/// Bounds a content type from upstream to at most `max_len` characters of printable ASCII, so a
+/// huge or garbled type can't bloat cache entries. Types that don't fit have their parameters
+/// dropped, and `None` is returned if even the bare type doesn't fit.
+pub(super) fn bounded(mime: mime::Mime, max_len: usize) -> Option<mime::Mime> {
+    let fits = |x: &str| x.len() <= max_len && x.bytes().all(|b| b == b' ' || b.is_ascii_graphic());
+    if fits(mime.as_ref()) {
+        Some(mime)
+    } else if fits(mime.essence_str()) {
+        mime.essence_str().parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Corrects the content type of an image using the override table, then sniffing the `bytes` of
+/// the image (if available) when the type isn't an image type.
+///
+/// Logs whenever the type is changed, using `uid` to identify the request.
+pub(super) fn correct(
+    uid: &str,
+    overrides: &HashMap<String, String>,
+    mime: mime::Mime,
+    bytes: Option<&[u8]>,
+) -> mime::Mime {
+    // the common case, there's nothing that could change an image type
+    if overrides.is_empty() && mime.type_() == mime::IMAGE {
+        return mime;
+    }
+
+    let corrected = overrides
+        .get(&mime.essence_str().to_ascii_lowercase())
+        .and_then(|x| x.parse::<mime::Mime>().ok())
+        .unwrap_or_else(|| mime.clone());
+    let corrected = match bytes {
+        Some(bytes) if corrected.type_() != mime::IMAGE => sniff(bytes).unwrap_or(corrected),
+        _ => corrected,
+    };
+
+    if corrected != mime {
+        log::info!("({}) corrected content type {} to {}", uid, mime, corrected);
+    }
+    corrected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    fn overrides() -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        map.insert("application/x-jpg".to_string(), "image/jpeg".to_string());
+        map
+    }
+
+    #[test]
+    fn overrides_are_applied() {
+        let mime = correct(
+            "test",
+            &overrides(),
+            "application/x-jpg".parse().unwrap(),
+            None,
+        );
+        assert_eq!(mime, mime::IMAGE_JPEG);
+
+        // correct types pass through unchanged, even if the bytes disagree
+        let mime = correct("test", &overrides(), mime::IMAGE_GIF, Some(PNG));
+        assert_eq!(mime, mime::IMAGE_GIF);
+    }
+
+    #[test]
+    fn content_types_are_bounded() {
+        let normal: mime::Mime = "image/png; charset=binary".parse().unwrap();
+        assert_eq!(bounded(normal.clone(), 64), Some(normal));
+
+        // parameters are dropped first, then the whole type
+        let padded = format!("image/png; padding={}", "x".repeat(1000));
+        assert_eq!(bounded(padded.parse().unwrap(), 64), Some(mime::IMAGE_PNG));
+        let long = format!("image/{}", "x".repeat(1000));
+        assert_eq!(bounded(long.parse().unwrap(), 64), None);
+
+        let garbled: mime::Mime = "image/png; name=\"\u{e9}\"".parse().unwrap();
+        assert_eq!(bounded(garbled, 64), Some(mime::IMAGE_PNG));
+    }
+
+    #[test]
+    fn non_image_types_are_sniffed() {
+        let mime = correct(
+            "test",
+            &overrides(),
+            mime::APPLICATION_OCTET_STREAM,
+            Some(PNG),
+        );
+        assert_eq!(mime, mime::IMAGE_PNG);
+
+        // unknown bytes keep the original type
+        let mime = correct(
+            "test",
+            &overrides(),
+            mime::APPLICATION_OCTET_STREAM,
+            Some(b"??"),
+        );
+        assert_eq!(mime, mime::APPLICATION_OCTET_STREAM);
+
+        assert_eq!(
+            sniff(b"RIFF\0\0\0\0WEBPVP8 ").map(|x| x.to_string()),
+            Some("image/webp".to_string())
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Global cap on the rate that image bytes are served at, shared by every response.
+//!
+//! The cap is a token bucket that holds up to one second worth of bytes. Every chunk of a response
+//! takes its size out of the bucket before being sent, waiting for the bucket to refill if it goes
+//! negative, so streams are paced (instead of refused) once the cap is reached.
+
+use bytes::Bytes;
+use futures::future::Either;
+use futures::stream::{Stream, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting the total number of bytes per second that are served
+pub struct EgressLimiter {
+    /// bytes per second (and the size of the bucket)
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// bytes that can be sent right away, negative when streams have to wait
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl EgressLimiter {
+    /// Creates a limiter that allows `bytes_per_sec` bytes to be served every second
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `len` bytes out of the bucket, returning how long to wait before sending them
+    fn reserve(&self, len: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.rate) - len as f64;
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+}
+
+/// Paces the stream of response chunks to the limiter, or passes it through untouched if there's
+/// no limiter
+pub(super) fn throttle<S, E>(
+    stream: S,
+    limiter: Option<&Arc<EgressLimiter>>,
+) -> impl Stream<Item = Result<Bytes, E>> + Unpin
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let limiter = match limiter {
+        Some(limiter) => Arc::clone(limiter),
+        None => return Either::Right(stream),
+    };
+    Either::Left(Box::pin(stream.then(move |chunk| {
+        let wait = match &chunk {
+            Ok(bytes) => limiter.reserve(bytes.len()),
+            Err(_) => Duration::ZERO,
+        };
+        async move {
+            if wait > Duration::ZERO {
+                tokio::time::sleep(wait).await;
+            }
+            chunk
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::chunked::chunk_bytes;
+
+    #[tokio::test]
+    async fn concurrent_streams_share_the_cap() {
+        const RATE: u64 = 1024 * 1024;
+        let limiter = Arc::new(EgressLimiter::new(RATE));
+
+        // 4 streams of 512KiB each is 2MiB in total, where the first 1MiB is the initial burst
+        let start = Instant::now();
+        let streams = (0..4).map(|_| {
+            let body = Bytes::from(vec![0u8; 512 * 1024]);
+            throttle(chunk_bytes(body, 16 * 1024), Some(&limiter))
+                .fold(0, |sent, chunk| async move { sent + chunk.unwrap().len() })
+        });
+        let sent: usize = futures::future::join_all(streams).await.into_iter().sum();
+        let elapsed = start.elapsed().as_secs_f64();
+
+        assert_eq!(sent, 2 * 1024 * 1024);
+        let burst = RATE as f64;
+        assert!(
+            (sent as f64 - burst) / elapsed <= RATE as f64 * 1.05,
+            "served {} bytes in {:.2}s",
+            sent,
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn unlimited_streams_pass_through() {
+        let body = Bytes::from(vec![0u8; 4 * 1024 * 1024]);
+        let start = Instant::now();
+        let chunks: Vec<_> = throttle(chunk_bytes(body, 64 * 1024), None).collect().await;
+        assert_eq!(chunks.len(), 64);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
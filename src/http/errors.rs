@@ -0,0 +1,174 @@
+//! Normalization of error responses.
+//!
+//! Errors produced by Actix itself (like failing to extract a request, or payloads that are too
+//! large) are rendered however the framework sees fit. This rewrites every error response into the
+//! configured body format, so they all look the same to clients. The `X-Request-Id` of the request
+//! (usually set by a load balancer) is echoed back to correlate errors with logs.
+
+use crate::config::ErrorBodyFormat;
+use crate::GlobalState;
+use actix_web::body::{AnyBody, Body};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, HeaderName, HeaderValue, StatusCode};
+use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::{web, Result as WebResult};
+use std::sync::Arc;
+
+/// Header that carries the correlation ID of a request
+const REQUEST_ID: &str = "x-request-id";
+
+/// Error statuses that are normalized. Actix can only handle specific statuses, so this covers
+/// every status that the client or the framework can respond with.
+const NORMALIZED: &[StatusCode] = &[
+    StatusCode::BAD_REQUEST,
+    StatusCode::UNAUTHORIZED,
+    StatusCode::FORBIDDEN,
+    StatusCode::NOT_FOUND,
+    StatusCode::METHOD_NOT_ALLOWED,
+    StatusCode::REQUEST_TIMEOUT,
+    StatusCode::LENGTH_REQUIRED,
+    StatusCode::PAYLOAD_TOO_LARGE,
+    StatusCode::URI_TOO_LONG,
+    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+    StatusCode::RANGE_NOT_SATISFIABLE,
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// Creates the middleware that normalizes error responses. It has to come before any middleware
+/// that wraps the response body, since it needs the unwrapped body.
+pub(super) fn normalize() -> ErrorHandlers<Body> {
+    NORMALIZED
+        .iter()
+        .fold(ErrorHandlers::new(), |handlers, &status| {
+            handlers.handler(status, render)
+        })
+}
+
+/// Rewrites the body of an error response into the configured format
+fn render(mut res: ServiceResponse<Body>) -> WebResult<ErrorHandlerResponse<Body>> {
+    let format = res
+        .request()
+        .app_data::<web::Data<Arc<GlobalState>>>()
+        .map(|gs| gs.config.error_body_format)
+        .unwrap_or_default();
+    let request_id = res
+        .request()
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string());
+
+    // errors always have their message as the body, but fall back to the reason just in case
+    let status = res.status();
+    let message = match res.response().body() {
+        AnyBody::Bytes(b) if !b.is_empty() => String::from_utf8_lossy(b).into_owned(),
+        _ => status.canonical_reason().unwrap_or("error").to_string(),
+    };
+
+    let (content_type, body) = match format {
+        ErrorBodyFormat::Plain => ("text/plain; charset=utf-8", message),
+        ErrorBodyFormat::Json => (
+            "application/json",
+            serde_json::json!({
+                "status": status.as_u16(),
+                "error": message,
+                "request_id": request_id,
+            })
+            .to_string(),
+        ),
+    };
+
+    let headers = res.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Some(value) = request_id.and_then(|x| HeaderValue::from_str(&x).ok()) {
+        headers.insert(HeaderName::from_static(REQUEST_ID), value);
+    }
+    let res = res.map_body(|_, _| AnyBody::from(body));
+    Ok(ErrorHandlerResponse::Response(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tests::TestCache;
+    use crate::config::tests::config_with;
+    use actix_web::{test, App, HttpResponse};
+
+    #[derive(serde::Deserialize)]
+    struct Query {
+        #[allow(dead_code)]
+        size: u32,
+    }
+
+    #[test]
+    fn framework_errors_are_normalized() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("error_body_format: json\n");
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(gs))
+                    .app_data(web::PayloadConfig::new(4))
+                    .wrap(normalize())
+                    .route(
+                        "/query",
+                        web::get().to(|_: web::Query<Query>| HttpResponse::Ok()),
+                    )
+                    .route(
+                        "/upload",
+                        web::post().to(|_: web::Bytes| HttpResponse::Ok()),
+                    ),
+            )
+            .await;
+
+            let req = test::TestRequest::get()
+                .uri("/query?size=big")
+                .insert_header((REQUEST_ID, "abc123"))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(res.headers().get(REQUEST_ID).unwrap(), "abc123");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["status"], 400);
+            assert_eq!(body["request_id"], "abc123");
+            assert!(body["error"].as_str().unwrap().contains("invalid digit"));
+
+            let req = test::TestRequest::post()
+                .uri("/upload")
+                .set_payload("too many bytes")
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["status"], 413);
+            assert!(body["request_id"].is_null());
+        });
+    }
+
+    #[test]
+    fn plain_errors_fall_back_to_the_reason() {
+        actix_web::rt::System::new().block_on(async {
+            let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(gs))
+                    .wrap(normalize())
+                    .route("/", web::get().to(HttpResponse::BadGateway)),
+            )
+            .await;
+
+            let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+            assert_eq!(
+                res.headers().get(header::CONTENT_TYPE).unwrap(),
+                "text/plain; charset=utf-8"
+            );
+            assert_eq!(test::read_body(res).await, "Bad Gateway");
+        });
+    }
+}
@@ -0,0 +1,218 @@
+//! Single-flight upstream fetches.
+//!
+//! While a MISS is being streamed from upstream, other requests for the same image join the fetch
+//! instead of starting their own. They are sent every chunk received so far followed by the rest
+//! as it arrives, so they always get the same bytes as the request that started the fetch.
+//!
+//! The fetch runs on a task of its own, with every request (the one that started it included)
+//! following it, so it isn't cut short when the request that started it goes away.
+//!
+//! Images are only saved to the cache once they've been received completely, so the cache never
+//! holds a partial image. A fetch stays joinable until its image is saved, which closes the gap
+//! between the end of the stream and the image showing up in the cache. If the fetch fails, the
+//! requests that joined it fail too.
+
+use crate::cache::ImageKey;
+use actix_web::http::header::HttpDate;
+use bytes::Bytes;
+use futures::stream::Stream;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// The upstream fetches that are currently in progress, by image
+#[derive(Default)]
+pub struct InFlightFetches {
+    fetches: Mutex<HashMap<String, Arc<SharedFetch>>>,
+}
+
+impl InFlightFetches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The fetch of the image that's in progress, if there is one
+    pub(super) fn join(&self, key: &ImageKey) -> Option<Arc<SharedFetch>> {
+        let fetches = self.fetches.lock().unwrap_or_else(|e| e.into_inner());
+        fetches.get(&key.to_string()).cloned()
+    }
+
+    /// Registers a fetch of the image that other requests can join. Returns `None` if another
+    /// fetch of the image was registered in the meantime, in which case the fetch goes on alone.
+    pub(super) fn start(
+        &self,
+        key: &ImageKey,
+        content_type: mime::Mime,
+        last_modified: HttpDate,
+        content_length: Option<u64>,
+    ) -> Option<Arc<SharedFetch>> {
+        let mut fetches = self.fetches.lock().unwrap_or_else(|e| e.into_inner());
+        let key = key.to_string();
+        if fetches.contains_key(&key) {
+            return None;
+        }
+        let fetch = Arc::new(SharedFetch::new(
+            content_type,
+            last_modified,
+            content_length,
+        ));
+        fetches.insert(key, Arc::clone(&fetch));
+        Some(fetch)
+    }
+
+    /// Unregisters the fetch once it's over, unless it was already replaced by another one
+    pub(super) fn finish(&self, key: &ImageKey, fetch: &Arc<SharedFetch>) {
+        let mut fetches = self.fetches.lock().unwrap_or_else(|e| e.into_inner());
+        let key = key.to_string();
+        if fetches.get(&key).is_some_and(|x| Arc::ptr_eq(x, fetch)) {
+            fetches.remove(&key);
+        }
+    }
+}
+
+/// The chunks of an image received from upstream so far, shared with the requests that joined
+/// the fetch
+pub(super) struct SharedFetch {
+    pub(super) content_type: mime::Mime,
+    pub(super) last_modified: HttpDate,
+    /// the size of the image, if upstream sent it
+    pub(super) content_length: Option<u64>,
+    state: Mutex<FetchState>,
+    /// signals the joined requests whenever the state changes
+    changed: watch::Sender<()>,
+    /// receiver that the receivers of joined requests are cloned from, which also keeps the
+    /// channel open
+    subscribed: watch::Receiver<()>,
+}
+
+#[derive(Default)]
+struct FetchState {
+    chunks: Vec<Bytes>,
+    /// `Some(true)` once the whole image was received, `Some(false)` if the fetch failed
+    outcome: Option<bool>,
+}
+
+impl SharedFetch {
+    fn new(content_type: mime::Mime, last_modified: HttpDate, content_length: Option<u64>) -> Self {
+        let (changed, subscribed) = watch::channel(());
+        Self {
+            content_type,
+            last_modified,
+            content_length,
+            state: Mutex::new(FetchState::default()),
+            changed,
+            subscribed,
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut FetchState)) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // the outcome is final, nothing can be added to a fetch that's over
+        if state.outcome.is_none() {
+            f(&mut state);
+        }
+        drop(state);
+        let _ = self.changed.send(());
+    }
+
+    /// Adds a chunk received from upstream
+    pub(super) fn push(&self, chunk: Bytes) {
+        self.update(|state| state.chunks.push(chunk));
+    }
+
+    /// Marks the image as received completely
+    pub(super) fn complete(&self) {
+        self.update(|state| state.outcome = Some(true));
+    }
+
+    /// Marks the fetch as failed, failing the requests that joined it
+    pub(super) fn fail(&self) {
+        self.update(|state| state.outcome = Some(false));
+    }
+
+    /// Streams the image to a request that joined the fetch, from the first chunk. The stream
+    /// ends with an error if the fetch fails.
+    pub(super) fn follow(
+        self: Arc<Self>,
+    ) -> impl Stream<Item = Result<Bytes, actix_web::Error>> + Unpin {
+        // the receiver is cloned before looking at the state, so no change after it is missed
+        let changed = self.subscribed.clone();
+        Box::pin(futures::stream::unfold(
+            Some((self, 0, changed)),
+            |follower| async move {
+                let (fetch, next, mut changed) = follower?;
+                loop {
+                    let outcome = {
+                        let state = fetch.state.lock().unwrap_or_else(|e| e.into_inner());
+                        if let Some(chunk) = state.chunks.get(next) {
+                            let chunk = chunk.clone();
+                            drop(state);
+                            return Some((Ok(chunk), Some((fetch, next + 1, changed))));
+                        }
+                        state.outcome
+                    };
+                    match outcome {
+                        Some(true) => return None,
+                        Some(false) => break,
+                        None if changed.changed().await.is_err() => break,
+                        None => {}
+                    }
+                }
+                let err = actix_web::error::ErrorBadGateway("upstream download failed");
+                Some((Err(err), None))
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn test_key() -> ImageKey {
+        ImageKey::new("chapter".to_string(), "1.png".to_string(), false)
+    }
+
+    #[tokio::test]
+    async fn followers_get_every_chunk() {
+        let fetches = InFlightFetches::new();
+        let key = test_key();
+        let now = HttpDate::from(std::time::SystemTime::now());
+        let fetch = fetches.start(&key, mime::IMAGE_PNG, now, None).unwrap();
+        assert!(fetches.start(&key, mime::IMAGE_PNG, now, None).is_none());
+
+        fetch.push(Bytes::from_static(b"ima"));
+        let early = fetches.join(&key).unwrap().follow();
+        let pushing = async {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            fetch.push(Bytes::from_static(b"ge"));
+            fetch.complete();
+        };
+        let (body, _) = tokio::join!(early.collect::<Vec<_>>(), pushing);
+        let body: Vec<u8> = body.into_iter().flat_map(|x| x.unwrap()).collect();
+        assert_eq!(body, b"image");
+
+        // requests that join after the image was received get all of it right away
+        let late: Vec<_> = fetches.join(&key).unwrap().follow().collect().await;
+        assert_eq!(late.len(), 2);
+
+        fetches.finish(&key, &fetch);
+        assert!(fetches.join(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn followers_fail_with_the_fetch() {
+        let fetches = InFlightFetches::new();
+        let key = test_key();
+        let now = HttpDate::from(std::time::SystemTime::now());
+        let fetch = fetches.start(&key, mime::IMAGE_PNG, now, None).unwrap();
+        fetch.push(Bytes::from_static(b"ima"));
+        fetch.fail();
+
+        let chunks: Vec<_> = fetch.follow().collect().await;
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        assert!(chunks[1].is_err());
+    }
+}
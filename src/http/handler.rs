@@ -3,20 +3,64 @@
 //! Module will handle HIT or MISS images by calling DB. On HIT, will simply stream the image, and
 //! on MISS, will download the image from upstream, save it, then stream it.
 
-use super::chunked::{ChunkedUpstreamPoll, UpstreamStream};
-use crate::backend::Backend;
-use crate::cache::ImageKey;
+use super::chunked::{self, ChunkedUpstreamPoll, UpstreamStream};
+use super::content_type;
+use super::egress;
+use super::flight::SharedFetch;
+use super::quota;
+use super::range::{self, ByteRange};
+use super::{cache_for, retry_after};
+use crate::cache::{ImageCache, ImageEntry, ImageKey};
+use crate::config::{AppConfig, ConcurrentMissPolicy};
 use crate::utils::Timer;
 use crate::GlobalState;
 use actix_web::{
+    body::SizedStream,
+    dev::BodyEncoding,
     http::{
-        header::{self, HttpDate},
+        header::{self, ContentEncoding, HttpDate},
         StatusCode,
     },
     HttpRequest, HttpResponse,
 };
+use bytes::Bytes;
+use futures::stream::Stream;
 use lazy_static::lazy_static;
-use std::{sync::Arc, time, time::Duration};
+use std::net::IpAddr;
+use std::{sync::Arc, time::Duration};
+
+/// How long each phase of handling a request took, which is sent to the client in a
+/// `Server-Timing` header if enabled in config
+#[derive(Default)]
+pub(super) struct ServerTiming {
+    /// whether phases are recorded at all, so nothing is allocated when the header is disabled
+    enabled: bool,
+    phases: Vec<(&'static str, f32)>,
+}
+impl ServerTiming {
+    pub(super) fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Records the time elapsed on the `timer` as the duration of the phase `name`
+    pub(super) fn record(&mut self, name: &'static str, timer: &Timer) {
+        if self.enabled {
+            self.phases.push((name, timer.elapsed()));
+        }
+    }
+
+    /// Formats the phases as the value of a `Server-Timing` header (durations in milliseconds)
+    fn header_value(&self) -> String {
+        self.phases
+            .iter()
+            .map(|(name, dur)| format!("{};dur={:.3}", name, dur))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
 
 /// Generates an [`HttpResponse`] by querying the cache and either returning HIT data or polling
 /// upstream, proxying, and saving the result on MISS.
@@ -26,71 +70,170 @@ pub(super) async fn response_from_cache(
     gs: &Arc<GlobalState>,
     key: ImageKey,
     req_start: Timer,
+    mut timing: ServerTiming,
 ) -> HttpResponse {
-    // attempt to load image from cache (timing response times)
-    let cache_hit = {
+    let mut res = response_from_cache_timed(uid, req, gs, key, req_start, &mut timing).await;
+    if timing.enabled {
+        if let Ok(value) = header::HeaderValue::from_str(&timing.header_value()) {
+            res.headers_mut()
+                .insert(header::HeaderName::from_static("server-timing"), value);
+        }
+    }
+    res
+}
+
+/// Does the actual work of [`response_from_cache`], recording the duration of each phase
+async fn response_from_cache_timed(
+    uid: &str,
+    req: &HttpRequest,
+    gs: &Arc<GlobalState>,
+    key: ImageKey,
+    req_start: Timer,
+    timing: &mut ServerTiming,
+) -> HttpResponse {
+    // attempt to load image from cache (timing response times). pass-through mode never touches
+    // the cache, so every request is a MISS
+    let cache_hit = if gs.config.pass_through {
+        None
+    } else {
         let timer = Timer::start();
         let cache_hit = gs.cache.load(&key).await;
         log::debug!("({}) cache lookup in {}", uid, timer);
         gs.metrics
             .cache_load_seconds
             .observe(timer.elapsed_secs() as f64);
+        timing.record("cache", &timer);
         cache_hit
     };
 
     if let Some(cache_hit) = cache_hit {
         // found in cache, aka HIT
-        let res = handle_cache_hit(uid, gs, req, cache_hit);
+        let res = handle_cache_hit(uid, gs, req, &key, cache_hit);
         // NOTE: recording metrics here because handle_cache_hit doesn't
         // contain logic for failure
-        gs.metrics
-            .hit_request_process_seconds
-            .observe(req_start.elapsed_secs() as f64);
-        gs.metrics.hit_requests_total.inc();
+        gs.sink.record_hit(req_start.elapsed_secs() as f64);
+        gs.recent_hits.record(true);
         res
     } else {
         // the result was not found in cache, aka MISS
         // NOTE: metrics are handled in chunked.rs
-        handle_cache_miss(uid, gs, key, req_start).await
+        gs.recent_hits.record(false);
+        let peer = req.peer_addr().map(|x| x.ip());
+        handle_cache_miss(uid, gs, peer, key, req_start, timing).await
     }
 }
 
+/// Prepares the chunks of a response body to be sent to the peer, counting them towards the byte
+/// quota of the peer and pacing them to the global egress cap (if either are enabled)
+fn egress_stream<S, E>(
+    gs: &GlobalState,
+    peer: Option<IpAddr>,
+    stream: S,
+) -> impl Stream<Item = Result<Bytes, E>> + Unpin
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let metered = quota::metered(stream, gs.byte_quota.as_ref(), peer);
+    egress::throttle(metered, gs.egress.as_ref())
+}
+
+/// Hard limit on the size of a response body that will be assembled and served.
+///
+/// This is far above the size of any legitimate image, and is only here as a defense against a
+/// corrupt cache entry or a misbehaving upstream exhausting the memory of a worker.
+pub(super) const MAX_RESPONSE_BYTES: u64 = 64 * 1024 * 1024;
+
 /* CACHE HIT HANDLER LOGIC BELOW */
 
 /// Returns whether the browser has the resource already cached locally.
 ///
-/// This is solely based on the `If-None-Match` header the client provides and the internally
-/// computed strong `ETag`. This will always return `false` if the provided `If-None-Match` is `*`.
-fn is_browser_cached(req: &HttpRequest, etag: &header::EntityTag) -> bool {
+/// This is based on the `If-None-Match` header the client provides and the internally computed
+/// strong `ETag`, which will never match if the provided `If-None-Match` is `*`. Only if the client
+/// didn't provide `If-None-Match` is `If-Modified-Since` checked against the time the image was
+/// cached instead (RFC 7232).
+fn is_browser_cached(
+    req: &HttpRequest,
+    etag: &header::EntityTag,
+    last_modified: std::time::SystemTime,
+) -> bool {
     use actix_web::HttpMessage;
+    use std::time::UNIX_EPOCH;
 
     match req.get_header::<header::IfNoneMatch>() {
         Some(header::IfNoneMatch::Items(ref items)) => items.iter().any(|x| etag.strong_eq(x)),
-        _ => false,
+        Some(header::IfNoneMatch::Any) => false,
+        // HTTP dates only have a precision of seconds
+        None => req
+            .get_header::<header::IfModifiedSince>()
+            .map(|x| {
+                let secs = |t: std::time::SystemTime| {
+                    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+                };
+                secs(last_modified) <= secs(x.0.into())
+            })
+            .unwrap_or(false),
     }
 }
 
 /// Handles a cache HIT, returning an HttpResponse that represents that data of the cached image
 ///
 /// Sends the bytes of the cached image to the client unless the client has already proved that
-/// they have the image cached locally. Will also set gzip (if enabled by client) and provide
-/// necessary headers (like `ETag` and `Vary`)
+/// they have the image cached locally. Will also provide necessary headers (like `ETag` and
+/// `Vary`)
 fn handle_cache_hit(
     uid: &str,
     gs: &Arc<GlobalState>,
     req: &HttpRequest,
-    image: crate::cache::ImageEntry,
+    key: &ImageKey,
+    image: ImageEntry,
 ) -> HttpResponse {
+    // refuse to serve entries that are too large to possibly be a valid image
+    let len = image.get_bytes().len() as u64;
+    if len > MAX_RESPONSE_BYTES {
+        log::error!(
+            "({}) refusing to serve cached image over the maximum response size ({}b)",
+            uid,
+            len
+        );
+        gs.metrics.failed_requests_total.inc();
+        return HttpResponse::BadGateway().body("cached image exceeds maximum response size");
+    }
+
     // check whether the browser already has the image cached locally
     let etag = header::EntityTag::strong(image.get_checksum_hex());
-    let is_client_cached = is_browser_cached(req, &etag);
+    let last_modified = image.get_save_time();
+    let is_client_cached = is_browser_cached(req, &etag, last_modified);
 
-    // create response object with headers that should be in every response
+    // fix up the content type in case upstream mislabeled the image when it was saved
+    let bytes = image.get_bytes();
+    let mime = content_type::correct(
+        uid,
+        &gs.config.content_type_overrides,
+        image.get_mime(
+            gs.config
+                .fallback_content_types
+                .for_archive(key.data_saver()),
+        ),
+        Some(&bytes),
+    );
+
+    // create response object with headers that should be in every response. images are already
+    // compressed, so they're never run through the compression middleware
     let mut res = HttpResponse::build(StatusCode::OK);
-    res.append_header(header::ContentType(image.get_mime()))
+    res.encoding(ContentEncoding::Identity)
+        .append_header(header::ContentType(mime))
         .append_header(header::ETag(etag))
+        .append_header(header::LastModified(last_modified.into()))
         .append_header(("Vary", "Accept-Encoding"));
 
+    // how long ago the image was cached, in seconds (RFC 7234)
+    let age = gs
+        .clock
+        .now()
+        .duration_since(image.get_save_time())
+        .unwrap_or_default();
+    res.append_header((header::AGE, age.as_secs()));
+
     // if the image is already cached in the browser, then we can just return the associated code
     // telling the browser that it doesn't need to download anything
     if is_client_cached {
@@ -98,10 +241,83 @@ fn handle_cache_hit(
         return res.status(StatusCode::NOT_MODIFIED).finish();
     }
 
-    // stream the data to the client
-    let bytes = image.get_bytes();
+    // let integrity-conscious clients verify the body (RFC 3230), the checksum is already stored
+    if gs.config.digest_header {
+        res.append_header(("Digest", format!("sha-256={}", image.get_checksum_base64())));
+    }
+
+    // only send the part of the image that was asked for (RFC 7233)
+    res.append_header((header::ACCEPT_RANGES, "bytes"));
+    let full_len = bytes.len() as u64;
+    let content_range = |range| {
+        header::ContentRange(header::ContentRangeSpec::Bytes {
+            range,
+            instance_length: Some(full_len),
+        })
+    };
+    let bytes = match range::parse(req.headers().get(header::RANGE), full_len) {
+        ByteRange::Full => bytes,
+        ByteRange::Partial(first, last) => {
+            res.status(StatusCode::PARTIAL_CONTENT)
+                .insert_header(content_range(Some((first, last))));
+            bytes.slice(first as usize..=last as usize)
+        }
+        ByteRange::Unsatisfiable => {
+            // this is an error about the request, so none of the image's headers apply to it
+            log::debug!("({}) unsatisfiable range requested", uid);
+            return HttpResponse::build(StatusCode::RANGE_NOT_SATISFIABLE)
+                .insert_header(content_range(None))
+                .finish();
+        }
+    };
+
     gs.metrics.bytes_up.inc_by(bytes.len() as u64);
-    res.body(bytes)
+    let len = bytes.len() as u64;
+
+    // small images are sent as a single body, which skips setting up a stream. only possible when
+    // the body doesn't have to be metered or paced chunk by chunk
+    let unmetered = gs.byte_quota.is_none() && gs.egress.is_none();
+    if unmetered && len <= gs.config.small_hit_size() {
+        return res.body(bytes);
+    }
+
+    // stream the data to the client
+    let chunks = chunked::chunk_bytes(bytes, gs.config.stream_chunk_size());
+    let peer = req.peer_addr().map(|x| x.ip());
+    res.body(SizedStream::new(len, egress_stream(gs, peer, chunks)))
+}
+
+/// Serves cache HITs of a single image the way the server does, so the benchmarks measure the
+/// actual HIT path. Not part of the public API.
+#[doc(hidden)]
+pub struct HitBench {
+    gs: Arc<GlobalState>,
+    key: ImageKey,
+}
+
+impl HitBench {
+    /// Caches `image` in RAM for a client running with `config`
+    pub async fn new(config: AppConfig, image: Bytes) -> Self {
+        let config = Arc::new(config);
+        let cache = crate::cache::MemoryCache::with_max_size(2 * image.len() as u64 + 1024);
+        let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+        assert!(cache.save(&key, "image/png".to_string(), image).await);
+
+        let backend = Arc::new(crate::backend::ApiBackend::new(Arc::clone(&config)));
+        let gs = GlobalState::standalone(config, Box::new(cache), backend);
+        Self { gs, key }
+    }
+
+    /// Loads the image from the cache and responds to `req` with it
+    pub async fn hit(&self, req: &HttpRequest) -> HttpResponse {
+        let image = self
+            .gs
+            .cache
+            .load(&self.key)
+            .await
+            .expect("image is cached");
+        handle_cache_hit("bench", &self.gs, req, &self.key, image)
+    }
 }
 
 /* CACHE MISS HANDLER LOGIC BELOW */
@@ -129,6 +345,43 @@ impl std::fmt::Display for NoUpstreamError {
 }
 impl std::error::Error for NoUpstreamError {}
 
+/// An error where upstream responded successfully, but without any image
+#[derive(Debug)]
+struct EmptyUpstreamError;
+impl std::fmt::Display for EmptyUpstreamError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "upstream sent an empty image")
+    }
+}
+impl std::error::Error for EmptyUpstreamError {}
+
+/// An error where upstream redirected the request somewhere that isn't allowed, or redirected too
+/// many times. Contains the URL that was redirected to, if there was a valid one.
+#[derive(Debug)]
+struct UpstreamRedirectError(Option<url::Url>);
+impl std::fmt::Display for UpstreamRedirectError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(url) => write!(fmt, "refusing to follow upstream redirect to {}", url),
+            None => write!(fmt, "upstream redirect has no valid location"),
+        }
+    }
+}
+impl std::error::Error for UpstreamRedirectError {}
+
+/// Whether a redirect from upstream to the `url` may be followed, which is only the case for
+/// HTTP(S) URLs to the configured redirect hosts
+fn is_redirect_allowed(config: &AppConfig, url: &url::Url) -> bool {
+    let host = match url.host_str() {
+        Some(host) if matches!(url.scheme(), "http" | "https") => host,
+        _ => return false,
+    };
+    config
+        .upstream_redirect_hosts
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(host))
+}
+
 /// A structure that includes all of the data needed to stream a response back to the client.
 struct UpstreamResponse {
     stream: Box<UpstreamStream<reqwest::Error>>,
@@ -146,13 +399,13 @@ struct UpstreamResponse {
 ///
 /// This function will return on first byte received
 async fn start_poll_upstream(
-    backend: &Backend,
+    gs: &GlobalState,
     key: &ImageKey,
 ) -> Result<UpstreamResponse, Box<dyn std::error::Error>> {
     use std::str::FromStr;
 
-    let url = {
-        let info = backend.ping_info.load();
+    let mut url = {
+        let info = gs.backend.ping_info();
         let upstream_url = Option::as_ref(&info)
             .map(|x| &x.upstream_url)
             .ok_or(NoUpstreamError)?;
@@ -167,18 +420,63 @@ async fn start_poll_upstream(
             ))?
     };
 
-    let res = HTTP_CLIENT.get(url).send().await?;
+    // redirects are followed here instead of by the client, so where they lead can be checked
+    let mut redirects = 0;
+    let res = loop {
+        let res = HTTP_CLIENT.get(url.clone()).send().await?;
+        if !matches!(res.status().as_u16(), 301 | 302 | 303 | 307 | 308) {
+            break res;
+        }
+
+        gs.metrics.upstream_redirects_total.inc();
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| url.join(x).ok());
+        log::info!(
+            "upstream redirected {} to {}",
+            url,
+            location
+                .as_ref()
+                .map_or("<invalid location>", |x| x.as_str())
+        );
+
+        redirects += 1;
+        match location {
+            Some(next)
+                if redirects <= gs.config.upstream_max_redirects
+                    && is_redirect_allowed(&gs.config, &next) =>
+            {
+                url = next
+            }
+            location => {
+                gs.metrics.upstream_redirects_rejected_total.inc();
+                return Err(UpstreamRedirectError(location).into());
+            }
+        }
+    };
     let status = res.status();
 
+    // an empty image is most likely a truncated response, so it's retried like other failures
+    if status == StatusCode::OK && res.content_length() == Some(0) {
+        return Err(EmptyUpstreamError.into());
+    }
+
     // get the mime type from upstream, or try to guess
     let content_type = res
         .headers()
         .get(header::CONTENT_TYPE)
         .and_then(|x| x.to_str().ok())
         .and_then(|x| x.parse::<mime::Mime>().ok())
-        // if this entire process fails for whatever reason, then just assume that the image is a
-        // PNG and move on with life
-        .unwrap_or(mime::IMAGE_PNG);
+        .and_then(|x| content_type::bounded(x, gs.config.max_content_type_length))
+        // if this entire process fails for whatever reason, then just assume that the image is the
+        // usual type of its archive and move on with life
+        .unwrap_or_else(|| {
+            gs.config
+                .fallback_content_types
+                .for_archive(key.data_saver())
+        });
 
     // get the last modified date from upstream, or else just use now
     let last_modified = res
@@ -186,7 +484,7 @@ async fn start_poll_upstream(
         .get(header::LAST_MODIFIED)
         .and_then(|x| x.to_str().ok())
         .and_then(|x| HttpDate::from_str(x).ok())
-        .unwrap_or_else(|| HttpDate::from(time::SystemTime::now()));
+        .unwrap_or_else(|| HttpDate::from(gs.clock.now()));
 
     let size_hint = res.content_length().map(|x| x as usize);
     Ok(UpstreamResponse {
@@ -202,18 +500,19 @@ async fn start_poll_upstream(
 /// Will attempt to retry `start_poll_upstream` until a successful result is returned
 /// or the total requests meets/exceeds the `retry` parameter.
 async fn start_poll_upstream_retry(
-    backend: &Backend,
+    gs: &GlobalState,
     key: &ImageKey,
     retry: usize,
 ) -> Result<UpstreamResponse, Box<dyn std::error::Error>> {
     let mut count = 0;
     loop {
-        let res = start_poll_upstream(backend, key).await;
+        let res = start_poll_upstream(gs, key).await;
 
         // end the function with the result value if the result is good OR
-        // the counter exceeds retry
+        // the counter exceeds retry. a refused redirect would just be refused again
         count += 1;
-        if res.is_ok() || count >= retry {
+        let refused = matches!(&res, Err(e) if e.is::<UpstreamRedirectError>());
+        if res.is_ok() || refused || count >= retry {
             return res;
         }
 
@@ -235,17 +534,27 @@ async fn start_poll_upstream_retry(
 async fn handle_cache_miss(
     uid: &str,
     gs: &Arc<GlobalState>,
+    peer: Option<IpAddr>,
     key: ImageKey,
     req_start: Timer,
+    timing: &mut ServerTiming,
 ) -> HttpResponse {
+    // join the fetch if the image is already being streamed from upstream. it isn't in the cache
+    // until it's complete, so this is the only way to get it before then
+    let join = gs.config.concurrent_misses == ConcurrentMissPolicy::Join;
+    if let Some(fetch) = join.then(|| gs.fetches.join(&key)).flatten() {
+        log::debug!("({}) joining in-flight upstream fetch", uid);
+        return follow_fetch(gs, peer, fetch);
+    }
+
     // poll upstream, finding the total time of the request
     let res = {
         let timer = Timer::start();
-        let res = start_poll_upstream_retry(&gs.backend, &key, 3).await;
+        let res = start_poll_upstream_retry(gs, &key, 3).await;
         log::debug!("({}) upstream TTFB: {}", uid, timer);
-        gs.metrics
-            .upstream_ttfb_seconds
-            .observe(timer.elapsed_secs() as f64);
+        gs.sink
+            .observe_upstream_latency(timer.elapsed_secs() as f64);
+        timing.record("upstream", &timer);
         res
     };
     // handle any errors that happen with res
@@ -254,35 +563,676 @@ async fn handle_cache_miss(
         Err(e) => {
             log::error!("unexpected upstream error before download ({})", e);
             gs.metrics.failed_requests_total.inc();
-            return HttpResponse::BadGateway().body("unexpected upstream response");
+            return retry_after(
+                &mut HttpResponse::BadGateway(),
+                gs.config.upstream_retry_after,
+            )
+            .body("unexpected upstream response");
         }
     };
 
     // error handling for the status, make sure it's 200 OK
     match res.status {
         StatusCode::OK => {}
-        StatusCode::NOT_FOUND => return HttpResponse::NotFound().finish(),
+        // the image might show up upstream later, so the 404 is only cached for a short while
+        StatusCode::NOT_FOUND => {
+            return cache_for(&mut HttpResponse::NotFound(), gs.config.not_found_max_age).finish()
+        }
         status => {
             log::error!("unexpected upstream status ({})", status);
             gs.metrics.failed_requests_total.inc();
-            return HttpResponse::BadGateway()
-                .body(format!("invalid upstream status code: {}", status));
+            return retry_after(
+                &mut HttpResponse::BadGateway(),
+                gs.config.upstream_retry_after,
+            )
+            .body(format!("invalid upstream status code: {}", status));
         }
     }
 
-    // create the chunk stream
+    // refuse to proxy anything that upstream says is too large to be a valid image
+    if let Some(size) = res.size_hint.filter(|&x| x as u64 > MAX_RESPONSE_BYTES) {
+        log::error!(
+            "({}) upstream image over the maximum response size ({}b)",
+            uid,
+            size
+        );
+        gs.metrics.failed_requests_total.inc();
+        return HttpResponse::BadGateway().body("upstream image exceeds maximum response size");
+    }
+
+    // the bytes aren't available yet, so only the overrides can be applied (which also fixes the
+    // content type that's saved to the cache)
+    let content_type = content_type::correct(
+        uid,
+        &gs.config.content_type_overrides,
+        res.content_type,
+        None,
+    );
+
+    // create the chunk stream, letting requests for the image that come in meanwhile join it
+    let content_length = res.size_hint.map(|x| x as u64);
+    let shared = if join {
+        gs.fetches.start(
+            &key,
+            content_type.clone(),
+            res.last_modified,
+            content_length,
+        )
+    } else {
+        None
+    };
     let chunked = ChunkedUpstreamPoll::new(
         gs,
         key,
-        res.content_type.clone(),
+        content_type.clone(),
         res.stream,
         res.size_hint.unwrap_or(0),
         req_start,
     );
 
+    // a shared fetch is driven by a task of its own, so it isn't cut short if this request goes
+    // away before the requests that joined it are done. this request follows it like they do
+    if let Some(fetch) = shared {
+        let mut chunked = chunked.with_followers(Some(Arc::clone(&fetch)));
+        tokio::spawn(async move {
+            use futures::StreamExt;
+            // the chunks are sent through the shared fetch, the fetch fails once the stream errors
+            while let Some(Ok(_)) = chunked.next().await {}
+        });
+        return follow_fetch(gs, peer, fetch);
+    }
+
     // proxy the image to the client
-    HttpResponse::Ok()
-        .append_header(header::ContentType(res.content_type))
-        .append_header(header::LastModified(res.last_modified))
-        .streaming(chunked)
+    let chunked = egress_stream(gs, peer, chunked);
+    miss_response(content_type, res.last_modified, content_length, chunked)
+}
+
+/// Streams the image of an in-flight upstream fetch to a request that follows it
+fn follow_fetch(gs: &GlobalState, peer: Option<IpAddr>, fetch: Arc<SharedFetch>) -> HttpResponse {
+    let (content_type, last_modified) = (fetch.content_type.clone(), fetch.last_modified);
+    let content_length = fetch.content_length;
+    let body = egress_stream(gs, peer, fetch.follow());
+    miss_response(content_type, last_modified, content_length, body)
+}
+
+/// Builds the response to a cache MISS, with the same headers whether the request fetches the
+/// image itself or follows a fetch that's in progress
+fn miss_response<S>(
+    content_type: mime::Mime,
+    last_modified: HttpDate,
+    content_length: Option<u64>,
+    body: S,
+) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, actix_web::Error>> + Unpin + 'static,
+{
+    let mut res = HttpResponse::Ok();
+    res.encoding(ContentEncoding::Identity)
+        .append_header(header::ContentType(content_type))
+        .append_header(header::LastModified(last_modified))
+        .append_header(("Vary", "Accept-Encoding"));
+    match content_length {
+        Some(len) => res.body(SizedStream::new(len, body)),
+        None => res.streaming(body),
+    }
+}
+
+/// Fetches an image from upstream in its entirety and saves it to the cache, overwriting any
+/// existing entry. Returns the entry that was saved.
+pub(super) async fn refetch(
+    uid: &str,
+    gs: &GlobalState,
+    key: &ImageKey,
+) -> Result<ImageEntry, Box<dyn std::error::Error>> {
+    use futures::StreamExt;
+
+    let mut res = start_poll_upstream_retry(gs, key, 3).await?;
+    if res.status != StatusCode::OK {
+        return Err(format!("invalid upstream status code: {}", res.status).into());
+    }
+
+    // collect the entire image, refusing anything too large like a regular MISS would. the size
+    // upstream claims isn't trusted any further than that
+    if res
+        .size_hint
+        .filter(|&x| x as u64 > MAX_RESPONSE_BYTES)
+        .is_some()
+    {
+        return Err("upstream image exceeds maximum response size".into());
+    }
+    let capacity = res.size_hint.unwrap_or(0).min(MAX_RESPONSE_BYTES as usize);
+    let mut body = bytes::BytesMut::with_capacity(capacity);
+    while let Some(chunk) = res.stream.next().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) as u64 > MAX_RESPONSE_BYTES {
+            return Err("upstream image exceeds maximum response size".into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    if body.is_empty() {
+        return Err(EmptyUpstreamError.into());
+    }
+
+    let mime = content_type::correct(
+        uid,
+        &gs.config.content_type_overrides,
+        res.content_type,
+        Some(&body),
+    );
+    let bytes = body.freeze();
+    if !gs.cache.save(key, mime.to_string(), bytes.clone()).await {
+        return Err("unable to save image to cache".into());
+    }
+    Ok(ImageEntry::new(bytes, mime.to_string(), gs.clock.now()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::tests::use_upstream;
+    use crate::cache::tests::TestCache;
+    use crate::config::tests::config_with;
+    use crate::http::tests::{image_response, mock_upstream, mock_upstream_serving};
+    use actix_web::body::{BodySize, MessageBody};
+    use actix_web::test::TestRequest;
+    use bytes::Bytes;
+
+    fn test_key(data_saver: bool) -> ImageKey {
+        ImageKey::new("chapter".to_string(), "1.png".to_string(), data_saver)
+    }
+
+    #[test]
+    fn oversized_entry_is_refused() {
+        let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let req = TestRequest::default().to_http_request();
+
+        let entry = ImageEntry::new_assume(
+            Bytes::from(vec![0u8; MAX_RESPONSE_BYTES as usize + 1]),
+            "image/png".to_string(),
+        );
+        let res = handle_cache_hit("test", &gs, &req, &test_key(false), entry);
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+
+        let entry = ImageEntry::new_assume(Bytes::from(vec![0u8; 1024]), "image/png".to_string());
+        let res = handle_cache_hit("test", &gs, &req, &test_key(false), entry);
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn digest_header_matches_body() {
+        use sha2::Digest;
+        use sodiumoxide::base64;
+
+        let gs = GlobalState::for_tests(
+            config_with("digest_header: true\n"),
+            Box::new(TestCache::default()),
+        );
+        let req = TestRequest::default().to_http_request();
+        let body = Bytes::from(vec![7u8; 1024]);
+        let entry = ImageEntry::new_assume(body.clone(), "image/png".to_string());
+        let res = handle_cache_hit("test", &gs, &req, &test_key(false), entry);
+
+        let expected = format!(
+            "sha-256={}",
+            base64::encode(sha2::Sha256::digest(&body), base64::Variant::Original)
+        );
+        assert_eq!(res.headers().get("Digest").unwrap(), expected.as_str());
+    }
+
+    #[test]
+    fn age_follows_the_clock() {
+        use crate::utils::{Clock, TestClock};
+
+        let mut gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let clock = Arc::new(TestClock::new(std::time::SystemTime::now()));
+        Arc::get_mut(&mut gs).unwrap().clock = Arc::clone(&clock) as _;
+        let req = TestRequest::default().to_http_request();
+
+        let body = Bytes::from(vec![0u8; 1024]);
+        let entry = ImageEntry::new(body, "image/png".to_string(), clock.now());
+        let res = handle_cache_hit("test", &gs, &req, &test_key(false), entry.clone());
+        assert_eq!(res.headers().get(header::AGE).unwrap(), "0");
+
+        clock.advance(Duration::from_secs(90));
+        let res = handle_cache_hit("test", &gs, &req, &test_key(false), entry);
+        assert_eq!(res.headers().get(header::AGE).unwrap(), "90");
+    }
+
+    #[test]
+    fn hits_skip_compression() {
+        use actix_web::{middleware, test, web, App};
+
+        actix_web::rt::System::new().block_on(async {
+            let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+            let app = test::init_service(
+                App::new()
+                    .wrap(middleware::Compress::new(ContentEncoding::Gzip))
+                    .route(
+                        "/",
+                        web::get().to(move |req: HttpRequest| {
+                            let entry = ImageEntry::new_assume(
+                                Bytes::from(vec![0u8; 1024]),
+                                "image/png".into(),
+                            );
+                            let res = handle_cache_hit("test", &gs, &req, &test_key(false), entry);
+                            std::future::ready(res)
+                        }),
+                    ),
+            )
+            .await;
+            let req = TestRequest::get()
+                .insert_header((header::ACCEPT_ENCODING, "gzip"))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        });
+    }
+
+    #[test]
+    fn small_hits_are_sent_as_one_body() {
+        use actix_web::body::AnyBody;
+
+        let gs = GlobalState::for_tests(
+            config_with("small_hit_kibibytes: 4\n"),
+            Box::new(TestCache::default()),
+        );
+        let req = TestRequest::default().to_http_request();
+        let hit = |len| {
+            let entry = ImageEntry::new_assume(Bytes::from(vec![0u8; len]), "image/png".into());
+            handle_cache_hit("test", &gs, &req, &test_key(false), entry).into_body()
+        };
+
+        assert!(matches!(hit(4096), AnyBody::Bytes(b) if b.len() == 4096));
+        assert!(matches!(hit(4097), AnyBody::Message(_)));
+    }
+
+    #[test]
+    fn conditional_hits_are_not_modified() {
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let saved = UNIX_EPOCH + Duration::from_millis(1_000_000_500);
+        let entry = ImageEntry::new(Bytes::from_static(b"image"), "image/png".into(), saved);
+        let etag = format!("\"{}\"", entry.get_checksum_hex());
+        let status = |headers: &[(header::HeaderName, String)]| {
+            let mut req = TestRequest::default();
+            for (name, value) in headers {
+                req = req.insert_header((name.clone(), value.clone()));
+            }
+            let res = handle_cache_hit(
+                "test",
+                &gs,
+                &req.to_http_request(),
+                &test_key(false),
+                entry.clone(),
+            );
+            res.status()
+        };
+        let date = |t: SystemTime| HttpDate::from(t).to_string();
+        let none_match = header::IF_NONE_MATCH;
+        let modified_since = header::IF_MODIFIED_SINCE;
+
+        assert_eq!(status(&[]), StatusCode::OK);
+        assert_eq!(
+            status(&[(none_match.clone(), etag.clone())]),
+            StatusCode::NOT_MODIFIED
+        );
+        assert_eq!(
+            status(&[(none_match.clone(), "\"other\"".to_string())]),
+            StatusCode::OK
+        );
+        // the date is only precise to the second the image was saved in
+        assert_eq!(
+            status(&[(modified_since.clone(), date(saved))]),
+            StatusCode::NOT_MODIFIED
+        );
+        let before = saved - Duration::from_secs(1);
+        assert_eq!(
+            status(&[(modified_since.clone(), date(before))]),
+            StatusCode::OK
+        );
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(
+            status(&[
+                (none_match, "\"other\"".to_string()),
+                (modified_since, date(saved)),
+            ]),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn ranges_are_served_from_hits() {
+        use actix_web::body::AnyBody;
+
+        let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let hit = |range: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(range) = range {
+                req = req.insert_header((header::RANGE, range));
+            }
+            let entry =
+                ImageEntry::new_assume(Bytes::from_static(b"0123456789"), "image/png".into());
+            handle_cache_hit("test", &gs, &req.to_http_request(), &test_key(false), entry)
+        };
+        let header = |res: &HttpResponse, name| res.headers().get(name).cloned();
+
+        let res = hit(None);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, header::ACCEPT_RANGES).unwrap(), "bytes");
+
+        let res = hit(Some("bytes=2-5"));
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header(&res, header::CONTENT_RANGE).unwrap(), "bytes 2-5/10");
+        assert!(matches!(res.into_body(), AnyBody::Bytes(b) if b == "2345"));
+
+        let res = hit(Some("bytes=20-"));
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header(&res, header::CONTENT_RANGE).unwrap(), "bytes */10");
+        assert!(header(&res, header::CONTENT_TYPE).is_none());
+        assert!(header(&res, header::ETAG).is_none());
+
+        // several ranges at once get the whole image
+        let res = hit(Some("bytes=0-1,4-5"));
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(header(&res, header::CONTENT_RANGE).is_none());
+    }
+
+    #[tokio::test]
+    async fn upstream_failure_has_retry_after() {
+        let gs = GlobalState::for_tests(
+            config_with("upstream_retry_after: 7\n"),
+            Box::new(TestCache::default()),
+        );
+        // the backend hasn't been pinged, so there's no upstream to poll
+        let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+        let res = handle_cache_miss(
+            "test",
+            &gs,
+            None,
+            key,
+            Timer::start(),
+            &mut ServerTiming::default(),
+        )
+        .await;
+
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "7");
+    }
+
+    #[tokio::test]
+    async fn upstream_not_found_is_cached_briefly() {
+        let mut gs = GlobalState::for_tests(
+            config_with("not_found_max_age: 60\n"),
+            Box::new(TestCache::default()),
+        );
+        let not_found = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        use_upstream(&mut gs, mock_upstream(not_found.to_vec()));
+
+        let res = handle_cache_miss(
+            "test",
+            &gs,
+            None,
+            test_key(false),
+            Timer::start(),
+            &mut ServerTiming::default(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=60"
+        );
+    }
+
+    #[test]
+    fn mislabeled_content_type_is_corrected() {
+        let gs = GlobalState::for_tests(
+            config_with("content_type_overrides:\n    application/x-jpg: image/jpeg\n"),
+            Box::new(TestCache::default()),
+        );
+        let req = TestRequest::default().to_http_request();
+        let content_type = |mime: &str| {
+            let entry = ImageEntry::new_assume(Bytes::from_static(b"??"), mime.to_string());
+            let res = handle_cache_hit("test", &gs, &req, &test_key(false), entry);
+            res.headers().get(header::CONTENT_TYPE).unwrap().clone()
+        };
+
+        assert_eq!(content_type("application/x-jpg"), "image/jpeg");
+        assert_eq!(content_type("image/png"), "image/png");
+    }
+
+    #[test]
+    fn corrupted_content_type_falls_back_by_archive() {
+        let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let req = TestRequest::default().to_http_request();
+        let content_type = |data_saver| {
+            let entry = ImageEntry::new_assume(Bytes::from_static(b"??"), "\0garbage".to_string());
+            let res = handle_cache_hit("test", &gs, &req, &test_key(data_saver), entry);
+            res.headers().get(header::CONTENT_TYPE).unwrap().clone()
+        };
+
+        assert_eq!(content_type(true), "image/jpeg");
+        assert_eq!(content_type(false), "image/png");
+    }
+
+    #[tokio::test]
+    async fn empty_upstream_body_is_a_failure() {
+        let mut gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let key = test_key(false);
+        let upstream = mock_upstream(image_response(b""));
+        use_upstream(&mut gs, upstream);
+
+        let res = handle_cache_miss(
+            "test",
+            &gs,
+            None,
+            key.clone(),
+            Timer::start(),
+            &mut ServerTiming::default(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert!(gs.cache.load(&key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn hits_and_misses_reach_the_sink() {
+        use crate::metrics::tests::CapturingSink;
+
+        let mut gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let sink = Arc::new(CapturingSink::default());
+        Arc::get_mut(&mut gs).unwrap().sink = Arc::clone(&sink) as _;
+        let upstream = mock_upstream(image_response(b"image bytes"));
+        use_upstream(&mut gs, upstream);
+        let req = TestRequest::default().to_http_request();
+
+        // the first request is a MISS, which is only recorded once the body has been streamed
+        let res = response_from_cache(
+            "test",
+            &req,
+            &gs,
+            test_key(false),
+            Timer::start(),
+            ServerTiming::default(),
+        )
+        .await;
+        actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(sink.take(), ["upstream_latency", "miss"]);
+
+        // wait for the cache save of the MISS to land
+        while gs.cache.load(&test_key(false)).await.is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let res = response_from_cache(
+            "test",
+            &req,
+            &gs,
+            test_key(false),
+            Timer::start(),
+            ServerTiming::default(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(sink.take(), ["hit"]);
+    }
+
+    #[tokio::test]
+    async fn pass_through_never_touches_cache() {
+        let mut config = config_with("");
+        config.pass_through = true;
+        let cache = TestCache::default();
+        let (loads, saves) = (Arc::clone(&cache.loads), Arc::clone(&cache.saves));
+        let mut gs = GlobalState::for_tests(config, Box::new(cache));
+        use_upstream(
+            &mut gs,
+            mock_upstream_serving(image_response(b"image bytes"), 2),
+        );
+        let req = TestRequest::default().to_http_request();
+
+        for _ in 0..2 {
+            let res = response_from_cache(
+                "test",
+                &req,
+                &gs,
+                test_key(false),
+                Timer::start(),
+                ServerTiming::default(),
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+            assert_eq!(body, &b"image bytes"[..]);
+        }
+
+        // give a cache save the chance to run, if one was (wrongly) spawned
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(loads.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert_eq!(saves.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_one_fetch() {
+        use std::io::{Read, Write};
+
+        let mut gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        // an upstream that only serves a single request, sending the second half of the image
+        // after a pause
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            let res = image_response(b"first half, second half");
+            let (first, second) = res.split_at(res.len() - 11);
+            stream.write_all(first).unwrap();
+            stream.flush().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            stream.write_all(second).unwrap();
+        });
+        use_upstream(&mut gs, format!("http://{}", addr).parse().unwrap());
+        let req = TestRequest::default().to_http_request();
+
+        let mut bodies = Vec::new();
+        for _ in 0..3 {
+            let res = response_from_cache(
+                "test",
+                &req,
+                &gs,
+                test_key(false),
+                Timer::start(),
+                ServerTiming::default(),
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            // the length is sent as Content-Length, like it is for a HIT
+            assert_eq!(res.body().size(), BodySize::Sized(23));
+            assert_eq!(res.headers().get("Vary").unwrap(), "Accept-Encoding");
+            bodies.push(actix_web::body::to_bytes(res.into_body()));
+        }
+
+        // the request that started the fetch going away doesn't cut it short for the others
+        let third = bodies.pop().unwrap();
+        let second = bodies.pop().unwrap();
+        drop(bodies);
+        let (second, third) = tokio::join!(second, third);
+        assert_eq!(second.unwrap(), &b"first half, second half"[..]);
+        assert_eq!(third.unwrap(), &b"first half, second half"[..]);
+
+        // the image is only saved once, after which the fetch can't be joined anymore
+        while gs.fetches.join(&test_key(false)).is_some() {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        assert!(gs.cache.load(&test_key(false)).await.is_some());
+    }
+
+    /// Creates a raw redirect response for a mock upstream, pointing at `location`
+    fn redirect_response(location: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\
+            Connection: close\r\n\r\n",
+            location
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn allowed_redirect_is_followed() {
+        let mut gs = GlobalState::for_tests(
+            config_with("upstream_redirect_hosts: [localhost]\n"),
+            Box::new(TestCache::default()),
+        );
+        let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+
+        // the image lives on a different host than the upstream that redirects to it
+        let mut target = mock_upstream(image_response(b"redirected"));
+        target.set_host(Some("localhost")).unwrap();
+        let target = target.join("/data/chapter/1.png").unwrap();
+        let upstream = mock_upstream(redirect_response(target.as_str()));
+        use_upstream(&mut gs, upstream);
+
+        let entry = refetch("test", &gs, &key).await.unwrap();
+        assert_eq!(entry.get_bytes(), &b"redirected"[..]);
+        assert!(gs.cache.load(&key).await.is_some());
+        assert_eq!(gs.metrics.upstream_redirects_total.get(), 1);
+        assert_eq!(gs.metrics.upstream_redirects_rejected_total.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn off_allowlist_redirect_is_rejected() {
+        let mut gs = GlobalState::for_tests(
+            config_with("upstream_redirect_hosts: [example.com]\n"),
+            Box::new(TestCache::default()),
+        );
+        let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+
+        let upstream = mock_upstream(redirect_response("http://localhost:1/data/chapter/1.png"));
+        use_upstream(&mut gs, upstream);
+
+        match refetch("test", &gs, &key).await {
+            Err(e) => assert!(e.is::<UpstreamRedirectError>()),
+            Ok(_) => panic!("redirect to a host that isn't allowed was followed"),
+        }
+        assert!(gs.cache.load(&key).await.is_none());
+        assert_eq!(gs.metrics.upstream_redirects_rejected_total.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn refetch_refuses_huge_content_length() {
+        let mut gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let key = ImageKey::new("chapter".to_string(), "1.png".to_string(), false);
+
+        // a whole TiB would be allocated up front if the length was trusted
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\
+            Content-Length: 1099511627776\r\nConnection: close\r\n\r\nimage";
+        use_upstream(&mut gs, mock_upstream(response.to_vec()));
+
+        match refetch("test", &gs, &key).await {
+            Err(e) => assert!(e.to_string().contains("maximum response size")),
+            Ok(_) => panic!("oversized refetch was accepted"),
+        }
+        assert!(gs.cache.load(&key).await.is_none());
+    }
 }
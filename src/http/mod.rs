@@ -1,17 +1,40 @@
 use crate::backend::TlsPayload;
-use crate::cache::ImageKey;
+use crate::cache::{ImageCache, ImageKey};
+use crate::config::{
+    AllowOrigin, AppConfig, Compression, TokenPolicy, UnknownPeerPolicy, SUPPORTED_ALPN,
+};
 use crate::utils::{self, constants as c};
 use crate::GlobalState;
 use actix_web::{
-    dev, error, http, middleware, web, App, HttpRequest, HttpResponse, HttpServer,
-    Result as WebResult,
+    dev, error, http, middleware, web, App, HttpRequest, HttpResponse, HttpResponseBuilder,
+    HttpServer, Result as WebResult,
 };
+use futures::future::{self, Either};
 use openssl::ssl;
+use openssl::x509::{X509VerifyResult, X509};
+use std::future::Future;
 use std::io;
+use std::net::IpAddr;
 use std::sync::{atomic, Arc};
 
+mod admin;
 mod chunked;
+mod connections;
+mod content_type;
+mod egress;
+mod errors;
+mod flight;
 mod handler;
+mod quota;
+mod range;
+#[cfg(feature = "tls-rustls")]
+mod tls_rustls;
+
+pub use connections::RequestLimit;
+pub use egress::EgressLimiter;
+pub use flight::InFlightFetches;
+pub use handler::HitBench;
+pub use quota::ByteQuota;
 
 #[derive(serde::Deserialize)]
 struct MdPathArgs {
@@ -21,6 +44,20 @@ struct MdPathArgs {
     image: String,
 }
 
+/// Counts a request as in-flight for as long as the guard is alive
+struct InFlightGuard<'a>(&'a atomic::AtomicUsize);
+impl<'a> InFlightGuard<'a> {
+    fn new(counter: &'a atomic::AtomicUsize) -> Self {
+        counter.fetch_add(1, atomic::Ordering::Relaxed);
+        Self(counter)
+    }
+}
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
 /// Request handler for the Actix web server
 ///
 /// This is the main portion of the program, as it takes requests, verifies tokens, and then
@@ -37,11 +74,20 @@ async fn md_service(
     gs: web::Data<Arc<GlobalState>>,
 ) -> WebResult<HttpResponse> {
     let req_start = utils::Timer::start();
+    let _in_flight = InFlightGuard::new(&gs.in_flight);
     let peer_addr = req
         .connection_info()
         .realip_remote_addr()
         .map(|x| x.to_string())
-        .unwrap_or_else(|| "-".to_string());
+        .unwrap_or_else(|| "unknown peer".to_string());
+
+    // the allowlist uses the address of the connection itself, since the "real ip" can be spoofed
+    // with forwarding headers
+    if !is_peer_allowed(&gs.config, req.peer_addr().map(|x| x.ip())) {
+        log::debug!("({}) peer is not in the allowlist", peer_addr);
+        gs.metrics.dropped_requests_total.inc();
+        return Err(error::ErrorForbidden("peer is not allowed"));
+    }
 
     // debug log the User-Agent header (or '-' if it isn't provided`)
     if log::log_enabled!(log::Level::Debug) {
@@ -52,6 +98,28 @@ async fn md_service(
         log::debug!("({}) User-Agent: {}", peer_addr, user_agent.unwrap_or("-"));
     }
 
+    // refuse peers that were already sent their quota of bytes until enough of it frees up
+    let quota = gs.byte_quota.as_ref().zip(req.peer_addr());
+    if let Some(remaining) = quota.and_then(|(quota, peer)| quota.exhausted(peer.ip())) {
+        log::debug!("({}) peer exceeded their byte quota", peer_addr);
+        gs.metrics.dropped_requests_total.inc();
+        return Ok(retry_after(
+            &mut HttpResponse::TooManyRequests(),
+            remaining.as_secs().max(1) as u32,
+        )
+        .body("byte quota exceeded"));
+    }
+
+    // images can't be served until the cache has been initialized
+    if !gs.cache.is_ready() {
+        gs.metrics.dropped_requests_total.inc();
+        return Ok(retry_after(
+            &mut HttpResponse::ServiceUnavailable(),
+            gs.config.initializing_retry_after,
+        )
+        .body("cache is initializing"));
+    }
+
     // stop early if archive type is not valid
     if path.archive_type != "data" && path.archive_type != "data-saver" {
         let fmt = format!(
@@ -63,9 +131,36 @@ async fn md_service(
     }
     let saver = path.archive_type == "data-saver";
 
-    // verify the token provided in the request url if verify tokens is enabled
-    if !gs.config.skip_tokens {
-        // unlock verifier mutex
+    // refuse paths that can't possibly be an image before verifying tokens or looking in the cache
+    if !is_well_formed(&path.chap_hash, &path.image) {
+        log::debug!("({}) refusing malformed image path", peer_addr);
+        gs.metrics.dropped_requests_total.inc();
+        return Err(error::ErrorBadRequest(
+            "malformed chapter hash or image name",
+        ));
+    }
+
+    // refuse image types that can't be served before doing any work on them
+    if let Some(ext) = unsupported_extension(&gs.config, &path.image) {
+        log::warn!(
+            "({}) refusing image with unsupported type \"{}\"",
+            peer_addr,
+            ext
+        );
+        gs.metrics.dropped_requests_total.inc();
+        return Err(error::ErrorUnsupportedMediaType(format!(
+            "unsupported image type \"{}\", must be one of {:?}",
+            ext, gs.config.allowed_extensions
+        )));
+    }
+
+    // verify the token provided in the request url unless the token policy disables it
+    let mut timing = handler::ServerTiming::new(gs.config.server_timing_header);
+    let mut verified = None;
+    let policy = gs.config.token_policy;
+    if policy != TokenPolicy::Disabled {
+        let timer = utils::Timer::start();
+        // load the current verifier (lock-free, so a panicking request can't poison it for others)
         let verifier = gs.verifier.load();
 
         match path
@@ -74,7 +169,15 @@ async fn md_service(
             .map(|token| verifier.verify_url_token(token, &path.chap_hash))
         {
             // result is good, so bypass
-            Some(Ok(_)) => {}
+            Some(Ok(token)) => {
+                log::trace!(
+                    "({}) token for client {} expires in {}s",
+                    peer_addr,
+                    token.client_id,
+                    token.time_to_expiry().as_secs()
+                );
+                verified = Some(token);
+            }
 
             // there was an error with the token, so transform into response and return
             Some(Err(e)) => {
@@ -83,26 +186,103 @@ async fn md_service(
                 return Err(e.into());
             }
 
+            // no token was provided, which is fine if tokens are optional
+            None if policy == TokenPolicy::Optional => {
+                log::trace!("({}) serving request without a token", peer_addr);
+            }
+
             // no token was even provided, so just say request is unauthorized
             None => {
                 gs.metrics.dropped_requests_total.inc();
                 return Err(error::ErrorUnauthorized("no token provided"));
             }
         }
+        timing.record("verify", &timer);
     }
 
     // increment request counter
     // only count requests if they've made it past token verification
     gs.request_counter.fetch_add(1, atomic::Ordering::Relaxed);
+    gs.sink.record_request();
 
     // respond using CacheResponder, which will handle cache HITs and MISSes
     let args = path.into_inner();
     let cache_key = ImageKey::new(args.chap_hash, args.image, saver);
-    Ok(handler::response_from_cache(&peer_addr, &req, &gs, cache_key, req_start).await)
+    if let Some(hot_keys) = &gs.hot_keys {
+        hot_keys.record(&cache_key);
+    }
+    let mut res =
+        handler::response_from_cache(&peer_addr, &req, &gs, cache_key, req_start, timing).await;
+    if let Some(token) = verified.filter(|_| gs.config.token_expiry_header) {
+        if let Ok(value) = http::HeaderValue::from_str(&token.expires.to_rfc3339()) {
+            res.headers_mut()
+                .insert(http::HeaderName::from_static("x-token-expires"), value);
+        }
+    }
+    Ok(res)
+}
+
+/// Whether a chapter hash and image name could belong to a real image. Chapter hashes are always
+/// 32 lowercase hex characters, and image names are short and only made of letters, digits, `-`,
+/// `_` and `.`.
+fn is_well_formed(chap_hash: &str, image: &str) -> bool {
+    const MAX_IMAGE_LEN: usize = 128;
+
+    let hash_ok = chap_hash.len() == 32
+        && chap_hash
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    let image_ok = !image.is_empty()
+        && image.len() <= MAX_IMAGE_LEN
+        && !image.starts_with('.')
+        && image
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    hash_ok && image_ok
+}
+
+/// Returns the extension of `image` if it isn't one of the allowed extensions. Images without an
+/// extension, and all images while no extensions are configured, are let through.
+fn unsupported_extension<'a>(config: &AppConfig, image: &'a str) -> Option<&'a str> {
+    let (_, ext) = image.rsplit_once('.')?;
+    let allowed = config.allowed_extensions.is_empty()
+        || config
+            .allowed_extensions
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(ext));
+    (!allowed).then_some(ext)
+}
+
+/// Whether a peer is allowed to make requests by the IP allowlist (if configured). Peers without
+/// a known address are handled according to the configured [`UnknownPeerPolicy`].
+fn is_peer_allowed(config: &AppConfig, peer: Option<IpAddr>) -> bool {
+    let allowlist = match &config.ip_allowlist {
+        Some(x) => x,
+        None => return true,
+    };
+    match peer {
+        Some(ip) => allowlist.iter().any(|net| net.contains(&ip)),
+        None => config.unknown_peer_policy == UnknownPeerPolicy::Allow,
+    }
 }
 
+/// Registers the prometheus metrics routes, if they're compiled in
+#[cfg(feature = "metrics")]
+fn metrics_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/prometheus", web::get().to(prom_service))
+        .route("/metrics", web::get().to(prom_service));
+}
+#[cfg(not(feature = "metrics"))]
+fn metrics_routes(_: &mut web::ServiceConfig) {}
+
 /// Prometheus metrics endpoint
-async fn prom_service(gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
+#[cfg(feature = "metrics")]
+async fn prom_service(req: HttpRequest, gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
+    if !gs.config.metrics_enabled {
+        return not_found_service(req, gs);
+    }
+    gs.metrics.set_warmth(&gs.warmth());
+    gs.metrics.set_cache_stats(&gs.cache.stats());
     match gs.metrics.encode_to_string() {
         Ok(s) => HttpResponse::Ok().body(s),
         Err(e) => {
@@ -111,6 +291,57 @@ async fn prom_service(gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
     }
 }
 
+/// Readiness endpoint for load balancers, which fails while the cache is initializing or the
+/// client is in lame duck mode
+async fn ready_service(gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
+    match unavailable_reason(&gs) {
+        Some((reason, secs)) => {
+            retry_after(&mut HttpResponse::ServiceUnavailable(), secs).body(reason)
+        }
+        None => HttpResponse::Ok().body("ready"),
+    }
+}
+
+/// Health endpoint for orchestration, which fails like the readiness endpoint and makes sure the
+/// cache still answers. Acts as if it doesn't exist unless `health_endpoint` is enabled.
+async fn health_service(req: HttpRequest, gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
+    if !gs.config.health_endpoint {
+        return not_found_service(req, gs);
+    }
+    if let Some((reason, secs)) = unavailable_reason(&gs) {
+        return retry_after(&mut HttpResponse::ServiceUnavailable(), secs).body(reason);
+    }
+    let size = gs.cache.report();
+    HttpResponse::Ok().body(format!("healthy, cache size {}B", size))
+}
+
+/// Why the client can't serve images right now (if it can't), along with how many seconds to wait
+/// before asking again
+fn unavailable_reason(gs: &GlobalState) -> Option<(&'static str, u32)> {
+    if !gs.cache.is_ready() {
+        Some(("cache is initializing", gs.config.initializing_retry_after))
+    } else if !gs.ready.load(atomic::Ordering::SeqCst) {
+        Some(("shutting down", gs.config.shutting_down_retry_after))
+    } else {
+        None
+    }
+}
+
+/// Tells the client to retry the request after `secs` seconds using the `Retry-After` header (in
+/// the delta-seconds format). Used for every response caused by a transient condition, like
+/// upstream failures or the client being overloaded.
+fn retry_after(res: &mut HttpResponseBuilder, secs: u32) -> &mut HttpResponseBuilder {
+    res.insert_header((http::header::RETRY_AFTER, secs.to_string()))
+}
+
+/// Lets the response be cached for `secs` instead of the default lifetime of images
+fn cache_for(res: &mut HttpResponseBuilder, secs: u32) -> &mut HttpResponseBuilder {
+    res.insert_header((
+        http::header::CACHE_CONTROL,
+        format!("public, max-age={}", secs),
+    ))
+}
+
 /// Default endpoint (404)
 fn not_found_service(req: HttpRequest, gs: web::Data<Arc<GlobalState>>) -> HttpResponse {
     log::warn!("request for invalid path: {}", req.path());
@@ -129,12 +360,28 @@ impl std::fmt::Display for PortBindError {
 }
 impl std::error::Error for PortBindError {}
 
+/// The encoding that the compression middleware uses for responses the client accepts it for
+fn content_encoding(compression: Compression) -> http::ContentEncoding {
+    match compression {
+        Compression::None => http::ContentEncoding::Identity,
+        Compression::Gzip => http::ContentEncoding::Gzip,
+        Compression::Br => http::ContentEncoding::Br,
+        Compression::Auto => http::ContentEncoding::Auto,
+    }
+}
+
+/// The TLS configuration that the HTTP server is spawned with
+#[cfg(not(feature = "tls-rustls"))]
+type Acceptor = ssl::SslAcceptorBuilder;
+#[cfg(feature = "tls-rustls")]
+type Acceptor = rustls::ServerConfig;
+
 /// Spawns an Actix HTTP server in this thread with the Ssl Acceptor provided
 ///
 /// This will bind to the port provided in the configuration using OpenSSL.
 fn spawn_http_server(
     gs: Arc<GlobalState>,
-    acceptor: ssl::SslAcceptorBuilder,
+    acceptor: Acceptor,
 ) -> Result<dev::Server, PortBindError> {
     // obtain config options
     let server_info = format!(
@@ -144,34 +391,31 @@ fn spawn_http_server(
         spec = c::SPEC,
         url = c::REPO_URL
     );
-    let ad_headers = !gs.config.disable_ad_headers;
+    let encoding = content_encoding(gs.config.compression);
     let bind_addr = format!("{}:{}", &gs.config.bind_address, gs.config.port);
     let data = web::Data::new(Arc::clone(&gs));
+    let payload_config = match gs.config.max_request_size_kibibytes {
+        Some(max) => web::PayloadConfig::new(max * 1024),
+        None => web::PayloadConfig::default(),
+    };
 
     // initialize server object
     let mut server = HttpServer::new(move || {
-        let mut default_headers = middleware::DefaultHeaders::new()
-            // Headers required by client spec
-            .header("X-Content-Type-Options", "nosniff")
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Expose-Headers", "*")
-            .header("Access-Control-Expose-Methods", "GET")
-            .header("Cache-Control", "public, max-age=1209600")
-            .header("Timing-Allow-Origin", "*");
-        // include Advertisement headers if enabled in configuration
-        if ad_headers {
-            default_headers = default_headers
-                .header("Server", &server_info)
-                .header("X-Powered-By", "Actix Web")
-                .header("X-Version", c::VERSION)
-        }
-
         App::new()
             .app_data(data.clone())
-            .wrap(default_headers)
+            .app_data(payload_config.clone())
+            .wrap_fn(limit_request_size)
+            .wrap(errors::normalize())
+            .wrap_fn(close_connections)
+            .wrap_fn(reflect_origin)
+            .wrap(default_headers(&data.config, &server_info))
+            .wrap(middleware::Compress::new(encoding))
             .wrap(
                 middleware::Logger::new("(%a) \"%r\" (status = %s, size = %bb) in %Dms")
-                    .exclude("/prometheus"),
+                    .exclude("/prometheus")
+                    .exclude("/metrics")
+                    .exclude("/ready")
+                    .exclude("/health"),
             )
             // regular MD@Home routes
             .route(
@@ -182,58 +426,230 @@ fn spawn_http_server(
                 "/{archive_type}/{chap_hash}/{image}", // untokenized route
                 web::get().to(md_service),
             )
-            // Prom metrics route
-            .route("/prometheus", web::get().to(prom_service))
+            // Prom metrics routes (a single segment, so they never shadow image routes)
+            .configure(metrics_routes)
+            // Readiness route for load balancers
+            .route("/ready", web::get().to(ready_service))
+            // Health route for orchestration (a single segment, so it never shadows image routes)
+            .route("/health", web::get().to(health_service))
+            // Admin routes (disabled unless admin_token is configured)
+            .configure(admin::configure)
             .default_service(web::route().to(not_found_service))
     })
     .keep_alive(gs.config.keep_alive)
     .shutdown_timeout(60)
     .disable_signals();
 
-    // manually set worker thread count to config amount
-    if let Some(worker_threads) = gs.config.worker_threads {
-        server = server.workers(worker_threads);
+    // set the worker thread count based on the config and the hardware
+    let cores = std::thread::available_parallelism().map_or(1, |x| x.get());
+    server = server.workers(worker_count(&gs.config, cores));
+
+    // cap the TLS handshakes in progress on each worker, so a flood of new connections can't take
+    // all of the CPU from the established ones. connections past the cap wait to be accepted
+    if let Some(max) = gs.config.max_concurrent_handshakes {
+        server = server.max_connection_rate(max);
+    }
+    if let Some(max) = gs.config.max_connections {
+        server = server.max_connections(max);
     }
 
     if gs.config.disable_ssl {
         server.bind(&bind_addr)
     } else {
-        server.bind_openssl(&bind_addr, acceptor)
+        #[cfg(not(feature = "tls-rustls"))]
+        let server = server.bind_openssl(&bind_addr, acceptor);
+        #[cfg(feature = "tls-rustls")]
+        let server = server.bind_rustls(&bind_addr, acceptor);
+        server
     }
     .map_err(PortBindError)
     .map(|s| s.run())
 }
 
+/// Creates the middleware that adds the headers every response should have
+fn default_headers(config: &AppConfig, server_info: &str) -> middleware::DefaultHeaders {
+    let mut default_headers = middleware::DefaultHeaders::new()
+        // Headers required by client spec
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Access-Control-Expose-Headers", "*")
+        .header("Access-Control-Expose-Methods", "GET")
+        .header("Cache-Control", "public, max-age=1209600")
+        .header("Timing-Allow-Origin", config.timing_allow_origin.as_str());
+    // a list of origins depends on the request instead (see `reflect_origin`)
+    if let AllowOrigin::Single(origin) = &config.allow_origin {
+        default_headers = default_headers.header("Access-Control-Allow-Origin", origin.as_str());
+    }
+    // include Advertisement headers if enabled in configuration
+    if !config.disable_ad_headers {
+        default_headers = default_headers
+            .header("Server", server_info)
+            .header("X-Powered-By", "Actix Web")
+            .header("X-Version", c::VERSION)
+    }
+    default_headers
+}
+
+/// Middleware that allows the origin of a request to read the response if it's in the configured
+/// list of origins. Responses vary by origin then, so they're marked as such for shared caches.
+fn reflect_origin<S, B>(
+    req: dev::ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = WebResult<dev::ServiceResponse<B>>>
+where
+    S: dev::Service<dev::ServiceRequest, Response = dev::ServiceResponse<B>, Error = error::Error>,
+{
+    let allowed = match req
+        .app_data::<web::Data<Arc<GlobalState>>>()
+        .map(|gs| &gs.config.allow_origin)
+    {
+        Some(AllowOrigin::List(allowed)) => Some(
+            req.headers()
+                .get(http::header::ORIGIN)
+                .filter(|x| allowed.iter().any(|a| a.as_bytes() == x.as_bytes()))
+                .cloned(),
+        ),
+        _ => None,
+    };
+
+    let res = srv.call(req);
+    async move {
+        let mut res = res.await?;
+        if let Some(origin) = allowed {
+            let headers = res.headers_mut();
+            headers.append(http::header::VARY, http::HeaderValue::from_static("Origin"));
+            if let Some(origin) = origin {
+                headers.insert(http::header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Middleware that asks clients to close their connection after the response while connections are
+/// being drained (so they don't reuse a connection that's about to be cut off), or once the
+/// connection made the most requests it's allowed to
+fn close_connections<S, B>(
+    req: dev::ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = WebResult<dev::ServiceResponse<B>>>
+where
+    S: dev::Service<dev::ServiceRequest, Response = dev::ServiceResponse<B>, Error = error::Error>,
+{
+    // requests are counted as they come in, so pipelined requests are counted in order
+    let gs = req.app_data::<web::Data<Arc<GlobalState>>>().cloned();
+    let last_request = gs
+        .as_ref()
+        .and_then(|gs| gs.request_limit.as_ref())
+        .zip(req.peer_addr())
+        .is_some_and(|(limit, peer)| limit.record(peer));
+
+    let res = srv.call(req);
+    async move {
+        let mut res = res.await?;
+        let draining = gs.is_some_and(|gs| gs.draining.load(atomic::Ordering::SeqCst));
+        if draining || last_request {
+            res.response_mut()
+                .head_mut()
+                .set_connection_type(http::ConnectionType::Close);
+        }
+        Ok(res)
+    }
+}
+
+/// Middleware that rejects requests with a body larger than `max_request_size_kibibytes` before
+/// they're handled. It comes before the error normalization, so the rejection is normalized too.
+fn limit_request_size<S>(
+    req: dev::ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = WebResult<dev::ServiceResponse>>
+where
+    S: dev::Service<dev::ServiceRequest, Response = dev::ServiceResponse, Error = error::Error>,
+{
+    let max = req
+        .app_data::<web::Data<Arc<GlobalState>>>()
+        .and_then(|gs| gs.config.max_request_size_kibibytes);
+    let len = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<usize>().ok());
+
+    match max.zip(len) {
+        Some((max, len)) if len > max * 1024 => {
+            let res = HttpResponse::PayloadTooLarge().body("request is too large");
+            Either::Right(future::ok(req.into_response(res)))
+        }
+        _ => Either::Left(srv.call(req)),
+    }
+}
+
+/// Finds the number of HTTP worker threads to spawn on a machine with `cores` logical cores.
+///
+/// Uses the configured `worker_threads` if set, otherwise the core count scaled by
+/// `worker_threads_multiplier` (at least one), capped at `max_worker_threads`.
+fn worker_count(config: &AppConfig, cores: usize) -> usize {
+    if let Some(worker_threads) = config.worker_threads {
+        return worker_threads;
+    }
+
+    let scaled = ((cores as f64 * config.worker_threads_multiplier).round() as usize).max(1);
+    match config.max_worker_threads {
+        Some(max) => scaled.min(max),
+        None => scaled,
+    }
+}
+
 /// Error that represents all of the addressable errors of creating the HTTP Server.
 #[derive(Debug)]
 pub enum Error {
     Acceptor(ssl::Error),
+    /// The TLS payload from the backend didn't contain a usable certificate
+    Certificate(&'static str),
     Port(PortBindError),
+    /// The HTTP server was stopped and neither the new nor the previous server could be spawned,
+    /// so the client isn't serving anything anymore
+    Down(Box<Error>),
+}
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        Self::Acceptor(e.into())
+    }
 }
 impl std::fmt::Display for Error {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Acceptor(e) => write!(fmt, "{}", e),
+            Self::Certificate(e) => write!(fmt, "invalid certificate: {}", e),
             Self::Port(e) => write!(fmt, "{}", e),
+            Self::Down(e) => write!(fmt, "HTTP server is down and couldn't be restored: {}", e),
         }
     }
 }
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(match self {
-            Self::Acceptor(e) => e,
-            Self::Port(e) => e,
-        })
+        match self {
+            Self::Acceptor(e) => Some(e),
+            Self::Certificate(_) => None,
+            Self::Port(e) => Some(e),
+            Self::Down(e) => Some(e),
+        }
     }
 }
 
 /// Lifecycle handler for the MD@Home HTTP server.
 ///
 /// Responsible for spawning and respawning the HTTP server and converting the specified plaintext
-/// certificates into the OpenSSL counterparts
+/// certificates into the OpenSSL (or rustls, with the `tls-rustls` feature) counterparts
 pub struct HttpServerLifecycle {
     gs: Arc<GlobalState>,
-    actix: dev::Server,
+    /// locked for the entirety of a respawn, so that concurrent respawns can't interleave
+    actix: tokio::sync::Mutex<RunningServer>,
+}
+
+/// The running Actix Web server, along with the certificate it was spawned with
+struct RunningServer {
+    server: dev::Server,
+    cert: TlsPayload,
 }
 
 impl HttpServerLifecycle {
@@ -242,55 +658,143 @@ impl HttpServerLifecycle {
     /// This will take the certificate it should use and the current global state and return a new
     /// instance of `Self` if successful. Errors will be propagated up the stack.
     pub fn new(gs: Arc<GlobalState>, cert: &TlsPayload) -> Result<Self, Error> {
-        // configures the SSL certificate with OpenSSL
-        let acceptor =
-            Self::create_openssl_acceptor(Arc::clone(&gs), cert).map_err(Error::Acceptor)?;
+        let server = Self::spawn(&gs, cert)?;
+        Ok(Self {
+            gs,
+            actix: tokio::sync::Mutex::new(RunningServer {
+                server,
+                cert: cert.clone(),
+            }),
+        })
+    }
 
-        // spawn the HTTP server and begin accepting requests
-        let srv = spawn_http_server(Arc::clone(&gs), acceptor).map_err(Error::Port)?;
+    /// Configures the SSL certificate, then spawns the HTTP server and begins accepting requests
+    fn spawn(gs: &Arc<GlobalState>, cert: &TlsPayload) -> Result<dev::Server, Error> {
+        let acceptor = Self::create_acceptor(Arc::clone(gs), cert)?;
+        spawn_http_server(Arc::clone(gs), acceptor).map_err(Error::Port)
+    }
 
-        Ok(Self { gs, actix: srv })
+    /// Converts a [`TLSPayload`] into the configuration of the TLS implementation that's in use
+    #[cfg(not(feature = "tls-rustls"))]
+    fn create_acceptor(gs: Arc<GlobalState>, cert: &TlsPayload) -> Result<Acceptor, Error> {
+        Self::create_openssl_acceptor(gs, cert)
+    }
+    #[cfg(feature = "tls-rustls")]
+    fn create_acceptor(gs: Arc<GlobalState>, cert: &TlsPayload) -> Result<Acceptor, Error> {
+        tls_rustls::create_acceptor(gs, cert)
     }
 
     /// Forcefully shuts down the last instance of the Actix Web Server, respawning with a new
     /// fullchain certificate and private key for SSL.
+    ///
+    /// For `rotation_drain_seconds` beforehand, responses ask clients to close their connections,
+    /// so that few of them are reset when the server is shut down.
+    ///
+    /// Respawns are serialized, so concurrent calls run one after another. If the new server can't
+    /// be spawned, the previous server is restored with its certificate before returning the error.
+    /// If that fails too, [`Error::Down`] is returned and the client should be shut down.
     // NOTE: Unfortunately, there is no way (to my knowledge) to change SSL cert while the Actix
     // Web server is running, therefore it must be shutdown and respawned
-    pub async fn respawn_with_new_cert(&mut self, cert: &TlsPayload) -> Result<(), Error> {
+    pub async fn respawn_with_new_cert(&self, cert: &TlsPayload) -> Result<(), Error> {
+        let mut running = self.actix.lock().await;
+
+        // check the certificate before stopping anything, so a bad one doesn't take the node down
+        Self::create_acceptor(Arc::clone(&self.gs), cert)?;
+
+        let drain = self.gs.config.rotation_drain_seconds;
+        if drain > 0 {
+            self.gs
+                .drain_connections(std::time::Duration::from_secs(drain))
+                .await;
+        }
+
         // stop old server immediately. if this were graceful, it would wait for all keep-alive
         // connections to close off first.
-        self.shutdown(false).await;
+        running.server.stop(false).await;
+        self.gs.draining.store(false, atomic::Ordering::SeqCst);
 
-        let acceptor =
-            Self::create_openssl_acceptor(Arc::clone(&self.gs), cert).map_err(Error::Acceptor)?;
+        match Self::spawn_after_stop(&self.gs, cert).await {
+            Ok(server) => {
+                running.server = server;
+                running.cert = cert.clone();
+                Ok(())
+            }
+            Err(e) => {
+                log::error!(
+                    "error respawning HTTP server, restoring the previous one: {}",
+                    e
+                );
+                match Self::spawn_after_stop(&self.gs, &running.cert).await {
+                    Ok(server) => {
+                        running.server = server;
+                        Err(e)
+                    }
+                    Err(restore) => {
+                        log::error!("unable to restore the previous HTTP server: {}", restore);
+                        Err(Error::Down(Box::new(e)))
+                    }
+                }
+            }
+        }
+    }
 
-        let srv = spawn_http_server(Arc::clone(&self.gs), acceptor).map_err(Error::Port)?;
-        self.actix = srv;
+    /// Spawns the HTTP server right after the previous one was stopped. The stopped server
+    /// releases its port in the background (and binding can fail for other transient reasons), so
+    /// binding is retried with an exponential backoff for a few seconds.
+    async fn spawn_after_stop(
+        gs: &Arc<GlobalState>,
+        cert: &TlsPayload,
+    ) -> Result<dev::Server, Error> {
+        const BIND_ATTEMPTS: usize = 10;
 
-        Ok(())
+        let mut backoff = utils::Backoff::new(
+            std::time::Duration::from_millis(50),
+            std::time::Duration::from_secs(1),
+        );
+        let mut attempts = 1;
+        loop {
+            match Self::spawn(gs, cert) {
+                Err(Error::Port(e)) if attempts < BIND_ATTEMPTS => {
+                    let delay = backoff.next_delay();
+                    log::debug!(
+                        "error binding HTTP server (attempt {}/{}), retrying in {:?}: {}",
+                        attempts,
+                        BIND_ATTEMPTS,
+                        delay,
+                        e
+                    );
+                    attempts += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                res => return res,
+            }
+        }
     }
 
     /// Checks the name provided by the client to make sure it matches either "localhost" or `client_url`.
     ///
     /// If this fails, it will return a fatal error which will immediately terminate the SSL connection.
     fn check_sni(gs: &Arc<GlobalState>, ssl: &mut ssl::SslRef) -> Result<(), ssl::SniError> {
+        if Self::is_valid_sni(gs, ssl.servername(ssl::NameType::HOST_NAME)) {
+            Ok(())
+        } else {
+            Err(ssl::SniError::ALERT_FATAL)
+        }
+    }
+
+    /// Whether the server name sent by a client is "localhost" or the hostname of `client_url`
+    fn is_valid_sni(gs: &GlobalState, servername: Option<&str>) -> bool {
         let timer = utils::Timer::start();
 
         // obtain the hostname from the ping_info in backend
-        let info = gs.backend.ping_info.load();
+        let info = gs.backend.ping_info();
         let client_hostname = Option::as_ref(&info).and_then(|x| x.client_url.host_str());
 
         // verify the servername equals "localhost" or the provided url from backend
-        let retval = match (ssl.servername(ssl::NameType::HOST_NAME), client_hostname) {
-            (Some("localhost" | "scalpel"), _) => Ok(()),
-            (Some(servername), Some(client_servername)) => {
-                if servername == client_servername {
-                    Ok(())
-                } else {
-                    Err(ssl::SniError::ALERT_FATAL)
-                }
-            }
-            _ => Err(ssl::SniError::ALERT_FATAL),
+        let retval = match (servername, client_hostname) {
+            (Some("localhost" | "scalpel"), _) => true,
+            (Some(servername), Some(client_servername)) => servername == client_servername,
+            _ => false,
         };
         log::debug!(
             "sni verification performed in {} with result {:?}",
@@ -301,18 +805,100 @@ impl HttpServerLifecycle {
     }
 
     /// Converts a [`TLSPayload`] into an Ssl Builder that ActixWeb will use for TLS
+    // still built with rustls, so the OpenSSL configuration keeps being tested
+    #[cfg_attr(feature = "tls-rustls", allow(dead_code))]
     fn create_openssl_acceptor(
         gs: Arc<GlobalState>,
         cert: &TlsPayload,
-    ) -> Result<ssl::SslAcceptorBuilder, ssl::Error> {
+    ) -> Result<ssl::SslAcceptorBuilder, Error> {
+        let mut builder = Self::configure_openssl(&gs.config, cert)?;
+
+        // actix overrides the ALPN selection of the builder it's given with "h2" and "http/1.1",
+        // so if the configured protocols differ, a second context that only selects from them is
+        // swapped in during the servername callback (which OpenSSL calls before ALPN selection)
+        let alpn_ctx = if gs.config.alpn_protocols != SUPPORTED_ALPN {
+            Some(Self::create_alpn_context(&gs.config, cert)?)
+        } else {
+            None
+        };
+
+        // count handshakes that OpenSSL will reject for offering an old TLS version, so downgrade
+        // attempts from scanners and old clients are visible
+        if gs.config.enforce_secure_tls {
+            let gs = Arc::clone(&gs);
+            builder.set_client_hello_callback(move |ssl, _| {
+                if let Some(version) = ssl.client_hello_legacy_version().filter(Self::is_downgrade)
+                {
+                    log::debug!("rejecting TLS handshake offering {:?}", version);
+                    gs.metrics.tls_downgrades_total.inc();
+                }
+                Ok(ssl::ClientHelloResponse::SUCCESS)
+            });
+        }
+
+        // register SNI check to reject invalid connections (if enabled)
+        let reject_invalid_sni = gs.config.reject_invalid_sni;
+        if reject_invalid_sni || alpn_ctx.is_some() {
+            builder.set_servername_callback(move |ssl, _| {
+                if reject_invalid_sni {
+                    Self::check_sni(&gs, ssl)?;
+                }
+                match &alpn_ctx {
+                    Some(ctx) => ssl
+                        .set_ssl_context(ctx)
+                        .map_err(|_| ssl::SniError::ALERT_FATAL),
+                    None => Ok(()),
+                }
+            });
+        }
+
+        log::debug!("ssl options: {:?}", builder.options());
+        Ok(builder)
+    }
+
+    /// Whether the highest version offered by a client is older than TLS 1.2. Clients that support
+    /// TLS 1.3 offer 1.2 here and list their real versions in an extension.
+    fn is_downgrade(version: &ssl::SslVersion) -> bool {
+        [
+            ssl::SslVersion::SSL3,
+            ssl::SslVersion::TLS1,
+            ssl::SslVersion::TLS1_1,
+        ]
+        .contains(version)
+    }
+
+    /// Creates an OpenSSL context that will only negotiate the ALPN protocols in the configuration
+    /// (in order of preference)
+    fn create_alpn_context(
+        config: &AppConfig,
+        cert: &TlsPayload,
+    ) -> Result<ssl::SslContext, Error> {
+        // encode the protocols into the length-prefixed wire format
+        let protos: Vec<u8> = config
+            .alpn_protocols
+            .iter()
+            .flat_map(|x| std::iter::once(x.len() as u8).chain(x.bytes()))
+            .collect();
+
+        let mut builder = Self::configure_openssl(config, cert)?;
+        builder.set_alpn_select_callback(move |_, client| {
+            ssl::select_next_proto(&protos, client).ok_or(ssl::AlpnError::NOACK)
+        });
+        Ok(builder.build().into_context())
+    }
+
+    /// Configures an Ssl Builder with the certificate and the TLS settings in the configuration
+    fn configure_openssl(
+        config: &AppConfig,
+        cert: &TlsPayload,
+    ) -> Result<ssl::SslAcceptorBuilder, Error> {
         use openssl::pkey::PKey;
         use openssl::rsa::Rsa;
-        use openssl::x509::X509;
 
         let mut builder = ssl::SslAcceptor::mozilla_intermediate_v5(ssl::SslMethod::tls_server())?;
 
         // push the full-chain certificate into the SslAcceptorBuilder
-        let mut full_chain = X509::stack_from_pem(cert.certificate.as_bytes())?.into_iter();
+        let mut full_chain = Self::parse_cert_chain(&cert.certificate)?.into_iter();
         if let Some(x509) = full_chain.next() {
             builder.set_certificate(&x509)?;
         }
@@ -327,7 +913,7 @@ impl HttpServerLifecycle {
 
         // manually revert to the mozilla_old TLS standard if we're not enforcing secure TLS
         // https://wiki.mozilla.org/Security/Server_Side_TLS
-        if !gs.config.enforce_secure_tls {
+        if !config.enforce_secure_tls {
             builder.set_cipher_list(
                 "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
                 ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
@@ -352,17 +938,1134 @@ impl HttpServerLifecycle {
         builder.set_session_cache_size(1024 * 4); // 4096 sessions (instead of the default 20000)
         builder.set_verify(ssl::SslVerifyMode::NONE);
 
-        // register SNI check to reject invalid connections (if enabled)
-        if gs.config.reject_invalid_sni {
-            builder.set_servername_callback(move |ssl, _| Self::check_sni(&gs, ssl));
-        }
+        // early data (0-RTT) is never read by the server (and it can be replayed), so make sure
+        // that clients are never told to send it, even if OpenSSL's default changes
+        builder.set_max_early_data(0)?;
 
-        log::debug!("ssl options: {:?}", builder.options());
         Ok(builder)
     }
 
+    /// Parses the full-chain certificate PEM, making sure the leaf certificate comes first.
+    ///
+    /// Any blocks in the PEM that aren't certificates are skipped, but there must be at least one
+    /// certificate. A warning is logged if the chain appears to be missing intermediates.
+    fn parse_cert_chain(pem: &str) -> Result<Vec<X509>, Error> {
+        let mut chain = X509::stack_from_pem(pem.as_bytes())?;
+        if chain.is_empty() {
+            return Err(Error::Certificate("no certificates found in PEM chain"));
+        }
+
+        // the leaf is the certificate that didn't issue any of the others in the chain
+        let leaf = (0..chain.len()).find(|&i| {
+            chain
+                .iter()
+                .enumerate()
+                .all(|(j, other)| i == j || chain[i].issued(other) != X509VerifyResult::OK)
+        });
+        if let Some(i) = leaf.filter(|&i| i != 0) {
+            log::warn!("leaf certificate isn't first in the PEM chain, reordering");
+            let leaf = chain.remove(i);
+            chain.insert(0, leaf);
+        }
+
+        if Self::chain_is_incomplete(&chain) {
+            log::warn!(
+                "PEM chain doesn't contain the issuer of the leaf certificate, \
+                some clients may fail to verify it"
+            );
+        }
+        Ok(chain)
+    }
+
+    /// Whether the leaf certificate (the first in the chain) was issued by a certificate that isn't
+    /// in the chain
+    fn chain_is_incomplete(chain: &[X509]) -> bool {
+        match chain.split_first() {
+            Some((leaf, rest)) => {
+                leaf.issued(leaf) != X509VerifyResult::OK
+                    && !rest
+                        .iter()
+                        .any(|ca| ca.issued(leaf) == X509VerifyResult::OK)
+            }
+            None => true,
+        }
+    }
+
     /// Wrapper for the internal Actix Web server stop function
     pub async fn shutdown(&self, graceful: bool) {
-        self.actix.stop(graceful).await
+        self.actix.lock().await.server.stop(graceful).await
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::cache::tests::TestCache;
+    use crate::config::tests::config_with;
+    use actix_web::test::{self, TestRequest};
+    use openssl::pkey::{PKey, Private};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    /// Chapter hash of the images requested in tests, which has to look like a real one
+    pub(crate) const CHAP_HASH: &str = "8172a46adc798f4f4ace6663322a383e";
+
+    /// Spawns a bare-bones upstream server that responds to a single request with the raw HTTP
+    /// `response`, returning the URL of the server
+    pub(crate) fn mock_upstream(response: Vec<u8>) -> url::Url {
+        mock_upstream_serving(response, 1)
+    }
+
+    /// Like [`mock_upstream`], but responds to the first `requests` requests
+    pub(crate) fn mock_upstream_serving(response: Vec<u8>, requests: usize) -> url::Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for _ in 0..requests {
+                let (mut stream, _) = listener.accept().unwrap();
+                // read until the end of the request headers
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(&response).unwrap();
+            }
+        });
+        url::Url::parse(&format!("http://{}", addr)).unwrap()
+    }
+
+    /// Creates a raw `200 OK` response for [`mock_upstream`] with a PNG image of `body`
+    pub(crate) fn image_response(body: &[u8]) -> Vec<u8> {
+        let mut res = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\
+            Connection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        res.extend_from_slice(body);
+        res
+    }
+
+    /// Generates a certificate for the common name, signed by the issuer (or self-signed if no
+    /// issuer is provided)
+    fn signed_cert(cn: &str, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+        use openssl::{asn1::Asn1Time, hash::MessageDigest, rsa::Rsa, x509};
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+
+        let mut builder = x509::X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((ca, ca_key)) => {
+                builder.set_issuer_name(ca.subject_name()).unwrap();
+                builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    /// Generates a self-signed [`TlsPayload`] for "localhost"
+    pub(crate) fn self_signed_payload() -> TlsPayload {
+        let (cert, key) = signed_cert("localhost", None);
+        TlsPayload {
+            created_at: String::new(),
+            private_key: String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+            certificate: String::from_utf8(cert.to_pem().unwrap()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn malformed_paths_are_detected() {
+        assert!(is_well_formed(CHAP_HASH, "1.png"));
+        assert!(is_well_formed(
+            CHAP_HASH,
+            "x1-b765e86d5ecbc932cf3f517a8604f6ac6d8a7f379b0277a117dc7c09c53d041e.png"
+        ));
+
+        assert!(!is_well_formed("chapter", "1.png"));
+        assert!(!is_well_formed(&CHAP_HASH.to_uppercase(), "1.png"));
+        assert!(!is_well_formed(&CHAP_HASH[1..], "1.png"));
+        assert!(!is_well_formed(CHAP_HASH, ""));
+        assert!(!is_well_formed(CHAP_HASH, "..png"));
+        assert!(!is_well_formed(CHAP_HASH, "1 .png"));
+        assert!(!is_well_formed(CHAP_HASH, &"a".repeat(200)));
+    }
+
+    #[test]
+    fn worker_count_is_scaled_and_capped() {
+        let config = config_with("worker_threads_multiplier: 1.5\nmax_worker_threads: 10\n");
+        assert_eq!(worker_count(&config, 4), 6);
+        assert_eq!(worker_count(&config, 16), 10);
+
+        let config = config_with("worker_threads_multiplier: 0.1\n");
+        assert_eq!(worker_count(&config, 2), 1);
+
+        // an explicit worker count always wins
+        let config = config_with("worker_threads: 3\nmax_worker_threads: 2\n");
+        assert_eq!(worker_count(&config, 16), 3);
+    }
+
+    #[test]
+    fn cert_chain_is_parsed() {
+        let (ca, ca_key) = signed_cert("Test CA", None);
+        let (leaf, _) = signed_cert("localhost", Some((&ca, &ca_key)));
+        let ca_pem = String::from_utf8(ca.to_pem().unwrap()).unwrap();
+        let leaf_pem = String::from_utf8(leaf.to_pem().unwrap()).unwrap();
+        let is_leaf = |x: &X509| x.to_der().unwrap() == leaf.to_der().unwrap();
+
+        // well-formed chains are kept as is
+        let chain = HttpServerLifecycle::parse_cert_chain(&(leaf_pem.clone() + &ca_pem)).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert!(is_leaf(&chain[0]));
+        assert!(!HttpServerLifecycle::chain_is_incomplete(&chain));
+
+        // the leaf is moved in front of the CA
+        let chain = HttpServerLifecycle::parse_cert_chain(&(ca_pem + &leaf_pem)).unwrap();
+        assert!(is_leaf(&chain[0]));
+
+        // leaf-only chains are accepted, but considered incomplete
+        let chain = HttpServerLifecycle::parse_cert_chain(&leaf_pem).unwrap();
+        assert!(HttpServerLifecycle::chain_is_incomplete(&chain));
+
+        // garbage has no certificates at all
+        assert!(matches!(
+            HttpServerLifecycle::parse_cert_chain("definitely not a certificate"),
+            Err(Error::Certificate(_))
+        ));
+    }
+
+    /// Performs a TLS handshake against `ctx` with a client offering the ALPN `client_protos`
+    /// (in wire format), returning the protocol that the server selected
+    fn negotiate_alpn(ctx: ssl::SslContext, client_protos: &[u8]) -> Option<Vec<u8>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = ssl::Ssl::new(&ctx).unwrap().accept(stream);
+        });
+
+        let mut connector = ssl::SslConnector::builder(ssl::SslMethod::tls_client()).unwrap();
+        connector.set_verify(ssl::SslVerifyMode::NONE);
+        connector.set_alpn_protos(client_protos).unwrap();
+        let stream = connector
+            .build()
+            .connect("localhost", TcpStream::connect(addr).unwrap())
+            .unwrap();
+        let selected = stream.ssl().selected_alpn_protocol().map(Vec::from);
+
+        drop(stream);
+        server.join().unwrap();
+        selected
+    }
+
+    #[test]
+    fn configured_alpn_is_advertised() {
+        let cert = self_signed_payload();
+
+        // http/1.1 is selected even when the client prefers h2
+        let config = config_with("alpn_protocols: [http/1.1]");
+        let ctx = HttpServerLifecycle::create_alpn_context(&config, &cert).unwrap();
+        let selected = negotiate_alpn(ctx, b"\x02h2\x08http/1.1");
+        assert_eq!(selected.as_deref(), Some(&b"http/1.1"[..]));
+
+        // server preference is respected when both protocols are configured
+        let config = config_with("alpn_protocols: [h2, http/1.1]");
+        let ctx = HttpServerLifecycle::create_alpn_context(&config, &cert).unwrap();
+        let selected = negotiate_alpn(ctx, b"\x08http/1.1\x02h2");
+        assert_eq!(selected.as_deref(), Some(&b"h2"[..]));
+    }
+
+    #[test]
+    fn configured_alpn_survives_actix_override() {
+        let config = config_with("alpn_protocols: [http/1.1]\nreject_invalid_sni: false\n");
+        let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+        let mut builder =
+            HttpServerLifecycle::create_openssl_acceptor(gs, &self_signed_payload()).unwrap();
+
+        // what actix does to the builder it's given when binding the server
+        builder.set_alpn_select_callback(|_, client| {
+            ssl::select_next_proto(b"\x02h2\x08http/1.1", client).ok_or(ssl::AlpnError::NOACK)
+        });
+        let selected = negotiate_alpn(builder.build().into_context(), b"\x02h2\x08http/1.1");
+        assert_eq!(selected.as_deref(), Some(&b"http/1.1"[..]));
+    }
+
+    #[test]
+    fn session_tickets_never_allow_early_data() {
+        let cert = self_signed_payload();
+
+        let acceptor = HttpServerLifecycle::configure_openssl(&config_with(""), &cert)
+            .unwrap()
+            .build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(stream).unwrap();
+            stream.write_all(b"x").unwrap();
+        });
+
+        let mut connector = ssl::SslConnector::builder(ssl::SslMethod::tls_client()).unwrap();
+        connector.set_verify(ssl::SslVerifyMode::NONE);
+        connector
+            .set_min_proto_version(Some(ssl::SslVersion::TLS1_3))
+            .unwrap();
+        let mut stream = connector
+            .build()
+            .connect("localhost", TcpStream::connect(addr).unwrap())
+            .unwrap();
+
+        // TLS 1.3 tickets arrive after the handshake, so read past them first
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf).unwrap();
+        let session = stream.ssl().session().unwrap();
+        assert_eq!(session.protocol_version(), ssl::SslVersion::TLS1_3);
+        assert_eq!(session.max_early_data(), 0);
+
+        drop(stream);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tls_downgrades_are_counted() {
+        let mut config = config_with("");
+        config.enforce_secure_tls = true;
+        let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+        let builder =
+            HttpServerLifecycle::create_openssl_acceptor(Arc::clone(&gs), &self_signed_payload())
+                .unwrap();
+        let ctx = builder.build().into_context();
+
+        let handshake = |max_version| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let ctx = ctx.clone();
+            let server = std::thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let _ = ssl::Ssl::new(&ctx).unwrap().accept(stream);
+            });
+
+            // old versions are only allowed by OpenSSL at security level 0
+            let mut connector = ssl::SslConnector::builder(ssl::SslMethod::tls_client()).unwrap();
+            connector.set_verify(ssl::SslVerifyMode::NONE);
+            connector.set_cipher_list("DEFAULT@SECLEVEL=0").unwrap();
+            connector
+                .set_min_proto_version(Some(ssl::SslVersion::TLS1))
+                .unwrap();
+            connector.set_max_proto_version(Some(max_version)).unwrap();
+            let res = connector
+                .build()
+                .connect("localhost", TcpStream::connect(addr).unwrap());
+            let succeeded = res.is_ok();
+
+            drop(res);
+            server.join().unwrap();
+            succeeded
+        };
+
+        assert!(!handshake(ssl::SslVersion::TLS1_1));
+        assert_eq!(gs.metrics.tls_downgrades_total.get(), 1);
+
+        assert!(handshake(ssl::SslVersion::TLS1_2));
+        assert_eq!(gs.metrics.tls_downgrades_total.get(), 1);
+    }
+
+    #[test]
+    fn verifier_survives_panicking_request() {
+        actix_web::rt::System::new().block_on(async {
+            let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+
+            // a request that panics while holding the verifier must not affect other requests
+            let panicking = Arc::clone(&gs);
+            let res = std::thread::spawn(move || {
+                let _verifier = panicking.verifier.load();
+                panic!("request panicked while verifying");
+            })
+            .join();
+            assert!(res.is_err());
+
+            let app = test::init_service(App::new().app_data(web::Data::new(gs)).route(
+                "/{token}/{archive_type}/{chap_hash}/{image}",
+                web::get().to(md_service),
+            ))
+            .await;
+            let req = TestRequest::get()
+                .uri("/invalid-token/data/8172a46adc798f4f4ace6663322a383e/1.png")
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert!(res.status().is_client_error());
+        });
+    }
+
+    #[test]
+    fn oversized_requests_are_rejected() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("max_request_size_kibibytes: 1\n");
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(gs))
+                    .wrap_fn(limit_request_size)
+                    .wrap(errors::normalize())
+                    .route("/ready", web::get().to(ready_service)),
+            )
+            .await;
+
+            for &(len, status) in &[
+                (1024, http::StatusCode::OK),
+                (1025, http::StatusCode::PAYLOAD_TOO_LARGE),
+            ] {
+                let req = TestRequest::get()
+                    .uri("/ready")
+                    .insert_header((http::header::CONTENT_LENGTH, len))
+                    .set_payload(vec![0u8; len])
+                    .to_request();
+                assert_eq!(test::call_service(&app, req).await.status(), status);
+            }
+        });
+    }
+
+    /// The CORS headers of a response to a request from `origin`, and its `Vary` headers
+    async fn cors_headers(config: &str, origin: &str) -> (Vec<String>, Vec<String>, Vec<String>) {
+        let gs = GlobalState::for_tests(config_with(config), Box::new(TestCache::default()));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::clone(&gs)))
+                .wrap_fn(reflect_origin)
+                .wrap(default_headers(&gs.config, "test"))
+                .route("/ready", web::get().to(ready_service)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/ready")
+            .insert_header((http::header::ORIGIN, origin))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let get = |name: &str| {
+            res.headers()
+                .get_all(name)
+                .map(|x| x.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        (
+            get("access-control-allow-origin"),
+            get("timing-allow-origin"),
+            get("vary"),
+        )
+    }
+
+    #[test]
+    fn allowed_origins_are_configurable() {
+        actix_web::rt::System::new().block_on(async {
+            // anyone is allowed by default
+            let (allow, timing, vary) = cors_headers("", "https://a.example").await;
+            assert_eq!((allow, timing), (vec!["*".into()], vec!["*".into()]));
+            assert!(vary.is_empty());
+
+            let config =
+                "allow_origin: https://a.example\ntiming_allow_origin: https://b.example\n";
+            let (allow, timing, vary) = cors_headers(config, "https://b.example").await;
+            assert_eq!(allow, vec!["https://a.example".to_string()]);
+            assert_eq!(timing, vec!["https://b.example".to_string()]);
+            assert!(vary.is_empty());
+
+            // only origins in the list are sent back
+            let config = "allow_origin:\n    - https://a.example\n    - https://b.example\n";
+            let (allow, _, vary) = cors_headers(config, "https://b.example").await;
+            assert_eq!(allow, vec!["https://b.example".to_string()]);
+            assert_eq!(vary, vec!["Origin".to_string()]);
+            let (allow, _, vary) = cors_headers(config, "https://c.example").await;
+            assert!(allow.is_empty());
+            assert_eq!(vary, vec!["Origin".to_string()]);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn metrics_routes_can_be_disabled() {
+        actix_web::rt::System::new().block_on(async {
+            for &enabled in &[true, false] {
+                let config = config_with(&format!("metrics_enabled: {}\n", enabled));
+                let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+                gs.metrics.hit_requests_total.inc();
+                let app = test::init_service(
+                    App::new()
+                        .app_data(web::Data::new(gs))
+                        .route("/metrics", web::get().to(prom_service))
+                        .route(
+                            "/{archive_type}/{chap_hash}/{image}",
+                            web::get().to(md_service),
+                        ),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/metrics").to_request();
+                let res = test::call_service(&app, req).await;
+                if enabled {
+                    assert_eq!(res.status(), http::StatusCode::OK);
+                    let body = test::read_body(res).await;
+                    let body = std::str::from_utf8(&body).unwrap();
+                    assert!(body.contains("hit_requests_total 1"));
+                    assert!(body.contains("bytes_up_total"));
+                } else {
+                    assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn compression_follows_config() {
+        actix_web::rt::System::new().block_on(async {
+            // the encoding of the response to each Accept-Encoding, if any
+            let cases = [
+                (Compression::None, "gzip, br", None),
+                (Compression::Gzip, "gzip, br", Some("gzip")),
+                (Compression::Gzip, "br", None),
+                (Compression::Br, "gzip, br", Some("br")),
+                (Compression::Auto, "gzip;q=0.5, br;q=0.8", Some("br")),
+                (Compression::Auto, "gzip", Some("gzip")),
+            ];
+            for (compression, accept, expected) in &cases {
+                let app = test::init_service(
+                    App::new()
+                        .wrap(middleware::Compress::new(content_encoding(*compression)))
+                        .route(
+                            "/",
+                            web::get().to(|| HttpResponse::Ok().body("a".repeat(1024))),
+                        ),
+                )
+                .await;
+                let req = TestRequest::get()
+                    .insert_header((http::header::ACCEPT_ENCODING, *accept))
+                    .to_request();
+                let res = test::call_service(&app, req).await;
+                let encoding = res.headers().get(http::header::CONTENT_ENCODING);
+                assert_eq!(
+                    encoding.map(|x| x.to_str().unwrap()),
+                    *expected,
+                    "{:?} with {}",
+                    compression,
+                    accept
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn token_policies() {
+        actix_web::rt::System::new().block_on(async {
+            let ok = |status: http::StatusCode| status == http::StatusCode::OK;
+            let unauthorized = |status: http::StatusCode| status == http::StatusCode::UNAUTHORIZED;
+            let refused = |status: http::StatusCode| status.is_client_error();
+            // expected outcome with a valid token, no token and an invalid token
+            type Expect = fn(http::StatusCode) -> bool;
+            let policies: [(&str, [Expect; 3]); 3] = [
+                ("required", [ok, unauthorized, refused]),
+                ("optional", [ok, ok, refused]),
+                ("disabled", [ok, ok, ok]),
+            ];
+            for (policy, expected) in &policies {
+                let config = config_with(&format!("token_policy: {}\n", policy));
+                let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+                let (verifier, token) = crate::tokens::tests::verifier_with_token(CHAP_HASH);
+                gs.verifier.store(Arc::new(verifier));
+                let key = ImageKey::new(CHAP_HASH.to_string(), "1.png".to_string(), false);
+                let image = bytes::Bytes::from_static(b"image");
+                assert!(gs.cache.save(&key, "image/png".to_string(), image).await);
+
+                let app = test::init_service(
+                    App::new()
+                        .app_data(web::Data::new(gs))
+                        .route(
+                            "/{token}/{archive_type}/{chap_hash}/{image}",
+                            web::get().to(md_service),
+                        )
+                        .route(
+                            "/{archive_type}/{chap_hash}/{image}",
+                            web::get().to(md_service),
+                        ),
+                )
+                .await;
+                let uris = [
+                    format!("/{}/data/8172a46adc798f4f4ace6663322a383e/1.png", token),
+                    "/data/8172a46adc798f4f4ace6663322a383e/1.png".to_string(),
+                    "/invalid-token/data/8172a46adc798f4f4ace6663322a383e/1.png".to_string(),
+                ];
+                for (uri, expected) in uris.iter().zip(expected) {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let status = test::call_service(&app, req).await.status();
+                    assert!(expected(status), "{} {} -> {}", policy, uri, status);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn allowlist_handles_unknown_peers() {
+        actix_web::rt::System::new().block_on(async {
+            let allowlist = "token_policy: disabled\nip_allowlist: [10.0.0.0/8]\n";
+            for (policy, unknown_status) in &[
+                ("reject", http::StatusCode::FORBIDDEN),
+                ("allow", http::StatusCode::OK),
+            ] {
+                let config = config_with(&format!("{}unknown_peer_policy: {}", allowlist, policy));
+                let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+                let key = ImageKey::new(CHAP_HASH.to_string(), "1.png".to_string(), false);
+                let image = bytes::Bytes::from_static(b"image");
+                assert!(gs.cache.save(&key, "image/png".to_string(), image).await);
+
+                let app = test::init_service(App::new().app_data(web::Data::new(gs)).route(
+                    "/{archive_type}/{chap_hash}/{image}",
+                    web::get().to(md_service),
+                ))
+                .await;
+                let status = |peer: Option<&str>| {
+                    let mut req =
+                        TestRequest::get().uri("/data/8172a46adc798f4f4ace6663322a383e/1.png");
+                    if let Some(peer) = peer {
+                        req = req.peer_addr(peer.parse().unwrap());
+                    }
+                    let req = req.to_request();
+                    let app = &app;
+                    async move { test::call_service(app, req).await.status() }
+                };
+
+                assert_eq!(status(Some("10.1.2.3:1234")).await, http::StatusCode::OK);
+                assert_eq!(
+                    status(Some("192.168.1.1:1234")).await,
+                    http::StatusCode::FORBIDDEN
+                );
+                assert_eq!(status(None).await, *unknown_status);
+            }
+        });
+    }
+
+    #[test]
+    fn lame_duck_fails_readiness_but_serves() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("token_policy: disabled\nlame_duck_seconds: 1\n");
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let key = ImageKey::new(CHAP_HASH.to_string(), "1.png".to_string(), false);
+            let image = bytes::Bytes::from_static(b"image");
+            assert!(gs.cache.save(&key, "image/png".to_string(), image).await);
+
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(Arc::clone(&gs)))
+                    .route("/ready", web::get().to(ready_service))
+                    .route(
+                        "/{archive_type}/{chap_hash}/{image}",
+                        web::get().to(md_service),
+                    ),
+            )
+            .await;
+            let status = |uri: &'static str| {
+                let app = &app;
+                async move {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = test::call_service(app, req).await;
+                    let retry_after = res.headers().get(http::header::RETRY_AFTER).cloned();
+                    (res.status(), retry_after)
+                }
+            };
+            assert_eq!(status("/ready").await, (http::StatusCode::OK, None));
+
+            let lame_duck = gs.lame_duck(Duration::from_millis(200));
+            // join polls the lame duck first, so readiness is already flipped once these run
+            let during = async {
+                (
+                    status("/ready").await,
+                    status("/data/8172a46adc798f4f4ace6663322a383e/1.png").await,
+                )
+            };
+            let (_, (ready, image)) = tokio::join!(lame_duck, during);
+            assert_eq!(ready.0, http::StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(ready.1.unwrap(), "60");
+            assert_eq!(image.0, http::StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn health_follows_shutdown_and_config() {
+        actix_web::rt::System::new().block_on(async {
+            for &enabled in &[true, false] {
+                let config = config_with(&format!("health_endpoint: {}\n", enabled));
+                let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+                let app = test::init_service(
+                    App::new()
+                        .app_data(web::Data::new(Arc::clone(&gs)))
+                        .route("/health", web::get().to(health_service))
+                        .route(
+                            "/{archive_type}/{chap_hash}/{image}",
+                            web::get().to(md_service),
+                        ),
+                )
+                .await;
+                let status = || {
+                    let app = &app;
+                    async move {
+                        let req = TestRequest::get().uri("/health").to_request();
+                        let res = test::call_service(app, req).await;
+                        if res.status() == http::StatusCode::SERVICE_UNAVAILABLE {
+                            let retry_after = res.headers().get(http::header::RETRY_AFTER);
+                            assert_eq!(retry_after.unwrap(), "60");
+                        }
+                        res.status()
+                    }
+                };
+
+                if !enabled {
+                    assert_eq!(status().await, http::StatusCode::NOT_FOUND);
+                    continue;
+                }
+                assert_eq!(status().await, http::StatusCode::OK);
+                gs.ready.store(false, atomic::Ordering::SeqCst);
+                assert_eq!(status().await, http::StatusCode::SERVICE_UNAVAILABLE);
+                // health checks aren't image requests
+                assert_eq!(gs.request_counter.load(atomic::Ordering::SeqCst), 0);
+            }
+        });
+    }
+
+    #[test]
+    fn draining_closes_connections() {
+        actix_web::rt::System::new().block_on(async {
+            let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(Arc::clone(&gs)))
+                    .wrap_fn(close_connections)
+                    .route("/ready", web::get().to(ready_service)),
+            )
+            .await;
+            let connection_type = || {
+                let app = &app;
+                async move {
+                    let req = TestRequest::get().uri("/ready").to_request();
+                    let res = test::call_service(app, req).await;
+                    res.response().head().connection_type()
+                }
+            };
+            assert_ne!(connection_type().await, http::ConnectionType::Close);
+
+            // join polls the drain first, so the flag is already set once the request runs
+            let drain = gs.drain_connections(Duration::from_millis(200));
+            let (_, during) = tokio::join!(drain, connection_type());
+            assert_eq!(during, http::ConnectionType::Close);
+        });
+    }
+
+    #[test]
+    fn connections_close_after_max_requests() {
+        actix_web::rt::System::new().block_on(async {
+            let mut config = config_with("");
+            config.max_requests_per_connection = Some(3);
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(gs))
+                    .wrap_fn(close_connections)
+                    .route("/ready", web::get().to(ready_service)),
+            )
+            .await;
+            let closes = |peer: &str| {
+                let app = &app;
+                let req = TestRequest::get()
+                    .uri("/ready")
+                    .peer_addr(peer.parse().unwrap())
+                    .to_request();
+                async move {
+                    let res = test::call_service(app, req).await;
+                    res.response().head().connection_type() == http::ConnectionType::Close
+                }
+            };
+
+            let conn = "10.0.0.1:5000";
+            assert!(!closes(conn).await);
+            assert!(!closes(conn).await);
+            // other connections of the same peer are counted on their own
+            assert!(!closes("10.0.0.1:5001").await);
+            assert!(closes(conn).await);
+            assert!(!closes(conn).await);
+        });
+    }
+
+    #[test]
+    fn debug_headers_are_added() {
+        actix_web::rt::System::new().block_on(async {
+            let mut gs = GlobalState::for_tests(
+                config_with("server_timing_header: true\ntoken_expiry_header: true\n"),
+                Box::new(TestCache::default()),
+            );
+            let (verifier, token) = crate::tokens::tests::verifier_with_token(CHAP_HASH);
+            gs.verifier.store(Arc::new(verifier));
+            let upstream = mock_upstream(image_response(b"image"));
+            crate::backend::tests::use_upstream(&mut gs, upstream);
+
+            let app = test::init_service(App::new().app_data(web::Data::new(gs)).route(
+                "/{token}/{archive_type}/{chap_hash}/{image}",
+                web::get().to(md_service),
+            ))
+            .await;
+            let req = TestRequest::get()
+                .uri(&format!(
+                    "/{}/data/8172a46adc798f4f4ace6663322a383e/1.png",
+                    token
+                ))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), http::StatusCode::OK);
+
+            let timing = res
+                .headers()
+                .get("Server-Timing")
+                .unwrap()
+                .to_str()
+                .unwrap();
+            let names: Vec<_> = timing
+                .split(", ")
+                .map(|x| x.split(";dur=").next().unwrap())
+                .collect();
+            assert_eq!(names, ["verify", "cache", "upstream"]);
+
+            let expires = res
+                .headers()
+                .get("X-Token-Expires")
+                .unwrap()
+                .to_str()
+                .unwrap();
+            assert!(chrono::DateTime::parse_from_rfc3339(expires).unwrap() > chrono::Utc::now());
+        });
+    }
+
+    #[test]
+    fn images_wait_for_cache_init() {
+        use crate::cache::ImageCache;
+
+        actix_web::rt::System::new().block_on(async {
+            let gate = crate::cache::CacheGate::new();
+            let config = config_with("token_policy: disabled\ninitializing_retry_after: 3\n");
+            let gs = GlobalState::for_tests(config, Box::new(gate.clone()));
+
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(gs))
+                    .route("/ready", web::get().to(ready_service))
+                    .route(
+                        "/{archive_type}/{chap_hash}/{image}",
+                        web::get().to(md_service),
+                    ),
+            )
+            .await;
+            let status = |uri: &'static str| {
+                let app = &app;
+                async move {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = test::call_service(app, req).await;
+                    if res.status() == http::StatusCode::SERVICE_UNAVAILABLE {
+                        let retry_after = res.headers().get(http::header::RETRY_AFTER);
+                        assert_eq!(retry_after.unwrap(), "3");
+                    }
+                    res.status()
+                }
+            };
+            let unavailable = http::StatusCode::SERVICE_UNAVAILABLE;
+            assert_eq!(status("/ready").await, unavailable);
+            assert_eq!(
+                status("/data/8172a46adc798f4f4ace6663322a383e/1.png").await,
+                unavailable
+            );
+
+            // finish the (slow) initialization with the image already cached
+            let cache = TestCache::default();
+            let key = ImageKey::new(CHAP_HASH.to_string(), "1.png".to_string(), false);
+            let image = bytes::Bytes::from_static(b"image");
+            assert!(cache.save(&key, "image/png".to_string(), image).await);
+            gate.open(Box::new(cache));
+
+            assert_eq!(status("/ready").await, http::StatusCode::OK);
+            assert_eq!(
+                status("/data/8172a46adc798f4f4ace6663322a383e/1.png").await,
+                http::StatusCode::OK
+            );
+        });
+    }
+
+    #[test]
+    fn unsupported_extensions_are_refused() {
+        use crate::cache::ImageCache;
+
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with("token_policy: disabled\nallowed_extensions: [png, jpg]\n");
+            let cache = TestCache::default();
+            for image in &["1.png", "2.JPG"] {
+                let key = ImageKey::new(CHAP_HASH.to_string(), image.to_string(), false);
+                let body = bytes::Bytes::from_static(b"image");
+                assert!(cache.save(&key, "image/png".to_string(), body).await);
+            }
+            let gs = GlobalState::for_tests(config, Box::new(cache));
+            let app = test::init_service(App::new().app_data(web::Data::new(gs)).route(
+                "/{archive_type}/{chap_hash}/{image}",
+                web::get().to(md_service),
+            ))
+            .await;
+
+            let req = TestRequest::get()
+                .uri("/data/8172a46adc798f4f4ace6663322a383e/1.tiff")
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            let body = test::read_body(res).await;
+            assert_eq!(
+                body,
+                r#"unsupported image type "tiff", must be one of ["png", "jpg"]"#
+            );
+
+            for uri in &[
+                "/data/8172a46adc798f4f4ace6663322a383e/1.png",
+                "/data/8172a46adc798f4f4ace6663322a383e/2.JPG",
+            ] {
+                let req = TestRequest::get().uri(uri).to_request();
+                let res = test::call_service(&app, req).await;
+                assert_eq!(res.status(), http::StatusCode::OK);
+            }
+        });
+    }
+
+    #[test]
+    fn byte_quota_is_enforced_per_peer() {
+        actix_web::rt::System::new().block_on(async {
+            let config = config_with(
+                "token_policy: disabled\nip_byte_quota:\n    kibibytes: 1\n    window_seconds: 60\n",
+            );
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let key = ImageKey::new(CHAP_HASH.to_string(), "1.png".to_string(), false);
+            let image = bytes::Bytes::from(vec![0u8; 600]);
+            assert!(gs.cache.save(&key, "image/png".to_string(), image).await);
+
+            let app = test::init_service(App::new().app_data(web::Data::new(gs)).route(
+                "/{archive_type}/{chap_hash}/{image}",
+                web::get().to(md_service),
+            ))
+            .await;
+            let status = |peer: &'static str| {
+                let app = &app;
+                async move {
+                    let req = TestRequest::get()
+                        .uri("/data/8172a46adc798f4f4ace6663322a383e/1.png")
+                        .peer_addr(peer.parse().unwrap())
+                        .to_request();
+                    let res = test::call_service(app, req).await;
+                    let status = res.status();
+                    // the quota only counts bytes that were actually sent
+                    test::read_body(res).await;
+                    status
+                }
+            };
+
+            // a single request is well under any request limit, but the bytes add up
+            assert_eq!(status("10.0.0.1:1234").await, http::StatusCode::OK);
+            assert_eq!(status("10.0.0.1:1234").await, http::StatusCode::OK);
+            assert_eq!(
+                status("10.0.0.1:1234").await,
+                http::StatusCode::TOO_MANY_REQUESTS
+            );
+            assert_eq!(status("10.0.0.2:1234").await, http::StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn handshakes_are_capped() {
+        // find a free port for the server to bind to
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = config_with("worker_threads: 1\nmax_concurrent_handshakes: 1\n");
+        config.port = port;
+
+        actix_web::rt::System::new().block_on(async move {
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let acceptor =
+                HttpServerLifecycle::create_acceptor(Arc::clone(&gs), &self_signed_payload())
+                    .unwrap();
+            let server = spawn_http_server(gs, acceptor).unwrap();
+
+            let (blocked, completed) = tokio::task::spawn_blocking(move || {
+                let handshake = |timeout| {
+                    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                    stream.set_read_timeout(Some(timeout)).unwrap();
+                    let mut connector =
+                        ssl::SslConnector::builder(ssl::SslMethod::tls_client()).unwrap();
+                    connector.set_verify(ssl::SslVerifyMode::NONE);
+                    connector.build().connect("localhost", stream).is_ok()
+                };
+
+                // a connection that never sends a hello holds the only handshake slot
+                let staller = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                std::thread::sleep(Duration::from_millis(200));
+                let blocked = !handshake(Duration::from_millis(500));
+
+                // freeing the slot lets handshakes through again
+                drop(staller);
+                let completed = handshake(Duration::from_secs(5));
+                (blocked, completed)
+            })
+            .await
+            .unwrap();
+
+            server.stop(false).await;
+            assert!(blocked, "handshake completed while the cap was reached");
+            assert!(
+                completed,
+                "handshake never completed after the cap was freed"
+            );
+        });
+    }
+
+    #[test]
+    fn concurrent_respawns_leave_one_server() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = config_with("worker_threads: 1\nrotation_drain_seconds: 0\n");
+        config.port = port;
+
+        actix_web::rt::System::new().block_on(async move {
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let server = HttpServerLifecycle::new(gs, &self_signed_payload()).unwrap();
+            let served_cert = || {
+                tokio::task::spawn_blocking(move || {
+                    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(5)))
+                        .unwrap();
+                    let mut connector =
+                        ssl::SslConnector::builder(ssl::SslMethod::tls_client()).unwrap();
+                    connector.set_verify(ssl::SslVerifyMode::NONE);
+                    let stream = connector.build().connect("localhost", stream).unwrap();
+                    stream.ssl().peer_certificate().unwrap().to_pem().unwrap()
+                })
+            };
+
+            // respawns run in the order they were called, so the last certificate wins
+            let (first, second) = (self_signed_payload(), self_signed_payload());
+            let (a, b) = tokio::join!(
+                server.respawn_with_new_cert(&first),
+                server.respawn_with_new_cert(&second)
+            );
+            assert!(a.is_ok() && b.is_ok());
+            let pem = served_cert().await.unwrap();
+            assert_eq!(pem, second.certificate.as_bytes());
+
+            // a bad certificate leaves the working server running
+            let bad = TlsPayload {
+                certificate: "invalid".to_string(),
+                ..self_signed_payload()
+            };
+            assert!(server.respawn_with_new_cert(&bad).await.is_err());
+            let pem = served_cert().await.unwrap();
+            assert_eq!(pem, second.certificate.as_bytes());
+
+            server.shutdown(false).await;
+        });
+    }
+
+    #[test]
+    fn open_h2_connections_dont_hold_up_respawns() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = config_with("worker_threads: 1\nrotation_drain_seconds: 0\n");
+        config.port = port;
+
+        actix_web::rt::System::new().block_on(async move {
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            let server = HttpServerLifecycle::new(gs, &self_signed_payload()).unwrap();
+            // connects offering only h2, returning the stream if the server agreed to it
+            let connect_h2 = || {
+                tokio::task::spawn_blocking(move || {
+                    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(5)))
+                        .unwrap();
+                    let mut connector =
+                        ssl::SslConnector::builder(ssl::SslMethod::tls_client()).unwrap();
+                    connector.set_verify(ssl::SslVerifyMode::NONE);
+                    connector.set_alpn_protos(b"\x02h2").unwrap();
+                    let stream = connector.build().connect("localhost", stream).unwrap();
+                    assert_eq!(stream.ssl().selected_alpn_protocol(), Some(&b"h2"[..]));
+                    stream
+                })
+            };
+
+            // the old server is stopped right away, even with an h2 connection still open
+            let open = connect_h2().await.unwrap();
+            let cert = self_signed_payload();
+            let respawn = server.respawn_with_new_cert(&cert);
+            tokio::time::timeout(Duration::from_secs(10), respawn)
+                .await
+                .expect("respawn waited on the open h2 connection")
+                .unwrap();
+            drop(open);
+
+            // and the new server speaks h2 too
+            connect_h2().await.unwrap();
+            server.shutdown(false).await;
+        });
+    }
+
+    #[test]
+    fn transient_bind_failure_is_retried() {
+        // something else holds the port for a moment after the old server stopped
+        let squatter = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = squatter.local_addr().unwrap().port();
+        let mut config = config_with("worker_threads: 1\n");
+        config.port = port;
+
+        actix_web::rt::System::new().block_on(async move {
+            let gs = GlobalState::for_tests(config, Box::new(TestCache::default()));
+            assert!(matches!(
+                HttpServerLifecycle::spawn(&gs, &self_signed_payload()),
+                Err(Error::Port(_))
+            ));
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(300));
+                drop(squatter);
+            });
+
+            let server = HttpServerLifecycle::spawn_after_stop(&gs, &self_signed_payload())
+                .await
+                .unwrap();
+            assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+            server.stop(false).await;
+        });
     }
 }
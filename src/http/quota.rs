@@ -0,0 +1,168 @@
+//! Per-IP quota on the number of bytes served within a window of time.
+//!
+//! Each peer has a bucket that fills up with the bytes sent to them and drains continuously at a
+//! rate of the quota per window, so there's no window boundary at which a peer could be sent
+//! twice their quota in quick succession. Bytes are counted as they're sent, and requests from a
+//! peer whose bucket is full are refused until enough of it drained. Peers whose bucket is empty
+//! are forgotten, which keeps memory bounded to the peers that made requests within the last
+//! window.
+
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Tracks the bytes recently served to each peer
+pub struct ByteQuota {
+    max_bytes: u64,
+    window: Duration,
+    state: Mutex<QuotaState>,
+}
+
+struct QuotaState {
+    peers: HashMap<IpAddr, Usage>,
+    last_purge: Instant,
+}
+
+struct Usage {
+    updated: Instant,
+    /// Bytes in the bucket as of `updated`, which can go over the quota since a response that's
+    /// already being sent isn't cut off
+    bytes: f64,
+}
+
+impl Usage {
+    /// The bytes left in the bucket after draining it until `now`
+    fn drained(&self, now: Instant, rate: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.bytes - elapsed * rate).max(0.0)
+    }
+}
+
+impl ByteQuota {
+    /// Creates a quota allowing each peer `max_bytes` bytes within any `window`
+    pub fn new(max_bytes: u64, window: Duration) -> Self {
+        Self {
+            max_bytes,
+            window,
+            state: Mutex::new(QuotaState {
+                peers: HashMap::new(),
+                last_purge: Instant::now(),
+            }),
+        }
+    }
+
+    /// The number of bytes drained from each bucket per second
+    fn rate(&self) -> f64 {
+        self.max_bytes as f64 / self.window.as_secs_f64()
+    }
+
+    /// Checks whether the peer used up their quota, returning how long until they can be served
+    /// again if they did
+    pub fn exhausted(&self, peer: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.purge(&mut state, now);
+
+        let rate = self.rate();
+        let bytes = state.peers.get(&peer)?.drained(now, rate);
+        let max_bytes = self.max_bytes as f64;
+        if bytes >= max_bytes {
+            // the peer can be served once the bucket drained just below the quota
+            Some(Duration::from_secs_f64((bytes - max_bytes + 1.0) / rate))
+        } else {
+            None
+        }
+    }
+
+    /// Counts bytes that were sent to the peer
+    pub fn record(&self, peer: IpAddr, bytes: usize) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let rate = self.rate();
+        let usage = state.peers.entry(peer).or_insert(Usage {
+            updated: now,
+            bytes: 0.0,
+        });
+        usage.bytes = usage.drained(now, rate) + bytes as f64;
+        usage.updated = now;
+    }
+
+    /// Forgets the peers whose bucket is empty, at most once per window
+    fn purge(&self, state: &mut QuotaState, now: Instant) {
+        if now.duration_since(state.last_purge) < self.window {
+            return;
+        }
+        let rate = self.rate();
+        state
+            .peers
+            .retain(|_, usage| usage.drained(now, rate) > 0.0);
+        state.last_purge = now;
+    }
+}
+
+/// Counts the bytes of the response chunks towards the quota of the peer as they're sent, or
+/// passes the stream through untouched if there's no quota (or no known peer)
+pub(super) fn metered<S, E>(
+    stream: S,
+    quota: Option<&Arc<ByteQuota>>,
+    peer: Option<IpAddr>,
+) -> impl Stream<Item = Result<Bytes, E>> + Unpin
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let quota = quota.cloned().zip(peer);
+    stream.inspect(move |chunk| {
+        if let (Some((quota, peer)), Ok(bytes)) = (&quota, chunk) {
+            quota.record(*peer, bytes.len());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_drain() {
+        let quota = ByteQuota::new(100, Duration::from_millis(100));
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        quota.record(peer, 60);
+        assert!(quota.exhausted(peer).is_none());
+        quota.record(peer, 60);
+        assert!(quota.exhausted(peer).is_some());
+        assert!(quota.exhausted(other).is_none());
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(quota.exhausted(peer).is_none());
+        // the peer was forgotten once their bucket was empty
+        assert!(quota.state.lock().unwrap().peers.is_empty());
+    }
+
+    #[test]
+    fn quota_holds_across_window_boundaries() {
+        let quota = ByteQuota::new(1000, Duration::from_secs(10));
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // a fixed window would allow another 1000 bytes right after its boundary
+        quota.record(peer, 1000);
+        let now = Instant::now();
+        quota
+            .state
+            .lock()
+            .unwrap()
+            .peers
+            .get_mut(&peer)
+            .unwrap()
+            .updated = now - Duration::from_secs(6);
+        assert!(quota.exhausted(peer).is_none());
+        quota.record(peer, 1000);
+        let remaining = quota.exhausted(peer).unwrap();
+        // 1400 bytes are left in the bucket, which drains 100 bytes per second
+        assert!(remaining > Duration::from_secs(3) && remaining <= Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,106 @@
+//! Parsing of `Range` headers (RFC 7233) for cached images.
+//!
+//! Only a single byte range is served as `206 Partial Content`. Requests for several ranges get the
+//! whole image instead, which the RFC allows, since readers practically never ask for more than
+//! one. Ranges in units other than bytes are ignored.
+
+use actix_web::http::header::HeaderValue;
+
+/// What part of an image a request asked for
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum ByteRange {
+    /// the whole image
+    Full,
+    /// the bytes from the first to the last position (inclusive)
+    Partial(u64, u64),
+    /// a range that's malformed or outside of the image
+    Unsatisfiable,
+}
+
+/// Finds the part of an image of `len` bytes that the `Range` header asks for
+pub(super) fn parse(header: Option<&HeaderValue>, len: u64) -> ByteRange {
+    let value = match header {
+        Some(x) => match x.to_str() {
+            Ok(x) => x.trim(),
+            Err(_) => return ByteRange::Unsatisfiable,
+        },
+        None => return ByteRange::Full,
+    };
+    let set = match value.split_once('=') {
+        Some((unit, set)) if unit.trim().eq_ignore_ascii_case("bytes") => set.trim(),
+        Some(_) => return ByteRange::Full,
+        None => return ByteRange::Unsatisfiable,
+    };
+    if set.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let (first, last) = match set.split_once('-') {
+        Some((first, last)) => (first.trim(), last.trim()),
+        None => return ByteRange::Unsatisfiable,
+    };
+    let pos = |x: &str| {
+        if !x.is_empty() && x.bytes().all(|b| b.is_ascii_digit()) {
+            x.parse::<u64>().ok()
+        } else {
+            None
+        }
+    };
+    let range = match (first.is_empty(), last.is_empty()) {
+        // the last `n` bytes
+        (true, false) => pos(last)
+            .filter(|&n| n > 0)
+            .map(|n| (len.saturating_sub(n), len.saturating_sub(1))),
+        // from `first` to the end
+        (false, true) => pos(first).map(|first| (first, len.saturating_sub(1))),
+        (false, false) => match (pos(first), pos(last)) {
+            (Some(first), Some(last)) if first <= last => {
+                Some((first, last.min(len.saturating_sub(1))))
+            }
+            _ => None,
+        },
+        (true, true) => None,
+    };
+
+    match range {
+        Some((first, last)) if first < len => ByteRange::Partial(first, last),
+        _ => ByteRange::Unsatisfiable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(value: &str, len: u64) -> ByteRange {
+        parse(Some(&HeaderValue::from_str(value).unwrap()), len)
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse(None, 100), ByteRange::Full);
+        assert_eq!(parse_str("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(parse_str("bytes=90-", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_str("bytes=-10", 100), ByteRange::Partial(90, 99));
+        // the end is clamped to the image
+        assert_eq!(parse_str("bytes=50-500", 100), ByteRange::Partial(50, 99));
+        assert_eq!(parse_str("bytes=-500", 100), ByteRange::Partial(0, 99));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert_eq!(parse_str("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_str("bytes=10-5", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_str("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_str("bytes=a-b", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_str("bytes=-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_str("bytes", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_str("bytes=+1-2", 100), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn unsupported_ranges_get_everything() {
+        assert_eq!(parse_str("bytes=0-1,5-9", 100), ByteRange::Full);
+        assert_eq!(parse_str("pages=1-2", 100), ByteRange::Full);
+    }
+}
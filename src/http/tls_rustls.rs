@@ -0,0 +1,122 @@
+//! TLS backed by rustls instead of OpenSSL (enabled with the `tls-rustls` feature).
+//!
+//! The certificate chain is parsed the same way as with OpenSSL, but the private key may be either
+//! an RSA or an ECDSA key. rustls never speaks TLS versions older than 1.2 or accepts early data,
+//! and it always picks the ALPN protocol by the server's preference of "h2" over "http/1.1".
+
+use super::{Error, HttpServerLifecycle};
+use crate::backend::TlsPayload;
+use crate::GlobalState;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    Certificate, ClientHello, NoClientAuth, PrivateKey, ProtocolVersion, ResolvesServerCert,
+    ServerConfig, ServerSessionMemoryCache,
+};
+use std::sync::Arc;
+
+/// Converts a [`TlsPayload`] into the rustls configuration that Actix Web will use for TLS
+pub(super) fn create_acceptor(
+    gs: Arc<GlobalState>,
+    cert: &TlsPayload,
+) -> Result<ServerConfig, Error> {
+    let chain = HttpServerLifecycle::parse_cert_chain(&cert.certificate)?
+        .iter()
+        .map(|x| x.to_der().map(Certificate))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = sign::any_supported_type(&parse_private_key(&cert.private_key)?)
+        .map_err(|_| Error::Certificate("private key is neither an RSA nor an ECDSA key"))?;
+
+    let config = &gs.config;
+    let mut builder = ServerConfig::new(NoClientAuth::new());
+    builder.versions = vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2];
+    if !config.enforce_secure_tls {
+        log::warn!("enforce_secure_tls is off, but rustls never accepts TLS older than 1.2");
+    }
+    if config.alpn_protocols != super::SUPPORTED_ALPN {
+        log::warn!("alpn_protocols is ignored with rustls, which always prefers h2");
+    }
+
+    // always use the server preference for ciphersuites, like with OpenSSL
+    builder.ignore_client_order = true;
+    builder.session_storage = ServerSessionMemoryCache::new(1024 * 4);
+
+    builder.cert_resolver = Arc::new(SniResolver {
+        reject_invalid_sni: config.reject_invalid_sni,
+        key: CertifiedKey::new(chain, Arc::new(key)),
+        gs: Arc::clone(&gs),
+    });
+    Ok(builder)
+}
+
+/// Parses the first private key in a PEM, either in the PKCS#8 format (RSA or ECDSA) or the
+/// PKCS#1 format (RSA only)
+fn parse_private_key(pem: &str) -> Result<PrivateKey, Error> {
+    use rustls_pemfile::Item;
+
+    let items = rustls_pemfile::read_all(&mut pem.as_bytes())
+        .map_err(|_| Error::Certificate("private key PEM is malformed"))?;
+    items
+        .into_iter()
+        .find_map(|x| match x {
+            Item::PKCS8Key(der) | Item::RSAKey(der) => Some(PrivateKey(der)),
+            Item::X509Certificate(_) => None,
+        })
+        .ok_or(Error::Certificate(
+            "no PKCS#8 or RSA private key found in PEM",
+        ))
+}
+
+/// Serves the certificate, aborting handshakes with an invalid server name if configured
+struct SniResolver {
+    gs: Arc<GlobalState>,
+    reject_invalid_sni: bool,
+    key: CertifiedKey,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        if self.reject_invalid_sni {
+            let servername: Option<&str> = client_hello.server_name().map(Into::into);
+            if !HttpServerLifecycle::is_valid_sni(&self.gs, servername) {
+                return None;
+            }
+        }
+        Some(self.key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tests::TestCache;
+    use crate::config::tests::config_with;
+
+    #[test]
+    fn rsa_and_ecdsa_keys_are_accepted() {
+        use openssl::{ec, nid::Nid, pkey::PKey};
+
+        let gs = GlobalState::for_tests(config_with(""), Box::new(TestCache::default()));
+        let payload = crate::http::tests::self_signed_payload();
+        assert!(create_acceptor(Arc::clone(&gs), &payload).is_ok());
+
+        // PKCS#1 RSA keys
+        let rsa = PKey::private_key_from_pem(payload.private_key.as_bytes())
+            .unwrap()
+            .rsa()
+            .unwrap();
+        let pkcs1 = String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap();
+        assert!(parse_private_key(&pkcs1).is_ok());
+
+        // PKCS#8 ECDSA keys
+        let group = ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ecdsa = PKey::from_ec_key(ec::EcKey::generate(&group).unwrap()).unwrap();
+        let pkcs8 = String::from_utf8(ecdsa.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let key = parse_private_key(&pkcs8).unwrap();
+        assert!(sign::any_supported_type(&key).is_ok());
+
+        assert!(matches!(
+            parse_private_key(&payload.certificate),
+            Err(Error::Certificate(_))
+        ));
+    }
+}
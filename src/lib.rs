@@ -0,0 +1,1227 @@
+//! Yet another unofficial MD@Home client implementation.
+//!
+//! The client is usually run through the `scalpel` binary, but it can also be embedded in a larger
+//! application as a [`Node`]. A [`NodeBuilder`] creates the node from a configuration, optionally
+//! with a custom [`ImageCache`] or [`Backend`] in place of the configured ones.
+
+use arc_swap::ArcSwap;
+use std::sync::{atomic, Arc, Mutex};
+use std::time;
+
+pub mod backend;
+pub mod cache;
+pub mod config;
+mod http;
+mod metrics;
+mod tokens;
+mod utils;
+
+use backend::ApiBackend;
+pub use backend::Backend;
+pub use cache::ImageCache;
+#[doc(hidden)]
+pub use http::HitBench;
+pub use utils::constants;
+
+/// Structure that holds thread-safe data that should be accessible throughout most of the
+/// application. This is created by the NodeBuilder below and passed throughout the Node as an
+/// Arc
+pub struct GlobalState {
+    config: Arc<config::AppConfig>,
+    /// the active cache, which can be swapped for a new one while running (see `/admin/rotate`)
+    cache: cache::SwappableCache,
+    verifier: ArcSwap<tokens::TokenVerifier>,
+    backend: Arc<dyn Backend>,
+    request_counter: atomic::AtomicUsize,
+    /// number of requests that are currently being handled
+    in_flight: Arc<atomic::AtomicUsize>,
+    /// whether load balancers should send traffic to this client (false while in lame duck mode)
+    ready: atomic::AtomicBool,
+    /// whether responses ask clients to close their connections (before a certificate rotation)
+    draining: atomic::AtomicBool,
+    /// request counts of the most requested images, if the warm snapshot is enabled
+    hot_keys: Option<cache::HotKeys>,
+    /// global cap on the rate images are served at, if enabled
+    egress: Option<Arc<http::EgressLimiter>>,
+    /// bytes served to each peer within their quota window, if enabled
+    byte_quota: Option<Arc<http::ByteQuota>>,
+    /// requests made over each connection, if they're limited
+    request_limit: Option<http::RequestLimit>,
+    /// upstream fetches in progress, which other requests for the same image join
+    fetches: http::InFlightFetches,
+    /// whether each of the most recent image requests was a HIT
+    recent_hits: metrics::RecentHits,
+    metrics: Arc<metrics::Metrics>,
+    /// where the metrics of image requests are sent
+    sink: Arc<dyn metrics::MetricsSink>,
+    /// source of the current time for entry ages and timestamps
+    clock: Arc<dyn utils::Clock>,
+}
+
+impl GlobalState {
+    /// Describes how warm the cache is relative to the configured maximum size
+    fn warmth(&self) -> metrics::Warmth {
+        let max_sz = self.config.cache_size_mebibytes as f64 * 1024f64 * 1024f64;
+        metrics::Warmth {
+            size_ratio: self.cache.report() as f64 / max_sz,
+            entries: self.cache.report_entries(),
+            recent_hit_ratio: self.recent_hits.ratio(),
+        }
+    }
+
+    /// Enters lame duck mode for the duration provided, reporting the client as not ready (so load
+    /// balancers stop sending traffic) while still serving any requests that come in
+    async fn lame_duck(&self, duration: time::Duration) {
+        log::info!("entering lame duck mode for {} seconds", duration.as_secs());
+        self.ready.store(false, atomic::Ordering::SeqCst);
+        tokio::time::sleep(duration).await;
+        log::info!("lame duck period over, continuing shutdown");
+    }
+
+    /// Asks clients to close their connections for the duration provided, by sending
+    /// `Connection: close` with every response. The flag stays set until it's cleared, so that the
+    /// connections opened in the meantime are closed too.
+    async fn drain_connections(&self, duration: time::Duration) {
+        log::info!(
+            "draining connections for {} seconds",
+            duration.as_secs_f32()
+        );
+        self.draining.store(true, atomic::Ordering::SeqCst);
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Creates a global state that isn't part of a running node, for the tests and benchmarks
+    fn standalone(
+        config: Arc<config::AppConfig>,
+        cache: Box<dyn cache::ImageCache>,
+        backend: Arc<dyn Backend>,
+    ) -> Arc<Self> {
+        let metrics = Arc::new(metrics::Metrics::new().expect("metrics initialize"));
+        let sink = metrics_sink(&config, &metrics);
+        Arc::new(Self {
+            backend,
+            hot_keys: hot_keys(&config),
+            egress: egress_limiter(&config),
+            byte_quota: byte_quota(&config),
+            request_limit: request_limit(&config),
+            fetches: http::InFlightFetches::new(),
+            config,
+            cache: cache::SwappableCache::new(cache),
+            verifier: ArcSwap::from_pointee(tokens::TokenVerifier::new()),
+            request_counter: atomic::AtomicUsize::new(0),
+            in_flight: Arc::new(atomic::AtomicUsize::new(0)),
+            ready: atomic::AtomicBool::new(true),
+            draining: atomic::AtomicBool::new(false),
+            recent_hits: metrics::RecentHits::new(RECENT_REQUESTS),
+            sink,
+            metrics,
+            clock: Arc::new(utils::SystemClock),
+        })
+    }
+}
+
+#[cfg(test)]
+impl GlobalState {
+    /// Creates a global state from a configuration and cache for use in tests
+    pub(crate) fn for_tests(
+        config: config::AppConfig,
+        cache: Box<dyn cache::ImageCache>,
+    ) -> Arc<Self> {
+        let backend = Arc::new(backend::tests::MockBackend::default());
+        Self::standalone(Arc::new(config), cache, backend)
+    }
+}
+
+/// Creates the sink that the metrics of image requests are sent to
+fn metrics_sink(
+    config: &config::AppConfig,
+    metrics: &Arc<metrics::Metrics>,
+) -> Arc<dyn metrics::MetricsSink> {
+    match config.metrics_sink {
+        config::MetricsSinkKind::Prometheus => Arc::clone(metrics) as _,
+        config::MetricsSinkKind::None => Arc::new(metrics::NoopSink),
+    }
+}
+
+/// Creates the counter for the most requested images if the warm snapshot is enabled
+fn hot_keys(config: &config::AppConfig) -> Option<cache::HotKeys> {
+    config
+        .warm_snapshot
+        .as_ref()
+        .map(|x| cache::HotKeys::new(x.keys))
+}
+
+/// Creates the global cap on the rate images are served at, if enabled
+fn egress_limiter(config: &config::AppConfig) -> Option<Arc<http::EgressLimiter>> {
+    config
+        .max_egress_kibibytes
+        .map(|x| Arc::new(http::EgressLimiter::new(x * 1024)))
+}
+
+/// Creates the limit on the requests made over each connection, if enabled
+fn request_limit(config: &config::AppConfig) -> Option<http::RequestLimit> {
+    config
+        .max_requests_per_connection
+        .map(|x| http::RequestLimit::new(x, time::Duration::from_secs(config.keep_alive as u64)))
+}
+
+/// Creates the per-IP byte quota, if enabled
+fn byte_quota(config: &config::AppConfig) -> Option<Arc<http::ByteQuota>> {
+    config.ip_byte_quota.as_ref().map(|x| {
+        Arc::new(http::ByteQuota::new(
+            x.kibibytes * 1024,
+            time::Duration::from_secs(x.window_seconds),
+        ))
+    })
+}
+
+// constant multipliers for cache threshold and shrink-to sizes
+// SHRINK_MULT = multiplier to the maximum size after shrinking, if shrink was triggered
+// MAX_MULT = multiplier to the max db size before triggering a shrink
+const SHRINK_MULT: f64 = 0.9;
+const MAX_MULT: f64 = 0.95;
+
+/// Number of the most recent image requests that the recent hit ratio is calculated over
+const RECENT_REQUESTS: usize = 1000;
+
+/// How often the backend is pinged while the client is running
+const PING_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+/// How often the backend is told that the client is stopping before giving up on it
+const STOP_ATTEMPTS: usize = 4;
+
+/// A MD@Home node: the cache, the backend the node is registered with and the HTTP server that
+/// serves images, along with the upkeep of all of them. Created with a [`NodeBuilder`].
+///
+/// The node runs with [`run`](Self::run) until [`shutdown`](Self::shutdown) is called.
+pub struct Node {
+    gs: Arc<GlobalState>,
+    compaction: Mutex<Option<cache::CompactionScheduler>>,
+    janitor: Arc<cache::Janitor>,
+    /// set once the node should shut down
+    stop: Arc<atomic::AtomicBool>,
+    /// the bytes served as of the last successful backend ping
+    bytes_at_ping: atomic::AtomicU64,
+    /// set once the node was asked to shut down again while shutting down, which cuts the
+    /// graceful shutdown short
+    forced: tokio::sync::watch::Sender<bool>,
+}
+
+/// Builds a [`Node`] from a configuration. The cache and backend are created from the
+/// configuration, unless they're replaced with [`with_cache`](Self::with_cache) or
+/// [`with_backend`](Self::with_backend).
+pub struct NodeBuilder {
+    config: config::AppConfig,
+    cache: Option<Box<dyn ImageCache>>,
+    backend: Option<Arc<dyn Backend>>,
+}
+
+/// Dynamically creates the cache implementation based on the configured cache engine, with the
+/// configured slower tier behind it (if any), mirroring it to the configured mirror engine (if
+/// any), with a hot cache in front of it (if configured)
+///
+/// ## Panic
+///
+/// This function will 100% of the time panic if there is a problem with the configuration of the
+/// cache engine, there is an error creating the cache engine itself, or if the provided name is
+/// invaid.
+async fn create_dyn_cache(
+    config: &config::AppConfig,
+    pacer: &cache::MaintenancePacer,
+    clock: &Arc<dyn utils::Clock>,
+) -> Box<dyn cache::ImageCache> {
+    let mut primary = create_cache_engine(&config.cache_engine, config, pacer, clock).await;
+    if let Some(tiers) = &config.tiers {
+        primary = Box::new(cache::TieredCache::new(
+            primary,
+            create_cache_engine(&tiers.engine, config, pacer, clock).await,
+            tiers,
+        ));
+    }
+    let cache: Box<dyn cache::ImageCache> = match &config.mirror_engine {
+        Some(engine) => Box::new(cache::MirroredCache::new(
+            primary,
+            create_cache_engine(engine, config, pacer, clock).await,
+            config.mirror_queue_size,
+        )),
+        None => primary,
+    };
+    match config.hot_cache_mebibytes {
+        Some(_) if config.cache_engine == "memory" => {
+            log::warn!("hot_cache_mebibytes is pointless with the memory engine, ignoring");
+            cache
+        }
+        Some(size) => Box::new(cache::HotCache::new(cache, size * 1024 * 1024)),
+        None => cache,
+    }
+}
+
+/// Creates the cache, warning about any of the configured cache features that it doesn't support
+async fn init_cache(
+    config: &config::AppConfig,
+    pacer: &cache::MaintenancePacer,
+    clock: &Arc<dyn utils::Clock>,
+) -> Box<dyn cache::ImageCache> {
+    let cache = create_dyn_cache(config, pacer, clock).await;
+    if config.archive_budgets.is_some() && cache.report_archive(false).is_none() {
+        log::warn!("archive_budgets are not supported by the cache engine, ignoring");
+    }
+    if !config.pinned_chapters.is_empty() && cache.report_pinned().is_none() {
+        log::warn!("pinned_chapters are not supported by the cache engine, ignoring");
+    }
+    if let Some(conf) = &config.size_reconciliation {
+        reconcile_cache_size(cache.as_ref(), conf).await;
+    }
+    cache
+}
+
+/// Checks the size tracked by the cache against the data it actually stores, correcting it if
+/// they drifted apart too far
+async fn reconcile_cache_size(
+    cache: &dyn cache::ImageCache,
+    conf: &config::SizeReconciliationConfig,
+) {
+    let timer = utils::Timer::start();
+    let max_drift = conf.max_drift_percent / 100.0;
+    match cache.reconcile_size(conf.sample_entries, max_drift).await {
+        Ok(res) if res.corrected => log::warn!(
+            "cache size drifted by {:.2}% over {} entries ({} bytes tracked, {} bytes stored), \
+            corrected in {:#}",
+            res.drift() * 100.0,
+            res.entries_checked,
+            res.tracked,
+            res.actual,
+            timer
+        ),
+        Ok(res) => log::info!(
+            "cache size drifted by {:.2}% over {} entries, checked in {:#}",
+            res.drift() * 100.0,
+            res.entries_checked,
+            timer
+        ),
+        Err(_) => log::warn!("unable to reconcile the size of the cache, ignoring"),
+    }
+}
+
+/// Shrinks the cache database (in steps, see [`cache::Janitor`]) if the reported size is above
+/// the maximum size in the config. Will log if an error occurs (but not the specific error) and the time it took.
+async fn try_shrink_db(gs: &GlobalState, janitor: &cache::Janitor) {
+    let db_sz = gs.cache.report() as f64;
+    let max_sz = gs.config.cache_size_mebibytes as f64 * 1024f64 * 1024f64;
+    log::info!(
+        "reported cache size: {:.2}MiB ({:.2}%)",
+        db_sz / 1024f64 / 1024f64,
+        db_sz / max_sz * 100.0
+    );
+    gs.sink.set_cache_size(db_sz as u64);
+    gs.metrics.cache_max_size.set(max_sz as i64);
+    if let Some(pinned_sz) = gs.cache.report_pinned() {
+        log::info!(
+            "reported pinned size: {:.2}MiB",
+            pinned_sz as f64 / 1024f64 / 1024f64
+        );
+        gs.metrics.cache_pinned_size.set(pinned_sz as i64);
+    }
+
+    // shrink database if reported size is above the maximum size reported in the config
+    if db_sz > (max_sz * MAX_MULT) {
+        log::warn!("database is over maximum size, shrinking...");
+        let timer = utils::Timer::start();
+        let min = (max_sz * SHRINK_MULT) as u64;
+        match janitor.shrink(&gs.cache, min).await {
+            Ok(res) => {
+                log::warn!(
+                    "db shrinked to size {}B ({} entries and {}B evicted)",
+                    res.size,
+                    res.entries_evicted,
+                    res.bytes_evicted
+                );
+                gs.metrics
+                    .record_eviction(metrics::EvictionReason::SizeCap, &res);
+            }
+            Err(_) => log::error!("problem shrinking database! hopefully there's more logs"),
+        }
+        log::info!("shrinking db took {}ms", timer.elapsed());
+    }
+
+    // archive types are shrunk separately if they're over their own budget
+    if let Some(budgets) = &gs.config.archive_budgets {
+        for &data_saver in &[false, true] {
+            if let Some(budget) = budgets.for_archive(data_saver) {
+                try_shrink_archive(gs, janitor, data_saver, budget).await;
+            }
+        }
+    }
+}
+
+/// Evicts the entries that haven't been accessed within the configured idle TTL, if enabled.
+/// Will log if an error occurs (but not the specific error) and the time it took.
+async fn try_sweep_idle(gs: &GlobalState) {
+    let ttl = match gs.config.idle_ttl_hours {
+        Some(hours) => time::Duration::from_secs(hours * 60 * 60),
+        None => return,
+    };
+
+    let timer = utils::Timer::start();
+    match gs.cache.sweep_idle(ttl).await {
+        Ok(res) => {
+            log::info!(
+                "idle sweep evicted {} entries ({}B)",
+                res.entries_evicted,
+                res.bytes_evicted
+            );
+            gs.metrics
+                .record_eviction(metrics::EvictionReason::IdleTtl, &res);
+        }
+        Err(_) => log::error!("problem sweeping idle entries! hopefully there's more logs"),
+    }
+    log::info!("idle sweep took {}ms", timer.elapsed());
+}
+
+/// Shrinks the images of one archive type if they are above the budget (in mebibytes) for
+/// that archive type.
+async fn try_shrink_archive(
+    gs: &GlobalState,
+    janitor: &cache::Janitor,
+    data_saver: bool,
+    budget: u32,
+) {
+    let name = if data_saver { "data-saver" } else { "data" };
+    let sz = match gs.cache.report_archive(data_saver) {
+        Some(sz) => sz as f64,
+        None => return,
+    };
+    let max_sz = budget as f64 * 1024f64 * 1024f64;
+    log::info!(
+        "reported {} archive size: {:.2}MiB ({:.2}%)",
+        name,
+        sz / 1024f64 / 1024f64,
+        sz / max_sz * 100.0
+    );
+
+    if sz > (max_sz * MAX_MULT) {
+        log::warn!("{} archive is over its budget, shrinking...", name);
+        let timer = utils::Timer::start();
+        let min = (max_sz * SHRINK_MULT) as u64;
+        let res = janitor.shrink_archive(&gs.cache, data_saver, min).await;
+        match res {
+            Ok(res) => {
+                log::warn!(
+                    "{} archive shrinked to size {}B ({} entries evicted)",
+                    name,
+                    res.size,
+                    res.entries_evicted
+                );
+                let reason = metrics::EvictionReason::ArchiveBudget;
+                gs.metrics.record_eviction(reason, &res);
+            }
+            Err(_) => log::error!("problem shrinking {} archive!", name),
+        }
+        log::info!("shrinking {} archive took {}ms", name, timer.elapsed());
+    }
+}
+
+/// Initializes the cache in the background, returning a gate that stands in for the cache until
+/// it's ready. Stops the client (through `stop`) if the cache fails to initialize.
+fn spawn_cache_init(
+    config: Arc<config::AppConfig>,
+    pacer: cache::MaintenancePacer,
+    clock: Arc<dyn utils::Clock>,
+    stop: Arc<atomic::AtomicBool>,
+) -> cache::CacheGate {
+    let gate = cache::CacheGate::new();
+    let opener = gate.clone();
+
+    // opening the cache can block for a long time, so it gets its own thread to keep the rest of
+    // the client responsive
+    let handle = tokio::runtime::Handle::current();
+    let init = tokio::task::spawn_blocking(move || {
+        let timer = utils::Timer::start();
+        let cache = handle.block_on(init_cache(&config, &pacer, &clock));
+        log::info!("cache initialized in {:#}", timer);
+        opener.open(cache);
+    });
+    tokio::spawn(async move {
+        if init.await.is_err() {
+            log::error!("cache failed to initialize, shutting down");
+            stop.store(true, atomic::Ordering::SeqCst);
+        }
+    });
+    gate
+}
+
+/// Creates the cache implementation with the name provided. See [`create_dyn_cache`] for panics.
+async fn create_cache_engine(
+    name: &str,
+    config: &config::AppConfig,
+    pacer: &cache::MaintenancePacer,
+    clock: &Arc<dyn utils::Clock>,
+) -> Box<dyn cache::ImageCache> {
+    // only RocksDB paces its maintenance
+    #[cfg(not(feature = "ce-rocksdb"))]
+    let _ = pacer;
+    match name {
+        "memory" => Box::new(
+            cache::MemoryCache::new(
+                config
+                    .mem_opt
+                    .as_ref()
+                    .expect("memory ce config not provided"),
+            )
+            .with_clock(Arc::clone(clock)),
+        ),
+        #[cfg(feature = "ce-filesystem")]
+        "fs" => Box::new(
+            cache::FileSystemCache::new(config.fs_opt.as_ref().expect("fs ce config not provided"))
+                .await
+                .expect("unable to initialize fs cache engine")
+                .with_clock(Arc::clone(clock)),
+        ),
+        #[cfg(feature = "ce-rocksdb")]
+        "rocksdb" => Box::new(
+            cache::RocksCache::new(
+                config
+                    .rocks_opt
+                    .as_ref()
+                    .expect("rocksdb ce config not provided"),
+            )
+            .expect("unable to initialize RocksDB cache engine")
+            .with_pacer(pacer.clone())
+            .with_clock(Arc::clone(clock))
+            .with_pins(config.pinned_chapters.clone()),
+        ),
+        #[cfg(feature = "ce-sled")]
+        "sled" => Box::new(
+            cache::SledCache::new(
+                config
+                    .sled_opt
+                    .as_ref()
+                    .expect("sled ce config not provided"),
+            )
+            .expect("unable to initialize sled cache engine")
+            .with_clock(Arc::clone(clock)),
+        ),
+        #[cfg(feature = "ce-redis")]
+        "redis" => Box::new(
+            cache::RedisCache::new(
+                config
+                    .redis_opt
+                    .as_ref()
+                    .expect("redis ce config not provided"),
+            )
+            .await
+            .expect("unable to initialize redis cache engine")
+            .with_clock(Arc::clone(clock)),
+        ),
+        #[cfg(feature = "ce-s3")]
+        "s3" => Box::new(
+            cache::S3Cache::new(config.s3_opt.as_ref().expect("s3 ce config not provided"))
+                .await
+                .expect("unable to initialize s3 cache engine")
+                .with_clock(Arc::clone(clock)),
+        ),
+        a => panic!("\"{}\" is not a valid cache engine", a),
+    }
+}
+
+impl NodeBuilder {
+    pub fn new(config: config::AppConfig) -> Self {
+        Self {
+            config,
+            cache: None,
+            backend: None,
+        }
+    }
+
+    /// Serves images from `cache` instead of the configured cache engine
+    pub fn with_cache(mut self, cache: Box<dyn ImageCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Registers the node with `backend` instead of the MD@Home API
+    pub fn with_backend(mut self, backend: Arc<dyn Backend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Validates the configuration and creates the node, initializing the cache.
+    ///
+    /// ## Panic
+    ///
+    /// Creating the configured cache engine panics if it fails, see [`create_dyn_cache`].
+    pub async fn build(self) -> Result<Node, String> {
+        let Self {
+            mut config,
+            cache,
+            backend,
+        } = self;
+        config.apply_deprecated();
+        config
+            .validate()
+            .map_err(|e| format!("invalid configuration: {}", e))?;
+        if config.pass_through {
+            log::warn!("pass-through mode is enabled, images will never be served from the cache");
+        }
+
+        // initialize sodiumoxide for thread safety
+        sodiumoxide::init().map_err(|_| "unable to initialize sodiumoxide".to_string())?;
+
+        // initialize the global state Arc
+        let stop = Arc::new(atomic::AtomicBool::new(false));
+        let gs = {
+            // place config into it's own Arc so it can be shared to other portions of the
+            // application without the entire global state
+            //
+            // this is mainly for the `Backend` module, as the `GlobalState` refers to the backend
+            // structure and it wouldn't be wise to cyclically refer back to `GlobalState` inside
+            // of the backend module
+            let config = Arc::new(config);
+            let metrics = Arc::new(metrics::Metrics::new().expect("metrics intialize"));
+
+            // may panic, but it's fine because it's before ping
+            log::debug!("initializing cache...");
+            let in_flight = Arc::new(atomic::AtomicUsize::new(0));
+            let pacer = cache::MaintenancePacer::new(
+                Arc::clone(&in_flight),
+                config.maintenance_busy_requests,
+            );
+            let clock: Arc<dyn utils::Clock> = Arc::new(utils::SystemClock);
+            let cache: Box<dyn cache::ImageCache> = match cache {
+                Some(cache) => cache,
+                None if config.serve_during_cache_init => Box::new(spawn_cache_init(
+                    Arc::clone(&config),
+                    pacer,
+                    Arc::clone(&clock),
+                    Arc::clone(&stop),
+                )),
+                None => init_cache(&config, &pacer, &clock).await,
+            };
+
+            // initialize the backend
+            let backend = backend.unwrap_or_else(|| Arc::new(ApiBackend::new(Arc::clone(&config))));
+            let hot_keys = hot_keys(&config);
+            let egress = egress_limiter(&config);
+            let byte_quota = byte_quota(&config);
+            let request_limit = request_limit(&config);
+            let sink = metrics_sink(&config, &metrics);
+
+            // create Atomic Reference Counter global state, that is passed to almost every aspect
+            // of the application
+            Arc::new(GlobalState {
+                config,
+                cache: cache::SwappableCache::new(cache),
+                backend,
+                verifier: ArcSwap::from_pointee(tokens::TokenVerifier::new()),
+                request_counter: atomic::AtomicUsize::new(0),
+                in_flight,
+                ready: atomic::AtomicBool::new(true),
+                draining: atomic::AtomicBool::new(false),
+                hot_keys,
+                egress,
+                byte_quota,
+                request_limit,
+                fetches: http::InFlightFetches::new(),
+                recent_hits: metrics::RecentHits::new(RECENT_REQUESTS),
+                metrics,
+                sink,
+                clock,
+            })
+        };
+
+        let compaction = gs
+            .config
+            .compaction_window
+            .clone()
+            .map(cache::CompactionScheduler::new);
+        let pacer = cache::MaintenancePacer::new(
+            Arc::clone(&gs.in_flight),
+            gs.config.maintenance_busy_requests,
+        );
+        let janitor = cache::Janitor::new(
+            gs.config.shrink_step_mebibytes * 1024 * 1024,
+            pacer,
+            Arc::clone(&stop),
+        );
+        Ok(Node {
+            gs,
+            compaction: Mutex::new(compaction),
+            janitor: Arc::new(janitor),
+            stop,
+            bytes_at_ping: atomic::AtomicU64::new(0),
+            forced: tokio::sync::watch::channel(false).0,
+        })
+    }
+}
+
+impl Node {
+    /// Pings the backend server (reporting any errors that occur), then returns the ssl
+    /// certificate and whether this ssl certificate is new
+    async fn ping_backend(
+        &self,
+    ) -> Result<Option<backend::TlsPayload>, Box<dyn std::error::Error>> {
+        // perform the ping on the backend server
+        let (crt, token_key) = self.gs.backend.ping().await?;
+        let bytes_served = self.gs.metrics.bytes_up.get();
+        self.bytes_at_ping
+            .store(bytes_served, atomic::Ordering::Relaxed);
+
+        // update the token verifier with the new token_key
+        if let Some(token_key) = &token_key {
+            let mut verifier = tokens::TokenVerifier::new();
+            verifier.push_key_b64(token_key)?;
+            self.gs.verifier.store(Arc::new(verifier));
+        }
+
+        // return certificate for HTTP server
+        Ok(crt)
+    }
+
+    /// Pings the backend server, returning an error if there is no ssl certificate provided (like
+    /// it should on the initial ping)
+    async fn ping_for_cert(&self) -> Result<backend::TlsPayload, Box<dyn std::error::Error>> {
+        self.ping_backend()
+            .await?
+            .ok_or_else(|| "TLS certificate wasn't provided in ping".into())
+    }
+
+    /// Loads the images in the warm snapshot (if enabled and one was written) from the cache in the
+    /// background, so the most requested images are quick to serve soon after a restart
+    fn spawn_warm_up(&self) {
+        let path = match &self.gs.config.warm_snapshot {
+            Some(x) => x.path.clone(),
+            None => return,
+        };
+
+        let gs = Arc::clone(&self.gs);
+        tokio::spawn(async move {
+            while !gs.cache.is_ready() {
+                tokio::time::sleep(time::Duration::from_secs(1)).await;
+            }
+            let keys = match cache::read_snapshot(&path).await {
+                Ok(keys) => keys,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+                Err(e) => {
+                    log::error!("error reading warm snapshot: {}", e);
+                    return;
+                }
+            };
+            let timer = utils::Timer::start();
+            let found = cache::warm_cache(&gs.cache, &keys).await;
+            log::info!(
+                "warmed {} of {} images from the snapshot in {:#}",
+                found,
+                keys.len(),
+                timer
+            );
+        });
+    }
+
+    /// Writes the most requested images to the warm snapshot, if enabled
+    async fn write_warm_snapshot(&self) {
+        let (hot_keys, snapshot) = match (&self.gs.hot_keys, &self.gs.config.warm_snapshot) {
+            (Some(hot_keys), Some(snapshot)) => (hot_keys, snapshot),
+            _ => return,
+        };
+        match hot_keys.write_snapshot(&snapshot.path).await {
+            Ok(n) => log::info!("wrote {} images to the warm snapshot", n),
+            Err(e) => log::error!("error writing warm snapshot: {}", e),
+        }
+    }
+
+    /// Checks the size of the cache in the background on the configured interval, shrinking it if
+    /// it's oversized. Shrinking can take a while, and this keeps it from holding up backend pings.
+    fn spawn_janitor(&self) {
+        let gs = Arc::clone(&self.gs);
+        let janitor = Arc::clone(&self.janitor);
+        let stop = Arc::clone(&self.stop);
+        tokio::spawn(async move {
+            // shrinking is checked right away, then every `shrink_interval_seconds`
+            let shrink_interval = time::Duration::from_secs(gs.config.shrink_interval_seconds);
+            let mut interval = tokio::time::interval(time::Duration::from_secs(1));
+            let mut last_shrink: Option<time::Instant> = None;
+            while !stop.load(atomic::Ordering::SeqCst) {
+                interval.tick().await;
+                if last_shrink.is_none_or(|x| x.elapsed() >= shrink_interval) {
+                    last_shrink = Some(time::Instant::now());
+                    try_shrink_db(&gs, &janitor).await;
+                }
+            }
+        });
+    }
+
+    /// Sweeps idle entries out of the cache in the background every hour, if an idle TTL is
+    /// configured. A sweep scans the whole cache, and this keeps it from holding up backend pings.
+    fn spawn_sweeper(&self) {
+        if self.gs.config.idle_ttl_hours.is_none() {
+            return;
+        }
+        let gs = Arc::clone(&self.gs);
+        let stop = Arc::clone(&self.stop);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(time::Duration::from_secs(1));
+            let mut last_sweep = time::Instant::now();
+            while !stop.load(atomic::Ordering::SeqCst) {
+                interval.tick().await;
+                if last_sweep.elapsed().as_secs() >= 3600 {
+                    last_sweep = time::Instant::now();
+                    try_sweep_idle(&gs).await;
+                }
+            }
+        });
+    }
+
+    /// Starts a full compaction of the cache database in the background if the configured
+    /// compaction window allows for it.
+    fn try_compact_db(&self, requests_per_sec: f64) {
+        let now = chrono::Local::now().naive_local();
+        let should_run = self
+            .compaction
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_mut()
+            .map(|x| x.should_run(now, requests_per_sec))
+            .unwrap_or(false);
+        if !should_run {
+            return;
+        }
+
+        // compaction can take a long time, so it's spawned on its own task to prevent blocking
+        // backend pings
+        let gs = Arc::clone(&self.gs);
+        tokio::spawn(async move {
+            log::info!("starting scheduled cache compaction");
+            let timer = utils::Timer::start();
+            gs.cache.compact().await;
+            log::info!("scheduled cache compaction finished in {:#}", timer);
+        });
+    }
+
+    /// Runs the node until [`shutdown`](Self::shutdown) is called, then shuts it down gracefully.
+    ///
+    /// This function handles:
+    /// - Warming the cache with the most requested images from before the restart
+    /// - Creating and orchestrating the HTTP Server
+    /// - Updating the backend server with client settings
+    /// - Shrinking the cache in the background when it's oversized
+    /// - Compacting the cache inside of the configured window
+    /// - Calls function to instigate graceful shutdown once stopped
+    ///
+    /// Actix Web servers must be spawned inside of an Actix system, so this has to be run inside of
+    /// one too.
+    pub async fn run(&self) {
+        self.spawn_warm_up();
+
+        // perform initial ping to backend to get HTTP certificate, retrying until it succeeds since
+        // the backend being temporarily unreachable shouldn't take down the client
+        let backoff =
+            utils::Backoff::new(time::Duration::from_secs(1), time::Duration::from_secs(60));
+        let mut crt =
+            match utils::retry_with_backoff("initial backend ping", backoff, &self.stop, || {
+                self.ping_for_cert()
+            })
+            .await
+            {
+                Some(crt) => crt,
+                None => {
+                    log::warn!("stopped before the initial backend ping succeeded");
+                    return;
+                }
+            };
+
+        // spawn the HTTP server with the certificate
+        // if there is a problem creating it, gracefully shutdown and panic
+        let server = match http::HttpServerLifecycle::new(Arc::clone(&self.gs), &crt) {
+            Ok(srv) => srv,
+            Err(e) => {
+                log::error!("there was a problem creating the http server: {}", e);
+                log::error!("gracefully shutting down then panic due to error...");
+                self.graceful_shutdown(None).await;
+                panic!("error creating HTTP server");
+            }
+        };
+
+        let mut interval = tokio::time::interval(time::Duration::from_secs(1));
+        let mut last_ping = time::Instant::now();
+        let mut next_ping = PING_INTERVAL;
+        let mut ping_backoff = utils::Backoff::new(time::Duration::from_secs(1), PING_INTERVAL);
+        self.spawn_janitor();
+        self.spawn_sweeper();
+        let mut last_compaction_check = time::Instant::now();
+        let mut last_requests = self.get_num_requests();
+
+        // run until we should begin shutdown sequence
+        while !self.stop.load(atomic::Ordering::SeqCst) {
+            interval.tick().await;
+
+            // re-ping server every minute, or sooner after a failed ping
+            if last_ping.elapsed() >= next_ping {
+                last_ping = time::Instant::now();
+                let res = self.ping_backend().await;
+                if res.is_ok() {
+                    next_ping = PING_INTERVAL;
+                    ping_backoff = utils::Backoff::new(time::Duration::from_secs(1), PING_INTERVAL);
+                } else {
+                    next_ping = ping_backoff.next_delay();
+                }
+
+                // restart actix server if there is a new certificate
+                match res {
+                    Ok(Some(new_crt)) => {
+                        crt = new_crt;
+                        match server.respawn_with_new_cert(&crt).await {
+                            Err(e @ http::Error::Down(_)) => {
+                                log::error!("{}, shutting down", e);
+                                self.shutdown();
+                            }
+                            Err(e) => log::error!(
+                                "error respawning HTTP server with new certificate: {}",
+                                e
+                            ),
+                            Ok(()) => {}
+                        }
+                    }
+                    Err(e) => log::error!(
+                        "error pinging backend, retrying in {}s: {}",
+                        next_ping.as_secs(),
+                        e
+                    ),
+                    _ => {} // pass-over
+                }
+            }
+
+            // check the compaction window every minute
+            if last_compaction_check.elapsed().as_secs() >= 60 {
+                let requests = self.get_num_requests();
+                let requests_per_sec = (requests - last_requests) as f64
+                    / last_compaction_check.elapsed().as_secs_f64();
+                last_compaction_check = time::Instant::now();
+                last_requests = requests;
+                self.try_compact_db(requests_per_sec);
+            }
+        }
+
+        // we are no longer running, we should begin graceful shutdown
+        self.graceful_shutdown(Some(server)).await;
+    }
+
+    /// Asks the node to shut down. This returns right away, while [`run`](Self::run) returns once
+    /// the node has shut down gracefully.
+    ///
+    /// Asking again while the node is shutting down skips what's left of the graceful shutdown,
+    /// closing open connections right away.
+    pub fn shutdown(&self) {
+        if self.stop.swap(true, atomic::Ordering::SeqCst) {
+            log::warn!("asked to shut down again, skipping the rest of the graceful shutdown");
+            self.forced.send_replace(true);
+        }
+    }
+
+    /// Runs `fut` to completion, unless the shutdown is forced first (see
+    /// [`shutdown`](Self::shutdown)), in which case `None` is returned
+    async fn unless_forced<F: std::future::Future>(&self, fut: F) -> Option<F::Output> {
+        let mut forced = self.forced.subscribe();
+        let forced = async move {
+            while !*forced.borrow() {
+                if forced.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::select! {
+            x = fut => Some(x),
+            _ = forced => None,
+        }
+    }
+
+    #[inline]
+    fn get_num_requests(&self) -> usize {
+        self.gs.request_counter.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Function for gracefully shutting down the actix server and application as a whole. This
+    /// function will wait until there are no more requests coming in OR that the time has exceeded
+    /// the configured maximum grace period.
+    ///
+    /// This does not, however, gracefully shut down the actix server (wait for all keep-alives to
+    /// drop) as that would take much time on top of the grace period. If the shutdown is forced
+    /// (see [`shutdown`](Self::shutdown)), whatever waiting is left is skipped.
+    async fn graceful_shutdown(&self, server: Option<http::HttpServerLifecycle>) {
+        // give load balancers time to stop sending traffic before we stop accepting it
+        if server.is_some() && self.gs.config.lame_duck_seconds > 0 {
+            let duration = time::Duration::from_secs(self.gs.config.lame_duck_seconds);
+            self.unless_forced(self.gs.lame_duck(duration)).await;
+        }
+
+        // ping the backend server for stop, so that we'll stop receiving requests sometime soon
+        self.unless_forced(self.stop_backend()).await;
+
+        self.unless_forced(self.wait_for_requests()).await;
+
+        if let Some(srv) = server {
+            log::info!("shutting down actix web server");
+            if self.unless_forced(srv.shutdown(true)).await.is_none() {
+                log::warn!("closing open connections without waiting for them");
+                srv.shutdown(false).await;
+            }
+        }
+
+        // make sure every image that was saved survives the restart
+        self.gs.cache.flush().await;
+
+        // no more requests can come in, so the request counts are final
+        self.write_warm_snapshot().await;
+        self.flush_metrics();
+    }
+
+    /// Tells the backend that the client is stopping, retrying a few times if that fails since the
+    /// backend keeps sending requests to the client otherwise
+    async fn stop_backend(&self) {
+        log::info!("sending stop signal to API");
+        let mut backoff = utils::Backoff::new(
+            time::Duration::from_millis(250),
+            time::Duration::from_secs(2),
+        );
+        for attempt in 1..=STOP_ATTEMPTS {
+            let e = match self.gs.backend.stop().await {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            if attempt == STOP_ATTEMPTS {
+                log::error!("error fulfilling stop API request, giving up: {}", e);
+                return;
+            }
+            let delay = backoff.next_delay();
+            log::error!(
+                "error fulfilling stop API request, retrying in {:.1}s: {}",
+                delay.as_secs_f32(),
+                e
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Waits until there are no more requests coming in, or the configured maximum grace period
+    /// is over
+    async fn wait_for_requests(&self) {
+        let start = time::Instant::now();
+        let mut requests = self.get_num_requests();
+        let grace = self.gs.config.max_grace_period;
+        loop {
+            // immediately stop graceful shutdown if configured
+            if grace < 0 {
+                break;
+            }
+            tokio::time::sleep(time::Duration::from_secs(5)).await;
+
+            // break if we've had no requests in the interval
+            let x = self.get_num_requests();
+            if x == requests {
+                break;
+            }
+            requests = x;
+
+            let elapsed = start.elapsed().as_secs() as i32;
+            log::info!("waited for shutdown for {} seconds", elapsed);
+            // break if we've waited for more seconds than the max grace period
+            if grace != 0 && elapsed >= grace {
+                break;
+            }
+        }
+    }
+
+    /// Reports the metrics one last time, so whatever was served since the last backend ping
+    /// isn't lost. The backend isn't pinged again, since that would register the client again
+    /// after it was stopped.
+    fn flush_metrics(&self) {
+        let bytes_at_ping = self.bytes_at_ping.load(atomic::Ordering::Relaxed);
+        let bytes_since_ping = self.gs.metrics.bytes_up.get().saturating_sub(bytes_at_ping);
+        self.gs.sink.flush(bytes_since_ping);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::{CacheInfo, ImageEntry, ImageKey, ShrinkResult};
+    #[cfg(feature = "metrics")]
+    use metrics::{EvictionReason, LastEviction};
+
+    /// Size that the [`OverfullCache`] reports for itself and each archive type (2 MiB)
+    const OVERFULL_SIZE: u64 = 2 * 1024 * 1024;
+    /// What the [`OverfullCache`] evicts whenever it's shrunk or swept
+    const EVICTED: ShrinkResult = ShrinkResult {
+        size: OVERFULL_SIZE,
+        bytes_evicted: 100,
+        entries_evicted: 1,
+    };
+
+    /// Cache that never gets any smaller, evicting a single entry whenever it's shrunk or swept
+    struct OverfullCache;
+
+    #[async_trait::async_trait]
+    impl ImageCache for OverfullCache {
+        async fn load(&self, _: &ImageKey) -> Option<ImageEntry> {
+            None
+        }
+        async fn save(&self, _: &ImageKey, _: String, _: bytes::Bytes) -> bool {
+            false
+        }
+        fn report(&self) -> u64 {
+            OVERFULL_SIZE
+        }
+        fn info(&self) -> CacheInfo {
+            CacheInfo::new("overfull")
+        }
+        async fn shrink(&self, _: u64) -> Result<ShrinkResult, ()> {
+            Ok(EVICTED)
+        }
+        fn report_archive(&self, _: bool) -> Option<u64> {
+            Some(OVERFULL_SIZE)
+        }
+        async fn shrink_archive(&self, _: bool, _: u64) -> Result<ShrinkResult, ()> {
+            Ok(EVICTED)
+        }
+        async fn sweep_idle(&self, _: time::Duration) -> Result<ShrinkResult, ()> {
+            Ok(EVICTED)
+        }
+    }
+
+    fn app_with(config: config::AppConfig) -> Node {
+        node_with(config, Box::new(OverfullCache))
+    }
+
+    fn node_with(config: config::AppConfig, cache: Box<dyn ImageCache>) -> Node {
+        Node {
+            gs: GlobalState::for_tests(config, cache),
+            compaction: Mutex::new(None),
+            janitor: Arc::new(cache::Janitor::new(
+                1024 * 1024,
+                Default::default(),
+                Default::default(),
+            )),
+            stop: Arc::new(atomic::AtomicBool::new(false)),
+            bytes_at_ping: atomic::AtomicU64::new(0),
+            forced: tokio::sync::watch::channel(false).0,
+        }
+    }
+
+    /// Asserts that the last eviction had the reason provided, and that it's the only reason with
+    /// runs counted
+    #[cfg(feature = "metrics")]
+    fn assert_evicted_by(app: &Node, reason: EvictionReason) {
+        use prometheus::core::Collector;
+
+        let metrics = &app.gs.metrics;
+        assert_eq!(
+            metrics.last_eviction(),
+            Some(LastEviction {
+                reason,
+                entries_evicted: 1,
+                bytes_evicted: 100,
+            })
+        );
+        let label = [reason.as_str()];
+        assert_eq!(
+            metrics.eviction_runs_total.with_label_values(&label).get(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .evicted_entries_total
+                .with_label_values(&label)
+                .get(),
+            1
+        );
+        assert_eq!(
+            metrics.evicted_bytes_total.with_label_values(&label).get(),
+            100
+        );
+        assert_eq!(
+            metrics.last_eviction_reason.with_label_values(&label).get(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .eviction_runs_total
+                .collect()
+                .iter()
+                .map(|x| x.get_metric().len())
+                .sum::<usize>(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "metrics")]
+    async fn evictions_are_recorded_by_reason() {
+        // over the maximum size of the whole cache
+        let mut config = config::tests::config_with("");
+        config.cache_size_mebibytes = 1;
+        let app = app_with(config);
+        assert_eq!(app.gs.metrics.last_eviction(), None);
+        try_shrink_db(&app.gs, &app.janitor).await;
+        assert_evicted_by(&app, EvictionReason::SizeCap);
+
+        // over the budget of an archive type, but not the maximum size
+        let mut config = config::tests::config_with("");
+        config.cache_size_mebibytes = 1024;
+        config.archive_budgets = Some(config::ArchiveBudgets {
+            data: None,
+            data_saver: Some(1),
+        });
+        let app = app_with(config);
+        try_shrink_db(&app.gs, &app.janitor).await;
+        assert_evicted_by(&app, EvictionReason::ArchiveBudget);
+
+        // idle entries past their ttl
+        let mut config = config::tests::config_with("");
+        config.idle_ttl_hours = Some(1);
+        let app = app_with(config);
+        try_sweep_idle(&app.gs).await;
+        assert_evicted_by(&app, EvictionReason::IdleTtl);
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_bytes_served_since_last_ping() {
+        use metrics::tests::CapturingSink;
+
+        let mut config = config::tests::config_with("");
+        config.max_grace_period = -1;
+        let mut app = app_with(config);
+        let sink = Arc::new(CapturingSink::default());
+        let upstream = url::Url::parse("https://upstream.invalid").unwrap();
+        let gs = Arc::get_mut(&mut app.gs).unwrap();
+        gs.sink = Arc::clone(&sink) as _;
+        gs.backend = Arc::new(backend::tests::MockBackend::with_upstream(upstream));
+
+        app.gs.metrics.bytes_up.inc_by(100);
+        app.ping_backend().await.unwrap();
+        app.gs.metrics.bytes_up.inc_by(42);
+        app.graceful_shutdown(None).await;
+
+        assert_eq!(sink.take(), vec!["flush"]);
+        assert_eq!(*sink.flushed.lock().unwrap(), Some(42));
+    }
+
+    #[tokio::test]
+    async fn second_shutdown_skips_the_grace_period() {
+        // the grace period lasts until there weren't any requests for 5 seconds
+        let mut config = config::tests::config_with("");
+        config.max_grace_period = 0;
+        let cache = cache::tests::TestCache::default();
+        let flushes = Arc::clone(&cache.flushes);
+        let app = node_with(config, Box::new(cache));
+
+        app.shutdown();
+        let force = async {
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
+            app.shutdown();
+        };
+        let start = time::Instant::now();
+        tokio::join!(app.graceful_shutdown(None), force);
+        assert!(start.elapsed() < time::Duration::from_secs(2));
+        // the cache is flushed even when the shutdown is cut short
+        assert_eq!(flushes.load(atomic::Ordering::SeqCst), 1);
+    }
+}
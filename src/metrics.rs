@@ -1,9 +1,115 @@
-#[cfg(target_os = "linux")]
+#[cfg(not(feature = "metrics"))]
+use self::inert::{
+    Gauge, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry,
+    Result as PromResult,
+};
+#[cfg(feature = "metrics")]
+use crate::cache::CacheStats;
+use crate::cache::ShrinkResult;
+#[cfg(all(target_os = "linux", feature = "metrics"))]
 use prometheus::process_collector::ProcessCollector;
+#[cfg(feature = "metrics")]
 use prometheus::{
-    histogram_opts, Encoder, Histogram, IntCounter, IntGauge, Registry, Result as PromResult,
-    TextEncoder,
+    histogram_opts, opts, Encoder, Gauge, Histogram, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Registry, Result as PromResult, TextEncoder,
 };
+use std::sync::Mutex;
+
+/// Stand-ins for the prometheus metrics when the `metrics` feature is disabled. Counters and
+/// gauges still count (so totals like the bytes served can be read back), but nothing is exposed
+/// and the values of labelled metrics aren't kept.
+#[cfg(not(feature = "metrics"))]
+mod inert {
+    use std::marker::PhantomData;
+    use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    pub type Result<T> = std::result::Result<T, std::convert::Infallible>;
+
+    #[derive(Default)]
+    pub struct Registry;
+    impl Registry {
+        pub fn new() -> Self {
+            Self
+        }
+        pub fn register<T>(&self, _: Box<T>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub struct IntCounter(Arc<AtomicU64>);
+    impl IntCounter {
+        pub fn new(_: &str, _: &str) -> Result<Self> {
+            Ok(Self::default())
+        }
+        pub fn inc(&self) {
+            self.inc_by(1);
+        }
+        pub fn inc_by(&self, v: u64) {
+            self.0.fetch_add(v, Ordering::Relaxed);
+        }
+        pub fn get(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub struct IntGauge(Arc<AtomicI64>);
+    impl IntGauge {
+        pub fn new(_: &str, _: &str) -> Result<Self> {
+            Ok(Self::default())
+        }
+        pub fn set(&self, v: i64) {
+            self.0.store(v, Ordering::Relaxed);
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub struct Gauge;
+    impl Gauge {
+        pub fn new(_: &str, _: &str) -> Result<Self> {
+            Ok(Self)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub struct Histogram;
+    impl Histogram {
+        pub fn with_opts(_: ()) -> Result<Self> {
+            Ok(Self)
+        }
+        pub fn observe(&self, _: f64) {}
+    }
+
+    /// A labelled metric, which hands out a fresh metric for every set of labels
+    #[derive(Clone)]
+    pub struct MetricVec<T>(PhantomData<T>);
+    impl<T: Default> MetricVec<T> {
+        pub fn new(_: (), _: &[&str]) -> Result<Self> {
+            Ok(Self(PhantomData))
+        }
+        pub fn with_label_values(&self, _: &[&str]) -> T {
+            T::default()
+        }
+    }
+    pub type IntCounterVec = MetricVec<IntCounter>;
+    pub type IntGaugeVec = MetricVec<IntGauge>;
+}
+
+/// Options of the [`inert`] metrics, which don't need any
+#[cfg(not(feature = "metrics"))]
+macro_rules! opts {
+    ($($x:expr),* $(,)?) => {
+        ()
+    };
+}
+#[cfg(not(feature = "metrics"))]
+macro_rules! histogram_opts {
+    ($name:expr, $help:expr, $buckets:expr $(,)?) => {{
+        let _ = $buckets;
+    }};
+}
 
 /// Macro that creates a struct with the `$struct_name` identifier that includes different prometheus metric definitions
 ///
@@ -11,13 +117,15 @@ use prometheus::{
 /// it will be pushed up the stack using the Result type.
 macro_rules! create_metrics {
     ($struct_name:ident, $(($name:ident: $ty:ty, $init:expr)),* $(,)?) => {
+        // without the metrics feature, the gauges that are only updated for scrapes are never read
+        #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
         pub struct $struct_name {
             $(
                 pub $name: $ty,
             )*
         }
         impl $struct_name {
-            fn new(r: &prometheus::Registry) -> prometheus::Result<Self> {
+            fn new(r: &Registry) -> PromResult<Self> {
                 $(
                     let $name = ($init);
                     r.register(Box::new($name.clone()))?;
@@ -58,7 +166,62 @@ create_metrics!(
             "Maximum size as specified in the configuration"
         )?
     ),
+    (
+        cache_pinned_size: IntGauge,
+        IntGauge::new(
+            "cache_pinned_size",
+            "Size of the pinned images in the cache (which are never evicted) in bytes"
+        )?
+    ),
+    (
+        cache_entries: IntGauge,
+        IntGauge::new(
+            "cache_entries",
+            "Number of images in the cache (may be an estimate, only if the engine counts them)"
+        )?
+    ),
+    (
+        cache_warmth_ratio: Gauge,
+        Gauge::new(
+            "cache_warmth_ratio",
+            "Reported size of the cache divided by the maximum size"
+        )?
+    ),
+    (
+        recent_hit_ratio: Gauge,
+        Gauge::new(
+            "recent_hit_ratio",
+            "Fraction of the most recent image requests that were HITs"
+        )?
+    ),
+    (
+        last_eviction_reason: IntGaugeVec,
+        IntGaugeVec::new(
+            opts!(
+                "cache_last_eviction_reason",
+                "1 for the reason of the most recent eviction that evicted entries, 0 otherwise"
+            ),
+            &["reason"]
+        )?
+    ),
+    (
+        cache_backend_stats: IntGaugeVec,
+        IntGaugeVec::new(
+            opts!(
+                "cache_backend_stat",
+                "Statistics that only apply to the cache engine, such as writes dropped by a mirror"
+            ),
+            &["stat"]
+        )?
+    ),
     /* COUNTER METRICS */
+    (
+        requests_total: IntCounter,
+        IntCounter::new(
+            "requests_total",
+            "Total image requests that passed token verification"
+        )?
+    ),
     (
         hit_requests_total: IntCounter,
         IntCounter::new("hit_requests_total", "Total HIT requests")?
@@ -81,6 +244,54 @@ create_metrics!(
             "Total requests that had an error while processing"
         )?
     ),
+    (
+        upstream_redirects_total: IntCounter,
+        IntCounter::new(
+            "upstream_redirects_total",
+            "Total redirects received from upstream while fetching images"
+        )?
+    ),
+    (
+        upstream_redirects_rejected_total: IntCounter,
+        IntCounter::new(
+            "upstream_redirects_rejected_total",
+            "Total upstream redirects that weren't followed (too many or to a host not allowed)"
+        )?
+    ),
+    (
+        tls_downgrades_total: IntCounter,
+        IntCounter::new(
+            "tls_downgrades_total",
+            "Total TLS handshakes rejected for offering a version older than TLS 1.2"
+        )?
+    ),
+    (
+        eviction_runs_total: IntCounterVec,
+        IntCounterVec::new(
+            opts!("cache_eviction_runs_total", "Total eviction runs by reason"),
+            &["reason"]
+        )?
+    ),
+    (
+        evicted_entries_total: IntCounterVec,
+        IntCounterVec::new(
+            opts!(
+                "cache_evicted_entries_total",
+                "Total entries evicted from the cache by reason"
+            ),
+            &["reason"]
+        )?
+    ),
+    (
+        evicted_bytes_total: IntCounterVec,
+        IntCounterVec::new(
+            opts!(
+                "cache_evicted_bytes_total",
+                "Total bytes evicted from the cache by reason"
+            ),
+            &["reason"]
+        )?
+    ),
     (
         bytes_down: IntCounter,
         IntCounter::new("bytes_down_total", "The total number of downloaded bytes")?
@@ -135,14 +346,156 @@ create_metrics!(
     ),
 );
 
+/// Ring buffer of whether each of the most recent image requests was a HIT, for a hit ratio that
+/// follows the current traffic
+pub struct RecentHits {
+    state: Mutex<RecentHitsState>,
+}
+
+struct RecentHitsState {
+    results: Vec<bool>,
+    capacity: usize,
+    /// where the next result is written, overwriting the oldest one once the buffer is full
+    next: usize,
+    hits: usize,
+}
+
+impl RecentHits {
+    /// Creates a buffer that remembers the last `capacity` requests
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(RecentHitsState {
+                results: Vec::with_capacity(capacity.max(1)),
+                capacity: capacity.max(1),
+                next: 0,
+                hits: 0,
+            }),
+        }
+    }
+
+    /// Records whether a request was a HIT
+    pub fn record(&self, hit: bool) {
+        let mut state = self.state.lock().unwrap();
+        let next = state.next;
+        if next < state.results.len() {
+            let evicted = std::mem::replace(&mut state.results[next], hit);
+            state.hits -= evicted as usize;
+        } else {
+            state.results.push(hit);
+        }
+        state.hits += hit as usize;
+        state.next = (next + 1) % state.capacity;
+    }
+
+    /// The fraction of the remembered requests that were HITs, or `None` if there weren't any
+    pub fn ratio(&self) -> Option<f64> {
+        let state = self.state.lock().unwrap();
+        match state.results.len() {
+            0 => None,
+            len => Some(state.hits as f64 / len as f64),
+        }
+    }
+}
+
+/// How warm the cache is, relative to its budget
+#[derive(serde::Serialize, Debug)]
+pub struct Warmth {
+    /// Reported size of the cache divided by the configured maximum size
+    pub size_ratio: f64,
+    /// Number of images in the cache, if the engine counts them. There's no limit on the number of
+    /// images (only on their size), so there's no ratio for them.
+    pub entries: Option<u64>,
+    /// Fraction of the most recent requests that were HITs, if there were any
+    pub recent_hit_ratio: Option<f64>,
+}
+
+/// Why entries were evicted from the cache
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionReason {
+    /// The cache was over its maximum size
+    SizeCap,
+    /// An archive type was over its budget
+    ArchiveBudget,
+    /// The entries weren't accessed within the idle TTL
+    IdleTtl,
+    /// The cache was cleared by an operator
+    Clear,
+}
+
+impl EvictionReason {
+    const ALL: [Self; 4] = [
+        Self::SizeCap,
+        Self::ArchiveBudget,
+        Self::IdleTtl,
+        Self::Clear,
+    ];
+
+    /// Name of the reason, as used in the metric labels
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::SizeCap => "size_cap",
+            Self::ArchiveBudget => "archive_budget",
+            Self::IdleTtl => "idle_ttl",
+            Self::Clear => "clear",
+        }
+    }
+}
+
+/// The most recent eviction that evicted any entries
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastEviction {
+    pub reason: EvictionReason,
+    pub entries_evicted: u64,
+    pub bytes_evicted: u64,
+}
+
+/// Destination for the metrics of image requests, so instrumentation isn't tied to prometheus
+///
+/// Durations are given in seconds and sizes in bytes.
+pub trait MetricsSink: Send + Sync {
+    /// Records an image request that passed token verification
+    fn record_request(&self);
+
+    /// Records a request that was served from the cache, and how long it took
+    fn record_hit(&self, process_secs: f64);
+
+    /// Records a request that had to be fetched from upstream, and how long it took
+    fn record_miss(&self, process_secs: f64);
+
+    /// Observes the time it took upstream to respond to a MISS
+    fn observe_upstream_latency(&self, ttfb_secs: f64);
+
+    /// Sets the size of the cache as reported by the cache engine
+    fn set_cache_size(&self, bytes: u64);
+
+    /// Reports what's left once the client has stopped serving requests, including the bytes
+    /// served since the last backend ping, so the final interval isn't lost
+    fn flush(&self, bytes_since_ping: u64);
+}
+
+/// Sink that throws away every metric
+pub struct NoopSink;
+
+impl MetricsSink for NoopSink {
+    fn record_request(&self) {}
+    fn record_hit(&self, _: f64) {}
+    fn record_miss(&self, _: f64) {}
+    fn observe_upstream_latency(&self, _: f64) {}
+    fn set_cache_size(&self, _: u64) {}
+    fn flush(&self, _: u64) {}
+}
+
 /// Structure that contains all prometheus metrics of the scalpel program
 ///
 /// All definitions are actually above and inside the `MetricsInner` class, but that can all be
 /// accessed through this struct via `Deref`. It also includes a method of encoding to text for
 /// scrapers.
 pub struct Metrics {
+    #[cfg(feature = "metrics")]
     registry: Registry,
     inner: MetricsInner,
+    last_eviction: Mutex<Option<LastEviction>>,
 }
 
 impl Metrics {
@@ -152,13 +505,76 @@ impl Metrics {
         let inner = MetricsInner::new(&registry)?;
 
         // register program metrics (if on linux)
-        #[cfg(target_os = "linux")]
+        #[cfg(all(target_os = "linux", feature = "metrics"))]
         registry.register(Box::new(ProcessCollector::for_self()))?;
 
-        Ok(Self { registry, inner })
+        Ok(Self {
+            #[cfg(feature = "metrics")]
+            registry,
+            inner,
+            last_eviction: Mutex::new(None),
+        })
+    }
+
+    /// Records an eviction run and what it evicted. Runs that evicted entries become the last
+    /// eviction.
+    pub fn record_eviction(&self, reason: EvictionReason, res: &ShrinkResult) {
+        let label = [reason.as_str()];
+        self.eviction_runs_total.with_label_values(&label).inc();
+        self.evicted_entries_total
+            .with_label_values(&label)
+            .inc_by(res.entries_evicted);
+        self.evicted_bytes_total
+            .with_label_values(&label)
+            .inc_by(res.bytes_evicted);
+        if res.entries_evicted == 0 {
+            return;
+        }
+
+        for &other in EvictionReason::ALL.iter() {
+            self.last_eviction_reason
+                .with_label_values(&[other.as_str()])
+                .set((other == reason) as i64);
+        }
+        *self.last_eviction.lock().unwrap() = Some(LastEviction {
+            reason,
+            entries_evicted: res.entries_evicted,
+            bytes_evicted: res.bytes_evicted,
+        });
+    }
+
+    /// The most recent eviction that evicted any entries, if there was one
+    pub fn last_eviction(&self) -> Option<LastEviction> {
+        *self.last_eviction.lock().unwrap()
+    }
+
+    /// Updates the gauges that describe how warm the cache is
+    #[cfg(feature = "metrics")]
+    pub fn set_warmth(&self, warmth: &Warmth) {
+        self.cache_warmth_ratio.set(warmth.size_ratio);
+        if let Some(entries) = warmth.entries {
+            self.cache_entries.set(entries as i64);
+        }
+        if let Some(ratio) = warmth.recent_hit_ratio {
+            self.recent_hit_ratio.set(ratio);
+        }
+    }
+
+    /// Updates the gauges of the statistics reported by the cache engine, skipping the ones that
+    /// aren't integers
+    #[cfg(feature = "metrics")]
+    pub fn set_cache_stats(&self, stats: &CacheStats) {
+        for (name, value) in &stats.backend {
+            if let Some(value) = value.as_i64() {
+                self.cache_backend_stats
+                    .with_label_values(&[name])
+                    .set(value);
+            }
+        }
     }
 
     /// Encodes the metrics into a string to pass onto a scraper
+    #[cfg(feature = "metrics")]
     pub fn encode_to_string(&self) -> PromResult<String> {
         let mut buf = vec![];
         let encoder = TextEncoder::new();
@@ -175,3 +591,168 @@ impl std::ops::Deref for Metrics {
         &self.inner
     }
 }
+
+impl MetricsSink for Metrics {
+    fn record_request(&self) {
+        self.requests_total.inc();
+    }
+
+    fn record_hit(&self, process_secs: f64) {
+        self.hit_requests_total.inc();
+        self.hit_request_process_seconds.observe(process_secs);
+    }
+
+    fn record_miss(&self, process_secs: f64) {
+        self.miss_requests_total.inc();
+        self.miss_request_process_seconds.observe(process_secs);
+    }
+
+    fn observe_upstream_latency(&self, ttfb_secs: f64) {
+        self.upstream_ttfb_seconds.observe(ttfb_secs);
+    }
+
+    fn set_cache_size(&self, bytes: u64) {
+        self.cache_size.set(bytes as i64);
+    }
+
+    /// Nothing can scrape the metrics once the client stopped, so the totals are logged instead
+    fn flush(&self, bytes_since_ping: u64) {
+        log::info!(
+            "final metrics: {} requests ({} HITs, {} MISSes), {}B served ({}B since the last ping)",
+            self.requests_total.get(),
+            self.hit_requests_total.get(),
+            self.miss_requests_total.get(),
+            self.bytes_up.get(),
+            bytes_since_ping
+        );
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Sink that remembers the name of every call made to it
+    #[derive(Default)]
+    pub(crate) struct CapturingSink {
+        pub(crate) calls: Mutex<Vec<&'static str>>,
+        /// the bytes served since the last ping that the sink was flushed with
+        pub(crate) flushed: Mutex<Option<u64>>,
+    }
+
+    impl CapturingSink {
+        fn push(&self, call: &'static str) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        /// Takes the calls made to the sink so far
+        pub(crate) fn take(&self) -> Vec<&'static str> {
+            std::mem::take(&mut *self.calls.lock().unwrap())
+        }
+    }
+
+    impl MetricsSink for CapturingSink {
+        fn record_request(&self) {
+            self.push("request");
+        }
+        fn record_hit(&self, _: f64) {
+            self.push("hit");
+        }
+        fn record_miss(&self, _: f64) {
+            self.push("miss");
+        }
+        fn observe_upstream_latency(&self, _: f64) {
+            self.push("upstream_latency");
+        }
+        fn set_cache_size(&self, _: u64) {
+            self.push("cache_size");
+        }
+        fn flush(&self, bytes_since_ping: u64) {
+            self.push("flush");
+            *self.flushed.lock().unwrap() = Some(bytes_since_ping);
+        }
+    }
+
+    #[test]
+    fn recent_hit_ratio_follows_requests() {
+        let recent = RecentHits::new(4);
+        assert_eq!(recent.ratio(), None);
+
+        for &hit in &[true, false, true, true] {
+            recent.record(hit);
+        }
+        assert_eq!(recent.ratio(), Some(0.75));
+
+        // the oldest results are forgotten as new ones come in
+        recent.record(false);
+        recent.record(false);
+        assert_eq!(recent.ratio(), Some(0.5));
+        for _ in 0..4 {
+            recent.record(true);
+        }
+        assert_eq!(recent.ratio(), Some(1.0));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn prometheus_sink_updates_metrics() {
+        let metrics = Metrics::new().unwrap();
+        let sink: &dyn MetricsSink = &metrics;
+        sink.record_request();
+        sink.record_hit(0.001);
+        sink.record_miss(0.5);
+        sink.set_cache_size(1024);
+
+        assert_eq!(metrics.requests_total.get(), 1);
+        assert_eq!(metrics.hit_requests_total.get(), 1);
+        assert_eq!(metrics.miss_requests_total.get(), 1);
+        assert_eq!(metrics.cache_size.get(), 1024);
+        assert!(metrics
+            .encode_to_string()
+            .unwrap()
+            .contains("hit_request_process_seconds_count 1"));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn only_evicting_runs_become_the_last_eviction() {
+        let metrics = Metrics::new().unwrap();
+        let evicted = ShrinkResult {
+            size: 0,
+            bytes_evicted: 100,
+            entries_evicted: 2,
+        };
+        metrics.record_eviction(EvictionReason::SizeCap, &evicted);
+        metrics.record_eviction(EvictionReason::IdleTtl, &ShrinkResult::default());
+
+        assert_eq!(
+            metrics.last_eviction().map(|x| x.reason),
+            Some(EvictionReason::SizeCap)
+        );
+        let runs = |reason: EvictionReason| {
+            let label = [reason.as_str()];
+            metrics.eviction_runs_total.with_label_values(&label).get()
+        };
+        assert_eq!(runs(EvictionReason::SizeCap), 1);
+        assert_eq!(runs(EvictionReason::IdleTtl), 1);
+        assert!(metrics
+            .encode_to_string()
+            .unwrap()
+            .contains("cache_last_eviction_reason{reason=\"size_cap\"} 1"));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn integer_cache_stats_are_exported() {
+        let metrics = Metrics::new().unwrap();
+        let stats = CacheStats::new(0, None)
+            .with_stat("mirror_dropped_writes", 3)
+            .with_stat("engine_version", "6.20");
+        metrics.set_cache_stats(&stats);
+
+        let encoded = metrics.encode_to_string().unwrap();
+        assert!(encoded.contains("cache_backend_stat{stat=\"mirror_dropped_writes\"} 3"));
+        assert!(!encoded.contains("engine_version"));
+    }
+}
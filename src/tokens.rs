@@ -1,9 +1,14 @@
 use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use lru::LruCache;
 use serde_json as json;
 use sodiumoxide::{base64, crypto::box_};
+use std::sync::{Mutex, MutexGuard};
 use std::{error::Error, fmt};
 
 const NONCE_SIZE: usize = 24;
+/// Number of verified tokens that are remembered, so the images of a chapter only verify its token
+/// once
+const VERIFIED_CAPACITY: usize = 1024;
 
 /// Expected object representation of the JSON payload inside an MD@Home Token.
 ///
@@ -15,6 +20,25 @@ struct TokenPayload {
     client_id: String,
 }
 
+/// The claims of a token that was successfully verified
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedToken {
+    /// When the token stops being valid
+    pub expires: chrono::DateTime<chrono::FixedOffset>,
+    /// The client the token was issued for
+    pub client_id: String,
+}
+
+impl VerifiedToken {
+    /// How much longer the token is valid for, which is how long a verification of it can be
+    /// reused for. Zero if it already expired.
+    pub fn time_to_expiry(&self) -> std::time::Duration {
+        (self.expires.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default()
+    }
+}
+
 /// Every Error Kind that could happen when inside the TokenVerifier.
 ///
 /// Most of the kinds are related to the decryption and verification of tokens, however some of
@@ -72,7 +96,7 @@ impl ResponseError for TokenError {
 ///
 /// # Example
 ///
-/// ```rust
+/// ```ignore
 /// const TOKEN_KEY: &str = "EXAMPLE"; // base64 encoded precomputed key (provided by API)
 ///
 /// use crate::utils::FromBase64;
@@ -86,13 +110,23 @@ impl ResponseError for TokenError {
 ///     // Token is verified
 /// }
 /// ```
-pub struct TokenVerifier(Option<box_::PrecomputedKey>);
+///
+/// Successful verifications of url tokens are remembered until the token expires, keyed by the
+/// token and chapter hash, so the rest of the images of a chapter skip decrypting the token.
+/// Failed verifications are never remembered, and pushing a new key forgets all of them.
+pub struct TokenVerifier {
+    key: Option<box_::PrecomputedKey>,
+    verified: Mutex<LruCache<(String, String), VerifiedToken>>,
+}
 
 // functions for the Token Verifier
 impl TokenVerifier {
     /// Creates a TokenVerifier using the bytes provided as a PrecomputedKey.
     pub fn new() -> Self {
-        TokenVerifier(None)
+        TokenVerifier {
+            key: None,
+            verified: Mutex::new(LruCache::new(VERIFIED_CAPACITY)),
+        }
     }
 
     /// Decodes a base64 byte array with the option to choose between the URL variant and original
@@ -108,7 +142,9 @@ impl TokenVerifier {
 
     /// Pushes a new PrecomputedKey byte array to use for decrypting Tokens
     pub fn push_key<T: AsRef<[u8]>>(&mut self, key_bytes: T) -> Result<(), TokenError> {
-        self.0 = Some(Self::key_from_bytes(key_bytes)?);
+        self.key = Some(Self::key_from_bytes(key_bytes)?);
+        // tokens verified with the old key may not be valid under the new one
+        self.verified().clear();
         Ok(())
     }
     /// Pushes a new PrecomputedKey base64 byte array to use for decrypting Tokens
@@ -124,13 +160,13 @@ impl TokenVerifier {
     /// verifies that it matches the chapter hash and is not expired.
     ///
     /// This method can result in a multitude of different errors, however it only results in
-    /// `Ok(())` if the result itself can be successfully decrypted, parsed, matches the chapter hash
-    /// and is not expired.
+    /// `Ok` (with the claims of the token) if the result itself can be successfully decrypted,
+    /// parsed, matches the chapter hash and is not expired.
     pub fn verify_token<T: AsRef<[u8]>>(
         &self,
         token: T,
         chap_hash: &str,
-    ) -> Result<(), TokenError> {
+    ) -> Result<VerifiedToken, TokenError> {
         // extract nonce and cipher, then decypt
         let payload = {
             let (nonce, cipher) = Self::token_to_cipher(token.as_ref())?;
@@ -156,7 +192,10 @@ impl TokenVerifier {
             })?;
             return if date > chrono::Local::now() {
                 // token is not expired, so it's valid
-                Ok(())
+                Ok(VerifiedToken {
+                    expires: date,
+                    client_id: payload.client_id,
+                })
             } else {
                 Err(TokenError::TokenExpired)
             };
@@ -166,12 +205,41 @@ impl TokenVerifier {
     }
 
     /// Helper method to call `verify_token` after decoding a base64 url-encoded byte array.
+    /// Tokens that were already verified for the chapter are accepted without decrypting them
+    /// again, until they expire.
     ///
     /// See `verify_token` for more information.
-    pub fn verify_url_token(&self, token_str: &str, chap_hash: &str) -> Result<(), TokenError> {
+    pub fn verify_url_token(
+        &self,
+        token_str: &str,
+        chap_hash: &str,
+    ) -> Result<VerifiedToken, TokenError> {
+        let id = (token_str.to_string(), chap_hash.to_string());
+        {
+            let mut verified = self.verified();
+            match verified.get(&id) {
+                Some(token) if token.time_to_expiry() > std::time::Duration::ZERO => {
+                    return Ok(token.clone())
+                }
+                // expired tokens are verified again, which rejects them
+                Some(_) => {
+                    verified.pop(&id);
+                }
+                None => (),
+            }
+        }
+
         // convert base64 to bytes then send to other function
         let token = Self::decode_b64(token_str, true)?;
-        self.verify_token(token, chap_hash)
+        let res = self.verify_token(token, chap_hash)?;
+        self.verified().put(id, res.clone());
+        Ok(res)
+    }
+
+    /// Locks the verified tokens. A request that panicked while holding the lock can't have left
+    /// them inconsistent, so a poisoned lock is used as is.
+    fn verified(&self) -> MutexGuard<'_, LruCache<(String, String), VerifiedToken>> {
+        self.verified.lock().unwrap_or_else(|e| e.into_inner())
     }
 
     /// Parse ciphertext and nonce bytes from provided token bytes.
@@ -191,13 +259,13 @@ impl TokenVerifier {
     /// Decrypts ciphertext using the internal `PrecomputedKey`. If `Err` is present, it is
     /// always `TokenErrorKind::DecryptFailed`.
     fn decrypt_token(&self, nonce: &box_::Nonce, cipher: &[u8]) -> Result<Vec<u8>, TokenError> {
-        let key = self.0.as_ref().ok_or(TokenError::NoKey)?;
+        let key = self.key.as_ref().ok_or(TokenError::NoKey)?;
         box_::open_precomputed(cipher, nonce, key).map_err(|_| TokenError::DecryptFailed)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use serde_json as json;
     use sodiumoxide::{base64, crypto::box_};
@@ -251,6 +319,25 @@ mod tests {
         chrono::Utc::now() + chrono::Duration::hours(1)
     }
 
+    /// Generates a base64 encoded token key, and a token for `chap_hash` that's valid under it
+    pub(crate) fn key_and_token(chap_hash: &str) -> (String, String) {
+        let data = json::json!({
+            "expires": in_one_hour().to_rfc3339(),
+            "hash": chap_hash,
+            "client_id": "1"
+        })
+        .to_string();
+        PCryptoData::new().key_token_pair(data.as_bytes())
+    }
+
+    /// Creates a verifier along with a valid token for the chapter that it accepts
+    pub(crate) fn verifier_with_token(chap_hash: &str) -> (TokenVerifier, String) {
+        let (token_key, token) = key_and_token(chap_hash);
+        let mut verifier = TokenVerifier::new();
+        verifier.push_key_b64(&token_key).unwrap();
+        (verifier, token)
+    }
+
     /// Construct a `TokenVerifier` and decrypt a valid generated token
     /// Expected Result: No Panic (from unwrap)
     #[test]
@@ -272,6 +359,64 @@ mod tests {
         verifier.verify_url_token(&token, CHAP_HASH).unwrap();
     }
 
+    /// Makes sure that the claims of a verified token are returned, and that the time until it
+    /// expires (used to reuse the verification) matches the token
+    #[test]
+    fn verified_claims_match_token() {
+        let expires = chrono::Utc::now() + chrono::Duration::minutes(10);
+        let data = json::json!({
+            "expires": expires.to_rfc3339(),
+            "hash": CHAP_HASH,
+            "client_id": "client"
+        })
+        .to_string();
+        let (token_key, token) = PCryptoData::new().key_token_pair(data.as_bytes());
+
+        let mut verifier = TokenVerifier::new();
+        verifier.push_key_b64(&token_key).unwrap();
+        let verified = verifier.verify_url_token(&token, CHAP_HASH).unwrap();
+        assert_eq!(verified.expires, expires);
+        assert_eq!(verified.client_id, "client");
+
+        let ttl = verified.time_to_expiry().as_secs();
+        assert!((590..=600).contains(&ttl), "ttl was {}s", ttl);
+
+        let expired = VerifiedToken {
+            expires: (chrono::Utc::now() - chrono::Duration::seconds(1)).into(),
+            client_id: "client".to_string(),
+        };
+        assert_eq!(expired.time_to_expiry(), std::time::Duration::ZERO);
+    }
+
+    /// Makes sure that verified tokens are remembered for their chapter only, and forgotten once
+    /// the key changes
+    #[test]
+    fn verifications_are_remembered_until_key_changes() {
+        let (token_key, token) = key_and_token(CHAP_HASH);
+        let mut verifier = TokenVerifier::new();
+        verifier.push_key_b64(&token_key).unwrap();
+        verifier.verify_url_token(&token, CHAP_HASH).unwrap();
+        assert_eq!(verifier.verified().len(), 1);
+
+        // a remembered token is accepted without its key
+        verifier.key = None;
+        verifier.verify_url_token(&token, CHAP_HASH).unwrap();
+        // but only for the chapter it was verified for
+        assert_eq!(
+            verifier.verify_url_token(&token, "other"),
+            Err(TokenError::NoKey)
+        );
+
+        // failures aren't remembered, and a new key forgets the old tokens
+        let (other_key, _) = key_and_token(CHAP_HASH);
+        verifier.push_key_b64(&other_key).unwrap();
+        assert_eq!(
+            verifier.verify_url_token(&token, CHAP_HASH),
+            Err(TokenError::DecryptFailed)
+        );
+        assert_eq!(verifier.verified().len(), 0);
+    }
+
     /// Makes sure that the `TokenVerifier` ignores extra fields in the JSON payload
     /// Expected Result: No Panic (from unwrap)
     #[test]
@@ -8,13 +8,14 @@ pub mod constants {
     pub const REPO_URL: &str = env!("CARGO_PKG_REPOSITORY");
 }
 
-use std::time;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{fmt, future::Future, time};
 
 /// Basic timer implementation that can be used for benchmarking
 ///
 /// # Example
 ///
-/// ```rust
+/// ```ignore
 /// let timer = Timer::start();
 /// // do a long task here...
 /// log::debug!("time taken: {}ms", timer.elapsed());
@@ -49,13 +50,111 @@ impl std::fmt::Display for Timer {
     }
 }
 
-/// Time since epoch in milliseconds
-#[inline]
-pub fn now_as_millis() -> u64 {
-    time::SystemTime::now()
-        .duration_since(time::UNIX_EPOCH)
-        .map(|x| x.as_millis() as u64)
-        .unwrap_or(0)
+/// Source of the current time. Anything that depends on the wall clock (entry ages, idle times)
+/// should ask a [`Clock`] instead of calling [`SystemTime::now`](time::SystemTime::now) directly,
+/// so tests can control the time.
+pub trait Clock: Send + Sync {
+    /// The current time
+    fn now(&self) -> time::SystemTime;
+
+    /// Time since epoch in milliseconds
+    fn now_as_millis(&self) -> u64 {
+        self.now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// [`Clock`] that reads the system time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
+}
+
+/// [`Clock`] that only moves when told to
+#[cfg(test)]
+pub struct TestClock(std::sync::Mutex<time::SystemTime>);
+#[cfg(test)]
+impl TestClock {
+    pub fn new(start: time::SystemTime) -> Self {
+        Self(std::sync::Mutex::new(start))
+    }
+
+    /// Moves the clock forward by `by`
+    pub fn advance(&self, by: time::Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> time::SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Capped exponential backoff for retrying failed operations
+pub struct Backoff {
+    next: time::Duration,
+    max: time::Duration,
+}
+impl Backoff {
+    pub fn new(initial: time::Duration, max: time::Duration) -> Self {
+        Self { next: initial, max }
+    }
+
+    /// Returns the delay to wait before the next attempt, doubling the delay for the attempt after
+    /// (up to the maximum)
+    pub fn next_delay(&mut self) -> time::Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+}
+
+/// Repeatedly calls `f` until it returns `Ok`, waiting between attempts according to `backoff`.
+///
+/// Returns `None` if `stop` is set (because the node is shutting down) before `f` succeeds. `what`
+/// is a description of the operation used for logging.
+pub async fn retry_with_backoff<T, E, F, Fut>(
+    what: &str,
+    mut backoff: Backoff,
+    stop: &AtomicBool,
+    mut f: F,
+) -> Option<T>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    // the maximum amount of time to sleep before checking whether we're shutting down
+    const STEP: time::Duration = time::Duration::from_millis(250);
+
+    loop {
+        if stop.load(Ordering::SeqCst) {
+            return None;
+        }
+        let e = match f().await {
+            Ok(x) => return Some(x),
+            Err(e) => e,
+        };
+
+        let delay = backoff.next_delay();
+        log::error!(
+            "{} failed, retrying in {:.1}s ({})",
+            what,
+            delay.as_secs_f32(),
+            e
+        );
+        let start = time::Instant::now();
+        while start.elapsed() < delay && !stop.load(Ordering::SeqCst) {
+            tokio::time::sleep(STEP.min(delay - start.elapsed())).await;
+        }
+    }
 }
 
 /// Struct that contains a secret of the client.
@@ -93,3 +192,35 @@ impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Secret<T> {
         T::deserialize(deserializer).map(Secret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut backoff = Backoff::new(time::Duration::from_secs(1), time::Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let mut attempts = 0;
+        let backoff = Backoff::new(
+            time::Duration::from_millis(1),
+            time::Duration::from_millis(4),
+        );
+        let res = retry_with_backoff("test", backoff, &AtomicBool::new(false), || {
+            attempts += 1;
+            let res = if attempts > 3 {
+                Ok(attempts)
+            } else {
+                Err("unreachable backend")
+            };
+            async move { res }
+        })
+        .await;
+        assert_eq!(res, Some(4));
+    }
+}
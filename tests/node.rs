@@ -0,0 +1,221 @@
+//! Runs a whole node the way an application embedding the client would, with its own cache and
+//! backend.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use scalpel::backend::{Backend, PingStore, TlsPayload};
+use scalpel::cache::{CacheInfo, ImageCache, ImageEntry, ImageKey, ShrinkResult};
+use scalpel::config::AppConfig;
+use scalpel::NodeBuilder;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Cache that keeps every image in memory
+#[derive(Clone, Default)]
+struct MemoryCache(Arc<Mutex<HashMap<String, ImageEntry>>>);
+
+#[async_trait]
+impl ImageCache for MemoryCache {
+    async fn load(&self, key: &ImageKey) -> Option<ImageEntry> {
+        self.0.lock().unwrap().get(&key.to_string()).cloned()
+    }
+    async fn save(&self, key: &ImageKey, mime_type: String, data: Bytes) -> bool {
+        let entry = ImageEntry::new_assume(data, mime_type);
+        self.0.lock().unwrap().insert(key.to_string(), entry);
+        true
+    }
+    fn report(&self) -> u64 {
+        let entries = self.0.lock().unwrap();
+        entries.values().map(|x| x.get_bytes().len() as u64).sum()
+    }
+    fn info(&self) -> CacheInfo {
+        CacheInfo::new("memory")
+    }
+    async fn shrink(&self, _: u64) -> Result<ShrinkResult, ()> {
+        Ok(ShrinkResult {
+            size: self.report(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Backend that registers the node with a self-signed certificate, pointing it at a local upstream
+struct LocalBackend {
+    upstream_url: url::Url,
+    info: Mutex<Arc<Option<PingStore>>>,
+    stops: AtomicUsize,
+}
+
+impl LocalBackend {
+    fn new(upstream_url: url::Url) -> Self {
+        Self {
+            upstream_url,
+            info: Mutex::new(Arc::new(None)),
+            stops: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for LocalBackend {
+    async fn ping(
+        &self,
+    ) -> Result<(Option<TlsPayload>, Option<String>), Box<dyn std::error::Error>> {
+        let mut info = self.info.lock().unwrap();
+        if info.is_some() {
+            return Ok((None, None));
+        }
+        let tls = self_signed_payload();
+        let url = self.upstream_url.clone();
+        *info = Arc::new(Some(PingStore::new(
+            tls.clone(),
+            String::new(),
+            url.clone(),
+            url,
+        )));
+        Ok((Some(tls), None))
+    }
+
+    async fn stop(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.stops.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn ping_info(&self) -> Arc<Option<PingStore>> {
+        Arc::clone(&self.info.lock().unwrap())
+    }
+}
+
+/// Generates a self-signed certificate for "localhost"
+fn self_signed_payload() -> TlsPayload {
+    use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509};
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = x509::X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut builder = x509::X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    TlsPayload::new(
+        String::new(),
+        String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap(),
+    )
+}
+
+/// Serves a single PNG image of `body` over plain HTTP, returning the URL of the server
+fn mock_upstream(body: &'static [u8]) -> url::Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).unwrap();
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\
+            Connection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+    });
+    url::Url::parse(&format!("http://{}", addr)).unwrap()
+}
+
+fn test_config(port: u16) -> AppConfig {
+    serde_yaml::from_str(&format!(
+        "
+client_secret: secret
+max_grace_period: -1
+cache_size_mebibytes: 40960
+cache_engine: none
+port: {}
+bind_address: 127.0.0.1
+keep_alive: 30
+enforce_secure_tls: false
+disable_ssl: true
+token_policy: disabled
+worker_threads: 1
+",
+        port
+    ))
+    .unwrap()
+}
+
+#[test]
+fn embedded_node_serves_and_shuts_down() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let cache = MemoryCache::default();
+    let backend = Arc::new(LocalBackend::new(mock_upstream(b"image bytes")));
+
+    actix_web::rt::System::new().block_on(async {
+        let node = NodeBuilder::new(test_config(port))
+            .with_cache(Box::new(cache.clone()))
+            .with_backend(Arc::clone(&backend) as _)
+            .build()
+            .await
+            .unwrap();
+
+        let client = async {
+            let base = format!("http://127.0.0.1:{}", port);
+            // the server is spawned once the backend handed out the certificate
+            let mut ready = false;
+            for _ in 0..100 {
+                if let Ok(res) = reqwest::get(format!("{}/ready", base)).await {
+                    ready = res.status().is_success();
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            assert!(ready, "node never became ready");
+
+            // the image isn't in the cache, so it's fetched from the backend's upstream
+            let res = reqwest::get(format!(
+                "{}/data/8172a46adc798f4f4ace6663322a383e/1.png",
+                base
+            ))
+            .await
+            .unwrap();
+            assert!(res.status().is_success());
+            assert_eq!(res.bytes().await.unwrap(), &b"image bytes"[..]);
+
+            let key = ImageKey::new(
+                "8172a46adc798f4f4ace6663322a383e".to_string(),
+                "1.png".to_string(),
+                false,
+            );
+            // the image is saved after the response, so it may take a moment to show up
+            let saved = tokio::time::timeout(Duration::from_secs(5), async {
+                while cache.load(&key).await.is_none() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await;
+            assert!(saved.is_ok(), "image was never saved to the cache");
+            node.shutdown();
+        };
+        tokio::join!(node.run(), client);
+    });
+
+    assert_eq!(backend.stops.load(Ordering::SeqCst), 1);
+}